@@ -0,0 +1,103 @@
+//! Throughput of the server with different numbers of I/O threads.
+//! Run with `cargo bench --bench io_threads`.
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::{read_full, write_all};
+
+const CLIENTS: usize = 16;
+const REQUESTS_PER_CLIENT: usize = 20_000;
+/// Requests written before reading the replies
+const PIPELINE: usize = 16;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn start_server(io_threads: usize) -> (Child, String) {
+    let addr = format!("127.0.0.1:{}", free_port());
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--bind", &addr, "--io-threads", &io_threads.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Couldn't start server");
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            return (child, addr);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("Server didn't come up");
+}
+
+fn frame(args: &[&str]) -> Vec<u8> {
+    let mut buf = (args.len() as u32).to_le_bytes().to_vec();
+    for arg in args {
+        buf.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+    }
+    let mut frame = (buf.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&buf);
+    frame
+}
+
+fn client(addr: &str, id: usize) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let value = "x".repeat(64);
+    let mut res = vec![0u8; 8192];
+
+    for batch in 0..REQUESTS_PER_CLIENT / PIPELINE {
+        let mut out = vec![];
+        for i in 0..PIPELINE {
+            let key = format!("key:{}:{}", id, (batch * PIPELINE + i) % 1000);
+            if i % 2 == 0 {
+                out.extend(frame(&["set", &key, &value]));
+            } else {
+                out.extend(frame(&["get", &key]));
+            }
+        }
+        assert!(write_all(&mut stream, &out, out.len()));
+        for _ in 0..PIPELINE {
+            assert!(read_full(&mut stream, &mut res, 4));
+            let len = u32::from_le_bytes(res[0..4].try_into().unwrap()) as usize;
+            assert!(read_full(&mut stream, &mut res, len));
+        }
+    }
+}
+
+fn main() {
+    println!("{} clients, {} requests each, pipeline {}", CLIENTS, REQUESTS_PER_CLIENT, PIPELINE);
+    for io_threads in [1, 2, 4, 8] {
+        let (mut server, addr) = start_server(io_threads);
+
+        let start = Instant::now();
+        let handles = (0..CLIENTS)
+            .map(|id| {
+                let addr = addr.clone();
+                thread::spawn(move || client(&addr, id))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        let total = (CLIENTS * REQUESTS_PER_CLIENT) as f64;
+        println!(
+            "io-threads {:>2}: {:>10.0} requests/s ({:.2?})",
+            io_threads,
+            total / elapsed.as_secs_f64(),
+            elapsed
+        );
+
+        let _ = server.kill();
+        let _ = server.wait();
+    }
+}
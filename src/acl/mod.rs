@@ -0,0 +1,463 @@
+//! Users, their passwords and what they may do. Rules use the Redis ACL
+//! syntax, `acl setuser alice on >secret ~cache:* +@read`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::command::{self, Command, CATEGORIES};
+use crate::glob::glob_match;
+use crate::protocol::ErrorCode;
+
+pub const DEFAULT_USER: &str = "default";
+
+pub fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub enabled: bool,
+    /// Any password is accepted
+    pub nopass: bool,
+    /// SHA256 of the passwords, hex encoded
+    pub passwords: BTreeSet<String>,
+    pub commands: BTreeSet<&'static str>,
+    pub key_patterns: Vec<String>,
+    /// Channels the user may publish to and subscribe to, checked when the
+    /// command runs. Keyspace notifications are published by the server,
+    /// so they reach anyone allowed to subscribe to their channel, like
+    /// `&__keyspace@0__:*`, whatever keys they are about.
+    pub channel_patterns: Vec<String>,
+}
+
+impl User {
+    /// A new user can't do anything until rules are added
+    pub fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: BTreeSet::new(),
+            key_patterns: vec![],
+            channel_patterns: vec![],
+        }
+    }
+
+    /// The `default` user of a fresh server: anyone may do anything
+    pub fn new_default() -> User {
+        let mut user = User::new(DEFAULT_USER);
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply(rule).expect("valid rule");
+        }
+        user
+    }
+
+    /// Apply a single rule, see `acl setuser`
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lower = rule.to_ascii_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allchannels" => self.channel_patterns = vec!["*".to_string()],
+            "resetchannels" => self.channel_patterns.clear(),
+            "allcommands" => self.apply("+@all")?,
+            "nocommands" => self.apply("-@all")?,
+            "reset" => *self = User::new(&self.name),
+            _ => return self.apply_prefixed(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_prefixed(&mut self, rule: &str) -> Result<(), String> {
+        let Some(prefix) = rule.chars().next() else {
+            return Err("Syntax error".to_string());
+        };
+        let arg = &rule[prefix.len_utf8()..];
+        match prefix {
+            '>' => {
+                self.passwords.insert(hash_password(arg));
+                self.nopass = false;
+            }
+            '<' => {
+                self.passwords.remove(&hash_password(arg));
+            }
+            '#' | '!' => {
+                if arg.len() != 64 || !arg.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+                }
+                let hash = arg.to_ascii_lowercase();
+                if prefix == '#' {
+                    self.passwords.insert(hash);
+                    self.nopass = false;
+                } else {
+                    self.passwords.remove(&hash);
+                }
+            }
+            '~' => self.key_patterns.push(arg.to_string()),
+            '&' => self.channel_patterns.push(arg.to_string()),
+            '+' | '-' => {
+                let cmds = match arg.strip_prefix('@') {
+                    Some(category) => commands_in(category)?,
+                    None => vec![command::lookup(arg).ok_or("Unknown command")?],
+                };
+                for cmd in cmds {
+                    if prefix == '+' {
+                        self.commands.insert(cmd.name);
+                    } else {
+                        self.commands.remove(cmd.name);
+                    }
+                }
+            }
+            _ => return Err("Syntax error".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+
+    pub fn can_run(&self, cmd: &Command) -> bool {
+        self.commands.contains(cmd.name)
+    }
+
+    pub fn can_access_key(&self, key: &[u8]) -> bool {
+        self.key_patterns.iter().any(|p| glob_match(p.as_bytes(), key))
+    }
+
+    pub fn can_access_channel(&self, channel: &[u8]) -> bool {
+        self.channel_patterns.iter().any(|p| glob_match(p.as_bytes(), channel))
+    }
+
+    /// Flags as reported by `acl getuser`
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        if self.key_patterns.iter().any(|p| p == "*") {
+            flags.push("allkeys");
+        }
+        if self.channel_patterns.iter().any(|p| p == "*") {
+            flags.push("allchannels");
+        }
+        if self.commands.len() == command::all().len() {
+            flags.push("allcommands");
+        }
+        flags
+    }
+
+    /// Allowed commands in rule form
+    pub fn describe_commands(&self) -> String {
+        if self.commands.len() == command::all().len() {
+            return "+@all".to_string();
+        }
+        let mut rules = vec!["-@all".to_string()];
+        rules.extend(self.commands.iter().map(|name| format!("+{}", name)));
+        rules.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.key_patterns.iter().map(|p| format!("~{}", p)).collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        self.channel_patterns.iter().map(|p| format!("&{}", p)).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Rules that recreate this user, as in `acl list` and the ACL file
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name), self.flags()[0].to_string()];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|h| format!("#{}", h)));
+        if !self.key_patterns.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        if self.channel_patterns.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+}
+
+/// Commands in an ACL category, `all` included
+fn commands_in(category: &str) -> Result<Vec<&'static Command>, String> {
+    let category = category.to_ascii_lowercase();
+    if category != "all" && !CATEGORIES.contains(&category.as_str()) {
+        return Err("Unknown command category".to_string());
+    }
+    Ok(command::all()
+        .iter()
+        .filter(|cmd| category == "all" || cmd.categories().contains(&category.as_str()))
+        .collect())
+}
+
+/// All users, and the file they are saved to
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    file: Option<PathBuf>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl::new()
+    }
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        let mut users = BTreeMap::new();
+        users.insert(DEFAULT_USER.to_string(), User::new_default());
+        Acl { users, file: None }
+    }
+
+    /// Require `password` from the default user
+    pub fn set_requirepass(&mut self, password: &str) {
+        let user = self.users.get_mut(DEFAULT_USER).expect("default user exists");
+        user.apply("resetpass").expect("valid rule");
+        if !password.is_empty() {
+            user.apply(&format!(">{}", password)).expect("valid rule");
+        } else {
+            user.apply("nopass").expect("valid rule");
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    /// Create or modify `name`, all rules are applied or none
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut user = self.users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Returns false if there was no such user
+    pub fn del_user(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    /// Connections start out logged in as `default` unless it needs a
+    /// password
+    pub fn implicit_user(&self) -> Option<&str> {
+        let user = self.users.get(DEFAULT_USER)?;
+        (user.enabled && user.nopass).then_some(DEFAULT_USER)
+    }
+
+    /// Returns the user name on success
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<String> {
+        let user = self.users.get(name)?;
+        (user.enabled && user.check_password(password)).then(|| user.name.clone())
+    }
+
+    /// May `user` run `args`? `None` means not authenticated.
+    pub fn check(&self, user: Option<&str>, cmd: &Command, args: &[Vec<u8>]) -> Result<(), (ErrorCode, String)> {
+        if matches!(cmd.name, "auth" | "hello") {
+            return Ok(());
+        }
+        let Some(user) = user.or(self.implicit_user()).and_then(|name| self.users.get(name)) else {
+            return Err((ErrorCode::NoAuth, "Authentication required.".to_string()));
+        };
+        if !user.can_run(cmd) {
+            let msg = format!("User {} has no permissions to run the '{}' command", user.name, cmd.name);
+            return Err((ErrorCode::NoPerm, msg));
+        }
+        if cmd.key_indices(args).into_iter().any(|i| !user.can_access_key(&args[i])) {
+            return Err((ErrorCode::NoPerm, "No permissions to access a key".to_string()));
+        }
+        // Patterns are only allowed if the user has the very same one
+        let channels_ok = match cmd.name {
+            "publish" => user.can_access_channel(&args[1]),
+            "subscribe" => args[1..].iter().all(|c| user.can_access_channel(c)),
+            "psubscribe" => {
+                args[1..].iter().all(|p| user.channel_patterns.iter().any(|q| q == "*" || q.as_bytes() == p))
+            }
+            _ => true,
+        };
+        if !channels_ok {
+            return Err((ErrorCode::NoPerm, "No permissions to access a channel".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn file(&self) -> Option<&PathBuf> {
+        self.file.as_ref()
+    }
+
+    pub fn set_file(&mut self, path: Option<PathBuf>) {
+        self.file = path;
+    }
+
+    /// Replace all users with those in the ACL file. Nothing changes if the
+    /// file has errors.
+    pub fn load(&mut self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("There is no configured ACL file")?;
+        let text = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+
+        let mut users = BTreeMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            if words.len() < 2 || words[0] != "user" {
+                return Err(format!("{}:{}: should start with user keyword", path.display(), n + 1));
+            }
+            let mut user = User::new(words[1]);
+            for rule in &words[2..] {
+                user.apply(rule)
+                    .map_err(|e| format!("{}:{}: {}. {}", path.display(), n + 1, rule, e))?;
+            }
+            users.insert(user.name.clone(), user);
+        }
+        users
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(User::new_default);
+        self.users = users;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("There is no configured ACL file")?;
+        let text = self.users.values().map(|u| u.describe() + "\n").collect::<String>();
+        // Write a temporary file first so a failed write can't lose the
+        // previous users
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e: io::Error| format!("Can't write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn request(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut acl = Acl::new();
+        acl.set_user("alice", &args(&["on", ">secret", "~cache:*", "+@read", "-ttl"])).unwrap();
+        let alice = acl.get("alice").unwrap();
+        assert!(alice.check_password("secret"));
+        assert!(!alice.check_password("guess"));
+        assert!(alice.can_run(command::lookup("get").unwrap()));
+        assert!(!alice.can_run(command::lookup("set").unwrap()));
+        assert!(!alice.can_run(command::lookup("ttl").unwrap()));
+        assert!(alice.can_access_key(b"cache:1"));
+        assert!(!alice.can_access_key(b"secret:1"));
+        // Every read command but ttl, in name order
+        let mut read = command::all()
+            .iter()
+            .filter(|cmd| cmd.has_flag(command::CMD_READONLY) && cmd.name != "ttl")
+            .map(|cmd| format!("+{}", cmd.name))
+            .collect::<Vec<String>>();
+        read.sort();
+        let read = format!("-@all {}", read.join(" "));
+        assert_eq!(alice.describe_commands(), read);
+
+        assert!(acl.set_user("alice", &args(&["+nosuch"])).is_err());
+        assert!(acl.set_user("alice", &args(&["+@nosuch"])).is_err());
+        assert!(acl.set_user("alice", &args(&["#abc"])).is_err());
+        assert_eq!(acl.get("alice").unwrap().describe_commands(), read);
+
+        assert_eq!(acl.get(DEFAULT_USER).unwrap().describe(), "user default on nopass ~* &* +@all");
+    }
+
+    #[test]
+    fn test_check() {
+        let mut acl = Acl::new();
+        let get = command::lookup("get").unwrap();
+        let auth = command::lookup("auth").unwrap();
+        let read = request(&["get", "k"]);
+        assert_eq!(acl.check(None, get, &read), Ok(()));
+
+        acl.set_requirepass("pw");
+        assert_eq!(acl.check(None, get, &read).unwrap_err().0, ErrorCode::NoAuth);
+        assert_eq!(acl.check(None, auth, &request(&["auth", "pw"])), Ok(()));
+        assert_eq!(acl.authenticate(DEFAULT_USER, "pw").as_deref(), Some(DEFAULT_USER));
+        assert_eq!(acl.authenticate(DEFAULT_USER, "nope"), None);
+
+        acl.set_user("bob", &args(&["on", "nopass", "~bob:*", "+get"])).unwrap();
+        assert_eq!(acl.check(Some("bob"), get, &request(&["get", "bob:1"])), Ok(()));
+        let (code, msg) = acl.check(Some("bob"), get, &read).unwrap_err();
+        assert_eq!((code, msg.as_str()), (ErrorCode::NoPerm, "No permissions to access a key"));
+        let set = command::lookup("set").unwrap();
+        assert_eq!(acl.check(Some("bob"), set, &request(&["set", "bob:1", "v"])).unwrap_err().0, ErrorCode::NoPerm);
+
+        acl.set_user("bob", &args(&["&news.*", "+@pubsub"])).unwrap();
+        let publish = command::lookup("publish").unwrap();
+        let psubscribe = command::lookup("psubscribe").unwrap();
+        assert_eq!(acl.check(Some("bob"), publish, &request(&["publish", "news.today", "hi"])), Ok(()));
+        let (code, msg) = acl.check(Some("bob"), publish, &request(&["publish", "sport", "hi"])).unwrap_err();
+        assert_eq!((code, msg.as_str()), (ErrorCode::NoPerm, "No permissions to access a channel"));
+        assert_eq!(acl.check(Some("bob"), psubscribe, &request(&["psubscribe", "news.*"])), Ok(()));
+        assert!(acl.check(Some("bob"), psubscribe, &request(&["psubscribe", "news.t*"])).is_err());
+
+        acl.set_user("dave", &args(&["on", "nopass", "~app:*", "+migrate"])).unwrap();
+        let migrate = command::lookup("migrate").unwrap();
+        let one = |key: &str| request(&["migrate", "127.0.0.1", "7000", key, "0", "100"]);
+        assert_eq!(acl.check(Some("dave"), migrate, &one("app:1")), Ok(()));
+        assert_eq!(acl.check(Some("dave"), migrate, &one("other")).unwrap_err().0, ErrorCode::NoPerm);
+        let many = request(&["migrate", "127.0.0.1", "7000", "", "0", "100", "KEYS", "app:1", "other"]);
+        assert_eq!(acl.check(Some("dave"), migrate, &many).unwrap_err().0, ErrorCode::NoPerm);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("acl-test-{}.acl", std::process::id()));
+        let mut acl = Acl::new();
+        acl.set_file(Some(path.clone()));
+        acl.set_user("carol", &args(&["on", ">pw", "~*", "&news.*", "+@string"])).unwrap();
+        acl.save().unwrap();
+
+        let mut loaded = Acl::new();
+        loaded.set_file(Some(path.clone()));
+        loaded.load().unwrap();
+        assert_eq!(loaded.get("carol"), acl.get("carol"));
+        assert!(loaded.get("carol").unwrap().can_access_channel(b"news.today"));
+
+        fs::write(&path, "user dave on +bogus\n").unwrap();
+        assert!(loaded.load().is_err());
+        assert!(loaded.get("carol").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
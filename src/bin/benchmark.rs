@@ -0,0 +1,369 @@
+//! Load generator in the spirit of redis-benchmark: a number of clients
+//! send a weighted mix of commands, optionally pipelined and on random
+//! keys, then throughput and latency percentiles are reported per command.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redis::client::{encode_request, Client, Pipeline, Reply, HEADER_LEN};
+use redis::connection::MAX_MSG;
+
+/// Commands that can be part of the mix
+const COMMANDS: [&str; 9] = ["ping", "set", "get", "del", "exists", "pfadd", "pfcount", "setbit", "getbit"];
+
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    /// Keys are picked at random among this many, 0 uses a single key
+    keyspace: usize,
+    data_size: usize,
+    /// Commands and their weights
+    mix: Vec<(String, u32)>,
+}
+
+fn usage() {
+    println!("Usage: benchmark [-h host] [-p port] [-c clients] [-n requests] [-P pipeline] [-r keyspace] [-d size] [-t mix]");
+    println!("  -h <host>        Server hostname (default: 127.0.0.1)");
+    println!("  -p <port>        Server port (default: 1234)");
+    println!("  -c <clients>     Number of parallel connections (default: 50)");
+    println!("  -n <requests>    Total number of requests (default: 100000)");
+    println!("  -P <pipeline>    Requests sent at once by each client (default: 1)");
+    println!("  -r <keyspace>    Use random keys among <keyspace> of them instead of a");
+    println!("                   single one");
+    println!("  -d <size>        Size in bytes of SET values (default: 3)");
+    println!("  -t <mix>         Comma separated commands, each optionally weighted as");
+    println!("                   command:weight (default: set,get)");
+    println!("Commands: {}", COMMANDS.join(", "));
+}
+
+/// `set:3,get:7` or `set,get`, with weights defaulting to 1
+fn parse_mix(s: &str) -> Option<Vec<(String, u32)>> {
+    let mut mix = vec![];
+    for item in s.split(',') {
+        let (name, weight) = match item.split_once(':') {
+            Some((name, weight)) => (name, weight.parse().ok().filter(|w| *w > 0)?),
+            None => (item, 1),
+        };
+        let name = name.to_ascii_lowercase();
+        if !COMMANDS.contains(&name.as_str()) || mix.iter().any(|(n, _)| *n == name) {
+            return None;
+        }
+        mix.push((name, weight));
+    }
+    Some(mix)
+}
+
+fn parse_options() -> Option<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 1234,
+        clients: 50,
+        requests: 100_000,
+        pipeline: 1,
+        keyspace: 0,
+        data_size: 3,
+        mix: parse_mix("set,get").unwrap(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => options.host = args.next()?,
+            "-p" => options.port = args.next()?.parse().ok()?,
+            "-c" => options.clients = args.next()?.parse().ok().filter(|n| *n > 0)?,
+            "-n" => options.requests = args.next()?.parse().ok().filter(|n| *n > 0)?,
+            "-P" => options.pipeline = args.next()?.parse().ok().filter(|n| *n > 0)?,
+            "-r" => options.keyspace = args.next()?.parse().ok()?,
+            "-d" => options.data_size = args.next()?.parse().ok()?,
+            "-t" => options.mix = parse_mix(&args.next()?)?,
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+/// Largest `-d` whose SET still fits into one request
+fn max_data_size() -> usize {
+    let mut frame = vec![];
+    encode_request(&["set", &format!("key:{:012}", 0), ""], &mut frame).unwrap();
+    MAX_MSG - (frame.len() - HEADER_LEN)
+}
+
+/// Xorshift, giving the same numbers for the same seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `0..n`, `n` must be > 0
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Builds the requests of the mix
+struct Generator {
+    rng: Rng,
+    mix: Vec<(String, u32)>,
+    total_weight: u32,
+    keyspace: usize,
+    value: String,
+}
+
+impl Generator {
+    fn new(options: &Options, seed: u64) -> Generator {
+        Generator {
+            rng: Rng::new(seed),
+            mix: options.mix.clone(),
+            total_weight: options.mix.iter().map(|(_, w)| w).sum(),
+            keyspace: options.keyspace,
+            value: "x".repeat(options.data_size),
+        }
+    }
+
+    fn random(&mut self) -> usize {
+        if self.keyspace == 0 { 0 } else { self.rng.below(self.keyspace) }
+    }
+
+    /// Index of the command in the mix and its arguments
+    fn next(&mut self) -> (usize, Vec<String>) {
+        let mut pick = self.rng.below(self.total_weight as usize) as u32;
+        let index = self
+            .mix
+            .iter()
+            .position(|(_, weight)| {
+                if pick < *weight {
+                    return true;
+                }
+                pick -= weight;
+                false
+            })
+            .unwrap();
+        let key = format!("key:{:012}", self.random());
+        let offset = self.random().to_string();
+        let args = match self.mix[index].0.as_str() {
+            "ping" => vec!["ping".to_string()],
+            "set" => vec!["set".to_string(), key, self.value.clone()],
+            "pfadd" => vec!["pfadd".to_string(), "hll".to_string(), key],
+            "pfcount" => vec!["pfcount".to_string(), "hll".to_string()],
+            "setbit" => vec!["setbit".to_string(), "bits".to_string(), offset, "1".to_string()],
+            "getbit" => vec!["getbit".to_string(), "bits".to_string(), offset],
+            name => vec![name.to_string(), key],
+        };
+        (index, args)
+    }
+}
+
+/// Latencies in microseconds and errors of one command
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<u64>,
+    errors: usize,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+}
+
+/// The latency that a fraction `p` of the sorted `latencies` don't exceed
+fn percentile(latencies: &[u64], p: f64) -> u64 {
+    if latencies.is_empty() {
+        return 0;
+    }
+    let rank = (p * latencies.len() as f64).ceil() as usize;
+    latencies[rank.clamp(1, latencies.len()) - 1]
+}
+
+/// Send batches of requests until `remaining` runs out. Each request of a
+/// batch is counted with the time the whole batch took.
+fn run_client(mut client: Client, mut generator: Generator, pipeline: usize, remaining: Arc<AtomicUsize>) -> Vec<Stats> {
+    let mut stats = generator.mix.iter().map(|_| Stats::default()).collect::<Vec<_>>();
+    loop {
+        let claimed = match remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            (n > 0).then(|| n - pipeline.min(n))
+        }) {
+            Ok(n) => pipeline.min(n),
+            Err(_) => return stats,
+        };
+        let mut batch = Pipeline::new();
+        let mut commands = Vec::with_capacity(claimed);
+        for _ in 0..claimed {
+            let (index, args) = generator.next();
+            batch.cmd(&args);
+            commands.push(index);
+        }
+        let start = Instant::now();
+        let replies = match client.pipeline(&batch) {
+            Ok(replies) => replies,
+            Err(e) => {
+                eprintln!("Connection error: {}", e);
+                return stats;
+            }
+        };
+        let micros = start.elapsed().as_micros() as u64;
+        for (index, reply) in commands.into_iter().zip(replies) {
+            stats[index].latencies.push(micros);
+            if matches!(reply, Reply::Error { .. }) {
+                stats[index].errors += 1;
+            }
+        }
+    }
+}
+
+fn print_row(name: &str, stats: &Stats, elapsed: Duration) {
+    let ms = |micros: u64| micros as f64 / 1000.0;
+    let count = stats.latencies.len();
+    let avg = stats.latencies.iter().sum::<u64>() as f64 / count.max(1) as f64 / 1000.0;
+    println!(
+        "{:<10} {:>10} {:>12.2} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8}",
+        name,
+        count,
+        count as f64 / elapsed.as_secs_f64(),
+        avg,
+        ms(percentile(&stats.latencies, 0.5)),
+        ms(percentile(&stats.latencies, 0.99)),
+        ms(percentile(&stats.latencies, 0.999)),
+        ms(stats.latencies.last().copied().unwrap_or(0)),
+        stats.errors,
+    );
+}
+
+fn main() {
+    let Some(options) = parse_options() else {
+        usage();
+        std::process::exit(1);
+    };
+    if options.data_size > max_data_size() {
+        let max = max_data_size();
+        eprintln!("-d {} doesn't fit into a request, values can be at most {} bytes", options.data_size, max);
+        std::process::exit(1);
+    }
+    let addr = format!("{}:{}", options.host, options.port);
+
+    let mut clients = vec![];
+    for _ in 0..options.clients {
+        match Client::connect(&addr) {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                eprintln!("Couldn't connect to {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut seeds = Rng::new(now.as_nanos() as u64);
+    let start = Instant::now();
+    let threads = clients
+        .into_iter()
+        .map(|client| {
+            let generator = Generator::new(&options, seeds.next_u64());
+            let (pipeline, remaining) = (options.pipeline, remaining.clone());
+            thread::spawn(move || run_client(client, generator, pipeline, remaining))
+        })
+        .collect::<Vec<_>>();
+    let mut stats = BTreeMap::new();
+    for thread in threads {
+        for (index, client_stats) in thread.join().unwrap().into_iter().enumerate() {
+            stats.entry(index).or_insert_with(Stats::default).merge(client_stats);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    // Less than asked for if connections failed along the way
+    let completed = stats.values().map(|s| s.latencies.len()).sum::<usize>();
+    let mix = options.mix.iter().map(|(name, weight)| format!("{}:{}", name, weight)).collect::<Vec<_>>();
+    println!(
+        "{} of {} requests of {} completed in {:.2} seconds, {} clients, pipeline {}, keyspace {}, {} byte values",
+        completed,
+        options.requests,
+        mix.join(","),
+        elapsed.as_secs_f64(),
+        options.clients,
+        options.pipeline,
+        options.keyspace,
+        options.data_size,
+    );
+    println!(
+        "{:<10} {:>10} {:>12} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "command", "requests", "rps", "avg", "p50", "p99", "p999", "max", "errors"
+    );
+    let mut all = Stats::default();
+    for (index, mut stats) in stats {
+        stats.latencies.sort_unstable();
+        print_row(&options.mix[index].0.to_uppercase(), &stats, elapsed);
+        all.merge(stats);
+    }
+    all.latencies.sort_unstable();
+    print_row("ALL", &all, elapsed);
+    println!("Latencies in milliseconds");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mix() {
+        assert_eq!(parse_mix("set:3,GET").unwrap(), [("set".to_string(), 3), ("get".to_string(), 1)]);
+        for bad in ["", "set,set", "bogus", "set:0", "set:x"] {
+            assert!(parse_mix(bad).is_none(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_generator() {
+        let options = Options {
+            host: String::new(),
+            port: 0,
+            clients: 1,
+            requests: 1,
+            pipeline: 1,
+            keyspace: 10,
+            data_size: 2,
+            mix: parse_mix("set:3,getbit").unwrap(),
+        };
+        let mut generator = Generator::new(&options, 42);
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            let (index, args) = generator.next();
+            counts[index] += 1;
+            match args[0].as_str() {
+                "set" => assert!(args[1].len() == 16 && args[1].as_str() < "key:000000000010" && args[2] == "xx"),
+                _ => assert!(args[2].parse::<usize>().unwrap() < 10),
+            }
+        }
+        assert!(counts[0] > 2 * counts[1], "{:?}", counts);
+        // The count of arguments, then each with its length
+        assert_eq!(MAX_MSG - max_data_size(), 4 + (4 + 3) + (4 + 16) + 4);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=1000).collect::<Vec<u64>>();
+        assert_eq!(percentile(&latencies, 0.5), 500);
+        assert_eq!(percentile(&latencies, 0.99), 990);
+        assert_eq!(percentile(&latencies, 0.999), 999);
+        assert_eq!(percentile(&latencies, 0.0), 1);
+        assert_eq!(percentile(&[], 0.5), 0);
+    }
+}
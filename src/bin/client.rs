@@ -1,70 +1,419 @@
-use std::{net::{TcpStream}};
-use std::io;
-use redis::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::Path;
 
-//use redis::{send_req,read_res};
+use redis::client::{self, Client, Reply};
+use redis::tls;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 
-fn main() {
-    let client = TcpStream::connect("0.0.0.0:1234");
-    if let Err(ref e) = client {
-        println!("Couldn't connect: {}", e);
-        return;
+const HISTORY_FILE: &str = ".rediscli_history";
+
+struct Options {
+    host: String,
+    port: u16,
+    /// Print values as they are instead of quoted and escaped
+    raw: bool,
+    file: Option<String>,
+    /// Command given on the command line, run once
+    command: Vec<String>,
+    tls: bool,
+    cacert: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+}
+
+fn usage() {
+    println!("Usage: client [-h host] [-p port] [--raw] [-f file] [--tls --cacert file [--cert file --key file]] [cmd [arg ...]]");
+    println!("  -h <host>        Server hostname (default: 127.0.0.1)");
+    println!("  -p <port>        Server port (default: 1234)");
+    println!("  --raw            Print raw replies, the default when stdout isn't a tty");
+    println!("  -f <file>        Run the commands in <file>, one per line");
+    println!("  --tls            Connect over TLS");
+    println!("  --cacert <file>  CA the server certificate is checked against");
+    println!("  --cert <file>    Client certificate to present to the server");
+    println!("  --key <file>     Private key of the client certificate");
+    println!("Without a command, commands are read from stdin if it's piped,");
+    println!("otherwise an interactive prompt is started.");
+}
+
+fn parse_options() -> Option<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 1234,
+        raw: !io::stdout().is_terminal(),
+        file: None,
+        command: vec![],
+        tls: false,
+        cacert: None,
+        cert: None,
+        key: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => options.host = args.next()?,
+            "-p" => options.port = args.next()?.parse().ok()?,
+            "-f" => options.file = Some(args.next()?),
+            "--raw" => options.raw = true,
+            "--tls" => options.tls = true,
+            "--cacert" => options.cacert = Some(args.next()?),
+            "--cert" => options.cert = Some(args.next()?),
+            "--key" => options.key = Some(args.next()?),
+            "--help" => return None,
+            _ => {
+                options.command.push(arg);
+                options.command.extend(args.by_ref());
+            }
+        }
     }
-    let mut client = client.unwrap();
+    Some(options)
+}
+
+/// Split a line into arguments the way a shell would: whitespace separates
+/// arguments, "double quotes" understand \n \r \t \b \a \\ \" and \xHH
+/// escapes, 'single quotes' only \'
+fn split_args(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
 
     loop {
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(args);
+        }
+
+        let mut arg = vec![];
+        let mut utf8 = [0u8; 4];
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push(b'\n'),
+                            Some('r') => arg.push(b'\r'),
+                            Some('t') => arg.push(b'\t'),
+                            Some('b') => arg.push(8),
+                            Some('a') => arg.push(7),
+                            Some('x') => {
+                                let hex = [chars.next(), chars.next()];
+                                let byte = match hex {
+                                    [Some(h), Some(l)] => {
+                                        u8::from_str_radix(&format!("{}{}", h, l), 16).ok()
+                                    }
+                                    _ => None,
+                                };
+                                arg.push(byte.ok_or("Invalid \\x escape")?);
+                            }
+                            Some(c) => arg.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()),
+                            None => return Err("Unbalanced quotes".to_string()),
+                        },
+                        Some(c) => arg.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()),
+                        None => return Err("Unbalanced quotes".to_string()),
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            arg.push(b'\'');
+                        }
+                        Some(c) => arg.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()),
+                        None => return Err("Unbalanced quotes".to_string()),
+                    }
+                },
+                c if c.is_whitespace() => break,
+                c => arg.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes()),
+            }
+            // A closing quote must be followed by a space or the end
+            if matches!(c, '"' | '\'') && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("Closing quote must be followed by a space".to_string());
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Quote `bytes` so that `split_args` would read it back unchanged
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            7 => out.push_str("\\a"),
+            8 => out.push_str("\\b"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Render a reply like redis-cli, nested arrays are numbered and indented.
+/// Raw strings are kept as they are, which may not be UTF-8.
+fn format_reply(reply: &Reply, raw: bool) -> Vec<u8> {
+    let mut out = vec![];
+    write_reply(&mut out, reply, raw, 0);
+    out
+}
 
-        if input.trim() == "exit" {
-            break;
+fn write_reply(out: &mut Vec<u8>, reply: &Reply, raw: bool, indent: usize) {
+    match reply {
+        Reply::Str(v) if raw => out.extend_from_slice(v),
+        Reply::Str(v) => out.extend_from_slice(quote(v).as_bytes()),
+        Reply::Int(n) if raw => out.extend_from_slice(n.to_string().as_bytes()),
+        Reply::Int(n) => out.extend_from_slice(format!("(integer) {}", n).as_bytes()),
+        Reply::Nil if raw => {}
+        Reply::Nil => out.extend_from_slice(b"(nil)"),
+        Reply::Error { code, message } if raw => out.extend_from_slice(format!("{} {}", code, message).as_bytes()),
+        Reply::Error { code, message } => out.extend_from_slice(format!("(error) {} {}", code, message).as_bytes()),
+        Reply::Array(items) | Reply::Push(items) if raw => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                write_reply(out, item, raw, 0);
+            }
+        }
+        Reply::Array(items) | Reply::Push(items) if items.is_empty() => out.extend_from_slice(b"(empty array)"),
+        Reply::Array(items) | Reply::Push(items) => {
+            let width = items.len().to_string().len();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                    out.resize(out.len() + indent, b' ');
+                }
+                let prefix = format!("{:>width$}) ", i + 1);
+                out.extend_from_slice(prefix.as_bytes());
+                write_reply(out, item, raw, indent + prefix.len());
+            }
         }
+    }
+}
 
-        if !send_req(&mut client, &input) {
-            return;
+struct Cli {
+    options: Options,
+    /// Dropped after a connection error, reconnected on the next command
+    client: Option<Client>,
+}
+
+impl Cli {
+    fn addr(&self) -> String {
+        format!("{}:{}", self.options.host, self.options.port)
+    }
+
+    fn open(&self) -> client::Result<Client> {
+        if !self.options.tls {
+            return Client::connect(self.addr());
         }
+        let Some(cacert) = &self.options.cacert else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--tls needs --cacert").into());
+        };
+        let identity = match (&self.options.cert, &self.options.key) {
+            (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+            _ => None,
+        };
+        let config = tls::client_config(Path::new(cacert), identity)?;
+        Client::connect_tls(self.addr(), &self.options.host, config)
+    }
 
-        if !read_res(&mut client) {
-            return;
+    fn connect(&mut self) -> bool {
+        if self.client.is_none() {
+            match self.open() {
+                Ok(client) => self.client = Some(client),
+                Err(e) => {
+                    eprintln!("Could not connect to {}: {}", self.addr(), e);
+                    return false;
+                }
+            }
         }
+        true
+    }
 
-        println!("Response received");
+    /// Run one command and print its reply, returns false on failure
+    fn run(&mut self, args: &[Vec<u8>]) -> bool {
+        if !self.connect() {
+            return false;
+        }
+        match self.client.as_mut().unwrap().command(args) {
+            Ok(reply) => {
+                let mut line = format_reply(&reply, self.options.raw);
+                line.push(b'\n');
+                let mut stdout = io::stdout().lock();
+                if let Err(e) = stdout.write_all(&line).and_then(|_| stdout.flush()) {
+                    eprintln!("{}", e);
+                }
+                !matches!(reply, Reply::Error { .. })
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                self.client = None;
+                false
+            }
+        }
     }
 
-    // let query_list = ["hello1", "hello2", "hello3"];
-    //
-    // for query in query_list {
-    //    if !send_req(&mut client, query) {
-    //        return;
-    //    }
-    // }
-    //
-    // for _ in 0..3 {
-    //     if !read_res(&mut client) {
-    //         return;
-    //     }
-    // }
-
-
-
-    // let mut client = client.ok().unwrap();
-    // println!("Connected to the server!");
-    // let succ = query(&mut client, "hello1");
-    // if !succ {
-    //     return;
-    // }
-    // let succ = query(&mut client, "hello2");
-    // if !succ {
-    //     return;
-    // }
-    // let succ = query(&mut client, "hello3");
-    // if !succ {
-    //     return;
-    //}
-    // client.write(b"hello").unwrap();
-    //
-    // let mut buf: [u8; 128] = [0; 128];
-    // let n = client.read(&mut buf).unwrap();
-    // println!("Read {} bytes", n);
-    // println!("Server says {}", String::from_utf8_lossy(&buf));
+    /// Run a command per line of `input`, returns false if any failed
+    fn run_lines<R: BufRead>(&mut self, input: R) -> bool {
+        let mut ok = true;
+        for line in input.lines() {
+            let Ok(line) = line else {
+                return false;
+            };
+            match split_args(&line) {
+                Ok(args) if args.is_empty() => {}
+                Ok(args) => ok &= self.run(&args),
+                Err(e) => {
+                    eprintln!("Invalid argument(s): {}", e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    fn repl(&mut self) {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                eprintln!("Couldn't set up line editing: {}", e);
+                return;
+            }
+        };
+        let history = std::env::var("HOME")
+            .ok()
+            .map(|home| std::path::Path::new(&home).join(HISTORY_FILE));
+        if let Some(ref history) = history {
+            let _ = editor.load_history(history);
+        }
+
+        self.connect();
+        loop {
+            let prompt = match self.client {
+                Some(_) => format!("{}> ", self.addr()),
+                None => "not connected> ".to_string(),
+            };
+            let line = match editor.readline(&prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break;
+                }
+            };
+
+            let args = match split_args(&line) {
+                Ok(args) if args.is_empty() => continue,
+                Ok(args) => args,
+                Err(e) => {
+                    println!("Invalid argument(s): {}", e);
+                    continue;
+                }
+            };
+            let _ = editor.add_history_entry(line.as_str());
+
+            if args.len() == 1 && matches!(args[0].to_ascii_lowercase().as_slice(), b"exit" | b"quit") {
+                break;
+            }
+            self.run(&args);
+        }
+
+        if let Some(ref history) = history {
+            let _ = editor.save_history(history);
+        }
+    }
+}
+
+fn main() {
+    let Some(options) = parse_options() else {
+        usage();
+        std::process::exit(1);
+    };
+
+    let mut cli = Cli { options, client: None };
+
+    let ok = if !cli.options.command.is_empty() {
+        let args = cli.options.command.iter().map(|a| a.as_bytes().to_vec()).collect::<Vec<_>>();
+        cli.run(&args)
+    } else if let Some(path) = cli.options.file.clone() {
+        match File::open(&path) {
+            Ok(file) => cli.run_lines(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("Couldn't open {}: {}", path, e);
+                false
+            }
+        }
+    } else if !io::stdin().is_terminal() {
+        cli.run_lines(io::stdin().lock())
+    } else {
+        cli.repl();
+        true
+    };
+
+    if !ok {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Vec<String> {
+        split_args(line)
+            .unwrap()
+            .into_iter()
+            .map(|a| String::from_utf8(a).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split("  set  key value "), ["set", "key", "value"]);
+        assert_eq!(split(r#"set key "hello world""#), ["set", "key", "hello world"]);
+        assert_eq!(split(r#"set "a\"b\n\x41" 'it\'s "x"'"#), ["set", "a\"b\nA", "it's \"x\""]);
+        assert_eq!(split(r#"set "" ''"#), ["set", "", ""]);
+        assert!(split("").is_empty());
+
+        assert!(split_args(r#"set "unbalanced"#).is_err());
+        assert!(split_args(r#"set "a"b"#).is_err());
+        assert!(split_args(r#"set "\xZZ""#).is_err());
+    }
+
+    #[test]
+    fn test_quote_roundtrip() {
+        let value = b"a \"quoted\"\n\t\\ \x00\xff".to_vec();
+        let line = format!("get {}", quote(&value));
+        assert_eq!(split_args(&line).unwrap()[1], value);
+    }
+
+    #[test]
+    fn test_format_reply() {
+        let value = Reply::Str(b"hello world".to_vec());
+        assert_eq!(format_reply(&value, false), b"\"hello world\"");
+        assert_eq!(format_reply(&value, true), b"hello world");
+        assert_eq!(format_reply(&Reply::Str(b"\x80\x00a".to_vec()), true), b"\x80\x00a");
+        assert_eq!(format_reply(&Reply::Nil, false), b"(nil)");
+        assert_eq!(format_reply(&Reply::Int(3), false), b"(integer) 3");
+        let error = Reply::Error { code: "ERR".to_string(), message: "oops".to_string() };
+        assert_eq!(format_reply(&error, false), b"(error) ERR oops");
+
+        let mut items = vec![Reply::Int(1); 9];
+        items.push(Reply::Array(vec![Reply::Str(b"a".to_vec()), Reply::Array(vec![])]));
+        let nested = Reply::Array(items);
+        let formatted = String::from_utf8(format_reply(&nested, false)).unwrap();
+        let lines = formatted.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], " 1) (integer) 1");
+        assert_eq!(lines[9], "10) 1) \"a\"");
+        assert_eq!(lines[10], "    2) (empty array)");
+        assert_eq!(format_reply(&nested, true).lines().count(), 10);
+    }
 }
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use redis::log::{self, Format, Level};
+use redis::sentinel::{Config, Sentinel};
+use redis::warning;
+
+fn main() {
+    let mut config = Config::default();
+    let mut loglevel = Level::Notice;
+    let mut logfile = String::new();
+    let mut log_format = Format::Redis;
+
+    let args = std::env::args().collect::<Vec<String>>();
+    let mut i = 1;
+    while i < args.len() {
+        // `--monitor name host port quorum`
+        if args[i] == "--monitor" {
+            let Some([name, host, port, quorum]) = args.get(i + 1..i + 5) else {
+                eprintln!("Usage: --monitor <name> <host> <port> <quorum>");
+                return;
+            };
+            let Ok(quorum) = quorum.parse::<usize>() else {
+                eprintln!("Invalid quorum: {}", quorum);
+                return;
+            };
+            config.name = name.clone();
+            config.primary = format!("{}:{}", host, port);
+            config.quorum = quorum;
+            i += 5;
+            continue;
+        }
+        let value = args.get(i + 1);
+        let parsed = match (args[i].as_str(), value) {
+            ("--bind", Some(v)) => {
+                config.bind = v.clone();
+                Some(())
+            }
+            ("--announce-ip", Some(v)) => {
+                config.announce_ip = Some(v.clone());
+                Some(())
+            }
+            ("--down-after-milliseconds", Some(v)) => {
+                v.parse().ok().map(|ms| config.down_after = Duration::from_millis(ms))
+            }
+            ("--failover-timeout", Some(v)) => {
+                v.parse().ok().map(|ms| config.failover_timeout = Duration::from_millis(ms))
+            }
+            ("--loglevel", Some(v)) => v.parse().ok().map(|l| loglevel = l),
+            ("--logfile", Some(v)) => {
+                logfile = v.clone();
+                Some(())
+            }
+            ("--log-format", Some(v)) => v.parse().ok().map(|f| log_format = f),
+            _ => None,
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid argument: {} {}", args[i], value.map_or("", |v| v.as_str()));
+            return;
+        }
+        i += 2;
+    }
+
+    log::set_level(loglevel);
+    if let Err(e) = log::set_output(&logfile, log_format) {
+        eprintln!("Can't open the log file {}: {}", logfile, e);
+        return;
+    }
+
+    match Sentinel::bind(config) {
+        Ok(sentinel) => sentinel.run(),
+        Err(e) => warning!("Couldn't bind: {}", e),
+    }
+}
@@ -1,59 +1,109 @@
-use std::net::{TcpListener};
-
-use redis::connection::*;
-use redis::database::*;
+use redis::database::notify;
+use redis::log::{self, Format, Level};
+use redis::server::*;
+use redis::warning;
 
 fn main() {
-    let listener = TcpListener::bind("0.0.0.0:1234");
-    if let Err(ref e) = listener {
-        println!("Couldn't bind: {}", e);
-        return;
-    }
-    let listener = listener.ok().unwrap();
-    listener
-        .set_nonblocking(true)
-        .expect("Cannot set non-blocking");
+    let mut config = Config::default();
+    let mut loglevel = Level::Notice;
+    let mut logfile = String::new();
+    let mut log_format = Format::Redis;
 
-    let mut connections = vec![];
-
-    loop {
-        match listener.accept() {
-            Ok((client, addr)) => {
-                client
-                    .set_nonblocking(true)
-                    .expect("Couldn't set non-blocking mode on accepted connection");
-                println!("Got a connection from {}", addr);
-                let conn = Connection {
-                    fd: client,
-                    state: ConnectionState::StateReq,
-                    rbuf_size: 0,
-                    rbuf: [0; 4 + MAX_MSG],
-                    wbuf_size: 0,
-                    wbuf_sent: 0,
-                    wbuf: [0; 4 + MAX_MSG],
-                    database: Database::new(),
-                };
-                connections.push(conn);
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(e) => eprintln!("Error accepting connection: {}", e),
-        }
-        for client in &mut connections {
-            match client.state {
-                ConnectionState::StateReq => {
-                    client.state_req();
-                },
-                ConnectionState::StateRes => {
-                    client.state_res();
+    let args = std::env::args().collect::<Vec<String>>();
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        let parsed = match (args[i].as_str(), value) {
+            ("--bind", Some(v)) => {
+                config.bind = v.clone();
+                Some(())
+            }
+            ("--io-threads", Some(v)) => v.parse().ok().map(|n| config.io_threads = n),
+            ("--databases", Some(v)) => v.parse().ok().filter(|&n| n > 0).map(|n| config.databases = n),
+            ("--maxmemory", Some(v)) => parse_memory(v).map(|n| config.maxmemory = n),
+            ("--maxmemory-policy", Some(v)) => v.parse().ok().map(|p| config.maxmemory_policy = p),
+            ("--maxmemory-samples", Some(v)) => v.parse().ok().map(|n| config.maxmemory_samples = n),
+            (flag, Some(v)) if flag.starts_with("--") && config.encoding_limits.get(&flag[2..]).is_some() => {
+                v.parse().ok().map(|n| {
+                    config.encoding_limits.set(&flag[2..], n);
+                })
+            }
+            ("--notify-keyspace-events", Some(v)) => {
+                notify::parse_flags(v).map(|flags| config.notify_keyspace_events = flags)
+            }
+            ("--lua-time-limit", Some(v)) => v
+                .parse()
+                .ok()
+                .map(|ms| config.lua_time_limit = std::time::Duration::from_millis(ms)),
+            ("--slowlog-log-slower-than", Some(v)) => v.parse().ok().map(|n| config.slowlog_log_slower_than = n),
+            ("--slowlog-max-len", Some(v)) => v.parse().ok().map(|n| config.slowlog_max_len = n),
+            ("--requirepass", Some(v)) => {
+                config.requirepass = Some(v.clone());
+                Some(())
+            }
+            ("--aclfile", Some(v)) => {
+                config.aclfile = Some(v.into());
+                Some(())
+            }
+            ("--tls-bind", Some(v)) => {
+                config.tls_bind = Some(v.clone());
+                Some(())
+            }
+            ("--tls-cert-file", Some(v)) => {
+                config.tls_cert_file = Some(v.into());
+                Some(())
+            }
+            ("--tls-key-file", Some(v)) => {
+                config.tls_key_file = Some(v.into());
+                Some(())
+            }
+            ("--tls-ca-cert-file", Some(v)) => {
+                config.tls_ca_cert_file = Some(v.into());
+                Some(())
+            }
+            ("--tls-auth-clients", Some(v)) => match v.as_str() {
+                "yes" | "no" => {
+                    config.tls_auth_clients = v == "yes";
+                    Some(())
+                }
+                _ => None,
+            },
+            ("--cluster-enabled", Some(v)) => match v.as_str() {
+                "yes" | "no" => {
+                    config.cluster_enabled = v == "yes";
+                    Some(())
                 }
-                _ => {}
+                _ => None,
+            },
+            ("--cluster-bus-bind", Some(v)) => {
+                config.cluster_bus_bind = Some(v.clone());
+                Some(())
             }
-        }
-        connections.retain(|e| { 
-            if e.state == ConnectionState::StateEnd {
-                println!("Client disconnected");
+            ("--loglevel", Some(v)) => v.parse().ok().map(|l| loglevel = l),
+            ("--logfile", Some(v)) => {
+                logfile = v.clone();
+                Some(())
             }
-            e.state != ConnectionState::StateEnd
-        });
+            ("--log-format", Some(v)) => v.parse().ok().map(|f| log_format = f),
+            _ => None,
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid argument: {} {}", args[i], value.map_or("", |v| v.as_str()));
+            return;
+        }
+        i += 2;
+    }
+
+    log::set_level(loglevel);
+    if let Err(e) = log::set_output(&logfile, log_format) {
+        eprintln!("Can't open the log file {}: {}", logfile, e);
+        return;
+    }
+
+    let server = Server::bind(config);
+    if let Err(ref e) = server {
+        warning!("Couldn't bind: {}", e);
+        return;
     }
+    server.ok().unwrap().run();
 }
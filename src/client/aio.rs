@@ -0,0 +1,343 @@
+//! Asynchronous client on top of Tokio, with a connection pool
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{encode_request, reply_len, Error, Pipeline, Reply, Result, HEADER_LEN};
+
+/// A single connection. After an I/O or protocol error the socket is
+/// dropped and the next call connects again.
+pub struct Connection {
+    addr: String,
+    connect_timeout: Duration,
+    stream: Option<BufStream<TcpStream>>,
+}
+
+impl Connection {
+    pub async fn connect(addr: &str) -> Result<Connection> {
+        Connection::connect_timeout(addr, Duration::from_secs(5)).await
+    }
+
+    pub async fn connect_timeout(addr: &str, timeout: Duration) -> Result<Connection> {
+        let mut conn = Connection {
+            addr: addr.to_string(),
+            connect_timeout: timeout,
+            stream: None,
+        };
+        conn.ensure_connected().await?;
+        Ok(conn)
+    }
+
+    /// Whether the last call left us without a usable socket
+    pub fn is_broken(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    async fn ensure_connected(&mut self) -> Result<&mut BufStream<TcpStream>> {
+        if self.stream.is_none() {
+            let stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(&self.addr))
+                .await
+                .map_err(|_| Error::Io(std::io::ErrorKind::TimedOut.into()))??;
+            stream.set_nodelay(true)?;
+            self.stream = Some(BufStream::new(stream));
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Write `frames` and read back `count` replies
+    async fn roundtrip(&mut self, frames: &[u8], count: usize) -> Result<Vec<Reply>> {
+        let res = async {
+            let stream = self.ensure_connected().await?;
+            stream.write_all(frames).await?;
+            stream.flush().await?;
+
+            let mut replies = Vec::with_capacity(count);
+            while replies.len() < count {
+                let mut header = [0u8; HEADER_LEN];
+                stream.read_exact(&mut header).await?;
+                let mut body = vec![0u8; reply_len(header)?];
+                stream.read_exact(&mut body).await?;
+                // There's no way to receive pushes here, they're dropped
+                match Reply::decode(&body)? {
+                    Reply::Push(_) => {}
+                    reply => replies.push(reply),
+                }
+            }
+            Ok(replies)
+        }
+        .await;
+
+        // We can't tell where in the stream we are anymore
+        if matches!(res, Err(Error::Io(_)) | Err(Error::Protocol(_))) {
+            self.stream = None;
+        }
+        res
+    }
+
+    /// Send an arbitrary command
+    pub async fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<Reply> {
+        let mut frame = vec![];
+        encode_request(args, &mut frame)?;
+        Ok(self.roundtrip(&frame, 1).await?.pop().unwrap())
+    }
+
+    /// Send all requests of `pipeline` at once, the replies come back in
+    /// the same order. Server errors are returned as `Reply::Error`.
+    pub async fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Reply>> {
+        if pipeline.is_empty() {
+            return Ok(vec![]);
+        }
+        let frames = pipeline.encode()?;
+        self.roundtrip(&frames, pipeline.len()).await
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.command(&["ping"]).await?.into_bytes().map(|_| ())
+    }
+
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.command(&[b"get", key]).await?.into_bytes()
+    }
+
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.command(&[b"set", key, value]).await?.into_bytes().map(|_| ())
+    }
+
+    /// Returns false if the key didn't exist
+    pub async fn del(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.command(&[b"del", key]).await?.into_int()? == 1)
+    }
+
+    /// Returns false if the key doesn't exist
+    pub async fn expire(&mut self, key: &[u8], seconds: i64) -> Result<bool> {
+        let seconds = seconds.to_string();
+        let reply = self.command(&[b"expire", key, seconds.as_bytes()]).await?;
+        Ok(reply.into_int()? == 1)
+    }
+
+    /// Seconds to live, -1 without expiry, -2 if the key doesn't exist
+    pub async fn ttl(&mut self, key: &[u8]) -> Result<i64> {
+        self.command(&[b"ttl", key]).await?.into_int()
+    }
+}
+
+pub struct PoolConfig {
+    /// Most connections open at the same time
+    pub max_size: usize,
+    /// Connections idle for longer are pinged before being handed out
+    pub health_check_after: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 16,
+            health_check_after: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct PoolInner {
+    addr: String,
+    config: PoolConfig,
+    idle: Mutex<Vec<(Connection, Instant)>>,
+    permits: Arc<Semaphore>,
+}
+
+/// A pool of connections to one server, cheap to clone
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+impl Pool {
+    pub fn new(addr: &str, config: PoolConfig) -> Pool {
+        let permits = Arc::new(Semaphore::new(config.max_size.max(1)));
+        Pool {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_string(),
+                config,
+                idle: Mutex::new(vec![]),
+                permits,
+            }),
+        }
+    }
+
+    /// Take a connection, waiting if `max_size` are in use. Idle
+    /// connections are reused, after a ping if they sat around for long.
+    pub async fn get(&self) -> Result<PooledConnection> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Pool semaphore is never closed");
+
+        loop {
+            let idle = self.inner.idle.lock().unwrap().pop();
+            let Some((mut conn, since)) = idle else {
+                break;
+            };
+            if since.elapsed() < self.inner.config.health_check_after || conn.ping().await.is_ok() {
+                return Ok(self.wrap(conn, permit));
+            }
+        }
+
+        let conn = Connection::connect_timeout(&self.inner.addr, self.inner.config.connect_timeout).await?;
+        Ok(self.wrap(conn, permit))
+    }
+
+    fn wrap(&self, conn: Connection, permit: OwnedSemaphorePermit) -> PooledConnection {
+        PooledConnection {
+            conn: Some(conn),
+            pool: self.inner.clone(),
+            _permit: permit,
+        }
+    }
+
+    /// Number of connections waiting to be reused
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+/// A connection borrowed from a `Pool`, returned to it when dropped
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if !conn.is_broken() {
+                self.pool.idle.lock().unwrap().push((conn, Instant::now()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Config, Server};
+
+    fn start_server() -> String {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || server.run());
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let addr = start_server();
+        let mut conn = Connection::connect(&addr).await.unwrap();
+
+        conn.ping().await.unwrap();
+        conn.set(b"hello", b"world").await.unwrap();
+        assert_eq!(conn.get(b"hello").await.unwrap(), Some(b"world".to_vec()));
+        assert_eq!(conn.ttl(b"hello").await.unwrap(), -1);
+        assert!(conn.expire(b"hello", 100).await.unwrap());
+        assert_eq!(conn.ttl(b"hello").await.unwrap(), 100);
+        assert!(conn.del(b"hello").await.unwrap());
+        assert_eq!(conn.get(b"hello").await.unwrap(), None);
+        assert!(!conn.expire(b"hello", 100).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let addr = start_server();
+        let mut conn = Connection::connect(&addr).await.unwrap();
+
+        let mut pipeline = Pipeline::new();
+        for i in 0..100 {
+            pipeline.set(format!("key{}", i).as_bytes(), format!("value{}", i).as_bytes());
+        }
+        pipeline.get(b"key42").get(b"missing");
+        let replies = conn.pipeline(&pipeline).await.unwrap();
+        assert_eq!(replies.len(), 102);
+        assert_eq!(replies[100], Reply::Str(b"value42".to_vec()));
+        assert_eq!(replies[101], Reply::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let addr = start_server();
+        let mut conn = Connection::connect(&addr).await.unwrap();
+        conn.set(b"hello", b"world").await.unwrap();
+
+        let reply = conn.command(&["bogus"]).await.unwrap();
+        assert!(matches!(reply, Reply::Error { code, .. } if code == "ERR"));
+        assert!(!conn.is_broken());
+
+        // The first request after the server hung up fails and drops the socket
+        assert_eq!(conn.command(&["quit"]).await.unwrap(), Reply::Str(b"OK".to_vec()));
+        assert!(matches!(conn.get(b"hello").await, Err(Error::Io(_))));
+        assert!(conn.is_broken());
+
+        assert_eq!(conn.get(b"hello").await.unwrap(), Some(b"world".to_vec()));
+        assert!(!conn.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let addr = start_server();
+        let pool = Pool::new(&addr, PoolConfig {
+            max_size: 2,
+            health_check_after: Duration::ZERO,
+            ..PoolConfig::default()
+        });
+
+        let tasks = (0..10)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let mut conn = pool.get().await.unwrap();
+                    let key = format!("key{}", i);
+                    conn.set(key.as_bytes(), b"value").await.unwrap();
+                    conn.get(key.as_bytes()).await.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Some(b"value".to_vec()));
+        }
+        assert!(pool.idle_count() <= 2);
+
+        // Connections the server hung up on fail the health check
+        {
+            let mut conn = pool.get().await.unwrap();
+            conn.command(&["quit"]).await.unwrap();
+        }
+        let mut conn = pool.get().await.unwrap();
+        conn.ping().await.unwrap();
+    }
+}
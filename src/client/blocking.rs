@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+
+use crate::tls::{ClientConnection, Stream};
+
+use super::{encode_request, reply_len, Error, Pipeline, Reply, Result, HEADER_LEN};
+
+/// A blocking connection to the server
+pub struct Client {
+    stream: Stream<ClientConnection>,
+    /// Set once an I/O or protocol error left the stream in an unknown state
+    broken: bool,
+    /// Pushes that came in while waiting for replies, for `receive`
+    pushes: VecDeque<Reply>,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client { stream: Stream::Plain(stream), broken: false, pushes: VecDeque::new() })
+    }
+
+    /// Like `connect`, giving up after `timeout`
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Client> {
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_nodelay(true)?;
+        Ok(Client { stream: Stream::Plain(stream), broken: false, pushes: VecDeque::new() })
+    }
+
+    /// Connect over TLS, `server_name` is what the server certificate must
+    /// be valid for. See `tls::client_config` for building `config`.
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Client> {
+        let name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let conn = ClientConnection::new(config, name)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client { stream: Stream::tls(conn, stream), broken: false, pushes: VecDeque::new() })
+    }
+
+    /// Give up on replies taking longer than `timeout`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.tcp().set_read_timeout(timeout)?;
+        self.stream.tcp().set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn read_reply(&mut self) -> Result<Reply> {
+        let mut header = [0u8; HEADER_LEN];
+        self.stream.read_exact(&mut header)?;
+        let mut body = vec![0u8; reply_len(header)?];
+        self.stream.read_exact(&mut body)?;
+        Reply::decode(&body)
+    }
+
+    fn roundtrip(&mut self, frames: &[u8], count: usize) -> Result<Vec<Reply>> {
+        if self.broken {
+            return Err(Error::Io(std::io::ErrorKind::NotConnected.into()));
+        }
+        let res = (|| {
+            self.stream.write_all(frames)?;
+            self.stream.flush()?;
+            let mut replies = Vec::with_capacity(count);
+            while replies.len() < count {
+                match self.read_reply()? {
+                    push @ Reply::Push(_) => self.pushes.push_back(push),
+                    reply => replies.push(reply),
+                }
+            }
+            Ok(replies)
+        })();
+        if matches!(res, Err(Error::Io(_)) | Err(Error::Protocol(_))) {
+            self.broken = true;
+        }
+        res
+    }
+
+    /// Send an arbitrary command
+    pub fn command<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<Reply> {
+        let mut frame = vec![];
+        encode_request(args, &mut frame)?;
+        Ok(self.roundtrip(&frame, 1)?.pop().unwrap())
+    }
+
+    /// Send all requests of `pipeline` at once, the replies come back in
+    /// the same order. Server errors are returned as `Reply::Error`.
+    pub fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Reply>> {
+        if pipeline.is_empty() {
+            return Ok(vec![]);
+        }
+        let frames = pipeline.encode()?;
+        self.roundtrip(&frames, pipeline.len())
+    }
+
+    /// Wait for the next message pushed by the server, after `subscribe`,
+    /// `psubscribe` or `client tracking`. Pushes that came in along with
+    /// replies are returned first.
+    pub fn receive(&mut self) -> Result<Reply> {
+        if let Some(push) = self.pushes.pop_front() {
+            return Ok(push);
+        }
+        if self.broken {
+            return Err(Error::Io(std::io::ErrorKind::NotConnected.into()));
+        }
+        let res = self.read_reply();
+        if matches!(res, Err(Error::Io(_)) | Err(Error::Protocol(_))) {
+            self.broken = true;
+        }
+        res
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.command(&["ping"])?.into_bytes().map(|_| ())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.command(&[b"get", key])?.into_bytes()
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.command(&[b"set", key, value])?.into_bytes().map(|_| ())
+    }
+
+    /// Returns false if the key didn't exist
+    pub fn del(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.command(&[b"del", key])?.into_int()? == 1)
+    }
+
+    /// Returns false if the key doesn't exist
+    pub fn expire(&mut self, key: &[u8], seconds: i64) -> Result<bool> {
+        let seconds = seconds.to_string();
+        Ok(self.command(&[b"expire", key, seconds.as_bytes()])?.into_int()? == 1)
+    }
+
+    /// Seconds to live, -1 without expiry, -2 if the key doesn't exist
+    pub fn ttl(&mut self, key: &[u8]) -> Result<i64> {
+        self.command(&[b"ttl", key])?.into_int()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Config, Server};
+
+    fn start_server(maxmemory: usize) -> String {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            maxmemory,
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || server.run());
+        addr
+    }
+
+    #[test]
+    fn test_client() {
+        let mut client = Client::connect(start_server(0)).unwrap();
+
+        client.set(b"greeting", b"hello world").unwrap();
+        assert_eq!(client.get(b"greeting").unwrap(), Some(b"hello world".to_vec()));
+        assert_eq!(client.command(&["get", "greeting"]).unwrap(), Reply::Str(b"hello world".to_vec()));
+        assert!(client.expire(b"greeting", 10).unwrap());
+        assert_eq!(client.ttl(b"greeting").unwrap(), 10);
+        assert!(client.del(b"greeting").unwrap());
+        assert!(!client.del(b"greeting").unwrap());
+        assert_eq!(client.get(b"greeting").unwrap(), None);
+        assert_eq!(client.ttl(b"greeting").unwrap(), -2);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.set(b"a", b"1").set(b"b", b"2").get(b"a").get(b"b");
+        let replies = client.pipeline(&pipeline).unwrap();
+        assert_eq!(replies[2..], [Reply::Str(b"1".to_vec()), Reply::Str(b"2".to_vec())]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut client = Client::connect(start_server(1)).unwrap();
+        assert!(matches!(client.set(b"key", b"value"), Err(Error::Server { code, .. }) if code == "OOM"));
+
+        let big = vec![b'x'; crate::connection::MAX_MSG];
+        assert!(matches!(client.set(b"big", &big), Err(Error::Protocol(_))));
+        client.ping().unwrap();
+
+        let reply = client.command(&["bogus"]).unwrap();
+        assert!(matches!(reply, Reply::Error { code, .. } if code == "ERR"));
+        assert!(matches!(client.get(b"a"), Ok(None)));
+        assert!(matches!(client.expire(b"a", 1), Ok(false)));
+        let reply = client.command(&["expire", "a", "soon"]).unwrap();
+        assert_eq!(reply, Reply::Error {
+            code: "ERR".to_string(),
+            message: "value is not an integer or out of range".to_string()
+        });
+
+        client.command(&["quit"]).unwrap();
+        assert!(matches!(client.ping(), Err(Error::Io(_))));
+        assert!(matches!(client.ping(), Err(Error::Io(_))));
+    }
+}
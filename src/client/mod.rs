@@ -0,0 +1,296 @@
+use std::fmt;
+
+use crate::connection::MAX_MSG;
+use crate::protocol::{TAG_ARR, TAG_ERR, TAG_INT, TAG_NIL, TAG_PSH, TAG_STR};
+
+#[cfg(feature = "async-client")]
+pub mod aio;
+mod blocking;
+
+pub use blocking::Client;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection failed or was closed
+    Io(std::io::Error),
+    /// The peer sent something that isn't a valid reply
+    Protocol(String),
+    /// The server answered with an error
+    Server { code: String, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Server { code, message } => write!(f, "{} {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Deepest nesting of arrays accepted in a reply
+const MAX_DEPTH: usize = 64;
+
+/// A decoded server reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Nil,
+    Error { code: String, message: String },
+    Str(Vec<u8>),
+    Int(i64),
+    Array(Vec<Reply>),
+    /// Sent by the server on its own, like invalidation messages, rather
+    /// than in reply to a request
+    Push(Vec<Reply>),
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(Error::Protocol("truncated reply".to_string()));
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("need 4-byte array")) as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Reply> {
+        match self.take(1)?[0] {
+            TAG_NIL => Ok(Reply::Nil),
+            TAG_ERR => {
+                let code = self.string()?;
+                let message = self.string()?;
+                Ok(Reply::Error { code, message })
+            }
+            TAG_STR => {
+                let len = self.len()?;
+                Ok(Reply::Str(self.take(len)?.to_vec()))
+            }
+            TAG_INT => Ok(Reply::Int(i64::from_le_bytes(self.take(8)?.try_into().expect("need 8-byte array")))),
+            tag @ (TAG_ARR | TAG_PSH) => {
+                if depth >= MAX_DEPTH {
+                    return Err(Error::Protocol("reply nested too deeply".to_string()));
+                }
+                let n = self.len()?;
+                // Every element takes at least a byte, don't trust n blindly
+                let mut items = Vec::with_capacity(n.min(self.data.len() - self.pos));
+                for _ in 0..n {
+                    items.push(self.value(depth + 1)?);
+                }
+                match tag {
+                    TAG_ARR => Ok(Reply::Array(items)),
+                    _ => Ok(Reply::Push(items)),
+                }
+            }
+            tag => Err(Error::Protocol(format!("unknown tag {}", tag))),
+        }
+    }
+}
+
+impl Reply {
+    /// Decode the body of a reply frame (everything after the length)
+    pub fn decode(body: &[u8]) -> Result<Reply> {
+        let mut decoder = Decoder { data: body, pos: 0 };
+        let reply = decoder.value(0)?;
+        if decoder.pos != body.len() {
+            return Err(Error::Protocol("trailing data after reply".to_string()));
+        }
+        Ok(reply)
+    }
+
+    /// Turn error replies into `Err`
+    pub fn into_result(self) -> Result<Reply> {
+        match self {
+            Reply::Error { code, message } => Err(Error::Server { code, message }),
+            reply => Ok(reply),
+        }
+    }
+
+    /// A string value, `None` for nil
+    pub fn into_bytes(self) -> Result<Option<Vec<u8>>> {
+        match self.into_result()? {
+            Reply::Str(v) => Ok(Some(v)),
+            Reply::Nil => Ok(None),
+            Reply::Int(n) => Ok(Some(n.to_string().into_bytes())),
+            _ => Err(Error::Protocol("expected a string reply".to_string())),
+        }
+    }
+
+    pub fn into_int(self) -> Result<i64> {
+        match self.into_result()? {
+            Reply::Int(n) => Ok(n),
+            _ => Err(Error::Protocol("expected an integer reply".to_string())),
+        }
+    }
+}
+
+/// Size of the length prefix of every frame
+pub const HEADER_LEN: usize = 4;
+/// Largest reply body we're willing to allocate for
+pub const MAX_REPLY: usize = 512 * 1024 * 1024;
+
+/// Append the frame for one request to `out`
+pub fn encode_request<A: AsRef<[u8]>>(args: &[A], out: &mut Vec<u8>) -> Result<()> {
+    let body_len = 4 + args.iter().map(|a| 4 + a.as_ref().len()).sum::<usize>();
+    if body_len > MAX_MSG {
+        return Err(Error::Protocol(format!("request of {} bytes exceeds {}", body_len, MAX_MSG)));
+    }
+    out.reserve(HEADER_LEN + body_len);
+    out.extend_from_slice(&(body_len as u32).to_le_bytes());
+    out.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+        out.extend_from_slice(arg);
+    }
+    Ok(())
+}
+
+/// Length of a reply body given its header, checked against the limits
+pub fn reply_len(header: [u8; HEADER_LEN]) -> Result<usize> {
+    let len = u32::from_le_bytes(header) as usize;
+    if !(1..=MAX_REPLY).contains(&len) {
+        return Err(Error::Protocol(format!("invalid reply length {}", len)));
+    }
+    Ok(len)
+}
+
+/// A batch of requests sent in one write, replies are read back in order
+#[derive(Default, Debug, Clone)]
+pub struct Pipeline {
+    requests: Vec<Vec<Vec<u8>>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn cmd<A: AsRef<[u8]>>(&mut self, args: &[A]) -> &mut Pipeline {
+        self.requests.push(args.iter().map(|a| a.as_ref().to_vec()).collect());
+        self
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> &mut Pipeline {
+        self.cmd(&[&b"get"[..], key])
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Pipeline {
+        self.cmd(&[&b"set"[..], key, value])
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> &mut Pipeline {
+        self.cmd(&[&b"del"[..], key])
+    }
+
+    pub fn expire(&mut self, key: &[u8], seconds: i64) -> &mut Pipeline {
+        self.cmd(&[&b"expire"[..], key, seconds.to_string().as_bytes()])
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// All request frames back to back
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        for args in &self.requests {
+            encode_request(args, &mut out)?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let mut out = vec![];
+        encode_request(&["set", "hello", "world"], &mut out).unwrap();
+        assert_eq!(u32::from_le_bytes(out[0..4].try_into().unwrap()) as usize, out.len() - 4);
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 3);
+        assert_eq!(&out[12..15], b"set");
+
+        let big = "x".repeat(MAX_MSG);
+        assert!(matches!(encode_request(&["set", "k", big.as_str()], &mut out), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_decode_reply() {
+        use crate::protocol::*;
+
+        let mut body = vec![];
+        out_arr(&mut body, 4);
+        out_str(&mut body, b"hello");
+        out_int(&mut body, -42);
+        out_nil(&mut body);
+        out_arr(&mut body, 1);
+        out_err(&mut body, ErrorCode::WrongType, "nope");
+        assert_eq!(
+            Reply::decode(&body).unwrap(),
+            Reply::Array(vec![
+                Reply::Str(b"hello".to_vec()),
+                Reply::Int(-42),
+                Reply::Nil,
+                Reply::Array(vec![Reply::Error {
+                    code: "WRONGTYPE".to_string(),
+                    message: "nope".to_string()
+                }]),
+            ])
+        );
+
+        assert!(Reply::decode(&body[..body.len() - 1]).is_err());
+        body.push(0);
+        assert!(Reply::decode(&body).is_err());
+        assert!(Reply::decode(&[9]).is_err());
+
+        let mut deep = vec![];
+        for _ in 0..100 {
+            out_arr(&mut deep, 1);
+        }
+        out_nil(&mut deep);
+        assert!(Reply::decode(&deep).is_err());
+
+        let mut err = vec![];
+        out_err(&mut err, ErrorCode::Err, "boom");
+        let err = Reply::decode(&err).unwrap().into_bytes();
+        assert!(matches!(err, Err(Error::Server { code, .. }) if code == "ERR"));
+    }
+}
@@ -0,0 +1,161 @@
+//! Messages nodes exchange on the cluster bus. Every message is framed like
+//! a reply, the length of the body followed by:
+//!
+//! | field          | layout                                        |
+//! |----------------|-----------------------------------------------|
+//! | kind           | u8, `PING` or `MEET`                          |
+//! | sender         | node id, `NODE_ID_LEN` bytes                  |
+//! | ports          | u16 client port, u16 bus port                 |
+//! | epochs         | u64 current epoch, u64 config epoch           |
+//! | slots          | bitmap of the slots the sender serves         |
+//! | gossip         | u16 count, then per node: id, ip len, ip, u16 client port, u16 bus port |
+//!
+//! All integers little endian.
+
+use super::{NODE_ID_LEN, SLOTS};
+
+const KIND_PING: u8 = 0;
+const KIND_MEET: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Ping,
+    /// A ping to a node given to `cluster meet`, asking it to add the sender
+    Meet,
+}
+
+/// Another node the sender knows about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gossip {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub bus_port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageKind,
+    pub sender: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub current_epoch: u64,
+    pub config_epoch: u64,
+    /// One bit per slot, set for the slots the sender serves
+    pub slots: Vec<u8>,
+    pub gossip: Vec<Gossip>,
+}
+
+impl Message {
+    pub fn serves(&self, slot: usize) -> bool {
+        self.slots[slot / 8] & (1 << (slot % 8)) != 0
+    }
+
+    /// The framed message, ready to be written to the bus
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0; 4];
+        out.push(match self.kind {
+            MessageKind::Ping => KIND_PING,
+            MessageKind::Meet => KIND_MEET,
+        });
+        out.extend_from_slice(self.sender.as_bytes());
+        out.extend_from_slice(&self.port.to_le_bytes());
+        out.extend_from_slice(&self.bus_port.to_le_bytes());
+        out.extend_from_slice(&self.current_epoch.to_le_bytes());
+        out.extend_from_slice(&self.config_epoch.to_le_bytes());
+        out.extend_from_slice(&self.slots);
+        out.extend_from_slice(&(self.gossip.len() as u16).to_le_bytes());
+        for node in &self.gossip {
+            out.extend_from_slice(node.id.as_bytes());
+            out.push(node.ip.len() as u8);
+            out.extend_from_slice(node.ip.as_bytes());
+            out.extend_from_slice(&node.port.to_le_bytes());
+            out.extend_from_slice(&node.bus_port.to_le_bytes());
+        }
+        let len = (out.len() - 4) as u32;
+        out[..4].copy_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    /// Parse a message body, without the length, `None` if it's malformed
+    pub fn decode(body: &[u8]) -> Option<Message> {
+        let mut reader = Reader { buf: body };
+        let kind = match reader.u8()? {
+            KIND_PING => MessageKind::Ping,
+            KIND_MEET => MessageKind::Meet,
+            _ => return None,
+        };
+        let sender = reader.node_id()?;
+        let port = reader.u16()?;
+        let bus_port = reader.u16()?;
+        let current_epoch = reader.u64()?;
+        let config_epoch = reader.u64()?;
+        let slots = reader.bytes(SLOTS / 8)?.to_vec();
+        let mut gossip = vec![];
+        for _ in 0..reader.u16()? {
+            let id = reader.node_id()?;
+            let len = reader.u8()? as usize;
+            let ip = String::from_utf8(reader.bytes(len)?.to_vec()).ok()?;
+            gossip.push(Gossip { id, ip, port: reader.u16()?, bus_port: reader.u16()? });
+        }
+        Some(Message { kind, sender, port, bus_port, current_epoch, config_epoch, slots, gossip })
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn node_id(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes(NODE_ID_LEN)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut slots = vec![0; SLOTS / 8];
+        slots[0] = 0b101;
+        let message = Message {
+            kind: MessageKind::Meet,
+            sender: "a".repeat(NODE_ID_LEN),
+            port: 7000,
+            bus_port: 17000,
+            current_epoch: 3,
+            config_epoch: 2,
+            slots,
+            gossip: vec![Gossip { id: "b".repeat(NODE_ID_LEN), ip: "127.0.0.1".to_string(), port: 7001, bus_port: 17001 }],
+        };
+        let frame = message.encode();
+        assert_eq!(u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize, frame.len() - 4);
+        let decoded = Message::decode(&frame[4..]).unwrap();
+        assert!(decoded.serves(0) && !decoded.serves(1) && decoded.serves(2));
+        assert_eq!(decoded, message);
+        assert_eq!(Message::decode(&frame[4..frame.len() - 1]), None);
+    }
+}
@@ -0,0 +1,504 @@
+//! Cluster mode: the keyspace is split into `SLOTS` hash slots, each served
+//! by one node. Nodes gossip over the cluster bus about each other and about
+//! who serves which slot; clients asking the wrong node are redirected with
+//! `MOVED`, or with `ASK` while a slot is being migrated.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::database::eviction::now_ms;
+use crate::notice;
+
+pub mod message;
+
+use message::{Gossip, Message, MessageKind};
+
+pub const SLOTS: usize = 16384;
+pub const NODE_ID_LEN: usize = 40;
+/// The bus listens on the client port plus this unless told otherwise
+pub const BUS_PORT_OFFSET: u16 = 10000;
+/// How often every node pings every other node
+pub const PING_INTERVAL: Duration = Duration::from_millis(100);
+/// Nodes not heard from for this long are flagged `fail?`
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// CRC16/XMODEM, which maps keys to slots
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Slot of `key`. If the key has a non-empty `{hashtag}`, only the tag is
+/// hashed, so that related keys can be kept on one node.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|start| {
+        let len = key[start + 1..].iter().position(|&b| b == b'}')?;
+        (len > 0).then(|| &key[start + 1..start + 1 + len])
+    });
+    crc16(tag.unwrap_or(key)) & (SLOTS as u16 - 1)
+}
+
+/// A random id of `NODE_ID_LEN` hex digits
+pub fn random_node_id() -> String {
+    // Every `RandomState` is seeded differently
+    let state = RandomState::new();
+    let mut id = (0..3u8)
+        .map(|i| {
+            let mut hasher = state.build_hasher();
+            hasher.write_u8(i);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect::<String>();
+    id.truncate(NODE_ID_LEN);
+    id
+}
+
+pub struct Node {
+    pub id: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub bus_port: u16,
+    /// Claims on slots by nodes with a higher epoch win
+    pub config_epoch: u64,
+    /// When the node last pinged us, `None` for ourselves and for nodes only
+    /// heard of through gossip
+    pub last_seen: Option<Instant>,
+}
+
+impl Node {
+    /// Where clients reach the node, as sent in redirects
+    pub fn addr(&self) -> String {
+        SocketAddr::new(self.ip, self.port).to_string()
+    }
+
+    fn bus_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.bus_port)
+    }
+
+    fn is_connected(&self, now: Instant) -> bool {
+        self.last_seen.is_some_and(|at| now.duration_since(at) < NODE_TIMEOUT)
+    }
+}
+
+/// This node's view of the cluster
+pub struct Cluster {
+    myself: String,
+    nodes: BTreeMap<String, Node>,
+    /// Node serving each slot
+    slots: Vec<Option<String>>,
+    /// Slots we serve that are moving to another node, and the node
+    migrating: BTreeMap<u16, String>,
+    /// Slots we are taking over, and the node serving them
+    importing: BTreeMap<u16, String>,
+    current_epoch: u64,
+    /// Bus addresses given to `cluster meet` that haven't answered yet
+    meeting: Vec<SocketAddr>,
+    /// Clients that sent `asking` before their next command
+    asking: HashSet<u64>,
+}
+
+impl Cluster {
+    pub fn new(ip: IpAddr, port: u16, bus_port: u16) -> Cluster {
+        let id = random_node_id();
+        let myself = Node { id: id.clone(), ip, port, bus_port, config_epoch: 0, last_seen: None };
+        notice!("Cluster node id {}", id);
+        Cluster {
+            myself: id.clone(),
+            nodes: BTreeMap::from([(id, myself)]),
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            current_epoch: 0,
+            meeting: vec![],
+            asking: HashSet::new(),
+        }
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    fn myself_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    pub fn owner(&self, slot: u16) -> Option<&Node> {
+        self.slots[slot as usize].as_ref().map(|id| &self.nodes[id])
+    }
+
+    pub fn is_mine(&self, slot: u16) -> bool {
+        self.slots[slot as usize].as_ref() == Some(&self.myself)
+    }
+
+    pub fn migrating_to(&self, slot: u16) -> Option<&Node> {
+        self.migrating.get(&slot).map(|id| &self.nodes[id])
+    }
+
+    pub fn importing_from(&self, slot: u16) -> Option<&Node> {
+        self.importing.get(&slot).map(|id| &self.nodes[id])
+    }
+
+    /// Whether every slot is served
+    pub fn is_ok(&self) -> bool {
+        self.slots.iter().all(|owner| owner.is_some())
+    }
+
+    /// Serve `slots`, none of which may be served by anyone yet
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), String> {
+        if let Some(slot) = slots.iter().find(|&&slot| self.slots[slot as usize].is_some()) {
+            return Err(format!("Slot {} is already busy", slot));
+        }
+        for &slot in slots {
+            self.slots[slot as usize] = Some(self.myself.clone());
+        }
+        Ok(())
+    }
+
+    /// Forget who serves `slots`, all of which must be served by someone
+    pub fn del_slots(&mut self, slots: &[u16]) -> Result<(), String> {
+        if let Some(slot) = slots.iter().find(|&&slot| self.slots[slot as usize].is_none()) {
+            return Err(format!("Slot {} is already unassigned", slot));
+        }
+        for &slot in slots {
+            self.slots[slot as usize] = None;
+        }
+        Ok(())
+    }
+
+    fn check_known(&self, id: &str) -> Result<(), String> {
+        match self.nodes.contains_key(id) {
+            true => Ok(()),
+            false => Err(format!("I don't know about node {}", id)),
+        }
+    }
+
+    pub fn set_migrating(&mut self, slot: u16, id: &str) -> Result<(), String> {
+        if !self.is_mine(slot) {
+            return Err(format!("I'm not the owner of hash slot {}", slot));
+        }
+        self.check_known(id)?;
+        self.migrating.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    pub fn set_importing(&mut self, slot: u16, id: &str) -> Result<(), String> {
+        if self.is_mine(slot) {
+            return Err(format!("I'm already the owner of hash slot {}", slot));
+        }
+        self.check_known(id)?;
+        self.importing.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    pub fn set_stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    /// Hand `slot` to node `id`, ending a migration. Taking over an imported
+    /// slot bumps our epoch so that the other nodes accept our claim.
+    pub fn set_node(&mut self, slot: u16, id: &str, has_keys: bool) -> Result<(), String> {
+        self.check_known(id)?;
+        if id != self.myself {
+            if self.is_mine(slot) && has_keys {
+                let msg = format!(
+                    "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                    slot
+                );
+                return Err(msg);
+            }
+            self.migrating.remove(&slot);
+        } else if self.importing.remove(&slot).is_some() {
+            self.current_epoch += 1;
+            let epoch = self.current_epoch;
+            self.myself_mut().config_epoch = epoch;
+        }
+        self.slots[slot as usize] = Some(id.to_string());
+        Ok(())
+    }
+
+    /// Start pinging the node whose bus is at `addr` until it answers
+    pub fn meet(&mut self, addr: SocketAddr) {
+        if !self.meeting.contains(&addr) {
+            self.meeting.push(addr);
+        }
+    }
+
+    pub fn set_asking(&mut self, client: u64) {
+        self.asking.insert(client);
+    }
+
+    /// Whether `client` sent `asking`, which only holds for one command
+    pub fn take_asking(&mut self, client: u64) -> bool {
+        self.asking.remove(&client)
+    }
+
+    fn message(&self, kind: MessageKind) -> Message {
+        let mut slots = vec![0; SLOTS / 8];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_ref() == Some(&self.myself) {
+                slots[slot / 8] |= 1 << (slot % 8);
+            }
+        }
+        let gossip = self
+            .nodes
+            .values()
+            .filter(|node| node.id != self.myself)
+            .map(|node| Gossip { id: node.id.clone(), ip: node.ip.to_string(), port: node.port, bus_port: node.bus_port })
+            .collect();
+        let myself = self.myself();
+        Message {
+            kind,
+            sender: myself.id.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            current_epoch: self.current_epoch,
+            config_epoch: myself.config_epoch,
+            slots,
+            gossip,
+        }
+    }
+
+    /// Framed pings for every other node, sent every `PING_INTERVAL`
+    pub fn tick(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let ping = self.message(MessageKind::Ping).encode();
+        let mut out = self
+            .nodes
+            .values()
+            .filter(|node| node.id != self.myself)
+            .map(|node| (node.bus_addr(), ping.clone()))
+            .collect::<Vec<_>>();
+        if !self.meeting.is_empty() {
+            let meet = self.message(MessageKind::Meet).encode();
+            out.extend(self.meeting.iter().map(|addr| (*addr, meet.clone())));
+        }
+        out
+    }
+
+    /// Take in a message that came from `from` on a bus connection we
+    /// accepted on `local`
+    pub fn receive(&mut self, message: Message, from: IpAddr, local: IpAddr, now: Instant) {
+        let sender = message.sender.clone();
+        if sender == self.myself {
+            return;
+        }
+        if self.myself().ip.is_unspecified() {
+            self.myself_mut().ip = local;
+        }
+        // Strangers are only accepted if they meet us or we met them
+        let bus_addr = SocketAddr::new(from, message.bus_port);
+        let met = self.meeting.contains(&bus_addr);
+        if !self.nodes.contains_key(&sender) && !met && message.kind != MessageKind::Meet {
+            return;
+        }
+        self.meeting.retain(|addr| *addr != bus_addr);
+        if !self.nodes.contains_key(&sender) {
+            notice!("Node {} joined the cluster from {}", sender, bus_addr);
+        }
+
+        self.current_epoch = self.current_epoch.max(message.current_epoch);
+        let node = self.nodes.entry(sender.clone()).or_insert_with(|| Node {
+            id: sender.clone(),
+            ip: from,
+            port: 0,
+            bus_port: 0,
+            config_epoch: 0,
+            last_seen: None,
+        });
+        node.ip = from;
+        node.port = message.port;
+        node.bus_port = message.bus_port;
+        node.config_epoch = message.config_epoch;
+        node.last_seen = Some(now);
+
+        for slot in 0..SLOTS {
+            if !message.serves(slot) || self.importing.contains_key(&(slot as u16)) {
+                continue;
+            }
+            match &self.slots[slot] {
+                Some(owner) if *owner == sender => continue,
+                Some(owner) if self.nodes[owner].config_epoch >= message.config_epoch => continue,
+                _ => self.slots[slot] = Some(sender.clone()),
+            }
+        }
+
+        // Of two nodes with the same epoch the one with the smaller id moves
+        // on, so that claims can always be told apart
+        if message.config_epoch == self.myself().config_epoch && sender > self.myself {
+            self.current_epoch += 1;
+            let epoch = self.current_epoch;
+            self.myself_mut().config_epoch = epoch;
+        }
+
+        for gossip in message.gossip {
+            if gossip.id == self.myself || self.nodes.contains_key(&gossip.id) {
+                continue;
+            }
+            let Ok(ip) = gossip.ip.parse() else {
+                continue;
+            };
+            let node = Node {
+                id: gossip.id.clone(),
+                ip,
+                port: gossip.port,
+                bus_port: gossip.bus_port,
+                config_epoch: 0,
+                last_seen: None,
+            };
+            self.nodes.insert(gossip.id, node);
+        }
+    }
+
+    /// Ranges of consecutive slots served by the same node
+    pub fn slot_ranges(&self) -> Vec<(u16, u16, &Node)> {
+        let mut ranges: Vec<(u16, u16, &Node)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = owner else {
+                continue;
+            };
+            match ranges.last_mut() {
+                Some((_, end, node)) if *end as usize + 1 == slot && node.id == *owner => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, &self.nodes[owner])),
+            }
+        }
+        ranges
+    }
+
+    /// The body of `cluster info`
+    pub fn info(&self) -> String {
+        let assigned = self.slots.iter().filter(|owner| owner.is_some()).count();
+        let size = self.nodes.keys().filter(|id| self.slots.iter().any(|o| o.as_ref() == Some(*id))).count();
+        format!(
+            "cluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            if self.is_ok() { "ok" } else { "fail" },
+            assigned,
+            self.nodes.len(),
+            size,
+            self.current_epoch,
+            self.myself().config_epoch,
+        )
+    }
+
+    /// The body of `cluster nodes`, one line per node
+    pub fn describe_nodes(&self, now: Instant) -> String {
+        let ranges = self.slot_ranges();
+        let mut out = String::new();
+        for node in self.nodes.values() {
+            let myself = node.id == self.myself;
+            let connected = myself || node.is_connected(now);
+            let flags = match (myself, connected) {
+                (true, _) => "myself,master",
+                (false, true) => "master",
+                (false, false) => "master,fail?",
+            };
+            let pong = node.last_seen.map_or(0, |at| now_ms().saturating_sub(now.duration_since(at).as_millis() as u64));
+            out.push_str(&format!(
+                "{} {}@{} {} - 0 {} {} {}",
+                node.id,
+                node.addr(),
+                node.bus_port,
+                flags,
+                pong,
+                node.config_epoch,
+                if connected { "connected" } else { "disconnected" },
+            ));
+            for (start, end, _) in ranges.iter().filter(|(_, _, owner)| owner.id == node.id) {
+                match start == end {
+                    true => out.push_str(&format!(" {}", start)),
+                    false => out.push_str(&format!(" {}-{}", start, end)),
+                }
+            }
+            if myself {
+                for (slot, id) in &self.migrating {
+                    out.push_str(&format!(" [{}->-{}]", slot, id));
+                }
+                for (slot, id) in &self.importing {
+                    out.push_str(&format!(" [{}-<-{}]", slot, id));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(cluster: &Cluster) -> Message {
+        Message::decode(&cluster.message(MessageKind::Ping).encode()[4..]).unwrap()
+    }
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"bar"), 5061);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), key_hash_slot(b"{user1000}.followers"));
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        // Empty tags don't count
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & 16383);
+        assert_eq!(key_hash_slot(b"{bar"), crc16(b"{bar") & 16383);
+    }
+
+    #[test]
+    fn test_gossip() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut a = Cluster::new(localhost, 7000, 17000);
+        let mut b = Cluster::new(localhost, 7001, 17001);
+        let now = Instant::now();
+        a.add_slots(&[0]).unwrap();
+        assert_eq!(a.add_slots(&[5, 0]), Err("Slot 0 is already busy".to_string()));
+        assert!(a.owner(5).is_none());
+        b.add_slots(&[1, 2]).unwrap();
+        b.del_slots(&[2]).unwrap();
+        assert_eq!(b.del_slots(&[2]), Err("Slot 2 is already unassigned".to_string()));
+
+        // Plain pings from strangers are ignored
+        b.receive(ping(&a), localhost, localhost, now);
+        assert!(b.owner(0).is_none());
+        a.meet("127.0.0.1:17001".parse().unwrap());
+        let sent = a.tick();
+        assert_eq!(sent.len(), 1);
+        b.receive(Message::decode(&sent[0].1[4..]).unwrap(), localhost, localhost, now);
+        a.receive(ping(&b), localhost, localhost, now);
+        assert_eq!(a.owner(1).unwrap().id, b.myself().id);
+        assert_eq!(b.owner(0).unwrap().addr(), "127.0.0.1:7000");
+        assert_eq!(a.tick().len(), 1);
+        // One of the two moved to a new epoch
+        b.receive(ping(&a), localhost, localhost, now);
+        assert_ne!(a.myself().config_epoch, b.myself().config_epoch);
+
+        // Moving slot 0 from a to b
+        let (a_id, b_id) = (a.myself().id.clone(), b.myself().id.clone());
+        assert_eq!(b.set_migrating(0, &a_id), Err("I'm not the owner of hash slot 0".to_string()));
+        b.set_importing(0, &a_id).unwrap();
+        a.set_migrating(0, &b_id).unwrap();
+        assert!(a.describe_nodes(now).contains(&format!(" 0 [0->-{}]\n", b_id)));
+        assert!(a.set_node(0, &b_id, true).is_err());
+        b.set_node(0, &b_id, false).unwrap();
+        assert!(b.is_mine(0) && b.importing_from(0).is_none());
+        a.receive(ping(&b), localhost, localhost, now);
+        assert_eq!(a.owner(0).unwrap().id, b_id);
+        // a's stale claim doesn't undo it
+        a.slots[0] = Some(a_id);
+        b.receive(ping(&a), localhost, localhost, now);
+        assert!(b.is_mine(0));
+        assert_eq!(b.slot_ranges().iter().map(|(s, e, _)| (*s, *e)).collect::<Vec<_>>(), [(0, 1)]);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::acl::DEFAULT_USER;
+use crate::command::{self, CATEGORIES};
+use crate::protocol::*;
+
+use super::Context;
+
+/// `acl setuser|getuser|deluser|list|users|whoami|cat|save|load`
+pub fn acl(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let me = ctx.client_id;
+    let Some(acl) = ctx.acl.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, "ACL is not available");
+    };
+    // User names, rules and categories are all text
+    let Ok(args) = args.iter().map(|arg| String::from_utf8(arg.clone())).collect::<Result<Vec<String>, _>>() else {
+        return out_err(out, ErrorCode::Err, "ACL arguments must be valid UTF-8");
+    };
+
+    let sub = args[1].to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("setuser", n) if n >= 3 => match acl.set_user(&args[2], &args[3..]) {
+            Ok(()) => out_ok(out),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        },
+        ("getuser", 3) => {
+            let Some(user) = acl.get(&args[2]) else {
+                return out_nil(out);
+            };
+            out_arr(out, 10);
+            out_str(out, b"flags");
+            let flags = user.flags();
+            out_arr(out, flags.len());
+            for flag in flags {
+                out_str(out, flag.as_bytes());
+            }
+            out_str(out, b"passwords");
+            out_arr(out, user.passwords.len());
+            for hash in &user.passwords {
+                out_str(out, hash.as_bytes());
+            }
+            out_str(out, b"commands");
+            out_str(out, user.describe_commands().as_bytes());
+            out_str(out, b"keys");
+            out_str(out, user.describe_keys().as_bytes());
+            out_str(out, b"channels");
+            out_str(out, user.describe_channels().as_bytes());
+        }
+        ("deluser", n) if n >= 3 => {
+            if args[2..].iter().any(|name| name == DEFAULT_USER) {
+                return out_err(out, ErrorCode::Err, "The 'default' user cannot be removed");
+            }
+            let deleted = args[2..].iter().filter(|name| acl.del_user(name)).cloned().collect::<Vec<String>>();
+            // Whoever was logged in as them is disconnected
+            if let Some(clients) = ctx.clients.as_deref_mut() {
+                let victims = clients
+                    .iter()
+                    .filter(|c| c.user.as_ref().is_some_and(|u| deleted.contains(u)))
+                    .map(|c| c.id)
+                    .collect::<Vec<u64>>();
+                for id in victims {
+                    clients.kill(id);
+                }
+            }
+            out_int(out, deleted.len() as i64);
+        }
+        ("list", 2) => {
+            let users = acl.users().map(|u| u.describe()).collect::<Vec<String>>();
+            out_arr(out, users.len());
+            for user in users {
+                out_str(out, user.as_bytes());
+            }
+        }
+        ("users", 2) => {
+            let names = acl.users().map(|u| u.name.clone()).collect::<Vec<String>>();
+            out_arr(out, names.len());
+            for name in names {
+                out_str(out, name.as_bytes());
+            }
+        }
+        ("whoami", 2) => {
+            let user = ctx
+                .clients
+                .as_deref()
+                .and_then(|c| c.get(me))
+                .and_then(|c| c.user.clone())
+                .unwrap_or_else(|| DEFAULT_USER.to_string());
+            out_str(out, user.as_bytes());
+        }
+        ("cat", 2) => {
+            out_arr(out, CATEGORIES.len());
+            for category in CATEGORIES {
+                out_str(out, category.as_bytes());
+            }
+        }
+        ("cat", 3) => {
+            let category = args[2].to_ascii_lowercase();
+            if !CATEGORIES.contains(&category.as_str()) {
+                return out_err(out, ErrorCode::Err, &format!("Unknown category '{}'", args[2]));
+            }
+            let names = command::all()
+                .iter()
+                .filter(|cmd| cmd.categories().contains(&category.as_str()))
+                .map(|cmd| cmd.name)
+                .collect::<Vec<&str>>();
+            out_arr(out, names.len());
+            for name in names {
+                out_str(out, name.as_bytes());
+            }
+        }
+        ("save", 2) => match acl.save() {
+            Ok(()) => out_ok(out),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        },
+        ("load", 2) => match acl.load() {
+            Ok(()) => out_ok(out),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        },
+        ("setuser" | "getuser" | "deluser" | "list" | "users" | "whoami" | "cat" | "save" | "load", _) => {
+            out_wrong_arity(out, &format!("acl|{}", sub))
+        }
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", args[1])),
+    }
+}
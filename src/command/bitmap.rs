@@ -0,0 +1,443 @@
+use std::borrow::Cow;
+
+use crate::database::notify::{NOTIFY_GENERIC, NOTIFY_STRING};
+use crate::database::value::Value;
+use crate::database::Database;
+use crate::debug;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{parse_arg, Context, OOM_MSG, WRONGTYPE_MSG};
+
+/// Strings are limited to 512MB, so bits are addressed with 32 bits
+const MAX_BITS: u64 = 1 << 32;
+const OFFSET_MSG: &str = "bit offset is not an integer or out of range";
+const SYNTAX_MSG: &str = "syntax error";
+
+/// The bytes of a string and its expiry time
+type Stored<'a> = (Cow<'a, [u8]>, Option<u64>);
+
+/// The string at `key`, `Ok(None)` if there is none and `Err` if the key
+/// holds a collection
+fn bytes<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<Stored<'a>>, ()> {
+    match db.peek(key) {
+        Some((value, expire_at)) => value.as_bytes().map(|b| Some((b, expire_at))).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+/// Change the string at `key` with `f`, padded with zeros to at least `len`
+/// bytes. Strings that are long enough are changed where they are stored,
+/// others are copied and stored again. `f` returns whether it wrote, only
+/// then is the string stored or `event` notified.
+fn write(
+    ctx: &mut Context,
+    key: &[u8],
+    len: usize,
+    event: &'static str,
+    f: impl FnOnce(&mut [u8]) -> bool,
+) -> Result<(), (ErrorCode, &'static str)> {
+    let long_enough = match bytes(ctx.db(), key) {
+        Ok(existing) => existing.is_some_and(|(bytes, _)| bytes.len() >= len),
+        Err(()) => return Err((ErrorCode::WrongType, WRONGTYPE_MSG)),
+    };
+    if long_enough {
+        // Integers aren't stored as bytes, they go the long way
+        if let Some(bytes) = ctx.db().string_mut(key) {
+            if f(bytes) {
+                ctx.db().notify(NOTIFY_STRING, event, key);
+            }
+            return Ok(());
+        }
+    }
+    let Ok(existing) = bytes(ctx.db(), key) else {
+        return Err((ErrorCode::WrongType, WRONGTYPE_MSG));
+    };
+    let (mut bytes, expire_at) = existing.map(|(bytes, at)| (bytes.into_owned(), at)).unwrap_or_default();
+    if bytes.len() < len {
+        bytes.resize(len, 0);
+    }
+    if f(&mut bytes) && store(ctx, key, bytes, expire_at, event) == ResponseStatus::Err {
+        return Err((ErrorCode::Oom, OOM_MSG));
+    }
+    Ok(())
+}
+
+fn store(ctx: &mut Context, key: &[u8], bytes: Vec<u8>, expire_at: Option<u64>, event: &'static str) -> ResponseStatus {
+    let status = ctx.db().put(key.to_vec(), Value::from_bytes(bytes), expire_at);
+    if status != ResponseStatus::Err {
+        ctx.db().notify(NOTIFY_STRING, event, key);
+    }
+    status
+}
+
+fn parse_offset(arg: &[u8]) -> Option<u64> {
+    parse_arg::<u64>(arg).filter(|n| *n < MAX_BITS)
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    bytes.get((offset / 8) as usize).is_some_and(|b| b & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Write `bit` at `offset`, within `bytes`
+fn set_bit(bytes: &mut [u8], offset: u64, bit: bool) {
+    let index = (offset / 8) as usize;
+    let mask = 0x80 >> (offset % 8);
+    if bit {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+}
+
+/// `setbit key offset 0|1`, the bit that was there before
+pub fn setbit(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(offset) = parse_offset(&args[2]) else {
+        return out_err(out, ErrorCode::Err, OFFSET_MSG);
+    };
+    let bit = match args[3].as_slice() {
+        b"0" => false,
+        b"1" => true,
+        _ => return out_err(out, ErrorCode::Err, "bit is not an integer or out of range"),
+    };
+    let len = (offset / 8) as usize + 1;
+    let (old, grows) = match bytes(ctx.db(), &args[1]) {
+        Ok(existing) => existing.map_or((false, true), |(bytes, _)| (get_bit(&bytes, offset), bytes.len() < len)),
+        Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+    };
+    if old != bit || grows {
+        debug!("COMMAND: setbit {} {} {}", String::from_utf8_lossy(&args[1]), offset, bit as u8);
+        let written = write(ctx, &args[1], len, "setbit", |bytes| {
+            set_bit(bytes, offset, bit);
+            true
+        });
+        if let Err((code, msg)) = written {
+            return out_err(out, code, msg);
+        }
+    }
+    out_int(out, old as i64);
+}
+
+/// `getbit key offset`, 0 past the end of the string
+pub fn getbit(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(offset) = parse_offset(&args[2]) else {
+        return out_err(out, ErrorCode::Err, OFFSET_MSG);
+    };
+    match bytes(ctx.db(), &args[1]) {
+        Ok(bytes) => out_int(out, bytes.is_some_and(|(b, _)| get_bit(&b, offset)) as i64),
+        Err(()) => out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+    }
+}
+
+/// The bits from `start` to `end` of a string of `len` bytes, given in bytes
+/// or with BIT in bits, negative ones counting from the end. `Ok(None)` if
+/// the range is empty.
+fn bit_range(len: usize, start: &[u8], end: Option<&[u8]>, unit: Option<&[u8]>) -> Result<Option<(u64, u64)>, &'static str> {
+    let bits = match unit.map(|u| u.to_ascii_lowercase()).as_deref() {
+        None | Some(b"byte") => false,
+        Some(b"bit") => true,
+        Some(_) => return Err(SYNTAX_MSG),
+    };
+    let len = if bits { len as i64 * 8 } else { len as i64 };
+    let parse = |arg: &[u8]| parse_arg::<i64>(arg).ok_or("value is not an integer or out of range");
+    let mut start = parse(start)?;
+    let mut end = match end {
+        Some(end) => parse(end)?,
+        None => len - 1,
+    };
+    if start < 0 {
+        start = (start + len).max(0);
+    }
+    if end < 0 {
+        end = (end + len).max(0);
+    }
+    end = end.min(len - 1);
+    if len == 0 || start > end {
+        return Ok(None);
+    }
+    let (start, end) = (start as u64, end as u64);
+    Ok(Some(if bits { (start, end) } else { (start * 8, end * 8 + 7) }))
+}
+
+/// `bitcount key [start end [BYTE|BIT]]`, the number of set bits
+pub fn bitcount(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if args.len() == 3 || args.len() > 5 {
+        return out_err(out, ErrorCode::Err, SYNTAX_MSG);
+    }
+    let bytes = match bytes(ctx.db(), &args[1]) {
+        Ok(bytes) => bytes.map(|(b, _)| b).unwrap_or_default(),
+        Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+    };
+    let range = match args.get(2) {
+        Some(start) => bit_range(bytes.len(), start, args.get(3).map(|s| s.as_slice()), args.get(4).map(|s| s.as_slice())),
+        None => bit_range(bytes.len(), b"0", None, None),
+    };
+    match range {
+        Ok(Some((first, last))) => {
+            // Whole bytes in the middle are counted at once
+            let (first_byte, last_byte) = (first.div_ceil(8), (last + 1) / 8);
+            let count = if first_byte < last_byte {
+                let edges = (first..first_byte * 8).chain(last_byte * 8..=last);
+                let middle = bytes[first_byte as usize..last_byte as usize].iter().map(|b| b.count_ones() as u64).sum::<u64>();
+                middle + edges.filter(|i| get_bit(&bytes, *i)).count() as u64
+            } else {
+                (first..=last).filter(|i| get_bit(&bytes, *i)).count() as u64
+            };
+            out_int(out, count as i64)
+        }
+        Ok(None) => out_int(out, 0),
+        Err(msg) => out_err(out, ErrorCode::Err, msg),
+    }
+}
+
+/// `bitpos key 0|1 [start [end [BYTE|BIT]]]`, the first bit set to the
+/// given value, -1 if there is none. Looking for a clear bit without an end
+/// finds the first one past the string.
+pub fn bitpos(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if args.len() > 6 {
+        return out_err(out, ErrorCode::Err, SYNTAX_MSG);
+    }
+    let bit = match args[2].as_slice() {
+        b"0" => false,
+        b"1" => true,
+        _ => return out_err(out, ErrorCode::Err, "The bit argument must be 1 or 0."),
+    };
+    let bytes = match bytes(ctx.db(), &args[1]) {
+        Ok(Some((bytes, _))) => bytes,
+        Ok(None) => return out_int(out, if bit { -1 } else { 0 }),
+        Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+    };
+    let start = args.get(3).map_or(&b"0"[..], |s| s.as_slice());
+    let end = args.get(4).map(|s| s.as_slice());
+    let (first, last) = match bit_range(bytes.len(), start, end, args.get(5).map(|s| s.as_slice())) {
+        Ok(Some(range)) => range,
+        Ok(None) => return out_int(out, -1),
+        Err(msg) => return out_err(out, ErrorCode::Err, msg),
+    };
+    match (first..=last).find(|i| get_bit(&bytes, *i) == bit) {
+        Some(pos) => out_int(out, pos as i64),
+        None if !bit && end.is_none() => out_int(out, last as i64 + 1),
+        None => out_int(out, -1),
+    }
+}
+
+/// `bitop AND|OR|XOR|NOT destkey key [key ...]`, the length of the result.
+/// Shorter strings are padded with zeros, an empty result deletes `destkey`.
+pub fn bitop(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let op = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    if !matches!(op.as_str(), "and" | "or" | "xor" | "not") {
+        return out_err(out, ErrorCode::Err, SYNTAX_MSG);
+    }
+    if op == "not" && args.len() != 4 {
+        return out_err(out, ErrorCode::Err, "BITOP NOT must be called with a single source key.");
+    }
+    let mut sources = vec![];
+    for key in &args[3..] {
+        match bytes(ctx.db(), key) {
+            Ok(bytes) => sources.push(bytes.map(|(b, _)| b.into_owned()).unwrap_or_default()),
+            Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+        }
+    }
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let result = (0..len)
+        .map(|i| {
+            let mut bytes = sources.iter().map(|s| s.get(i).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match op.as_str() {
+                "and" => bytes.fold(first, |acc, b| acc & b),
+                "or" => bytes.fold(first, |acc, b| acc | b),
+                "xor" => bytes.fold(first, |acc, b| acc ^ b),
+                _ => !first,
+            }
+        })
+        .collect::<Vec<u8>>();
+
+    debug!(
+        "COMMAND: bitop {} {} {}",
+        op,
+        String::from_utf8_lossy(&args[2]),
+        String::from_utf8_lossy(&args[3..].join(&b' '))
+    );
+    if result.is_empty() {
+        if ctx.db().del(&args[2]) == ResponseStatus::Ok {
+            ctx.db().notify(NOTIFY_GENERIC, "del", &args[2]);
+        }
+        return out_int(out, 0);
+    }
+    if store(ctx, &args[2], result, None, "set") == ResponseStatus::Err {
+        return out_err(out, ErrorCode::Oom, OOM_MSG);
+    }
+    out_int(out, len as i64);
+}
+
+/// An integer type of `bitfield`, like `i8` or `u16`
+#[derive(Clone, Copy)]
+struct Field {
+    signed: bool,
+    bits: u32,
+}
+
+impl Field {
+    fn parse(arg: &[u8]) -> Option<Field> {
+        let signed = match arg.first()? {
+            b'i' | b'I' => true,
+            b'u' | b'U' => false,
+            _ => return None,
+        };
+        let bits = parse_arg::<u32>(&arg[1..])?;
+        let max = if signed { 64 } else { 63 };
+        (1..=max).contains(&bits).then_some(Field { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1i128 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1i128 << (self.bits - 1)) - 1 } else { (1i128 << self.bits) - 1 }
+    }
+
+    /// `offset` in bits, or with a `#` prefix in multiples of the width
+    fn parse_offset(&self, arg: &[u8]) -> Option<u64> {
+        let offset = match arg.strip_prefix(b"#") {
+            Some(n) => parse_arg::<u64>(n)?.checked_mul(self.bits as u64)?,
+            None => parse_arg::<u64>(arg)?,
+        };
+        (offset + self.bits as u64 <= MAX_BITS).then_some(offset)
+    }
+
+    fn get(&self, bytes: &[u8], offset: u64) -> i64 {
+        let raw = (0..self.bits as u64).fold(0u64, |acc, i| acc << 1 | get_bit(bytes, offset + i) as u64);
+        if self.signed && self.bits < 64 && raw >> (self.bits - 1) & 1 == 1 {
+            // Sign extend
+            (raw | (u64::MAX << self.bits)) as i64
+        } else {
+            raw as i64
+        }
+    }
+
+    fn set(&self, bytes: &mut [u8], offset: u64, value: i64) {
+        for i in 0..self.bits as u64 {
+            set_bit(bytes, offset + i, (value as u64) >> (self.bits as u64 - 1 - i) & 1 == 1);
+        }
+    }
+
+    /// Bring `value` within range, `None` if it doesn't fit and overflows
+    /// fail
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let wrapped = value.rem_euclid(1i128 << self.bits);
+                let wrapped = if wrapped > self.max() { wrapped - (1i128 << self.bits) } else { wrapped };
+                Some(wrapped as i64)
+            }
+            Overflow::Sat => Some(value.clamp(self.min(), self.max()) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+enum Op {
+    Get,
+    Set(i64),
+    Incrby(i64),
+}
+
+/// `bitfield key [GET type offset] [SET type offset value] [INCRBY type
+/// offset increment] [OVERFLOW WRAP|SAT|FAIL] ...`, a reply for each GET,
+/// SET and INCRBY: the value read, the old value and the new value, or nil
+/// when an overflow failed it
+pub fn bitfield(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let mut ops = vec![];
+    let mut overflow = Overflow::Wrap;
+    let mut i = 2;
+    while i < args.len() {
+        let sub = String::from_utf8_lossy(&args[i]).to_ascii_lowercase();
+        if sub == "overflow" {
+            overflow = match args.get(i + 1).map(|a| a.to_ascii_lowercase()).as_deref() {
+                Some(b"wrap") => Overflow::Wrap,
+                Some(b"sat") => Overflow::Sat,
+                Some(b"fail") => Overflow::Fail,
+                Some(_) => return out_err(out, ErrorCode::Err, "Invalid OVERFLOW type specified"),
+                None => return out_err(out, ErrorCode::Err, SYNTAX_MSG),
+            };
+            i += 2;
+            continue;
+        }
+        let operands = if sub == "get" { 2 } else { 3 };
+        if !matches!(sub.as_str(), "get" | "set" | "incrby") || i + operands >= args.len() {
+            return out_err(out, ErrorCode::Err, SYNTAX_MSG);
+        }
+        let Some(field) = Field::parse(&args[i + 1]) else {
+            let msg = "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.";
+            return out_err(out, ErrorCode::Err, msg);
+        };
+        let Some(offset) = field.parse_offset(&args[i + 2]) else {
+            return out_err(out, ErrorCode::Err, OFFSET_MSG);
+        };
+        let op = match sub.as_str() {
+            "get" => Op::Get,
+            _ => {
+                let Some(n) = parse_arg::<i64>(&args[i + 3]) else {
+                    return out_not_int(out);
+                };
+                if sub == "set" { Op::Set(n) } else { Op::Incrby(n) }
+            }
+        };
+        ops.push((op, field, offset, overflow));
+        i += 1 + operands;
+    }
+
+    // Written out only once the result is stored
+    let mut reply = vec![];
+    out_arr(&mut reply, ops.len());
+    // The string needs to reach as far as the fields that may be written
+    let writes = ops.iter().filter(|(op, ..)| !matches!(op, Op::Get));
+    let Some(len) = writes.map(|(_, field, offset, _)| (offset + field.bits as u64).div_ceil(8) as usize).max() else {
+        let bytes = match bytes(ctx.db(), &args[1]) {
+            Ok(bytes) => bytes.map(|(b, _)| b).unwrap_or_default(),
+            Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+        };
+        for (_, field, offset, _) in ops {
+            out_int(&mut reply, field.get(&bytes, offset));
+        }
+        return out.extend_from_slice(&reply);
+    };
+    let written = write(ctx, &args[1], len, "setbit", |bytes| {
+        let mut written = false;
+        for (op, field, offset, overflow) in ops {
+            let old = field.get(bytes, offset);
+            let new = match op {
+                Op::Get => {
+                    out_int(&mut reply, old);
+                    continue;
+                }
+                Op::Set(value) => field.fit(value as i128, overflow),
+                Op::Incrby(increment) => field.fit(old as i128 + increment as i128, overflow),
+            };
+            let Some(new) = new else {
+                out_nil(&mut reply);
+                continue;
+            };
+            field.set(bytes, offset, new);
+            written = true;
+            out_int(&mut reply, if matches!(op, Op::Set(_)) { old } else { new });
+        }
+        written
+    });
+    if let Err((code, msg)) = written {
+        return out_err(out, code, msg);
+    }
+    debug!("COMMAND: bitfield {}", String::from_utf8_lossy(&args[1..].join(&b' ')));
+    out.extend_from_slice(&reply);
+}
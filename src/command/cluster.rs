@@ -0,0 +1,350 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::client::{Client, Reply};
+use crate::cluster::{key_hash_slot, BUS_PORT_OFFSET, SLOTS};
+use crate::database::dump;
+use crate::database::eviction::now_ms;
+use crate::database::notify::NOTIFY_GENERIC;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{parse_arg, Command, Context, OOM_MSG};
+
+const DISABLED_MSG: &str = "This instance has cluster support disabled";
+
+/// Write a redirect or an error instead of running `cmd` if the slot of its
+/// keys isn't served here. Returns whether it did.
+pub fn redirect(ctx: &mut Context, cmd: &Command, args: &[Vec<u8>], out: &mut Vec<u8>) -> bool {
+    let client = ctx.client_id;
+    let Some(cluster) = ctx.cluster.as_deref_mut() else {
+        return false;
+    };
+    // MIGRATE looks its keys up locally, it is never redirected
+    if matches!(cmd.name, "asking" | "migrate") {
+        return false;
+    }
+    let asking = cluster.take_asking(client) || cmd.name == "restore-asking";
+    let keys = cmd.key_indices(args);
+    let Some(&first) = keys.first() else {
+        return false;
+    };
+    let slot = key_hash_slot(&args[first]);
+    if keys.iter().any(|&i| key_hash_slot(&args[i]) != slot) {
+        out_err(out, ErrorCode::CrossSlot, "Keys in request don't hash to the same slot");
+        return true;
+    }
+    let Some(owner) = cluster.owner(slot).map(|node| node.addr()) else {
+        out_err(out, ErrorCode::ClusterDown, "Hash slot not served");
+        return true;
+    };
+    let importing = cluster.importing_from(slot).is_some();
+    if !(cluster.is_mine(slot) || importing && asking) {
+        out_err(out, ErrorCode::Moved, &format!("{} {}", slot, owner));
+        return true;
+    }
+
+    // While a slot moves, each key is on one of the two nodes
+    let migrating = cluster.migrating_to(slot).map(|node| node.addr());
+    if migrating.is_none() && !importing {
+        return false;
+    }
+    let missing = keys.iter().filter(|&&i| !ctx.db().contains(&args[i])).count();
+    match migrating {
+        Some(target) if missing == keys.len() => out_err(out, ErrorCode::Ask, &format!("{} {}", slot, target)),
+        _ if missing > 0 && keys.len() > 1 => {
+            out_err(out, ErrorCode::TryAgain, "Multiple keys request during rehashing of slot")
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn parse_slot(arg: &[u8]) -> Result<u16, String> {
+    match parse_arg::<u16>(arg) {
+        Some(slot) if (slot as usize) < SLOTS => Ok(slot),
+        _ => Err(format!("Invalid or out of range slot {}", String::from_utf8_lossy(arg))),
+    }
+}
+
+/// Slot numbers, or pairs of first and last slot if `ranges`, each slot
+/// given only once
+fn parse_slots(args: &[Vec<u8>], ranges: bool) -> Result<Vec<u16>, String> {
+    let mut slots = vec![];
+    let mut seen = vec![false; SLOTS];
+    let step = if ranges { 2 } else { 1 };
+    for pair in args.chunks(step) {
+        let start = parse_slot(&pair[0])?;
+        let end = match pair.get(1) {
+            Some(arg) => parse_slot(arg)?,
+            None => start,
+        };
+        if start > end {
+            return Err(format!("start slot number {} is greater than end slot number {}", start, end));
+        }
+        for slot in start..=end {
+            if std::mem::replace(&mut seen[slot as usize], true) {
+                return Err(format!("Slot {} specified multiple times", slot));
+            }
+            slots.push(slot);
+        }
+    }
+    Ok(slots)
+}
+
+/// `cluster info|myid|nodes|slots|keyslot|countkeysinslot|getkeysinslot|
+/// addslots|delslots|addslotsrange|delslotsrange|setslot|meet ...`
+pub fn cluster(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(cluster) = ctx.cluster.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, DISABLED_MSG);
+    };
+    let db = &ctx.dbs[ctx.db_index];
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("info", 2) => out_str(out, cluster.info().as_bytes()),
+        ("myid", 2) => out_str(out, cluster.myself().id.as_bytes()),
+        ("nodes", 2) => out_str(out, cluster.describe_nodes(Instant::now()).as_bytes()),
+        ("slots", 2) => {
+            let ranges = cluster.slot_ranges();
+            out_arr(out, ranges.len());
+            for (start, end, node) in ranges {
+                out_arr(out, 3);
+                out_int(out, start as i64);
+                out_int(out, end as i64);
+                out_arr(out, 3);
+                out_str(out, node.ip.to_string().as_bytes());
+                out_int(out, node.port as i64);
+                out_str(out, node.id.as_bytes());
+            }
+        }
+        ("keyslot", 3) => out_int(out, key_hash_slot(&args[2]) as i64),
+        ("countkeysinslot", 3) => match parse_slot(&args[2]) {
+            Ok(slot) => out_int(out, db.keys().filter(|k| key_hash_slot(k) == slot).count() as i64),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        },
+        ("getkeysinslot", 4) => {
+            let slot = match parse_slot(&args[2]) {
+                Ok(slot) => slot,
+                Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+            };
+            let Some(count) = parse_arg::<usize>(&args[3]) else {
+                return out_err(out, ErrorCode::Err, "Invalid number of keys");
+            };
+            let keys = db.keys().filter(|k| key_hash_slot(k) == slot).take(count).collect::<Vec<_>>();
+            out_arr(out, keys.len());
+            for key in keys {
+                out_str(out, key);
+            }
+        }
+        ("addslots" | "delslots" | "addslotsrange" | "delslotsrange", n)
+            if n >= 3 && (!sub.ends_with("range") || n % 2 == 0) =>
+        {
+            let slots = match parse_slots(&args[2..], sub.ends_with("range")) {
+                Ok(slots) => slots,
+                Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+            };
+            let res = if sub.starts_with("add") { cluster.add_slots(&slots) } else { cluster.del_slots(&slots) };
+            match res {
+                Ok(()) => out_ok(out),
+                Err(msg) => out_err(out, ErrorCode::Err, &msg),
+            }
+        }
+        ("setslot", 4 | 5) => {
+            let slot = match parse_slot(&args[2]) {
+                Ok(slot) => slot,
+                Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+            };
+            let id = args.get(4).map(|id| String::from_utf8_lossy(id));
+            let res = match (args[3].to_ascii_lowercase().as_slice(), id.as_deref()) {
+                (b"migrating", Some(id)) => cluster.set_migrating(slot, id),
+                (b"importing", Some(id)) => cluster.set_importing(slot, id),
+                (b"stable", None) => {
+                    cluster.set_stable(slot);
+                    Ok(())
+                }
+                (b"node", Some(id)) => {
+                    let has_keys = db.keys().any(|k| key_hash_slot(k) == slot);
+                    cluster.set_node(slot, id, has_keys)
+                }
+                _ => Err("Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP".to_string()),
+            };
+            match res {
+                Ok(()) => out_ok(out),
+                Err(msg) => out_err(out, ErrorCode::Err, &msg),
+            }
+        }
+        ("meet", 4 | 5) => {
+            let Some(ip) = parse_arg::<IpAddr>(&args[2]) else {
+                let msg = format!(
+                    "Invalid node address specified: {}:{}",
+                    String::from_utf8_lossy(&args[2]),
+                    String::from_utf8_lossy(&args[3])
+                );
+                return out_err(out, ErrorCode::Err, &msg);
+            };
+            let Some(port) = parse_arg::<u16>(&args[3]) else {
+                let msg = format!("Invalid base port specified: {}", String::from_utf8_lossy(&args[3]));
+                return out_err(out, ErrorCode::Err, &msg);
+            };
+            let bus_port = match args.get(4) {
+                Some(arg) => parse_arg::<u16>(arg),
+                None => port.checked_add(BUS_PORT_OFFSET),
+            };
+            let Some(bus_port) = bus_port else {
+                let arg = args.get(4).map(|arg| String::from_utf8_lossy(arg)).unwrap_or_default();
+                return out_err(out, ErrorCode::Err, &format!("Invalid bus port specified: {}", arg));
+            };
+            cluster.meet(SocketAddr::new(ip, bus_port));
+            out_ok(out);
+        }
+        (
+            "info" | "myid" | "nodes" | "slots" | "keyslot" | "countkeysinslot" | "getkeysinslot" | "addslots"
+            | "delslots" | "addslotsrange" | "delslotsrange" | "setslot" | "meet",
+            _,
+        ) => out_wrong_arity(out, &format!("cluster|{}", sub)),
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1]))),
+    }
+}
+
+/// `asking`, lets the next command use a slot being imported
+pub fn asking(ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let client = ctx.client_id;
+    match ctx.cluster.as_deref_mut() {
+        Some(cluster) => {
+            cluster.set_asking(client);
+            out_ok(out);
+        }
+        None => out_err(out, ErrorCode::Err, DISABLED_MSG),
+    }
+}
+
+/// `migrate host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [KEYS key ...]`, moves keys to another node with `restore-asking`.
+/// Blocks everyone for up to `timeout` milliseconds per step.
+pub fn migrate(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let (Some(port), Some(db), Some(timeout)) =
+        (parse_arg::<u16>(&args[2]), parse_arg::<u64>(&args[4]), parse_arg::<u64>(&args[5]))
+    else {
+        return out_not_int(out);
+    };
+    let mut copy = false;
+    let mut replace = false;
+    let mut keys = &args[3..4];
+    let mut i = 6;
+    while i < args.len() {
+        match args[i].to_ascii_lowercase().as_slice() {
+            b"copy" => copy = true,
+            b"replace" => replace = true,
+            b"keys" => {
+                if !args[3].is_empty() {
+                    let msg = "When using MIGRATE KEYS option, the key argument must be set to the empty string";
+                    return out_err(out, ErrorCode::Err, msg);
+                }
+                keys = &args[i + 1..];
+                break;
+            }
+            _ => return out_err(out, ErrorCode::Err, "syntax error"),
+        }
+        i += 1;
+    }
+
+    // Keys that don't exist are skipped
+    let now = now_ms();
+    let mut requests = vec![];
+    for key in keys {
+        if let Some((value, expire_at)) = ctx.db().peek(key) {
+            let ttl = expire_at.map_or(0, |at| at.saturating_sub(now).max(1));
+            let payload = dump::to_hex(&dump::serialize(value)).into_bytes();
+            let mut request = vec![b"restore-asking".to_vec(), key.clone(), ttl.to_string().into_bytes(), payload];
+            if replace {
+                request.push(b"replace".to_vec());
+            }
+            requests.push(request);
+        }
+    }
+    if requests.is_empty() {
+        return out_str(out, b"NOKEY");
+    }
+
+    let timeout = Duration::from_millis(if timeout == 0 { 1000 } else { timeout });
+    let client = (String::from_utf8_lossy(&args[1]).as_ref(), port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .and_then(|addr| Client::connect_timeout(&addr, timeout).ok())
+        .and_then(|mut client| client.set_timeout(Some(timeout)).ok().map(|_| client));
+    let Some(mut client) = client else {
+        return out_err(out, ErrorCode::IoErr, "error or timeout connecting to the client");
+    };
+    if db != 0 {
+        requests.insert(0, vec![b"select".to_vec(), db.to_string().into_bytes()]);
+    }
+    for request in requests {
+        match client.command(&request) {
+            Ok(Reply::Error { code, message }) => {
+                let msg = format!("Target instance replied with error: {} {}", code, message);
+                return out_err(out, ErrorCode::Err, &msg);
+            }
+            Ok(_) if copy || request[0] != b"restore-asking" => {}
+            Ok(_) => {
+                ctx.db().del(&request[1]);
+                ctx.db().notify(NOTIFY_GENERIC, "del", &request[1]);
+            }
+            Err(e) => return out_err(out, ErrorCode::IoErr, &format!("error or timeout reading from the target: {}", e)),
+        }
+    }
+    out_ok(out);
+}
+
+/// `dump key`, the serialized value of `key` for `restore`, nil if it
+/// doesn't exist
+pub fn dump(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    match ctx.db().peek(&args[1]) {
+        Some((value, _)) => out_str(out, dump::to_hex(&dump::serialize(value)).as_bytes()),
+        None => out_nil(out),
+    }
+}
+
+/// `restore key ttl payload [REPLACE] [ABSTTL]`, also run as
+/// `restore-asking` for keys sent by `migrate`. `ttl` is in milliseconds,
+/// 0 for none, or the Unix time in milliseconds with `ABSTTL`.
+pub fn restore(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let mut replace = false;
+    let mut absolute = false;
+    for arg in &args[4..] {
+        match arg.to_ascii_lowercase().as_slice() {
+            b"replace" => replace = true,
+            b"absttl" => absolute = true,
+            _ => return out_err(out, ErrorCode::Err, "syntax error"),
+        }
+    }
+    let Some(ttl) = parse_arg::<u64>(&args[2]) else {
+        return out_err(out, ErrorCode::Err, "Invalid TTL value, must be >= 0");
+    };
+    let limits = *ctx.db().encoding_limits();
+    let payload = std::str::from_utf8(&args[3]).ok().and_then(dump::from_hex);
+    let Some(value) = payload.and_then(|payload| dump::deserialize(&payload, &limits)) else {
+        return out_err(out, ErrorCode::Err, "DUMP payload version or checksum are wrong");
+    };
+    if ctx.db().contains(&args[1]) {
+        if !replace {
+            return out_err(out, ErrorCode::BusyKey, "Target key name already exists.");
+        }
+        ctx.db().del(&args[1]);
+    }
+    let now = now_ms();
+    let expire_at = match (ttl, absolute) {
+        (0, _) => None,
+        (at, true) if at <= now => {
+            // Already expired, nothing to store
+            return out_ok(out);
+        }
+        (at, true) => Some(at),
+        (ttl, false) => Some(now.saturating_add(ttl)),
+    };
+    if ctx.db().put(args[1].clone(), value, expire_at) == ResponseStatus::Err {
+        return out_err(out, ErrorCode::Oom, OOM_MSG);
+    }
+    ctx.db().notify(NOTIFY_GENERIC, "restore", &args[1]);
+    out_ok(out);
+}
@@ -0,0 +1,329 @@
+use std::time::Duration;
+
+use crate::acl::DEFAULT_USER;
+use crate::protocol::*;
+use crate::server::tracking::TrackingOptions;
+use crate::server::{ClientInfo, Clients, PauseMode};
+
+use super::{parse_arg, Context};
+
+/// Names show up in `client list`, which is space and newline separated
+fn valid_name(name: &[u8]) -> bool {
+    name.iter().all(|b| (b'!'..=b'~').contains(b))
+}
+
+/// Which clients `client kill` applies to
+#[derive(Default)]
+struct KillFilter {
+    id: Option<u64>,
+    addr: Option<String>,
+    skip_me: bool,
+}
+
+impl KillFilter {
+    /// Parse `ID id`, `ADDR ip:port` and `SKIPME yes|no` pairs
+    fn parse(args: &[Vec<u8>]) -> Result<KillFilter, String> {
+        if !args.len().is_multiple_of(2) {
+            return Err("syntax error".to_string());
+        }
+        let mut filter = KillFilter { skip_me: true, ..KillFilter::default() };
+        for pair in args.chunks(2) {
+            let value = &pair[1];
+            match pair[0].to_ascii_lowercase().as_slice() {
+                b"id" => match parse_arg::<u64>(value) {
+                    Some(id) if id > 0 => filter.id = Some(id),
+                    _ => return Err("client-id should be greater than 0".to_string()),
+                },
+                b"addr" => filter.addr = Some(String::from_utf8_lossy(value).into_owned()),
+                b"skipme" => match value.to_ascii_lowercase().as_slice() {
+                    b"yes" => filter.skip_me = true,
+                    b"no" => filter.skip_me = false,
+                    _ => return Err("syntax error".to_string()),
+                },
+                _ => return Err("syntax error".to_string()),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, client: &ClientInfo, me: u64) -> bool {
+        !(self.skip_me && client.id == me)
+            && self.id.is_none_or(|id| id == client.id)
+            && self.addr.as_ref().is_none_or(|addr| client.addr.is_some_and(|a| a.to_string() == *addr))
+    }
+}
+
+/// Parse the options of `client tracking on`: `REDIRECT id`, `BCAST`,
+/// `PREFIX prefix` and `NOLOOP`
+fn parse_tracking(args: &[Vec<u8>], clients: &Clients) -> Result<TrackingOptions, String> {
+    let mut options = TrackingOptions::default();
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].to_ascii_lowercase().as_slice(), value) {
+            (b"redirect", Some(id)) => {
+                let Some(id) = parse_arg::<u64>(id) else {
+                    return Err("Invalid client ID".to_string());
+                };
+                if clients.get(id).is_none() {
+                    return Err("The client ID you want redirect to does not exist".to_string());
+                }
+                options.redirect = Some(id);
+                i += 1;
+            }
+            (b"prefix", Some(prefix)) => {
+                options.prefixes.push(prefix.clone());
+                i += 1;
+            }
+            (b"bcast", _) => options.bcast = true,
+            (b"noloop", _) => options.noloop = true,
+            _ => return Err("syntax error".to_string()),
+        }
+        i += 1;
+    }
+    if !options.prefixes.is_empty() && !options.bcast {
+        return Err("PREFIX option requires BCAST mode to be enabled".to_string());
+    }
+    Ok(options)
+}
+
+fn kill(clients: &mut Clients, filter: &KillFilter, me: u64) -> usize {
+    let ids = clients
+        .iter()
+        .filter(|c| filter.matches(c, me))
+        .map(|c| c.id)
+        .collect::<Vec<u64>>();
+    for id in &ids {
+        clients.kill(*id);
+    }
+    ids.len()
+}
+
+/// `client id|info|list|getname|setname|kill|pause|unpause`
+pub fn client(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let me = ctx.client_id;
+    let Some(clients) = ctx.clients.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, "client commands are not available");
+    };
+
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("id", 2) => out_int(out, me as i64),
+        ("getname", 2) => match clients.get(me) {
+            Some(client) if !client.name.is_empty() => out_str(out, client.name.as_bytes()),
+            _ => out_nil(out),
+        },
+        ("setname", 3) => {
+            if !valid_name(&args[2]) {
+                let msg = "Client names cannot contain spaces, newlines or special characters.";
+                return out_err(out, ErrorCode::Err, msg);
+            }
+            if let Some(client) = clients.get_mut(me) {
+                client.name = String::from_utf8_lossy(&args[2]).into_owned();
+            }
+            out_ok(out);
+        }
+        ("info", 2) => {
+            let mut info = String::new();
+            if let Some(client) = clients.get(me) {
+                client.describe(&mut info);
+            }
+            out_str(out, info.as_bytes());
+        }
+        ("list", 2) => {
+            let mut list = String::new();
+            for client in clients.iter() {
+                client.describe(&mut list);
+            }
+            out_str(out, list.as_bytes());
+        }
+        ("list", n) if n > 3 && args[2].eq_ignore_ascii_case(b"id") => {
+            let mut list = String::new();
+            for id in &args[3..] {
+                let Some(id) = parse_arg::<u64>(id) else {
+                    return out_err(out, ErrorCode::Err, "Invalid client ID");
+                };
+                if let Some(client) = clients.get(id) {
+                    client.describe(&mut list);
+                }
+            }
+            out_str(out, list.as_bytes());
+        }
+        // The old form, `client kill ip:port`
+        ("kill", 3) => {
+            let addr = String::from_utf8_lossy(&args[2]).into_owned();
+            let filter = KillFilter { addr: Some(addr), ..KillFilter::default() };
+            match kill(clients, &filter, me) {
+                0 => out_err(out, ErrorCode::Err, "No such client"),
+                _ => out_ok(out),
+            }
+        }
+        ("kill", n) if n > 3 => match KillFilter::parse(&args[2..]) {
+            Ok(filter) => out_int(out, kill(clients, &filter, me) as i64),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        },
+        ("pause", 3 | 4) => {
+            let Some(millis) = parse_arg::<u64>(&args[2]) else {
+                return out_err(out, ErrorCode::Err, "timeout is not an integer or out of range");
+            };
+            let mode = match args.get(3).map(|m| m.to_ascii_lowercase()).as_deref() {
+                None | Some(b"all") => PauseMode::All,
+                Some(b"write") => PauseMode::Write,
+                Some(_) => return out_err(out, ErrorCode::Err, "syntax error"),
+            };
+            clients.pause(Duration::from_millis(millis), mode);
+            out_ok(out);
+        }
+        ("unpause", 2) => {
+            clients.unpause();
+            out_ok(out);
+        }
+        ("tracking", n) if n >= 3 => {
+            let Some(tracking) = ctx.tracking.as_deref_mut() else {
+                return out_err(out, ErrorCode::Err, "client tracking is not available");
+            };
+            match args[2].to_ascii_lowercase().as_slice() {
+                b"on" => {
+                    let options = match parse_tracking(&args[3..], clients) {
+                        Ok(options) => options,
+                        Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+                    };
+                    if tracking.options(me).is_some_and(|o| o.bcast != options.bcast) {
+                        let msg = "You can't switch BCAST mode on/off before disabling tracking for this client, \
+                                   and then re-enabling it with a different mode.";
+                        return out_err(out, ErrorCode::Err, msg);
+                    }
+                    tracking.enable(me, options);
+                }
+                b"off" => tracking.disable(me),
+                _ => return out_err(out, ErrorCode::Err, "syntax error"),
+            }
+            // Databases only record written keys while someone is tracking
+            // them or replicating them
+            let active = tracking.is_active() || ctx.replication.as_deref().is_some_and(|r| r.has_replicas());
+            ctx.dbs.iter_mut().for_each(|db| db.set_track_modified(active));
+            out_ok(out);
+        }
+        ("getredir", 2) => {
+            let options = ctx.tracking.as_deref().and_then(|t| t.options(me));
+            out_int(out, options.map_or(-1, |o| o.redirect.map_or(0, |id| id as i64)));
+        }
+        ("id" | "getname" | "setname" | "info" | "list" | "kill" | "pause" | "unpause" | "tracking" | "getredir", _) => {
+            out_wrong_arity(out, &format!("client|{}", sub))
+        }
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1]))),
+    }
+}
+
+/// `select index`
+pub fn select(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if let Some(index) = ctx.parse_db_index(&args[1], out) {
+        if ctx.cluster.is_some() && index != 0 {
+            return out_err(out, ErrorCode::Err, "SELECT is not allowed in cluster mode");
+        }
+        ctx.db_index = index;
+        out_ok(out);
+    }
+}
+
+const WRONGPASS_MSG: &str = "invalid username-password pair or user is disabled.";
+
+/// Log the client in as `name`, returns false if the credentials are wrong.
+/// Names and passwords are text, other bytes never match.
+fn authenticate(ctx: &mut Context, name: &[u8], password: &[u8]) -> bool {
+    let (Ok(name), Ok(password)) = (std::str::from_utf8(name), std::str::from_utf8(password)) else {
+        return false;
+    };
+    let Some(user) = ctx.acl.as_deref().and_then(|acl| acl.authenticate(name, password)) else {
+        return false;
+    };
+    if let Some(client) = ctx.clients.as_deref_mut().and_then(|c| c.get_mut(ctx.client_id)) {
+        client.user = Some(user);
+    }
+    true
+}
+
+/// `auth [username] password`
+pub fn auth(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let (name, password) = match args.len() {
+        2 => (DEFAULT_USER.as_bytes(), args[1].as_slice()),
+        3 => (args[1].as_slice(), args[2].as_slice()),
+        _ => return out_err(out, ErrorCode::Err, "syntax error"),
+    };
+    if args.len() == 2 && ctx.acl.as_deref().is_none_or(|acl| acl.implicit_user().is_some()) {
+        let msg = "AUTH <password> called without any password configured for the default user. \
+                   Are you sure your configuration is correct?";
+        return out_err(out, ErrorCode::Err, msg);
+    }
+    if authenticate(ctx, name, password) {
+        out_ok(out);
+    } else {
+        out_err(out, ErrorCode::WrongPass, WRONGPASS_MSG);
+    }
+}
+
+/// `hello [protover [AUTH username password] [SETNAME name]]`, there is only
+/// protocol version 1
+pub fn hello(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if let Some(version) = args.get(1) {
+        match parse_arg::<i64>(version) {
+            Some(1) => {}
+            Some(_) => return out_err(out, ErrorCode::NoProto, "unsupported protocol version"),
+            None => return out_err(out, ErrorCode::Err, "Protocol version is not an integer or out of range"),
+        }
+    }
+
+    let mut name = None;
+    let mut authenticated = false;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].to_ascii_lowercase().as_slice() {
+            b"auth" if i + 2 < args.len() => {
+                if !authenticate(ctx, &args[i + 1], &args[i + 2]) {
+                    return out_err(out, ErrorCode::WrongPass, WRONGPASS_MSG);
+                }
+                authenticated = true;
+                i += 3;
+            }
+            b"setname" if i + 1 < args.len() => {
+                if !valid_name(&args[i + 1]) {
+                    let msg = "Client names cannot contain spaces, newlines or special characters.";
+                    return out_err(out, ErrorCode::Err, msg);
+                }
+                name = Some(String::from_utf8_lossy(&args[i + 1]).into_owned());
+                i += 2;
+            }
+            _ => {
+                let msg = format!("Syntax error in HELLO option '{}'", String::from_utf8_lossy(&args[i]));
+                return out_err(out, ErrorCode::Err, &msg);
+            }
+        }
+    }
+
+    let me = ctx.client_id;
+    let logged_in = ctx
+        .clients
+        .as_deref()
+        .and_then(|c| c.get(me))
+        .is_none_or(|c| c.user.is_some());
+    if !authenticated && !logged_in && ctx.acl.as_deref().is_some_and(|acl| acl.implicit_user().is_none()) {
+        let msg = "HELLO must be called with the client already authenticated, otherwise the \
+                   HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client";
+        return out_err(out, ErrorCode::NoAuth, msg);
+    }
+    if let (Some(name), Some(client)) = (name, ctx.clients.as_deref_mut().and_then(|c| c.get_mut(me))) {
+        client.name = name;
+    }
+
+    out_arr(out, 10);
+    out_str(out, b"server");
+    out_str(out, b"redis");
+    out_str(out, b"version");
+    out_str(out, env!("CARGO_PKG_VERSION").as_bytes());
+    out_str(out, b"proto");
+    out_int(out, 1);
+    out_str(out, b"id");
+    out_int(out, me as i64);
+    out_str(out, b"mode");
+    out_str(out, b"standalone");
+}
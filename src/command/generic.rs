@@ -0,0 +1,209 @@
+use crate::database::notify::NOTIFY_GENERIC;
+use crate::debug;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{parse_arg, Context, OOM_MSG};
+
+/// `del key [key ...]`, the number of keys deleted
+pub fn del(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    debug!("COMMAND: del {}", String::from_utf8_lossy(&args[1..].join(&b' ')));
+    let deleted = args[1..].iter().filter(|key| ctx.db().del(key) == ResponseStatus::Ok).collect::<Vec<_>>();
+    for key in &deleted {
+        ctx.db().notify(NOTIFY_GENERIC, "del", key);
+    }
+    out_int(out, deleted.len() as i64);
+}
+
+/// `unlink key [key ...]`, `del` that frees big values in the background
+pub fn unlink(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    debug!("COMMAND: unlink {}", String::from_utf8_lossy(&args[1..].join(&b' ')));
+    let deleted = args[1..].iter().filter(|key| ctx.db().unlink(key) == ResponseStatus::Ok).collect::<Vec<_>>();
+    for key in &deleted {
+        ctx.db().notify(NOTIFY_GENERIC, "del", key);
+    }
+    out_int(out, deleted.len() as i64);
+}
+
+/// `exists key [key ...]`, a key given twice is counted twice
+pub fn exists(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let found = args[1..].iter().filter(|key| ctx.db().contains(key)).count();
+    out_int(out, found as i64);
+}
+
+/// `touch key [key ...]`, the number of keys that exist
+pub fn touch(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let found = args[1..].iter().filter(|key| ctx.db().touch(key)).count();
+    out_int(out, found as i64);
+}
+
+/// `type key`
+pub fn type_(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    out_str(out, ctx.db().value_type(&args[1]).unwrap_or("none").as_bytes());
+}
+
+pub fn randomkey(ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    match ctx.db().random_key() {
+        Some(key) => out_str(out, &key),
+        None => out_nil(out),
+    }
+}
+
+/// Move the value of `args[1]` to `args[2]`, keeping its expiry. Returns
+/// false if `nx` and the target exists.
+fn rename_key(ctx: &mut Context, args: &mut [Vec<u8>], nx: bool) -> Result<bool, (ErrorCode, &'static str)> {
+    if !ctx.db().contains(&args[1]) {
+        return Err((ErrorCode::Err, "no such key"));
+    }
+    if args[1] == args[2] {
+        return Ok(!nx);
+    }
+    if nx && ctx.db().contains(&args[2]) {
+        return Ok(false);
+    }
+    debug!("COMMAND: rename {} {}", String::from_utf8_lossy(&args[1]), String::from_utf8_lossy(&args[2]));
+    let (value, expire_at) = ctx.db().take(&args[1]).unwrap();
+    // Only memory can fail the rename, then the value goes back where it was
+    if ctx.db().put(args[2].clone(), value.clone(), expire_at) == ResponseStatus::Err {
+        ctx.db().put(std::mem::take(&mut args[1]), value, expire_at);
+        return Err((ErrorCode::Oom, OOM_MSG));
+    }
+    ctx.db().notify(NOTIFY_GENERIC, "rename_from", &args[1]);
+    ctx.db().notify(NOTIFY_GENERIC, "rename_to", &args[2]);
+    Ok(true)
+}
+
+/// `rename key newkey`
+pub fn rename(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    match rename_key(ctx, args, false) {
+        Ok(_) => out_ok(out),
+        Err((code, msg)) => out_err(out, code, msg),
+    }
+}
+
+/// `renamenx key newkey`, 0 if `newkey` exists
+pub fn renamenx(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    match rename_key(ctx, args, true) {
+        Ok(renamed) => out_int(out, renamed as i64),
+        Err((code, msg)) => out_err(out, code, msg),
+    }
+}
+
+/// `copy source destination [DB index] [REPLACE]`, 1 if copied
+pub fn copy(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let mut target = ctx.db_index;
+    let mut replace = false;
+    let mut i = 3;
+    while i < args.len() {
+        match args[i].to_ascii_lowercase().as_slice() {
+            b"replace" => replace = true,
+            b"db" if i + 1 < args.len() => {
+                let Some(index) = ctx.parse_db_index(&args[i + 1], out) else {
+                    return;
+                };
+                target = index;
+                i += 1;
+            }
+            _ => return out_err(out, ErrorCode::Err, "syntax error"),
+        }
+        i += 1;
+    }
+    if target == ctx.db_index && args[1] == args[2] {
+        return out_err(out, ErrorCode::Err, "source and destination objects are the same");
+    }
+
+    let Some((value, expire_at)) = ctx.db().peek(&args[1]).map(|(v, at)| (v.clone(), at)) else {
+        return out_int(out, 0);
+    };
+    if !replace && ctx.dbs[target].contains(&args[2]) {
+        return out_int(out, 0);
+    }
+    debug!("COMMAND: copy {} {}", String::from_utf8_lossy(&args[1]), String::from_utf8_lossy(&args[2]));
+    match ctx.dbs[target].put(args[2].clone(), value, expire_at) {
+        ResponseStatus::Err => out_err(out, ErrorCode::Oom, OOM_MSG),
+        _ => {
+            ctx.dbs[target].notify(NOTIFY_GENERIC, "copy_to", &args[2]);
+            out_int(out, 1);
+        }
+    }
+}
+
+/// `object encoding|idletime|freq|refcount key`
+pub fn object(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    if !matches!(sub.as_str(), "encoding" | "idletime" | "freq" | "refcount") {
+        return out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1])));
+    }
+    if args.len() != 3 {
+        return out_wrong_arity(out, &format!("object|{}", sub));
+    }
+
+    let lfu = ctx.db().eviction_policy().is_lfu();
+    let Some(info) = ctx.db().object(&args[2]) else {
+        return out_nil(out);
+    };
+    match sub.as_str() {
+        "encoding" => out_str(out, info.encoding.as_bytes()),
+        "idletime" if lfu => {
+            let msg = "An LFU maxmemory policy is selected, idle time not tracked. \
+                       Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+            out_err(out, ErrorCode::Err, msg)
+        }
+        "idletime" => out_int(out, info.idle as i64),
+        "freq" if !lfu => {
+            let msg = "An LFU maxmemory policy is not selected, access frequency not tracked. \
+                       Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+            out_err(out, ErrorCode::Err, msg)
+        }
+        "freq" => out_int(out, info.freq as i64),
+        _ => out_int(out, 1),
+    }
+}
+
+pub fn expire(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(seconds) = parse_arg::<i64>(&args[2]) else {
+        return out_not_int(out);
+    };
+    debug!("COMMAND: expire {} {}", String::from_utf8_lossy(&args[1]), seconds);
+    let set = match ctx.db().expire(&args[1], seconds) {
+        ResponseStatus::Err => return out_err(out, ErrorCode::Err, "invalid expire time in 'expire' command"),
+        status => status == ResponseStatus::Ok,
+    };
+    if set {
+        // A time to live in the past deletes the key
+        let event = if seconds > 0 { "expire" } else { "del" };
+        ctx.db().notify(NOTIFY_GENERIC, event, &args[1]);
+    }
+    out_int(out, set as i64);
+}
+
+pub fn ttl(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    debug!("COMMAND: ttl {}", String::from_utf8_lossy(&args[1]));
+    out_int(out, ctx.db().ttl(&args[1]));
+}
+
+/// `move key db`, 1 if the key was moved, 0 if it doesn't exist or the
+/// target database already has it
+pub fn move_(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(target) = ctx.parse_db_index(&args[2], out) else {
+        return;
+    };
+    if target == ctx.db_index {
+        return out_err(out, ErrorCode::Err, "source and destination objects are the same");
+    }
+    debug!("COMMAND: move {} {}", String::from_utf8_lossy(&args[1]), target);
+    if ctx.dbs[target].contains(&args[1]) {
+        return out_int(out, 0);
+    }
+    let Some((value, expire_at)) = ctx.db().take(&args[1]) else {
+        return out_int(out, 0);
+    };
+    // Only memory can fail the move, then the value goes back where it was
+    if ctx.dbs[target].put(args[1].clone(), value.clone(), expire_at) == ResponseStatus::Err {
+        ctx.db().put(std::mem::take(&mut args[1]), value, expire_at);
+        return out_err(out, ErrorCode::Oom, OOM_MSG);
+    }
+    ctx.db().notify(NOTIFY_GENERIC, "move_from", &args[1]);
+    ctx.dbs[target].notify(NOTIFY_GENERIC, "move_to", &args[1]);
+    out_int(out, 1);
+}
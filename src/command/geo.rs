@@ -0,0 +1,337 @@
+use std::cmp::Ordering;
+
+use crate::database::notify::NOTIFY_ZSET;
+use crate::database::value::{Value, Zset};
+use crate::database::Database;
+use crate::debug;
+use crate::geo;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{parse_arg, Context, OOM_MSG, WRONGTYPE_MSG};
+
+const SYNTAX_MSG: &str = "syntax error";
+
+/// The sorted set at `key`, `Ok(None)` if there is none and `Err` if the
+/// key holds another type
+fn zset<'a>(db: &'a mut Database, key: &[u8]) -> Result<Option<&'a Zset>, ()> {
+    match db.peek(key) {
+        Some((Value::Zset(zset), _)) => Ok(Some(zset)),
+        Some(_) => Err(()),
+        None => Ok(None),
+    }
+}
+
+fn parse_float(arg: &[u8]) -> Result<f64, String> {
+    match parse_arg::<f64>(arg) {
+        Some(v) if !v.is_nan() => Ok(v),
+        _ => Err("value is not a valid float".to_string()),
+    }
+}
+
+fn parse_unit(arg: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(geo::unit_factor)
+        .ok_or_else(|| "unsupported unit provided. please use M, KM, FT, MI".to_string())
+}
+
+fn parse_lonlat(lon: &[u8], lat: &[u8]) -> Result<(f64, f64), String> {
+    let (lon, lat) = (parse_float(lon)?, parse_float(lat)?);
+    if !geo::is_valid(lon, lat) {
+        return Err(format!("invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+    }
+    Ok((lon, lat))
+}
+
+fn out_coord(out: &mut Vec<u8>, (lon, lat): (f64, f64)) {
+    out_arr(out, 2);
+    out_str(out, lon.to_string().as_bytes());
+    out_str(out, lat.to_string().as_bytes());
+}
+
+/// `geoadd key [NX|XX] [CH] longitude latitude member [...]`, the number of
+/// members added, or changed as well with CH
+pub fn geoadd(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].to_ascii_lowercase().as_slice() {
+            b"nx" => nx = true,
+            b"xx" => xx = true,
+            b"ch" => ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    if nx && xx {
+        return out_err(out, ErrorCode::Err, "XX and NX options at the same time are not compatible");
+    }
+    if i == args.len() || !(args.len() - i).is_multiple_of(3) {
+        return out_err(out, ErrorCode::Err, SYNTAX_MSG);
+    }
+    let mut points = vec![];
+    for triple in args[i..].chunks(3) {
+        match parse_lonlat(&triple[0], &triple[1]) {
+            Ok((lon, lat)) => points.push((geo::encode(lon, lat) as f64, triple[2].as_slice())),
+            Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+        }
+    }
+
+    let limits = *ctx.db().encoding_limits();
+    let expire_at = ctx.db().peek(&args[1]).and_then(|(_, at)| at);
+    let mut zset = match zset(ctx.db(), &args[1]) {
+        Ok(zset) => zset.cloned().unwrap_or_default(),
+        Err(()) => return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+    };
+    let (mut added, mut changed) = (0, 0);
+    for (score, member) in points {
+        match zset.score(member) {
+            Some(old) if !nx && old != score => {
+                zset.insert(member, score, &limits);
+                changed += 1;
+            }
+            None if !xx => {
+                zset.insert(member, score, &limits);
+                added += 1;
+            }
+            _ => {}
+        }
+    }
+    if added + changed > 0 {
+        debug!("COMMAND: geoadd {} {} members", String::from_utf8_lossy(&args[1]), added + changed);
+        if ctx.db().put(args[1].clone(), Value::Zset(zset), expire_at) == ResponseStatus::Err {
+            return out_err(out, ErrorCode::Oom, OOM_MSG);
+        }
+        ctx.db().notify(NOTIFY_ZSET, "zadd", &args[1]);
+    }
+    out_int(out, if ch { added + changed } else { added });
+}
+
+/// `geodist key member1 member2 [M|KM|FT|MI]`, nil if a member is missing
+pub fn geodist(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let factor = match args.get(4..) {
+        Some([]) => 1.0,
+        Some([unit]) => match parse_unit(unit) {
+            Ok(factor) => factor,
+            Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+        },
+        _ => return out_err(out, ErrorCode::Err, SYNTAX_MSG),
+    };
+    let Ok(zset) = zset(ctx.db(), &args[1]) else {
+        return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG);
+    };
+    let position = |member: &[u8]| zset?.score(member).map(|s| geo::decode(s as u64));
+    match (position(&args[2]), position(&args[3])) {
+        (Some((lon1, lat1)), Some((lon2, lat2))) => {
+            let distance = geo::distance(lon1, lat1, lon2, lat2) / factor;
+            out_str(out, format!("{:.4}", distance).as_bytes());
+        }
+        _ => out_nil(out),
+    }
+}
+
+/// `geopos key member [...]`, a longitude and latitude pair or nil for each
+pub fn geopos(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Ok(zset) = zset(ctx.db(), &args[1]) else {
+        return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG);
+    };
+    out_arr(out, args.len() - 2);
+    for member in &args[2..] {
+        match zset.and_then(|z| z.score(member)) {
+            Some(score) => out_coord(out, geo::decode(score as u64)),
+            None => out_nil(out),
+        }
+    }
+}
+
+/// `geohash key member [...]`, the standard geohash string or nil for each
+pub fn geohash(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Ok(zset) = zset(ctx.db(), &args[1]) else {
+        return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG);
+    };
+    out_arr(out, args.len() - 2);
+    for member in &args[2..] {
+        match zset.and_then(|z| z.score(member)) {
+            Some(score) => out_str(out, geo::to_string(score as u64).as_bytes()),
+            None => out_nil(out),
+        }
+    }
+}
+
+enum Center {
+    Member(Vec<u8>),
+    LonLat(f64, f64),
+}
+
+/// Sizes in meters
+enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+struct Search {
+    center: Center,
+    shape: Shape,
+    /// Meters per unit of the distances in the reply
+    factor: f64,
+    order: Option<Ordering>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+}
+
+impl Search {
+    /// Parse the arguments of `geosearch` after the key
+    fn parse(args: &[Vec<u8>]) -> Result<Search, String> {
+        let (mut center, mut shape, mut factor) = (None, None, 1.0);
+        let mut search = Search {
+            center: Center::LonLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            factor,
+            order: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        };
+        let center_msg = "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH";
+        let shape_msg = "exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH";
+        let mut i = 0;
+        while i < args.len() {
+            let left = args.len() - i - 1;
+            match args[i].to_ascii_lowercase().as_slice() {
+                b"frommember" if left >= 1 => {
+                    if center.replace(Center::Member(args[i + 1].clone())).is_some() {
+                        return Err(center_msg.to_string());
+                    }
+                    i += 1;
+                }
+                b"fromlonlat" if left >= 2 => {
+                    let (lon, lat) = parse_lonlat(&args[i + 1], &args[i + 2])?;
+                    if center.replace(Center::LonLat(lon, lat)).is_some() {
+                        return Err(center_msg.to_string());
+                    }
+                    i += 2;
+                }
+                b"byradius" if left >= 2 => {
+                    let radius = parse_float(&args[i + 1])?;
+                    if radius < 0.0 {
+                        return Err("radius cannot be negative".to_string());
+                    }
+                    factor = parse_unit(&args[i + 2])?;
+                    if shape.replace(Shape::Radius(radius * factor)).is_some() {
+                        return Err(shape_msg.to_string());
+                    }
+                    i += 2;
+                }
+                b"bybox" if left >= 3 => {
+                    let (width, height) = (parse_float(&args[i + 1])?, parse_float(&args[i + 2])?);
+                    if width < 0.0 || height < 0.0 {
+                        return Err("height or width cannot be negative".to_string());
+                    }
+                    factor = parse_unit(&args[i + 3])?;
+                    if shape.replace(Shape::Box(width * factor, height * factor)).is_some() {
+                        return Err(shape_msg.to_string());
+                    }
+                    i += 3;
+                }
+                b"asc" => search.order = Some(Ordering::Less),
+                b"desc" => search.order = Some(Ordering::Greater),
+                b"count" if left >= 1 => {
+                    match parse_arg::<i64>(&args[i + 1]) {
+                        Some(n) if n > 0 => search.count = Some(n as usize),
+                        Some(_) => return Err("COUNT must be > 0".to_string()),
+                        None => return Err("value is not an integer or out of range".to_string()),
+                    }
+                    i += 1;
+                    if args.get(i + 1).is_some_and(|arg| arg.eq_ignore_ascii_case(b"any")) {
+                        search.any = true;
+                        i += 1;
+                    }
+                }
+                b"withcoord" => search.with_coord = true,
+                b"withdist" => search.with_dist = true,
+                b"withhash" => search.with_hash = true,
+                _ => return Err(SYNTAX_MSG.to_string()),
+            }
+            i += 1;
+        }
+        search.center = center.ok_or(center_msg)?;
+        search.shape = shape.ok_or(shape_msg)?;
+        search.factor = factor;
+        Ok(search)
+    }
+}
+
+/// `geosearch key FROMMEMBER member|FROMLONLAT longitude latitude
+/// BYRADIUS radius unit|BYBOX width height unit [ASC|DESC] [COUNT count [ANY]]
+/// [WITHCOORD] [WITHDIST] [WITHHASH]`. Results are nearest first unless
+/// DESC is given; with ANY the first `count` matches found are returned.
+pub fn geosearch(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let search = match Search::parse(&args[2..]) {
+        Ok(search) => search,
+        Err(msg) => return out_err(out, ErrorCode::Err, &msg),
+    };
+    let Ok(zset) = zset(ctx.db(), &args[1]) else {
+        return out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG);
+    };
+    let center = match search.center {
+        Center::LonLat(lon, lat) => (lon, lat),
+        Center::Member(ref member) => match zset.and_then(|z| z.score(member)) {
+            Some(score) => geo::decode(score as u64),
+            None => return out_err(out, ErrorCode::Err, "could not decode requested zset member"),
+        },
+    };
+    let Some(zset) = zset else {
+        return out_arr(out, 0);
+    };
+
+    // Every member is looked at, the scores only give the positions
+    let mut found = vec![];
+    for (member, score) in zset.iter() {
+        let (lon, lat) = geo::decode(score as u64);
+        let distance = match search.shape {
+            Shape::Radius(radius) => Some(geo::distance(center.0, center.1, lon, lat)).filter(|d| *d <= radius),
+            Shape::Box(width, height) => geo::distance_in_box(center, width, height, (lon, lat)),
+        };
+        if let Some(distance) = distance {
+            found.push((member, score, distance));
+            if search.any && Some(found.len()) == search.count {
+                break;
+            }
+        }
+    }
+    // ANY without an order returns matches as they were found
+    let order = search.order.or((!search.any).then_some(Ordering::Less));
+    if let Some(order) = order {
+        found.sort_by(|a, b| {
+            let ord = a.2.total_cmp(&b.2);
+            if order == Ordering::Less { ord } else { ord.reverse() }
+        });
+    }
+    found.truncate(search.count.unwrap_or(usize::MAX));
+
+    let fields = 1 + search.with_dist as usize + search.with_hash as usize + search.with_coord as usize;
+    out_arr(out, found.len());
+    for (member, score, distance) in found {
+        if fields == 1 {
+            out_str(out, &member);
+            continue;
+        }
+        out_arr(out, fields);
+        out_str(out, &member);
+        if search.with_dist {
+            out_str(out, format!("{:.4}", distance / search.factor).as_bytes());
+        }
+        if search.with_hash {
+            out_int(out, score as i64);
+        }
+        if search.with_coord {
+            out_coord(out, geo::decode(score as u64));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::database::hll::{self, Hll};
+use crate::database::notify::NOTIFY_STRING;
+use crate::database::value::Value;
+use crate::database::Database;
+use crate::debug;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{Context, OOM_MSG, WRONGTYPE_MSG};
+
+const INVALID_MSG: &str = "Key is not a valid HyperLogLog string value.";
+
+/// The HyperLogLog at `key` and its expiry time, `Ok(None)` if there is none
+/// and `Err` with the error message if the key holds something else
+fn hll(db: &mut Database, key: &[u8]) -> Result<Option<(Hll, Option<u64>)>, &'static str> {
+    let Some((value, expire_at)) = db.peek(key) else {
+        return Ok(None);
+    };
+    let Some(s) = value.as_bytes() else {
+        return Err(WRONGTYPE_MSG);
+    };
+    Hll::parse(&s).map(|hll| Some((hll, expire_at))).ok_or(INVALID_MSG)
+}
+
+fn store(db: &mut Database, key: &[u8], mut hll: Hll, expire_at: Option<u64>) -> ResponseStatus {
+    let s = hll.encode(db.encoding_limits());
+    let status = db.put(key.to_vec(), Value::from_bytes(s), expire_at);
+    if status != ResponseStatus::Err {
+        db.notify(NOTIFY_STRING, "pfadd", key);
+    }
+    status
+}
+
+/// The registers of the dense HyperLogLog at `key` that adding `elements`
+/// raises, `None` if the key doesn't hold a dense one
+fn dense_updates(
+    db: &mut Database,
+    key: &[u8],
+    elements: &[Vec<u8>],
+) -> Option<Result<Vec<(usize, u8)>, &'static str>> {
+    match db.peek(key) {
+        Some((Value::Str(s), _)) if hll::is_dense(s) => Some(hll::dense_updates(s, elements).ok_or(INVALID_MSG)),
+        _ => None,
+    }
+}
+
+/// `pfadd key [element ...]`, 1 if the estimate may have changed or the key
+/// was created
+pub fn pfadd(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    // Dense values are updated where they are stored, sparse ones are small
+    // enough to decode
+    match dense_updates(ctx.db(), &args[1], &args[2..]) {
+        Some(Err(msg)) => return out_err(out, ErrorCode::WrongType, msg),
+        Some(Ok(updates)) if updates.is_empty() => return out_int(out, 0),
+        Some(Ok(updates)) => {
+            if let Some(s) = ctx.db().string_mut(&args[1]) {
+                hll::apply_dense(s, &updates);
+                ctx.db().notify(NOTIFY_STRING, "pfadd", &args[1]);
+                return out_int(out, 1);
+            }
+        }
+        None => {}
+    }
+    let (mut hll, expire_at, mut changed) = match hll(ctx.db(), &args[1]) {
+        Ok(Some((hll, expire_at))) => (hll, expire_at, false),
+        Ok(None) => (Hll::default(), None, true),
+        Err(msg) => return out_err(out, ErrorCode::WrongType, msg),
+    };
+    for element in &args[2..] {
+        changed |= hll.add(element);
+    }
+    if changed {
+        debug!("COMMAND: pfadd {} {} elements", String::from_utf8_lossy(&args[1]), args.len() - 2);
+        if store(ctx.db(), &args[1], hll, expire_at) == ResponseStatus::Err {
+            return out_err(out, ErrorCode::Oom, OOM_MSG);
+        }
+    }
+    out_int(out, changed as i64);
+}
+
+/// `pfcount key [key ...]`, the estimated number of distinct elements added
+/// to any of the keys
+pub fn pfcount(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if let [_, key] = args {
+        let count = match ctx.db().peek(key) {
+            Some((Value::Str(s), _)) if hll::is_dense(s) => hll::count_dense(s).ok_or(INVALID_MSG),
+            _ => hll(ctx.db(), key).map(|hll| hll.map_or(0, |(hll, _)| hll.count())),
+        };
+        return match count {
+            Ok(count) => out_int(out, count as i64),
+            Err(msg) => out_err(out, ErrorCode::WrongType, msg),
+        };
+    }
+    let mut union = Hll::default();
+    for key in &args[1..] {
+        match hll(ctx.db(), key) {
+            Ok(Some((hll, _))) => union.merge(&hll),
+            Ok(None) => {}
+            Err(msg) => return out_err(out, ErrorCode::WrongType, msg),
+        }
+    }
+    out_int(out, union.count() as i64);
+}
+
+/// `pfmerge destkey [sourcekey ...]`, stores the union of the sources and
+/// the destination itself
+pub fn pfmerge(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let mut union = Hll::default();
+    let mut expire_at = None;
+    for (i, key) in args[1..].iter().enumerate() {
+        match hll(ctx.db(), key) {
+            Ok(Some((hll, at))) => {
+                union.merge(&hll);
+                if i == 0 {
+                    expire_at = at;
+                }
+            }
+            Ok(None) => {}
+            Err(msg) => return out_err(out, ErrorCode::WrongType, msg),
+        }
+    }
+    debug!(
+        "COMMAND: pfmerge {} {}",
+        String::from_utf8_lossy(&args[1]),
+        String::from_utf8_lossy(&args[2..].join(&b' '))
+    );
+    match store(ctx.db(), &args[1], union, expire_at) {
+        ResponseStatus::Err => out_err(out, ErrorCode::Oom, OOM_MSG),
+        _ => out_ok(out),
+    }
+}
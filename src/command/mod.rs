@@ -0,0 +1,1716 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::acl::Acl;
+use crate::cluster::Cluster;
+use crate::database::{self, Database};
+use crate::debug;
+use crate::protocol::*;
+use crate::scripting::Scripting;
+use crate::server::{Clients, PubSub, Replication, SlowLog, Stats, Tracking};
+
+mod acl;
+mod bitmap;
+mod cluster;
+mod connection;
+mod generic;
+mod geo;
+mod hll;
+mod pubsub;
+mod replication;
+mod scripting;
+mod server;
+mod string;
+
+/// Command modifies the keyspace
+pub const CMD_WRITE: u32 = 1 << 0;
+/// Command only reads
+pub const CMD_READONLY: u32 = 1 << 1;
+/// Command may grow memory usage, refuse it when out of memory
+pub const CMD_DENYOOM: u32 = 1 << 2;
+/// Administrative command, not for regular clients
+pub const CMD_ADMIN: u32 = 1 << 3;
+/// Command may block the client
+pub const CMD_BLOCKING: u32 = 1 << 4;
+/// Command runs in O(1) or O(log N)
+pub const CMD_FAST: u32 = 1 << 5;
+/// Command can't be called from a script
+pub const CMD_NOSCRIPT: u32 = 1 << 6;
+/// Running the command again on a replica wouldn't do the same, because
+/// it goes by the clock or reaches out of the database. Replicas get the
+/// values it leaves instead.
+pub const CMD_NOREPLAY: u32 = 1 << 7;
+
+const OOM_MSG: &str = "command not allowed when used memory > 'maxmemory'";
+
+const WRONGTYPE_MSG: &str = "Operation against a key holding the wrong kind of value";
+
+/// ACL categories, derived from the group and flags of each command
+pub const CATEGORIES: [&str; 15] = [
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "admin",
+    "dangerous",
+    "fast",
+    "slow",
+    "blocking",
+    "connection",
+    "scripting",
+    "pubsub",
+    "geo",
+    "hyperloglog",
+    "bitmap",
+];
+
+const FLAG_NAMES: [(u32, &str); 8] = [
+    (CMD_WRITE, "write"),
+    (CMD_READONLY, "readonly"),
+    (CMD_DENYOOM, "denyoom"),
+    (CMD_ADMIN, "admin"),
+    (CMD_BLOCKING, "blocking"),
+    (CMD_FAST, "fast"),
+    (CMD_NOSCRIPT, "noscript"),
+    (CMD_NOREPLAY, "noreplay"),
+];
+
+/// Everything a command handler may touch
+pub struct Context<'a> {
+    /// All logical databases
+    pub dbs: &'a mut [Database],
+    /// Index of the database selected by the client
+    pub db_index: usize,
+    /// The script cache, `None` where scripts can't run
+    pub scripting: Option<&'a mut Scripting>,
+    /// Called periodically by scripts running past the time limit with
+    /// whether they may still be killed, returns true to kill them
+    pub on_busy: Option<Rc<dyn Fn(bool) -> bool>>,
+    /// Where calls are counted, `None` outside of a server
+    pub stats: Option<&'a Stats>,
+    /// Where slow commands are logged, `None` outside of a server and
+    /// for commands run by scripts
+    pub slowlog: Option<&'a mut SlowLog>,
+    /// Everyone connected, `None` outside of a server
+    pub clients: Option<&'a mut Clients>,
+    /// The client the command came from
+    pub client_id: u64,
+    /// Users and permissions, `None` if everyone may do anything
+    pub acl: Option<&'a mut Acl>,
+    /// Subscriptions, `None` outside of a server
+    pub pubsub: Option<&'a mut PubSub>,
+    /// Keys cached by clients, `None` outside of a server
+    pub tracking: Option<&'a mut Tracking>,
+    /// Slots and nodes, `None` unless cluster mode is enabled
+    pub cluster: Option<&'a mut Cluster>,
+    /// Our primary or replicas, `None` outside of a server and for the
+    /// commands coming from our primary
+    pub replication: Option<&'a mut Replication>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(db: &'a mut Database) -> Context<'a> {
+        Context::with_databases(std::slice::from_mut(db))
+    }
+
+    pub fn with_databases(dbs: &'a mut [Database]) -> Context<'a> {
+        Context {
+            dbs,
+            db_index: 0,
+            scripting: None,
+            on_busy: None,
+            stats: None,
+            slowlog: None,
+            clients: None,
+            client_id: 0,
+            acl: None,
+            pubsub: None,
+            tracking: None,
+            cluster: None,
+            replication: None,
+        }
+    }
+
+    /// The database selected by the client
+    pub fn db(&mut self) -> &mut Database {
+        &mut self.dbs[self.db_index]
+    }
+
+    /// Parse a database number, writing the error reply if it's invalid
+    fn parse_db_index(&self, arg: &[u8], out: &mut Vec<u8>) -> Option<usize> {
+        match parse_arg::<i64>(arg) {
+            Some(n) if n >= 0 && (n as usize) < self.dbs.len() => Some(n as usize),
+            Some(_) => {
+                out_err(out, ErrorCode::Err, "DB index is out of range");
+                None
+            }
+            None => {
+                out_not_int(out);
+                None
+            }
+        }
+    }
+}
+
+/// Handlers get the full argument vector, the command name included, with
+/// the arity already checked. Arguments are binary safe.
+pub type Handler = fn(&mut Context, &mut [Vec<u8>], &mut Vec<u8>);
+
+/// An argument holding a number or other text, `None` if it doesn't parse
+fn parse_arg<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+pub struct Command {
+    pub name: &'static str,
+    /// Exact number of arguments if positive, minimum number if negative,
+    /// counting the command name
+    pub arity: i32,
+    pub flags: u32,
+    /// Index of the first key argument, 0 if there are none
+    pub first_key: i32,
+    /// Index of the last key argument, negative counts from the end
+    pub last_key: i32,
+    pub key_step: i32,
+    pub group: &'static str,
+    pub summary: &'static str,
+    pub handler: Handler,
+}
+
+impl Command {
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has_flag(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    pub fn check_arity(&self, argc: usize) -> bool {
+        if self.arity >= 0 {
+            argc == self.arity as usize
+        } else {
+            argc >= self.arity.unsigned_abs() as usize
+        }
+    }
+
+    /// ACL categories the command belongs to
+    pub fn categories(&self) -> Vec<&'static str> {
+        let mut categories = vec![];
+        let group = if self.group == "generic" { "keyspace" } else { self.group };
+        if let Some(category) = CATEGORIES.iter().find(|c| **c == group) {
+            categories.push(*category);
+        }
+        if self.has_flag(CMD_WRITE) {
+            categories.push("write");
+        }
+        if self.has_flag(CMD_READONLY) {
+            categories.push("read");
+        }
+        if self.has_flag(CMD_ADMIN) {
+            categories.extend(["admin", "dangerous"]);
+        }
+        categories.push(if self.has_flag(CMD_FAST) { "fast" } else { "slow" });
+        if self.has_flag(CMD_BLOCKING) {
+            categories.push("blocking");
+        }
+        categories
+    }
+
+    /// Indices of the arguments of `args` that are keys
+    pub fn key_indices(&self, args: &[Vec<u8>]) -> Vec<usize> {
+        let argc = args.len();
+        // MIGRATE names one key, or an empty one and the keys after KEYS
+        if self.name == "migrate" {
+            if argc < 6 {
+                return vec![];
+            }
+            if !args[3].is_empty() {
+                return vec![3];
+            }
+            return match args[6..].iter().position(|arg| arg.eq_ignore_ascii_case(b"keys")) {
+                Some(pos) => (7 + pos..argc).collect(),
+                None => vec![],
+            };
+        }
+        if self.first_key <= 0 || argc <= self.first_key as usize {
+            return vec![];
+        }
+        let last = if self.last_key < 0 {
+            argc as i32 + self.last_key
+        } else {
+            self.last_key.min(argc as i32 - 1)
+        };
+        (self.first_key..=last)
+            .step_by(self.key_step.max(1) as usize)
+            .map(|i| i as usize)
+            .collect()
+    }
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "ping",
+        arity: -1,
+        flags: CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Returns the server's liveliness response.",
+        handler: server::ping,
+    },
+    Command {
+        name: "quit",
+        arity: -1,
+        flags: CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Closes the connection.",
+        handler: server::quit,
+    },
+    Command {
+        name: "client",
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Inspects, names, kills or pauses client connections.",
+        handler: connection::client,
+    },
+    Command {
+        name: "select",
+        arity: 2,
+        flags: CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Changes the selected database.",
+        handler: connection::select,
+    },
+    Command {
+        name: "auth",
+        arity: -2,
+        flags: CMD_NOSCRIPT | CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Authenticates the connection.",
+        handler: connection::auth,
+    },
+    Command {
+        name: "hello",
+        arity: -1,
+        flags: CMD_NOSCRIPT | CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "connection",
+        summary: "Handshakes with the server, optionally authenticating.",
+        handler: connection::hello,
+    },
+    Command {
+        name: "acl",
+        arity: -2,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Manages users and their permissions.",
+        handler: acl::acl,
+    },
+    Command {
+        name: "command",
+        arity: -1,
+        flags: 0,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Returns detailed information about commands.",
+        handler: server::command,
+    },
+    Command {
+        name: "info",
+        arity: -1,
+        flags: 0,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Returns information and statistics about the server.",
+        handler: server::info,
+    },
+    Command {
+        name: "config",
+        arity: -2,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Gets or sets configuration parameters at runtime.",
+        handler: server::config,
+    },
+    Command {
+        name: "slowlog",
+        arity: -2,
+        flags: CMD_ADMIN,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Reads or resets the log of slow commands.",
+        handler: server::slowlog,
+    },
+    Command {
+        name: "dbsize",
+        arity: 1,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Returns the number of keys in the database.",
+        handler: server::dbsize,
+    },
+    Command {
+        name: "flushdb",
+        arity: -1,
+        flags: CMD_WRITE,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Removes all keys from the current database.",
+        handler: server::flushdb,
+    },
+    Command {
+        name: "flushall",
+        arity: -1,
+        flags: CMD_WRITE,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Removes all keys from all databases.",
+        handler: server::flushall,
+    },
+    Command {
+        name: "swapdb",
+        arity: 3,
+        flags: CMD_WRITE | CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Swaps two databases.",
+        handler: server::swapdb,
+    },
+    Command {
+        name: "get",
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "string",
+        summary: "Returns the string value of a key.",
+        handler: string::get,
+    },
+    Command {
+        name: "set",
+        arity: 3,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "string",
+        summary: "Sets the string value of a key, ignoring its type.",
+        handler: string::set,
+    },
+    Command {
+        name: "setbit",
+        arity: 4,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist.",
+        handler: bitmap::setbit,
+    },
+    Command {
+        name: "getbit",
+        arity: 3,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Returns a bit value by offset.",
+        handler: bitmap::getbit,
+    },
+    Command {
+        name: "bitcount",
+        arity: -2,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Counts the number of set bits (population counting) in a string.",
+        handler: bitmap::bitcount,
+    },
+    Command {
+        name: "bitpos",
+        arity: -3,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Finds the first set (1) or clear (0) bit in a string.",
+        handler: bitmap::bitpos,
+    },
+    Command {
+        name: "bitop",
+        arity: -4,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 2,
+        last_key: -1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Performs bitwise operations on multiple strings, and stores the result.",
+        handler: bitmap::bitop,
+    },
+    Command {
+        name: "bitfield",
+        arity: -2,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "bitmap",
+        summary: "Performs arbitrary bitfield integer operations on strings.",
+        handler: bitmap::bitfield,
+    },
+    Command {
+        name: "del",
+        arity: -2,
+        flags: CMD_WRITE,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "generic",
+        summary: "Deletes one or more keys.",
+        handler: generic::del,
+    },
+    Command {
+        name: "unlink",
+        arity: -2,
+        flags: CMD_WRITE | CMD_FAST,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "generic",
+        summary: "Asynchronously deletes one or more keys.",
+        handler: generic::unlink,
+    },
+    Command {
+        name: "exists",
+        arity: -2,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "generic",
+        summary: "Determines whether one or more keys exist.",
+        handler: generic::exists,
+    },
+    Command {
+        name: "touch",
+        arity: -2,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "generic",
+        summary: "Updates the last access time of keys, returning how many exist.",
+        handler: generic::touch,
+    },
+    Command {
+        name: "type",
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Determines the type of value stored at a key.",
+        handler: generic::type_,
+    },
+    Command {
+        name: "randomkey",
+        arity: 1,
+        flags: CMD_READONLY,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "generic",
+        summary: "Returns a random key name from the database.",
+        handler: generic::randomkey,
+    },
+    Command {
+        name: "rename",
+        arity: 3,
+        flags: CMD_WRITE,
+        first_key: 1,
+        last_key: 2,
+        key_step: 1,
+        group: "generic",
+        summary: "Renames a key and overwrites the destination.",
+        handler: generic::rename,
+    },
+    Command {
+        name: "renamenx",
+        arity: 3,
+        flags: CMD_WRITE | CMD_FAST,
+        first_key: 1,
+        last_key: 2,
+        key_step: 1,
+        group: "generic",
+        summary: "Renames a key only when the target key name doesn't exist.",
+        handler: generic::renamenx,
+    },
+    Command {
+        name: "copy",
+        arity: -3,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: 2,
+        key_step: 1,
+        group: "generic",
+        summary: "Copies the value of a key to a new key.",
+        handler: generic::copy,
+    },
+    Command {
+        name: "object",
+        arity: -2,
+        flags: CMD_READONLY,
+        first_key: 2,
+        last_key: 2,
+        key_step: 1,
+        group: "generic",
+        summary: "Inspects the internals of a key's value.",
+        handler: generic::object,
+    },
+    Command {
+        name: "move",
+        arity: 3,
+        flags: CMD_WRITE | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Moves a key to another database.",
+        handler: generic::move_,
+    },
+    Command {
+        name: "expire",
+        arity: 3,
+        flags: CMD_WRITE | CMD_FAST | CMD_NOREPLAY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Sets the expiration time of a key in seconds.",
+        handler: generic::expire,
+    },
+    Command {
+        name: "ttl",
+        arity: 2,
+        flags: CMD_READONLY | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Returns the expiration time in seconds of a key.",
+        handler: generic::ttl,
+    },
+    Command {
+        name: "geoadd",
+        arity: -5,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "geo",
+        summary: "Adds one or more members to a geospatial index.",
+        handler: geo::geoadd,
+    },
+    Command {
+        name: "geodist",
+        arity: -4,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "geo",
+        summary: "Returns the distance between two members of a geospatial index.",
+        handler: geo::geodist,
+    },
+    Command {
+        name: "geopos",
+        arity: -2,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "geo",
+        summary: "Returns the longitude and latitude of members from a geospatial index.",
+        handler: geo::geopos,
+    },
+    Command {
+        name: "geohash",
+        arity: -2,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "geo",
+        summary: "Returns members from a geospatial index as geohash strings.",
+        handler: geo::geohash,
+    },
+    Command {
+        name: "geosearch",
+        arity: -7,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "geo",
+        summary: "Queries a geospatial index for members inside an area of a box or a circle.",
+        handler: geo::geosearch,
+    },
+    Command {
+        name: "pfadd",
+        arity: -2,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "hyperloglog",
+        summary: "Adds elements to a HyperLogLog key. Creates the key if it doesn't exist.",
+        handler: hll::pfadd,
+    },
+    Command {
+        name: "pfcount",
+        arity: -2,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "hyperloglog",
+        summary: "Returns the approximated cardinality of the set(s) observed by the HyperLogLog key(s).",
+        handler: hll::pfcount,
+    },
+    Command {
+        name: "pfmerge",
+        arity: -2,
+        flags: CMD_WRITE | CMD_DENYOOM,
+        first_key: 1,
+        last_key: -1,
+        key_step: 1,
+        group: "hyperloglog",
+        summary: "Merges one or more HyperLogLog values into a single key.",
+        handler: hll::pfmerge,
+    },
+    Command {
+        name: "eval",
+        arity: -3,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "scripting",
+        summary: "Executes a server-side Lua script.",
+        handler: scripting::eval,
+    },
+    Command {
+        name: "evalsha",
+        arity: -3,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "scripting",
+        summary: "Executes a server-side Lua script by SHA1 digest.",
+        handler: scripting::evalsha,
+    },
+    Command {
+        name: "script",
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "scripting",
+        summary: "Manages the server-side Lua script cache.",
+        handler: scripting::script,
+    },
+    Command {
+        name: "subscribe",
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Listens for messages published to channels.",
+        handler: pubsub::subscribe,
+    },
+    Command {
+        name: "unsubscribe",
+        arity: -1,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Stops listening to messages posted to channels.",
+        handler: pubsub::unsubscribe,
+    },
+    Command {
+        name: "psubscribe",
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Listens for messages published to channels that match one or more patterns.",
+        handler: pubsub::psubscribe,
+    },
+    Command {
+        name: "punsubscribe",
+        arity: -1,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Stops listening to messages published to channels that match one or more patterns.",
+        handler: pubsub::punsubscribe,
+    },
+    Command {
+        name: "publish",
+        arity: 3,
+        flags: CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Posts a message to a channel.",
+        handler: pubsub::publish,
+    },
+    Command {
+        name: "pubsub",
+        arity: -2,
+        flags: 0,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "pubsub",
+        summary: "Inspects the state of the Pub/Sub subsystem.",
+        handler: pubsub::pubsub,
+    },
+    Command {
+        name: "cluster",
+        arity: -2,
+        flags: CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "cluster",
+        summary: "Inspects and configures the cluster: nodes, slots and slot migrations.",
+        handler: cluster::cluster,
+    },
+    Command {
+        name: "asking",
+        arity: 1,
+        flags: CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "cluster",
+        summary: "Signals that the next command is for a slot being imported.",
+        handler: cluster::asking,
+    },
+    Command {
+        name: "migrate",
+        arity: -6,
+        flags: CMD_WRITE | CMD_NOREPLAY,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "generic",
+        summary: "Atomically transfers keys from one instance to another.",
+        handler: cluster::migrate,
+    },
+    Command {
+        name: "restore-asking",
+        arity: -4,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_NOREPLAY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Stores a key sent by MIGRATE, even in a slot being imported.",
+        handler: cluster::restore,
+    },
+    Command {
+        name: "dump",
+        arity: 2,
+        flags: CMD_READONLY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Returns a serialized representation of the value stored at a key.",
+        handler: cluster::dump,
+    },
+    Command {
+        name: "restore",
+        arity: -4,
+        flags: CMD_WRITE | CMD_DENYOOM | CMD_NOREPLAY,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        group: "generic",
+        summary: "Creates a key from the serialized representation of a value.",
+        handler: cluster::restore,
+    },
+    Command {
+        name: "replicaof",
+        arity: 3,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Configures a server as replica of another, or promotes it to a primary.",
+        handler: replication::replicaof,
+    },
+    Command {
+        name: "slaveof",
+        arity: 3,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Alias of REPLICAOF.",
+        handler: replication::replicaof,
+    },
+    Command {
+        name: "role",
+        arity: 1,
+        flags: CMD_NOSCRIPT | CMD_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Returns the replication role.",
+        handler: replication::role,
+    },
+    Command {
+        name: "replconf",
+        arity: -3,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Configures the replication link, sent by replicas.",
+        handler: replication::replconf,
+    },
+    Command {
+        name: "sync",
+        arity: 1,
+        flags: CMD_ADMIN | CMD_NOSCRIPT,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        group: "server",
+        summary: "Starts replicating to the calling replica.",
+        handler: replication::sync,
+    },
+];
+
+pub fn all() -> &'static [Command] {
+    COMMANDS
+}
+
+/// Find a command by name, ignoring case
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    static INDEX: OnceLock<HashMap<&'static str, &'static Command>> = OnceLock::new();
+    let index = INDEX.get_or_init(|| COMMANDS.iter().map(|c| (c.name, c)).collect());
+    match index.get(name) {
+        Some(cmd) => Some(cmd),
+        None => index.get(name.to_ascii_lowercase().as_str()).copied(),
+    }
+}
+
+/// Publish what happened to keys during the last command, dropping the
+/// events where there are no subscribers
+fn publish_key_events(ctx: &mut Context) {
+    for (index, db) in ctx.dbs.iter_mut().enumerate() {
+        let flags = db.notify_keyspace_events();
+        for event in db.take_notifications() {
+            if let Some(pubsub) = ctx.pubsub.as_deref_mut() {
+                pubsub.notify(index, flags, &event);
+            }
+        }
+    }
+}
+
+/// Send invalidation messages for the keys changed since the last call, by
+/// the client of `ctx`, and pass the changes on to our replicas. `request`
+/// is the write that made them, replicas run it themselves if it changed
+/// only the keys it names in the selected database.
+fn propagate_changes(ctx: &mut Context, request: Option<(&Command, &[Vec<u8>])>) {
+    if ctx.tracking.is_none() && ctx.replication.is_none() {
+        return;
+    }
+    let changes = ctx.dbs.iter_mut().map(Database::take_modified).collect::<Vec<_>>();
+    let replay = request.filter(|(cmd, args)| {
+        let keys = cmd.key_indices(args);
+        changes.iter().enumerate().all(|(index, modified)| {
+            if index != ctx.db_index {
+                return modified.keys.is_empty() && !modified.flushed;
+            }
+            let named = |key: &Vec<u8>| keys.iter().any(|&i| args[i] == *key);
+            let changed = !modified.keys.is_empty() && modified.keys.iter().all(named);
+            changed && !modified.flushed && !modified.dropped
+        })
+    });
+    let mut none = PubSub::new();
+    let pubsub = ctx.pubsub.as_deref_mut().unwrap_or(&mut none);
+    for (index, (db, modified)) in ctx.dbs.iter_mut().zip(changes).enumerate() {
+        if let Some(tracking) = ctx.tracking.as_deref_mut() {
+            if modified.flushed {
+                tracking.invalidate_all(pubsub);
+            }
+            if !modified.keys.is_empty() {
+                tracking.invalidate(&modified.keys, ctx.client_id, pubsub);
+            }
+        }
+        if let Some(replication) = ctx.replication.as_deref_mut() {
+            match replay {
+                Some((_, args)) if index == ctx.db_index => replication.propagate_command(index, args),
+                _ => replication.propagate_changes(index, db, modified.keys, modified.flushed),
+            }
+        }
+    }
+}
+
+/// Pass on the changes of the last command and remember the keys it read,
+/// for clients caching them. `request` is the command as it came in, if
+/// replicas may run it.
+fn track_keys(ctx: &mut Context, cmd: &Command, args: &[Vec<u8>], request: Option<&[Vec<u8>]>) {
+    propagate_changes(ctx, request.map(|request| (cmd, request)));
+    let Some(tracking) = ctx.tracking.as_deref_mut() else {
+        return;
+    };
+    if cmd.has_flag(CMD_READONLY) {
+        let keys = cmd.key_indices(args).into_iter().map(|i| args[i].as_slice());
+        tracking.remember(ctx.client_id, keys);
+    }
+}
+
+/// Delete keys whose time to live passed without anyone reading them, until
+/// `deadline`. Subscribers, tracking clients and replicas hear about it
+/// like they do about the changes of a command.
+pub fn active_expire(ctx: &mut Context, deadline: Instant) {
+    for db in ctx.dbs.iter_mut() {
+        db.active_expire(deadline);
+    }
+    publish_key_events(ctx);
+    propagate_changes(ctx, None);
+}
+
+/// Run a parsed request, serializing the reply to `out`
+pub fn execute(ctx: &mut Context, mut args: Vec<Vec<u8>>, out: &mut Vec<u8>) {
+    if args.is_empty() {
+        return out_err(out, ErrorCode::Err, "empty request");
+    }
+
+    let name = String::from_utf8_lossy(&args[0]);
+    let Some(cmd) = lookup(&name) else {
+        debug!("Unknown command: {}", name);
+        return out_err(out, ErrorCode::Err, &format!("unknown command '{}'", name));
+    };
+    if !cmd.check_arity(args.len()) {
+        return out_wrong_arity(out, cmd.name);
+    }
+    if let Some(acl) = ctx.acl.as_deref() {
+        // Without a client table there's no one to authenticate
+        let user = match ctx.clients.as_deref() {
+            Some(clients) => clients.get(ctx.client_id).and_then(|c| c.user.as_deref()),
+            None => Some(crate::acl::DEFAULT_USER),
+        };
+        if let Err((code, msg)) = acl.check(user, cmd, &args) {
+            return out_err(out, code, &msg);
+        }
+    }
+    let subscribed = ctx.pubsub.as_deref().is_some_and(|p| p.subscriptions(ctx.client_id) > 0);
+    if subscribed && !matches!(cmd.name, "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ping" | "quit") {
+        let msg = format!(
+            "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            cmd.name
+        );
+        return out_err(out, ErrorCode::Err, &msg);
+    }
+    if cmd.has_flag(CMD_WRITE) && ctx.replication.as_deref().is_some_and(Replication::is_replica) {
+        return out_err(out, ErrorCode::ReadOnly, "You can't write against a read only replica.");
+    }
+    if cluster::redirect(ctx, cmd, &args, out) {
+        return;
+    }
+    if cmd.has_flag(CMD_DENYOOM) {
+        // Writes only evict from their own database, so room for the
+        // arguments is made across all of them first
+        database::make_room(ctx.dbs, args.iter().map(Vec::len).sum());
+    }
+    // Handlers may take their arguments, replicas get a copy of writes
+    let replayable = cmd.has_flag(CMD_WRITE) && !cmd.has_flag(CMD_NOREPLAY);
+    let replicated = ctx.replication.as_deref().is_some_and(Replication::has_replicas);
+    let request = (replayable && replicated).then(|| args.clone());
+    let reply_at = out.len();
+    let start = Instant::now();
+    (cmd.handler)(ctx, &mut args, out);
+    let elapsed = start.elapsed();
+    publish_key_events(ctx);
+    // What a failed write did is left to the values
+    let failed = out.get(reply_at) == Some(&TAG_ERR);
+    track_keys(ctx, cmd, &args, request.as_deref().filter(|_| !failed));
+    if let Some(stats) = ctx.stats {
+        stats.record_command(cmd.name, elapsed);
+    }
+    if let Some(slowlog) = ctx.slowlog.as_deref_mut() {
+        let client = ctx.clients.as_deref().and_then(|c| c.get(ctx.client_id));
+        slowlog.record(&args, elapsed, client.and_then(|c| c.addr), client.map_or("", |c| &c.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Reply;
+    use crate::database::dump;
+    use crate::database::value::Value;
+
+    fn run(db: &mut Database, args: &[&str]) -> Reply {
+        let mut out = vec![];
+        let mut ctx = Context::new(db);
+        execute(&mut ctx, args.iter().map(|s| s.as_bytes().to_vec()).collect(), &mut out);
+        Reply::decode(&out).unwrap()
+    }
+
+    fn err(message: &str) -> Reply {
+        Reply::Error { code: "ERR".to_string(), message: message.to_string() }
+    }
+
+    #[test]
+    fn test_lookup_ignores_case() {
+        assert_eq!(lookup("GET").unwrap().name, "get");
+        assert_eq!(lookup("sEt").unwrap().name, "set");
+        assert!(lookup("nope").is_none());
+
+        let mut db = Database::new();
+        assert_eq!(run(&mut db, &["SET", "k", "v"]), Reply::Str(b"OK".to_vec()));
+        assert_eq!(run(&mut db, &["Get", "k"]), Reply::Str(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_arity() {
+        let mut db = Database::new();
+        assert_eq!(run(&mut db, &["get"]), err("wrong number of arguments for 'get' command"));
+        assert_eq!(run(&mut db, &["set", "k"]), err("wrong number of arguments for 'set' command"));
+        assert_eq!(run(&mut db, &["ping", "a", "b"]), err("wrong number of arguments for 'ping' command"));
+        assert_eq!(run(&mut db, &["command", "bogus"]), err("unknown subcommand 'bogus'"));
+    }
+
+    #[test]
+    fn test_key_indices() {
+        let set = lookup("set").unwrap();
+        let request = |args: &[&str]| args.iter().map(|a| a.as_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(set.key_indices(&request(&["set", "k", "v"])), [1]);
+        assert!(lookup("ping").unwrap().key_indices(&request(&["ping", "hi"])).is_empty());
+
+        let multi = Command { first_key: 1, last_key: -1, key_step: 2, ..*set };
+        assert_eq!(multi.key_indices(&request(&["mset", "a", "1", "b", "2", "c"])), [1, 3, 5]);
+
+        let migrate = lookup("migrate").unwrap();
+        assert_eq!(migrate.key_indices(&request(&["migrate", "h", "1", "k", "0", "10", "copy"])), [3]);
+        let keys = request(&["migrate", "h", "1", "", "0", "10", "replace", "KEYS", "a", "b"]);
+        assert_eq!(migrate.key_indices(&keys), [8, 9]);
+    }
+
+    #[test]
+    fn test_propagate_writes() {
+        let mut dbs = Database::group(2);
+        dbs.iter_mut().for_each(|db| db.set_track_modified(true));
+        let mut replication = Replication::new();
+        replication.announce(7, 7001);
+        replication.add_replica(7, None, &mut dbs);
+        replication.take_outbox();
+        let mut run = |args: &[&str]| {
+            let mut ctx = Context { replication: Some(&mut replication), ..Context::with_databases(&mut dbs) };
+            execute(&mut ctx, args.iter().map(|s| s.as_bytes().to_vec()).collect(), &mut vec![]);
+            let stream = replication.take_outbox().into_iter().map(|(_, body)| match Reply::decode(&body).unwrap() {
+                Reply::Array(items) => items[0].clone(),
+                reply => panic!("unexpected {:?}", reply),
+            });
+            stream.collect::<Vec<_>>()
+        };
+        let str = |s: &str| Reply::Str(s.as_bytes().to_vec());
+
+        // Writes of the keys they name go as they are, the others as values
+        assert_eq!(run(&["set", "k", "v"]), [str("set")]);
+        assert_eq!(run(&["pfadd", "h", "a"]), [str("pfadd")]);
+        assert_eq!(run(&["expire", "k", "100"]), [str("restore")]);
+        assert_eq!(run(&["move", "k", "1"]), [str("del"), str("select"), str("restore")]);
+        assert!(run(&["del", "nokey"]).is_empty());
+    }
+
+    #[test]
+    fn test_command_introspection() {
+        let mut db = Database::new();
+        assert_eq!(run(&mut db, &["command", "count"]), Reply::Int(COMMANDS.len() as i64));
+
+        let Reply::Array(all) = run(&mut db, &["command"]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(all.len(), COMMANDS.len());
+
+        let info = run(&mut db, &["COMMAND", "INFO", "get", "nope"]);
+        assert_eq!(
+            info,
+            Reply::Array(vec![
+                Reply::Array(vec![
+                    Reply::Str(b"get".to_vec()),
+                    Reply::Int(2),
+                    Reply::Array(vec![Reply::Str(b"readonly".to_vec()), Reply::Str(b"fast".to_vec())]),
+                    Reply::Int(1),
+                    Reply::Int(1),
+                    Reply::Int(1),
+                ]),
+                Reply::Nil,
+            ])
+        );
+
+        let Reply::Array(docs) = run(&mut db, &["command", "docs", "set"]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(docs[0], Reply::Str(b"set".to_vec()));
+        assert!(matches!(&docs[1], Reply::Array(fields) if fields.len() == 6));
+    }
+
+    #[test]
+    fn test_info() {
+        let mut db = Database::new();
+        let info = |db: &mut Database, args: &[&str]| match run(db, args) {
+            Reply::Str(s) => String::from_utf8(s).unwrap(),
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+
+        let all = info(&mut db, &["info"]);
+        assert!(all.starts_with("# Server\r\n"));
+        assert!(all.contains("\r\n\r\n# Keyspace\r\n"));
+        assert!(!all.contains("# Commandstats"));
+        assert!(info(&mut db, &["info", "ALL"]).contains("# Commandstats"));
+
+        run(&mut db, &["set", "a", "1"]);
+        run(&mut db, &["set", "b", "2"]);
+        run(&mut db, &["expire", "b", "100"]);
+        assert_eq!(info(&mut db, &["info", "keyspace"]), "# Keyspace\r\ndb0:keys=2,expires=1\r\n");
+        assert!(info(&mut db, &["info", "memory", "bogus"]).contains("maxmemory_policy:noeviction\r\n"));
+        assert_eq!(info(&mut db, &["info", "bogus"]), "");
+    }
+
+    #[test]
+    fn test_config() {
+        let mut db = Database::new();
+        let pair = |name: &str, value: &str| {
+            Reply::Array(vec![Reply::Str(name.as_bytes().to_vec()), Reply::Str(value.as_bytes().to_vec())])
+        };
+
+        assert_eq!(run(&mut db, &["config", "set", "maxmemory", "1kb"]), Reply::Str(b"OK".to_vec()));
+        assert_eq!(run(&mut db, &["config", "get", "maxmemory"]), pair("maxmemory", "1024"));
+        run(&mut db, &["config", "set", "maxmemory-policy", "allkeys-lru"]);
+        assert_eq!(run(&mut db, &["CONFIG", "GET", "maxmemory-policy"]), pair("maxmemory-policy", "allkeys-lru"));
+        assert!(matches!(run(&mut db, &["config", "get", "*"]), Reply::Array(all) if all.len() == 2 * server::CONFIG_PARAMS.len()));
+        assert_eq!(run(&mut db, &["config", "get", "nope"]), Reply::Array(vec![]));
+        run(&mut db, &["config", "set", "zset-max-listpack-entries", "16"]);
+        assert_eq!(run(&mut db, &["config", "get", "zset-max-listpack-entries"]), pair("zset-max-listpack-entries", "16"));
+        assert_eq!(db.encoding_limits().zset_max_listpack_entries, 16);
+
+        assert_eq!(
+            run(&mut db, &["config", "set", "maxmemory-samples", "0"]),
+            err("Invalid argument '0' for CONFIG SET 'maxmemory-samples'")
+        );
+        assert_eq!(
+            run(&mut db, &["config", "set", "nope", "1"]),
+            err("Unknown option or number of arguments for CONFIG SET - 'nope'")
+        );
+        assert_eq!(run(&mut db, &["config", "set", "loglevel", "loud"]), err("Invalid argument 'loud' for CONFIG SET 'loglevel'"));
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let mut dbs = [Database::new(), Database::new()];
+        let mut pubsub = PubSub::new();
+        let mut run_as = |client_id: u64, args: &[&str]| {
+            let mut ctx = Context::with_databases(&mut dbs);
+            ctx.client_id = client_id;
+            ctx.pubsub = Some(&mut pubsub);
+            let mut out = vec![];
+            execute(&mut ctx, args.iter().map(|s| s.as_bytes().to_vec()).collect(), &mut out);
+            (Reply::decode(&out).unwrap(), pubsub.take_outbox())
+        };
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let confirm = |kind: &str, name: &str, n: i64| Reply::Array(vec![bulk(kind), bulk(name), Reply::Int(n)]);
+        let message = |id: u64, parts: &[&str]| {
+            let mut body = vec![];
+            out_arr(&mut body, parts.len());
+            parts.iter().for_each(|p| out_str(&mut body, p.as_bytes()));
+            (id, body)
+        };
+
+        assert_eq!(
+            run_as(1, &["subscribe", "news", "sport"]).0,
+            Reply::Array(vec![confirm("subscribe", "news", 1), confirm("subscribe", "sport", 2)])
+        );
+        assert_eq!(run_as(2, &["psubscribe", "n*"]).0, Reply::Array(vec![confirm("psubscribe", "n*", 1)]));
+        assert_eq!(
+            run_as(1, &["get", "k"]).0,
+            err("Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context")
+        );
+        assert_eq!(
+            run_as(3, &["publish", "news", "hi"]),
+            (Reply::Int(2), vec![message(1, &["message", "news", "hi"]), message(2, &["pmessage", "n*", "news", "hi"])])
+        );
+        assert_eq!(run_as(3, &["pubsub", "channels"]).0, Reply::Array(vec![bulk("news"), bulk("sport")]));
+        assert_eq!(run_as(3, &["pubsub", "numsub", "news", "x"]).0, Reply::Array(vec![bulk("news"), Reply::Int(1), bulk("x"), Reply::Int(0)]));
+        assert_eq!(run_as(3, &["pubsub", "numpat"]).0, Reply::Int(1));
+
+        assert_eq!(
+            run_as(1, &["unsubscribe"]).0,
+            Reply::Array(vec![confirm("unsubscribe", "news", 1), confirm("unsubscribe", "sport", 0)])
+        );
+        assert_eq!(
+            run_as(1, &["unsubscribe"]).0,
+            Reply::Array(vec![Reply::Array(vec![bulk("unsubscribe"), Reply::Nil, Reply::Int(0)])])
+        );
+        assert_eq!(run_as(1, &["publish", "news", "hi"]).0, Reply::Int(1));
+
+        // Keyspace notifications, in the database they happened in
+        run_as(2, &["punsubscribe"]);
+        run_as(2, &["psubscribe", "__key*"]);
+        assert_eq!(run_as(3, &["set", "k", "v"]).1, []);
+        run_as(3, &["config", "set", "notify-keyspace-events", "KEg$"]);
+        assert_eq!(run_as(3, &["config", "get", "notify-keyspace-events"]).0, Reply::Array(vec![bulk("notify-keyspace-events"), bulk("g$KE")]));
+        let (_, sent) = run_as(3, &["set", "k", "v"]);
+        assert_eq!(sent, [
+            message(2, &["pmessage", "__key*", "__keyspace@0__:k", "set"]),
+            message(2, &["pmessage", "__key*", "__keyevent@0__:set", "k"]),
+        ]);
+        run_as(3, &["config", "set", "notify-keyspace-events", "Eg"]);
+        let (_, sent) = run_as(3, &["move", "k", "1"]);
+        assert_eq!(sent, [
+            message(2, &["pmessage", "__key*", "__keyevent@0__:move_from", "k"]),
+            message(2, &["pmessage", "__key*", "__keyevent@1__:move_to", "k"]),
+        ]);
+        // Strings aren't in the flags any more
+        assert_eq!(run_as(3, &["set", "k", "v"]).1, []);
+        assert_eq!(run_as(3, &["del", "k", "nope"]).1, [message(2, &["pmessage", "__key*", "__keyevent@0__:del", "k"])]);
+    }
+
+    #[test]
+    fn test_databases() {
+        let mut dbs = Database::group(4);
+        let mut ctx = Context::with_databases(&mut dbs);
+        let mut run = |args: &[&str]| {
+            let mut out = vec![];
+            execute(&mut ctx, args.iter().map(|s| s.as_bytes().to_vec()).collect(), &mut out);
+            Reply::decode(&out).unwrap()
+        };
+        let ok = Reply::Str(b"OK".to_vec());
+
+        run(&["set", "k", "v"]);
+        run(&["expire", "k", "100"]);
+        assert_eq!(run(&["select", "4"]), err("DB index is out of range"));
+        assert_eq!(run(&["select", "x"]), err("value is not an integer or out of range"));
+        assert_eq!(run(&["move", "k", "0"]), err("source and destination objects are the same"));
+        assert_eq!(run(&["move", "k", "2"]), Reply::Int(1));
+        assert_eq!(run(&["move", "k", "2"]), Reply::Int(0));
+        assert_eq!(run(&["dbsize"]), Reply::Int(0));
+
+        assert_eq!(run(&["select", "2"]), ok);
+        assert_eq!(run(&["get", "k"]), Reply::Str(b"v".to_vec()));
+        assert_eq!(run(&["ttl", "k"]), Reply::Int(100));
+        assert_eq!(run(&["swapdb", "2", "3"]), ok);
+        assert_eq!(run(&["dbsize"]), Reply::Int(0));
+        assert_eq!(run(&["select", "3"]), ok);
+        assert_eq!(run(&["dbsize"]), Reply::Int(1));
+
+        run(&["select", "1"]);
+        run(&["set", "other", "v"]);
+        assert_eq!(run(&["flushdb", "later"]), err("syntax error"));
+        assert_eq!(run(&["flushdb", "async"]), ok);
+        assert_eq!(run(&["dbsize"]), Reply::Int(0));
+        run(&["set", "other", "v"]);
+        assert_eq!(run(&["flushall"]), ok);
+
+        // The memory budget is shared, writes evict keys of other databases
+        run(&["select", "0"]);
+        for i in 0..10 {
+            run(&["set", &format!("key{}", i), "v"]);
+        }
+        let Reply::Str(info) = run(&["info", "memory"]) else { panic!() };
+        let info = String::from_utf8(info).unwrap();
+        let used = info.lines().find_map(|line| line.strip_prefix("used_memory:")).unwrap();
+        assert_eq!(run(&["config", "set", "maxmemory", used]), ok);
+        run(&["select", "1"]);
+        assert!(matches!(run(&["set", "k", "v"]), Reply::Error { code, .. } if code == "OOM"));
+        run(&["config", "set", "maxmemory-policy", "allkeys-random"]);
+        assert_eq!(run(&["set", "k", "v"]), ok);
+        run(&["select", "0"]);
+        assert_eq!(run(&["dbsize"]), Reply::Int(9));
+        assert_eq!(run(&["flushall"]), ok);
+        assert!(dbs.iter().all(Database::is_empty));
+    }
+
+    #[test]
+    fn test_cluster() {
+        let mut dbs = [Database::new(), Database::new()];
+        assert_eq!(run(&mut dbs[0], &["cluster", "info"]), err("This instance has cluster support disabled"));
+        let mut cluster = Cluster::new("127.0.0.1".parse().unwrap(), 7000, 17000);
+        let mut ctx = Context::with_databases(&mut dbs);
+        ctx.cluster = Some(&mut cluster);
+        let mut run = |args: &[&str]| {
+            let mut out = vec![];
+            execute(&mut ctx, args.iter().map(|s| s.as_bytes().to_vec()).collect(), &mut out);
+            Reply::decode(&out).unwrap()
+        };
+        let error = |code: &str, message: &str| Reply::Error { code: code.to_string(), message: message.to_string() };
+        let ok = Reply::Str(b"OK".to_vec());
+
+        assert_eq!(run(&["cluster", "keyslot", "{user1}.name"]), Reply::Int(8106));
+        assert_eq!(run(&["set", "foo", "1"]), error("CLUSTERDOWN", "Hash slot not served"));
+        assert_eq!(run(&["cluster", "addslots", "12182", "8106"]), ok);
+        assert_eq!(run(&["cluster", "addslots", "1", "1"]), err("Slot 1 specified multiple times"));
+        assert_eq!(run(&["cluster", "addslots", "16384"]), err("Invalid or out of range slot 16384"));
+        assert_eq!(run(&["cluster", "addslotsrange", "5", "3"]), err("start slot number 5 is greater than end slot number 3"));
+        assert_eq!(run(&["cluster", "addslotsrange", "0", "3", "3", "4"]), err("Slot 3 specified multiple times"));
+        assert_eq!(run(&["cluster", "addslotsrange", "100", "101"]), ok);
+        assert_eq!(run(&["cluster", "delslotsrange", "100", "101"]), ok);
+        assert_eq!(run(&["set", "foo", "1"]), ok);
+        assert_eq!(run(&["set", "{user1}.name", "a"]), ok);
+        assert_eq!(run(&["del", "foo", "{user1}.name"]), error("CROSSSLOT", "Keys in request don't hash to the same slot"));
+        assert_eq!(run(&["cluster", "countkeysinslot", "12182"]), Reply::Int(1));
+        assert_eq!(run(&["cluster", "getkeysinslot", "8106", "5"]), Reply::Array(vec![Reply::Str(b"{user1}.name".to_vec())]));
+        assert_eq!(run(&["select", "1"]), err("SELECT is not allowed in cluster mode"));
+
+        assert_eq!(run(&["cluster", "setslot", "0", "migrating", "x"]), err("I'm not the owner of hash slot 0"));
+        assert_eq!(run(&["cluster", "setslot", "0", "importing", "x"]), err("I don't know about node x"));
+        assert_eq!(run(&["cluster", "setslot", "0", "bogus"]), err("Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"));
+        let Reply::Str(myid) = run(&["cluster", "myid"]) else {
+            panic!("expected a node id");
+        };
+        let myid = String::from_utf8(myid).unwrap();
+        assert_eq!(run(&["cluster", "setslot", "12182", "node", &myid]), ok);
+        assert_eq!(run(&["cluster", "delslots", "12182"]), ok);
+        assert_eq!(run(&["get", "foo"]), error("CLUSTERDOWN", "Hash slot not served"));
+        assert_eq!(run(&["cluster", "meet", "localhost", "7001"]), err("Invalid node address specified: localhost:7001"));
+        assert_eq!(run(&["cluster", "meet", "127.0.0.1", "70000"]), err("Invalid base port specified: 70000"));
+        assert_eq!(run(&["cluster", "meet", "127.0.0.1", "7001"]), ok);
+
+        // Keys travel as serialized values
+        assert_eq!(run(&["restore-asking", "{user1}.copy", "0", "zz"]), err("DUMP payload version or checksum are wrong"));
+        let payload = dump::to_hex(&dump::serialize(&Value::from_string("v".to_string())));
+        assert_eq!(run(&["restore-asking", "{user1}.name", "0", &payload]), error("BUSYKEY", "Target key name already exists."));
+        assert_eq!(run(&["restore-asking", "{user1}.name", "5000", &payload, "replace"]), ok);
+        assert_eq!(run(&["get", "{user1}.name"]), Reply::Str(b"v".to_vec()));
+        assert_eq!(run(&["ttl", "{user1}.name"]), Reply::Int(5));
+        assert_eq!(run(&["migrate", "127.0.0.1", "1", "nokey", "0", "10"]), Reply::Str(b"NOKEY".to_vec()));
+        assert_eq!(
+            run(&["migrate", "127.0.0.1", "1", "x", "0", "10", "keys", "a"]),
+            err("When using MIGRATE KEYS option, the key argument must be set to the empty string")
+        );
+    }
+
+    #[test]
+    fn test_generic_keys() {
+        let mut db = Database::new();
+        let ok = Reply::Str(b"OK".to_vec());
+        let str = |s: &str| Reply::Str(s.as_bytes().to_vec());
+
+        run(&mut db, &["set", "a", "1"]);
+        run(&mut db, &["set", "b", "2"]);
+        assert_eq!(run(&mut db, &["exists", "a", "b", "a", "nope"]), Reply::Int(3));
+        assert_eq!(run(&mut db, &["touch", "a", "nope"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["type", "a"]), str("string"));
+        assert_eq!(run(&mut db, &["type", "nope"]), str("none"));
+        assert_eq!(run(&mut db, &["del", "a", "nope", "a"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["randomkey"]), str("b"));
+
+        run(&mut db, &["expire", "b", "100"]);
+        let overflow = i64::MAX.to_string();
+        assert_eq!(run(&mut db, &["expire", "b", &overflow]), err("invalid expire time in 'expire' command"));
+        assert_eq!(run(&mut db, &["ttl", "b"]), Reply::Int(100));
+        assert_eq!(run(&mut db, &["rename", "nope", "c"]), err("no such key"));
+        assert_eq!(run(&mut db, &["rename", "b", "c"]), ok);
+        assert_eq!(run(&mut db, &["ttl", "c"]), Reply::Int(100));
+        run(&mut db, &["set", "d", "4"]);
+        assert_eq!(run(&mut db, &["renamenx", "c", "d"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["renamenx", "c", "e"]), Reply::Int(1));
+
+        assert_eq!(run(&mut db, &["copy", "e", "d"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["copy", "e", "d", "REPLACE"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["get", "d"]), str("2"));
+        assert_eq!(run(&mut db, &["ttl", "d"]), Reply::Int(100));
+        assert_eq!(run(&mut db, &["copy", "e", "e"]), err("source and destination objects are the same"));
+        assert_eq!(run(&mut db, &["copy", "e", "f", "db", "1"]), err("DB index is out of range"));
+
+        assert_eq!(run(&mut db, &["object", "encoding", "d"]), str("int"));
+        assert_eq!(run(&mut db, &["object", "idletime", "d"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["object", "refcount", "nope"]), Reply::Nil);
+        assert!(matches!(run(&mut db, &["object", "freq", "d"]), Reply::Error { .. }));
+        assert_eq!(run(&mut db, &["object", "bogus", "d"]), err("unknown subcommand 'bogus'"));
+        assert_eq!(run(&mut db, &["unlink", "d", "e"]), Reply::Int(2));
+        assert_eq!(run(&mut db, &["randomkey"]), Reply::Nil);
+    }
+
+    #[test]
+    fn test_geo() {
+        let mut db = Database::new();
+        let str = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let names = |names: &[&str]| Reply::Array(names.iter().map(|n| str(n)).collect());
+        let add = ["geoadd", "sicily", "13.361389", "38.115556", "palermo", "15.087269", "37.502669", "catania"];
+
+        assert_eq!(run(&mut db, &add), Reply::Int(2));
+        assert_eq!(run(&mut db, &add), Reply::Int(0));
+        assert_eq!(run(&mut db, &["type", "sicily"]), str("zset"));
+        assert_eq!(run(&mut db, &["geoadd", "sicily", "xx", "ch", "13.4", "38.1", "palermo", "0", "0", "x"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["geoadd", "sicily", "nx", "13.361389", "38.115556", "palermo"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["geoadd", "sicily", "ch", "13.361389", "38.115556", "palermo"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["geoadd", "sicily", "181", "0", "x"]), err("invalid longitude,latitude pair 181.000000,0.000000"));
+        assert_eq!(run(&mut db, &["geoadd", "sicily", "xx", "1", "2"]), err("syntax error"));
+
+        assert_eq!(run(&mut db, &["geodist", "sicily", "palermo", "catania"]), str("166274.1516"));
+        assert_eq!(run(&mut db, &["geodist", "sicily", "palermo", "catania", "KM"]), str("166.2742"));
+        assert_eq!(run(&mut db, &["geodist", "sicily", "palermo", "catania", "mi"]), str("103.3182"));
+        assert_eq!(run(&mut db, &["geodist", "sicily", "palermo", "nope"]), Reply::Nil);
+        assert_eq!(run(&mut db, &["geodist", "sicily", "palermo", "catania", "yd"]), err("unsupported unit provided. please use M, KM, FT, MI"));
+        assert_eq!(run(&mut db, &["geohash", "sicily", "palermo", "nope"]), Reply::Array(vec![str("sqc8b49rny0"), Reply::Nil]));
+
+        let Reply::Array(pos) = run(&mut db, &["geopos", "sicily", "palermo", "nope"]) else {
+            panic!("expected an array");
+        };
+        let Reply::Array(coord) = &pos[0] else {
+            panic!("expected a pair");
+        };
+        let value = |r: &Reply| match r {
+            Reply::Str(s) => std::str::from_utf8(s).unwrap().parse::<f64>().unwrap(),
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert!((value(&coord[0]) - 13.361389).abs() < 1e-5 && (value(&coord[1]) - 38.115556).abs() < 1e-5);
+        assert_eq!(pos[1], Reply::Nil);
+
+        let search = |db: &mut Database, args: &[&str]| run(db, &[&["geosearch", "sicily"], args].concat());
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "byradius", "200", "km"]), names(&["catania", "palermo"]));
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "byradius", "100", "km"]), names(&["catania"]));
+        assert_eq!(search(&mut db, &["frommember", "palermo", "byradius", "200", "km", "desc"]), names(&["catania", "palermo"]));
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "bybox", "400", "400", "km", "count", "1"]), names(&["catania"]));
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "bybox", "400", "200", "km"]), names(&["catania"]));
+        assert_eq!(
+            search(&mut db, &["fromlonlat", "15", "37", "byradius", "200", "km", "withdist", "withhash", "count", "1"]),
+            Reply::Array(vec![Reply::Array(vec![str("catania"), str("56.4413"), Reply::Int(3479447370796909)])])
+        );
+        assert_eq!(search(&mut db, &["frommember", "nope", "byradius", "1", "m"]), err("could not decode requested zset member"));
+        assert_eq!(
+            search(&mut db, &["fromlonlat", "15", "37", "frommember", "palermo", "byradius", "1", "m"]),
+            err("exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH")
+        );
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "asc", "withdist"]), err("exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH"));
+        assert_eq!(search(&mut db, &["fromlonlat", "15", "37", "byradius", "1", "m", "count", "0"]), err("COUNT must be > 0"));
+        assert_eq!(run(&mut db, &["geosearch", "nope", "fromlonlat", "15", "37", "byradius", "1", "m"]), Reply::Array(vec![]));
+
+        run(&mut db, &["set", "s", "v"]);
+        assert!(matches!(run(&mut db, &["geopos", "s", "x"]), Reply::Error { code, .. } if code == "WRONGTYPE"));
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut db = Database::new();
+        let ok = Reply::Str(b"OK".to_vec());
+        let prefix = |db: &mut Database, key: &str| match run(db, &["get", key]) {
+            Reply::Str(s) => String::from_utf8(s[..5].to_vec()).unwrap(),
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+
+        assert_eq!(run(&mut db, &["pfadd", "h1"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["pfadd", "h1"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["pfadd", "h1", "a", "b", "c"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["pfadd", "h1", "a", "b"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["pfcount", "h1"]), Reply::Int(3));
+        assert_eq!(run(&mut db, &["pfadd", "h2", "c", "d"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["pfcount", "h1", "h2", "nope"]), Reply::Int(4));
+        assert_eq!(prefix(&mut db, "h1"), "HYLLS");
+
+        run(&mut db, &["expire", "h1", "100"]);
+        assert_eq!(run(&mut db, &["pfmerge", "h1", "h2", "nope"]), ok);
+        assert_eq!(run(&mut db, &["pfcount", "h1"]), Reply::Int(4));
+        assert_eq!(run(&mut db, &["ttl", "h1"]), Reply::Int(100));
+        assert_eq!(run(&mut db, &["pfmerge", "h3"]), ok);
+        assert_eq!(run(&mut db, &["pfcount", "h3"]), Reply::Int(0));
+
+        // A small sparse limit turns it dense
+        assert_eq!(run(&mut db, &["config", "set", "hll-sparse-max-bytes", "40"]), ok);
+        let elements = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        let args = [&["pfadd", "h2"][..], &elements.iter().map(|e| e.as_str()).collect::<Vec<_>>()].concat();
+        assert_eq!(run(&mut db, &args), Reply::Int(1));
+        assert_eq!(prefix(&mut db, "h2"), "HYLLD");
+        assert_eq!(run(&mut db, &["pfcount", "h2"]), Reply::Int(12));
+        // Then takes elements in place
+        assert_eq!(run(&mut db, &["pfadd", "h2", "0", "1"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["pfadd", "h2", "x", "y", "x"]), Reply::Int(1));
+        assert_eq!(prefix(&mut db, "h2"), "HYLLD");
+        assert_eq!(run(&mut db, &["pfcount", "h2"]), Reply::Int(14));
+        assert_eq!(run(&mut db, &["pfcount", "h2", "nope"]), Reply::Int(14));
+
+        let wrongtype = |reply: Reply| matches!(reply, Reply::Error { code, .. } if code == "WRONGTYPE");
+        run(&mut db, &["set", "s", "hello"]);
+        assert!(wrongtype(run(&mut db, &["pfadd", "s", "a"])));
+        assert!(wrongtype(run(&mut db, &["pfcount", "h1", "s"])));
+        assert!(wrongtype(run(&mut db, &["pfmerge", "h1", "s"])));
+        assert_eq!(run(&mut db, &["pfcount", "h1"]), Reply::Int(4));
+    }
+
+    #[test]
+    fn test_bitmaps() {
+        let mut db = Database::new();
+        let ints = |ints: &[i64]| Reply::Array(ints.iter().map(|n| Reply::Int(*n)).collect());
+        let bytes = |db: &mut Database, key: &str| match run(db, &["get", key]) {
+            Reply::Str(s) => s,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+
+        assert_eq!(run(&mut db, &["setbit", "b", "7", "1"]), Reply::Int(0));
+        assert_eq!(bytes(&mut db, "b"), [0x01]);
+        assert_eq!(run(&mut db, &["setbit", "b", "7", "0"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["setbit", "b", "0", "1"]), Reply::Int(0));
+        assert_eq!(bytes(&mut db, "b"), [0x80]);
+        assert_eq!(run(&mut db, &["getbit", "b", "0"]), Reply::Int(1));
+        assert_eq!(run(&mut db, &["getbit", "b", "100"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["getbit", "nope", "0"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["setbit", "b", "4294967296", "1"]), err("bit offset is not an integer or out of range"));
+        assert_eq!(run(&mut db, &["setbit", "b", "1", "2"]), err("bit is not an integer or out of range"));
+
+        // Written in place unless the string grows or holds an integer
+        run(&mut db, &["set", "w", "a"]);
+        let used = db.used_memory();
+        assert_eq!(run(&mut db, &["setbit", "w", "6", "1"]), Reply::Int(0));
+        assert_eq!(bytes(&mut db, "w"), b"c");
+        assert_eq!(db.used_memory(), used);
+        assert_eq!(run(&mut db, &["setbit", "w", "15", "1"]), Reply::Int(0));
+        assert_eq!(bytes(&mut db, "w"), b"c\x01");
+        assert_eq!(db.used_memory(), used + 1);
+        run(&mut db, &["set", "n", "1"]);
+        assert_eq!(run(&mut db, &["setbit", "n", "7", "0"]), Reply::Int(1));
+        assert_eq!(bytes(&mut db, "n"), b"0");
+
+        run(&mut db, &["set", "s", "foobar"]);
+        assert_eq!(run(&mut db, &["bitcount", "s"]), Reply::Int(26));
+        assert_eq!(run(&mut db, &["bitcount", "s", "0", "0"]), Reply::Int(4));
+        assert_eq!(run(&mut db, &["bitcount", "s", "1", "1", "BYTE"]), Reply::Int(6));
+        assert_eq!(run(&mut db, &["bitcount", "s", "-2", "-1"]), Reply::Int(7));
+        assert_eq!(run(&mut db, &["bitcount", "s", "5", "30", "BIT"]), Reply::Int(17));
+        assert_eq!(run(&mut db, &["bitcount", "s", "2", "1"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["bitcount", "nope"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["bitcount", "s", "0"]), err("syntax error"));
+
+        // 0x00 0xff 0xf0
+        for bit in 8..20 {
+            run(&mut db, &["setbit", "p", &bit.to_string(), "1"]);
+        }
+        run(&mut db, &["setbit", "p", "23", "0"]);
+        assert_eq!(bytes(&mut db, "p"), [0x00, 0xff, 0xf0]);
+        assert_eq!(run(&mut db, &["bitpos", "p", "1"]), Reply::Int(8));
+        assert_eq!(run(&mut db, &["bitpos", "p", "1", "2"]), Reply::Int(16));
+        assert_eq!(run(&mut db, &["bitpos", "p", "1", "2", "-1", "BYTE"]), Reply::Int(16));
+        assert_eq!(run(&mut db, &["bitpos", "p", "1", "7", "15", "BIT"]), Reply::Int(8));
+        assert_eq!(run(&mut db, &["bitpos", "p", "0", "1"]), Reply::Int(20));
+        assert_eq!(run(&mut db, &["bitpos", "p", "0", "1", "1"]), Reply::Int(-1));
+        run(&mut db, &["set", "ones", "\u{7f}"]);
+        run(&mut db, &["setbit", "ones", "0", "1"]);
+        assert_eq!(run(&mut db, &["bitpos", "ones", "0"]), Reply::Int(8));
+        assert_eq!(run(&mut db, &["bitpos", "nope", "1"]), Reply::Int(-1));
+        assert_eq!(run(&mut db, &["bitpos", "nope", "0"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["bitpos", "p", "2"]), err("The bit argument must be 1 or 0."));
+
+        run(&mut db, &["set", "t", "abcdef"]);
+        assert_eq!(run(&mut db, &["bitop", "and", "d", "s", "t"]), Reply::Int(6));
+        assert_eq!(bytes(&mut db, "d"), b"`bc`ab");
+        assert_eq!(run(&mut db, &["bitop", "or", "d", "p", "nope"]), Reply::Int(3));
+        assert_eq!(bytes(&mut db, "d"), [0x00, 0xff, 0xf0]);
+        assert_eq!(run(&mut db, &["bitop", "not", "d", "p"]), Reply::Int(3));
+        assert_eq!(bytes(&mut db, "d"), [0xff, 0x00, 0x0f]);
+        assert_eq!(run(&mut db, &["bitop", "xor", "d", "p", "p"]), Reply::Int(3));
+        assert_eq!(bytes(&mut db, "d"), [0, 0, 0]);
+        assert_eq!(run(&mut db, &["bitop", "and", "d", "nope"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["exists", "d"]), Reply::Int(0));
+        assert_eq!(run(&mut db, &["bitop", "not", "d", "s", "t"]), err("BITOP NOT must be called with a single source key."));
+
+        assert_eq!(run(&mut db, &["bitfield", "f", "incrby", "i5", "100", "1", "get", "u4", "0"]), ints(&[1, 0]));
+        assert_eq!(run(&mut db, &["bitfield", "f", "set", "i8", "#0", "100", "set", "i8", "#1", "200"]), ints(&[0, 0]));
+        assert_eq!(run(&mut db, &["bitfield", "f", "get", "i8", "0", "get", "u8", "8", "get", "i8", "8"]), ints(&[100, 200, -56]));
+        let counters = ["bitfield", "c", "incrby", "u2", "100", "1", "overflow", "sat", "incrby", "u2", "102", "1"];
+        for expected in [[1, 1], [2, 2], [3, 3], [0, 3]] {
+            assert_eq!(run(&mut db, &counters), ints(&expected));
+        }
+        assert_eq!(
+            run(&mut db, &["bitfield", "c", "overflow", "fail", "incrby", "u2", "102", "1", "incrby", "i64", "0", "-1"]),
+            Reply::Array(vec![Reply::Nil, Reply::Int(-1)])
+        );
+        assert_eq!(run(&mut db, &["bitfield", "c", "set", "i64", "0", "-9223372036854775808", "incrby", "i64", "0", "-1"]), ints(&[-1, i64::MAX]));
+        assert_eq!(run(&mut db, &["bitfield", "nope", "get", "u8", "0"]), ints(&[0]));
+        assert_eq!(run(&mut db, &["exists", "nope"]), Reply::Int(0));
+        assert_eq!(
+            run(&mut db, &["bitfield", "c", "get", "u64", "0"]),
+            err("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.")
+        );
+        assert_eq!(run(&mut db, &["bitfield", "c", "overflow", "bogus"]), err("Invalid OVERFLOW type specified"));
+        assert_eq!(run(&mut db, &["bitfield", "c", "get", "u8"]), err("syntax error"));
+
+        run(&mut db, &["geoadd", "z", "0", "0", "m"]);
+        assert!(matches!(run(&mut db, &["getbit", "z", "0"]), Reply::Error { code, .. } if code == "WRONGTYPE"));
+    }
+}
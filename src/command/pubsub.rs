@@ -0,0 +1,110 @@
+use crate::protocol::*;
+
+use super::Context;
+
+const UNAVAILABLE_MSG: &str = "Pub/Sub is not available";
+
+/// One entry of a (un)subscribe reply: the action, the channel or pattern
+/// and how many subscriptions the client has left
+fn out_confirmation(out: &mut Vec<u8>, kind: &str, name: Option<&[u8]>, count: usize) {
+    out_arr(out, 3);
+    out_str(out, kind.as_bytes());
+    match name {
+        Some(name) => out_str(out, name),
+        None => out_nil(out),
+    }
+    out_int(out, count as i64);
+}
+
+/// `subscribe channel [channel ...]` and `psubscribe pattern [pattern ...]`.
+/// There is a single reply for the whole request, an array with one
+/// confirmation per channel.
+fn subscribe_all(ctx: &mut Context, args: &[Vec<u8>], out: &mut Vec<u8>, patterns: bool) {
+    let id = ctx.client_id;
+    let Some(pubsub) = ctx.pubsub.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    out_arr(out, args.len() - 1);
+    for name in &args[1..] {
+        let (kind, count) = if patterns {
+            ("psubscribe", pubsub.psubscribe(id, name))
+        } else {
+            ("subscribe", pubsub.subscribe(id, name))
+        };
+        out_confirmation(out, kind, Some(name), count);
+    }
+}
+
+/// `unsubscribe [channel ...]` and `punsubscribe [pattern ...]`, without
+/// arguments from everything
+fn unsubscribe_all(ctx: &mut Context, args: &[Vec<u8>], out: &mut Vec<u8>, patterns: bool) {
+    let id = ctx.client_id;
+    let Some(pubsub) = ctx.pubsub.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    let kind = if patterns { "punsubscribe" } else { "unsubscribe" };
+    let names = match (args.len(), patterns) {
+        (1, true) => pubsub.patterns_of(id),
+        (1, false) => pubsub.channels_of(id),
+        _ => args[1..].to_vec(),
+    };
+    if names.is_empty() {
+        out_arr(out, 1);
+        return out_confirmation(out, kind, None, pubsub.subscriptions(id));
+    }
+    out_arr(out, names.len());
+    for name in &names {
+        let count = if patterns { pubsub.punsubscribe(id, name) } else { pubsub.unsubscribe(id, name) };
+        out_confirmation(out, kind, Some(name), count);
+    }
+}
+
+pub fn subscribe(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    subscribe_all(ctx, args, out, false);
+}
+
+pub fn psubscribe(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    subscribe_all(ctx, args, out, true);
+}
+
+pub fn unsubscribe(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    unsubscribe_all(ctx, args, out, false);
+}
+
+pub fn punsubscribe(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    unsubscribe_all(ctx, args, out, true);
+}
+
+/// `publish channel message`, the number of clients that got it
+pub fn publish(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let receivers = ctx.pubsub.as_deref_mut().map_or(0, |p| p.publish(&args[1], &args[2]));
+    out_int(out, receivers as i64);
+}
+
+/// `pubsub channels [pattern]`, `pubsub numsub [channel ...]` and
+/// `pubsub numpat`
+pub fn pubsub(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(pubsub) = ctx.pubsub.as_deref() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("channels", 2 | 3) => {
+            let channels = pubsub.active_channels(args.get(2).map(|p| p.as_slice()));
+            out_arr(out, channels.len());
+            for channel in channels {
+                out_str(out, channel);
+            }
+        }
+        ("numsub", _) => {
+            out_arr(out, 2 * (args.len() - 2));
+            for channel in &args[2..] {
+                out_str(out, channel);
+                out_int(out, pubsub.num_subscribers(channel) as i64);
+            }
+        }
+        ("numpat", 2) => out_int(out, pubsub.num_patterns() as i64),
+        ("channels" | "numpat", _) => out_wrong_arity(out, &format!("pubsub|{}", sub)),
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1]))),
+    }
+}
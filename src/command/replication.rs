@@ -0,0 +1,113 @@
+use crate::notice;
+use crate::protocol::*;
+
+use super::{parse_arg, Context};
+
+const UNAVAILABLE_MSG: &str = "replication is not available";
+
+/// `replicaof host port` starts replicating from another server, dropping
+/// our data once synced. `replicaof no one` turns a replica into a primary,
+/// keeping its data.
+pub fn replicaof(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(replication) = ctx.replication.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    if ctx.cluster.is_some() {
+        return out_err(out, ErrorCode::Err, "REPLICAOF not allowed in cluster mode.");
+    }
+    if args[1].eq_ignore_ascii_case(b"no") && args[2].eq_ignore_ascii_case(b"one") {
+        if replication.is_replica() {
+            notice!("Primary mode enabled");
+            replication.promote();
+        }
+        return out_ok(out);
+    }
+    let Some(port) = parse_arg::<u16>(&args[2]) else {
+        return out_err(out, ErrorCode::Err, "Invalid master port");
+    };
+    let host = String::from_utf8_lossy(&args[1]);
+    if replication.primary().is_some_and(|p| p.host == host && p.port == port) {
+        return out_ok(out);
+    }
+    notice!("Replicating from {}:{}", host, port);
+    // Our own replicas have to sync again, from the new primary's data
+    for id in replication.follow(&host, port) {
+        if let Some(clients) = ctx.clients.as_deref_mut() {
+            clients.kill(id);
+        }
+    }
+    out_ok(out);
+}
+
+/// `role`, `master offset [[ip port offset] ...]` on a primary and
+/// `slave host port state offset` on a replica
+pub fn role(ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(replication) = ctx.replication.as_deref() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    match replication.primary() {
+        Some(primary) => {
+            out_arr(out, 5);
+            out_str(out, b"slave");
+            out_str(out, primary.host.as_bytes());
+            out_int(out, primary.port as i64);
+            out_str(out, if primary.up { b"connected" } else { b"connect" });
+            out_int(out, replication.offset() as i64);
+        }
+        None => {
+            out_arr(out, 3);
+            out_str(out, b"master");
+            out_int(out, replication.offset() as i64);
+            out_arr(out, replication.replicas().count());
+            for replica in replication.replicas() {
+                out_arr(out, 3);
+                out_str(out, replica.ip.map_or(String::new(), |ip| ip.to_string()).as_bytes());
+                out_str(out, replica.port.to_string().as_bytes());
+                out_str(out, replication.offset().to_string().as_bytes());
+            }
+        }
+    }
+}
+
+/// `replconf option value [option value ...]`, sent by replicas before
+/// `sync`. Only `listening-port` means anything.
+pub fn replconf(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let id = ctx.client_id;
+    let Some(replication) = ctx.replication.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    if args.len().is_multiple_of(2) {
+        return out_err(out, ErrorCode::Err, "syntax error");
+    }
+    for pair in args[1..].chunks(2) {
+        if pair[0].eq_ignore_ascii_case(b"listening-port") {
+            let Some(port) = parse_arg::<u16>(&pair[1]) else {
+                return out_not_int(out);
+            };
+            replication.announce(id, port);
+        } else {
+            let msg = format!("Unrecognized REPLCONF option: {}", String::from_utf8_lossy(&pair[0]));
+            return out_err(out, ErrorCode::Err, &msg);
+        }
+    }
+    out_ok(out);
+}
+
+/// `sync`, makes the client a replica. Replies with our offset and the
+/// number of frames in the snapshot pushed right after.
+pub fn sync(ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let id = ctx.client_id;
+    let Some(replication) = ctx.replication.as_deref_mut() else {
+        return out_err(out, ErrorCode::Err, UNAVAILABLE_MSG);
+    };
+    if replication.is_replica() {
+        return out_err(out, ErrorCode::Err, "Replicas can't be replicated from");
+    }
+    let ip = ctx.clients.as_deref().and_then(|c| c.get(id)).and_then(|c| c.addr).map(|addr| addr.ip());
+    notice!("Replica {} asks for synchronization", ip.map_or(String::new(), |ip| ip.to_string()));
+    let (offset, frames) = replication.add_replica(id, ip, ctx.dbs);
+    ctx.dbs.iter_mut().for_each(|db| db.set_track_modified(true));
+    out_arr(out, 2);
+    out_int(out, offset as i64);
+    out_int(out, frames as i64);
+}
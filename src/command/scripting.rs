@@ -0,0 +1,78 @@
+use crate::protocol::*;
+use crate::scripting::Scripting;
+
+use super::{parse_arg, Context};
+
+/// How many of the `eval`/`evalsha` arguments after the script are keys,
+/// the rest is argv
+fn parse_numkeys(args: &[Vec<u8>], out: &mut Vec<u8>) -> Option<usize> {
+    let Some(numkeys) = parse_arg::<i64>(&args[2]) else {
+        out_not_int(out);
+        return None;
+    };
+    if numkeys < 0 {
+        out_err(out, ErrorCode::Err, "Number of keys can't be negative");
+        return None;
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > args.len() - 3 {
+        out_err(out, ErrorCode::Err, "Number of keys can't be greater than number of args");
+        return None;
+    }
+    Some(numkeys)
+}
+
+/// Run `f` with the script cache taken out of `ctx`, so that scripts can
+/// still be handed the rest of the context
+fn with_scripting(ctx: &mut Context, out: &mut Vec<u8>, f: impl FnOnce(&mut Scripting, &mut Context, &mut Vec<u8>)) {
+    let Some(scripting) = ctx.scripting.take() else {
+        return out_err(out, ErrorCode::Err, "scripting is not available");
+    };
+    f(scripting, ctx, out);
+    ctx.scripting = Some(scripting);
+}
+
+pub fn eval(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(numkeys) = parse_numkeys(args, out) else {
+        return;
+    };
+    let (keys, argv) = args[3..].split_at(numkeys);
+    with_scripting(ctx, out, |scripting, ctx, out| match scripting.load(&args[1]) {
+        Ok(sha) => scripting.run(ctx, &sha, keys, argv, out),
+        Err(msg) => out_err(out, ErrorCode::Err, &msg),
+    });
+}
+
+pub fn evalsha(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(numkeys) = parse_numkeys(args, out) else {
+        return;
+    };
+    let (keys, argv) = args[3..].split_at(numkeys);
+    let sha = String::from_utf8_lossy(&args[1]);
+    with_scripting(ctx, out, |scripting, ctx, out| scripting.run(ctx, &sha, keys, argv, out));
+}
+
+pub fn script(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("load", 3) => with_scripting(ctx, out, |scripting, _, out| match scripting.load(&args[2]) {
+            Ok(sha) => out_str(out, sha.as_bytes()),
+            Err(msg) => out_err(out, ErrorCode::Err, &msg),
+        }),
+        ("exists", n) if n > 2 => with_scripting(ctx, out, |scripting, _, out| {
+            out_arr(out, n - 2);
+            for sha in &args[2..] {
+                out_int(out, scripting.exists(&String::from_utf8_lossy(sha)) as i64);
+            }
+        }),
+        ("flush", 2) => with_scripting(ctx, out, |scripting, _, out| {
+            scripting.flush();
+            out_ok(out);
+        }),
+        // While a script runs this is answered by the executor, so getting
+        // here means nothing is running
+        ("kill", 2) => out_err(out, ErrorCode::NotBusy, "No scripts in execution right now."),
+        ("load" | "exists" | "flush" | "kill", _) => out_wrong_arity(out, "script"),
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1]))),
+    }
+}
@@ -0,0 +1,482 @@
+use std::fmt::Write;
+
+use crate::database::eviction::EvictionPolicy;
+use crate::database::notify;
+use crate::database::value::EncodingLimits;
+use crate::database::Database;
+use crate::log::{self, Level};
+use crate::protocol::*;
+use crate::server::slowlog::{self, SlowLog};
+use crate::server::{parse_memory, Stats};
+
+use super::{parse_arg, Command, Context, COMMANDS};
+
+/// Parameters known to `config get` and `config set`
+pub(super) const CONFIG_PARAMS: [&str; 15] = [
+    "hash-max-listpack-entries",
+    "hash-max-listpack-value",
+    "hll-sparse-max-bytes",
+    "list-max-listpack-size",
+    "loglevel",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "notify-keyspace-events",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+];
+
+/// Sections of `info` without arguments, `all` adds commandstats
+const DEFAULT_SECTIONS: [&str; 8] =
+    ["server", "clients", "memory", "persistence", "stats", "replication", "cluster", "keyspace"];
+
+pub fn ping(_ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    match args.len() {
+        1 => out_str(out, b"PONG"),
+        2 => out_str(out, &args[1]),
+        _ => out_wrong_arity(out, "ping"),
+    }
+}
+
+/// The connection is closed by the caller once the reply is sent
+pub fn quit(_ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    out_ok(out);
+}
+
+fn out_command_info(out: &mut Vec<u8>, cmd: &Command) {
+    out_arr(out, 6);
+    out_str(out, cmd.name.as_bytes());
+    out_int(out, cmd.arity as i64);
+    let flags = cmd.flag_names();
+    out_arr(out, flags.len());
+    for flag in flags {
+        out_str(out, flag.as_bytes());
+    }
+    out_int(out, cmd.first_key as i64);
+    out_int(out, cmd.last_key as i64);
+    out_int(out, cmd.key_step as i64);
+}
+
+fn out_command_docs(out: &mut Vec<u8>, cmd: &Command) {
+    out_str(out, cmd.name.as_bytes());
+    out_arr(out, 6);
+    out_str(out, b"summary");
+    out_str(out, cmd.summary.as_bytes());
+    out_str(out, b"group");
+    out_str(out, cmd.group.as_bytes());
+    out_str(out, b"arity");
+    out_int(out, cmd.arity as i64);
+}
+
+/// `command`, `command count`, `command info [name ...]` and
+/// `command docs [name ...]`
+pub fn command(_ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    if args.len() == 1 {
+        out_arr(out, COMMANDS.len());
+        for cmd in COMMANDS {
+            out_command_info(out, cmd);
+        }
+        return;
+    }
+
+    let names = args[2..].iter().map(|name| String::from_utf8_lossy(name)).collect::<Vec<_>>();
+    match String::from_utf8_lossy(&args[1]).to_ascii_lowercase().as_str() {
+        "count" if args.len() == 2 => out_int(out, COMMANDS.len() as i64),
+        "info" if names.is_empty() => {
+            out_arr(out, COMMANDS.len());
+            for cmd in COMMANDS {
+                out_command_info(out, cmd);
+            }
+        }
+        "info" => {
+            out_arr(out, names.len());
+            for name in &names {
+                match super::lookup(name) {
+                    Some(cmd) => out_command_info(out, cmd),
+                    None => out_nil(out),
+                }
+            }
+        }
+        "docs" => {
+            let cmds = if names.is_empty() {
+                COMMANDS.iter().collect::<Vec<&Command>>()
+            } else {
+                names.iter().filter_map(|n| super::lookup(n)).collect()
+            };
+            out_arr(out, cmds.len() * 2);
+            for cmd in cmds {
+                out_command_docs(out, cmd);
+            }
+        }
+        "count" => out_wrong_arity(out, "command|count"),
+        sub => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", sub)),
+    }
+}
+
+/// Bytes the way Redis shows them, like `1.50M`
+fn bytes_human(n: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if n < 1024 {
+        return format!("{}B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
+
+fn info_section(ctx: &Context, stats: &Stats, section: &str, info: &mut String) {
+    // Writing to a String can't fail
+    let _ = match section {
+        "server" => {
+            let uptime = stats.started.elapsed().as_secs();
+            write!(
+                info,
+                "# Server\r\nredis_version:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\n\
+                 uptime_in_seconds:{}\r\nuptime_in_days:{}\r\nio_threads_active:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id(),
+                stats.tcp_port,
+                uptime,
+                uptime / 86400,
+                stats.io_threads,
+            )
+        }
+        "clients" => write!(
+            info,
+            "# Clients\r\nconnected_clients:{}\r\n",
+            Stats::get(&stats.connected_clients),
+        ),
+        "memory" => {
+            let used = ctx.dbs.iter().map(Database::used_memory).sum::<usize>();
+            // The memory settings are the same for all databases
+            let settings = &ctx.dbs[0];
+            write!(
+                info,
+                "# Memory\r\nused_memory:{}\r\nused_memory_human:{}\r\nmaxmemory:{}\r\n\
+                 maxmemory_human:{}\r\nmaxmemory_policy:{}\r\nnumber_of_cached_scripts:{}\r\n",
+                used,
+                bytes_human(used),
+                settings.maxmemory(),
+                bytes_human(settings.maxmemory()),
+                settings.eviction_policy().as_str(),
+                ctx.scripting.as_ref().map_or(0, |s| s.len()),
+            )
+        }
+        // Nothing is persisted yet
+        "persistence" => write!(
+            info,
+            "# Persistence\r\nloading:0\r\nrdb_enabled:0\r\naof_enabled:0\r\n",
+        ),
+        "stats" => write!(
+            info,
+            "# Stats\r\ntotal_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+             total_net_input_bytes:{}\r\ntotal_net_output_bytes:{}\r\n\
+             expired_keys:{}\r\nevicted_keys:{}\r\n",
+            Stats::get(&stats.total_connections),
+            Stats::get(&stats.commands_processed),
+            Stats::get(&stats.net_input_bytes),
+            Stats::get(&stats.net_output_bytes),
+            ctx.dbs.iter().map(Database::expired_keys).sum::<u64>(),
+            ctx.dbs.iter().map(Database::evicted_keys).sum::<u64>(),
+        ),
+        "commandstats" => {
+            info.push_str("# Commandstats\r\n");
+            for (name, cmd) in stats.command_stats() {
+                let _ = write!(
+                    info,
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                    name,
+                    cmd.calls,
+                    cmd.usec,
+                    cmd.usec as f64 / cmd.calls as f64,
+                );
+            }
+            Ok(())
+        }
+        "replication" => {
+            info.push_str("# Replication\r\n");
+            let replication = ctx.replication.as_deref();
+            match replication.and_then(|r| r.primary()) {
+                Some(primary) => {
+                    let _ = write!(
+                        info,
+                        "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n\
+                         slave_repl_offset:{}\r\n",
+                        primary.host,
+                        primary.port,
+                        if primary.up { "up" } else { "down" },
+                        replication.map_or(0, |r| r.offset()),
+                    );
+                }
+                None => {
+                    let _ = write!(info, "role:master\r\nconnected_slaves:{}\r\n", replication.map_or(0, |r| r.replicas().count()));
+                    for (i, replica) in replication.into_iter().flat_map(|r| r.replicas()).enumerate() {
+                        let ip = replica.ip.map_or(String::new(), |ip| ip.to_string());
+                        let _ = write!(info, "slave{}:ip={},port={},state=online\r\n", i, ip, replica.port);
+                    }
+                }
+            }
+            write!(info, "master_repl_offset:{}\r\n", replication.map_or(0, |r| r.offset()))
+        }
+        "cluster" => write!(info, "# Cluster\r\ncluster_enabled:{}\r\n", ctx.cluster.is_some() as u8),
+        "keyspace" => {
+            info.push_str("# Keyspace\r\n");
+            for (i, db) in ctx.dbs.iter().enumerate().filter(|(_, db)| !db.is_empty()) {
+                let _ = write!(info, "db{}:keys={},expires={}\r\n", i, db.len(), db.volatile_len());
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+}
+
+/// `info [section ...]`, sections are `# Name` headers followed by
+/// `field:value` lines
+pub fn info(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let mut sections = vec![];
+    for arg in &args[1..] {
+        match String::from_utf8_lossy(arg).to_ascii_lowercase().as_str() {
+            "default" => sections.extend(DEFAULT_SECTIONS.map(String::from)),
+            "all" | "everything" => {
+                sections.extend(DEFAULT_SECTIONS[..6].iter().map(|s| s.to_string()));
+                sections.push("commandstats".to_string());
+                sections.extend(DEFAULT_SECTIONS[6..].iter().map(|s| s.to_string()));
+            }
+            section => sections.push(section.to_string()),
+        }
+    }
+    if args.len() == 1 {
+        sections.extend(DEFAULT_SECTIONS.map(String::from));
+    }
+
+    // Counters start at zero when not running in a server
+    let fallback;
+    let stats = match ctx.stats {
+        Some(stats) => stats,
+        None => {
+            fallback = Stats::new(0, 0);
+            &fallback
+        }
+    };
+
+    let mut info = String::new();
+    for section in &sections {
+        let mut text = String::new();
+        info_section(ctx, stats, section, &mut text);
+        if text.is_empty() {
+            continue;
+        }
+        if !info.is_empty() {
+            info.push_str("\r\n");
+        }
+        info.push_str(&text);
+    }
+    out_str(out, info.as_bytes());
+}
+
+fn config_get(ctx: &Context, param: &str) -> String {
+    match param {
+        "loglevel" => log::level().to_string(),
+        "maxmemory" => ctx.dbs[0].maxmemory().to_string(),
+        "maxmemory-policy" => ctx.dbs[0].eviction_policy().as_str().to_string(),
+        "maxmemory-samples" => ctx.dbs[0].maxmemory_samples().to_string(),
+        "notify-keyspace-events" => notify::flags_to_string(ctx.dbs[0].notify_keyspace_events()),
+        "slowlog-log-slower-than" => ctx
+            .slowlog
+            .as_ref()
+            .map_or(slowlog::DEFAULT_SLOWER_THAN, |log| log.slower_than())
+            .to_string(),
+        "slowlog-max-len" => ctx
+            .slowlog
+            .as_ref()
+            .map_or(slowlog::DEFAULT_MAX_LEN, |log| log.max_len())
+            .to_string(),
+        _ => match ctx.dbs[0].encoding_limits().get(param) {
+            Some(n) => n.to_string(),
+            None => unreachable!("not in CONFIG_PARAMS"),
+        },
+    }
+}
+
+/// Returns false if `value` isn't valid for `param`
+fn config_set(ctx: &mut Context, param: &str, value: &str) -> bool {
+    match param {
+        "loglevel" => value.parse::<Level>().map(log::set_level).is_ok(),
+        "maxmemory" => parse_memory(value)
+            .map(|n| ctx.dbs.iter_mut().for_each(|db| db.set_maxmemory(n)))
+            .is_some(),
+        "maxmemory-policy" => value
+            .parse::<EvictionPolicy>()
+            .map(|p| ctx.dbs.iter_mut().for_each(|db| db.set_eviction_policy(p)))
+            .is_ok(),
+        "maxmemory-samples" => match value.parse::<usize>() {
+            Ok(n) if n > 0 => {
+                ctx.dbs.iter_mut().for_each(|db| db.set_maxmemory_samples(n));
+                true
+            }
+            _ => false,
+        },
+        "notify-keyspace-events" => notify::parse_flags(value)
+            .map(|flags| ctx.dbs.iter_mut().for_each(|db| db.set_notify_keyspace_events(flags)))
+            .is_some(),
+        "slowlog-log-slower-than" => value
+            .parse::<i64>()
+            .map(|micros| ctx.slowlog.as_deref_mut().map(|log| log.set_slower_than(micros)))
+            .is_ok(),
+        "slowlog-max-len" => value
+            .parse::<usize>()
+            .map(|len| ctx.slowlog.as_deref_mut().map(|log| log.set_max_len(len)))
+            .is_ok(),
+        _ if EncodingLimits::PARAMS.contains(&param) => match value.parse::<usize>() {
+            Ok(n) => {
+                let mut limits = *ctx.dbs[0].encoding_limits();
+                limits.set(param, n);
+                ctx.dbs.iter_mut().for_each(|db| db.set_encoding_limits(limits));
+                true
+            }
+            Err(_) => false,
+        },
+        _ => unreachable!("not in CONFIG_PARAMS"),
+    }
+}
+
+/// `config get <param>` and `config set <param> <value>`, `*` gets every
+/// parameter
+pub fn config(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    // Parameters and their values are text
+    let args = args.iter().map(|arg| String::from_utf8_lossy(arg)).collect::<Vec<_>>();
+    let sub = args[1].to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("get", 3) => {
+            let pattern = args[2].to_ascii_lowercase();
+            let params = CONFIG_PARAMS
+                .iter()
+                .filter(|p| pattern == "*" || **p == pattern)
+                .collect::<Vec<_>>();
+            out_arr(out, params.len() * 2);
+            for param in params {
+                out_str(out, param.as_bytes());
+                out_str(out, config_get(ctx, param).as_bytes());
+            }
+        }
+        ("set", 4) => {
+            let param = args[2].to_ascii_lowercase();
+            if !CONFIG_PARAMS.contains(&param.as_str()) {
+                let msg = format!("Unknown option or number of arguments for CONFIG SET - '{}'", args[2]);
+                return out_err(out, ErrorCode::Err, &msg);
+            }
+            if !config_set(ctx, &param, &args[3]) {
+                let msg = format!("Invalid argument '{}' for CONFIG SET '{}'", args[3], args[2]);
+                return out_err(out, ErrorCode::Err, &msg);
+            }
+            out_ok(out);
+        }
+        ("get" | "set", _) => out_wrong_arity(out, &format!("config|{}", sub)),
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", args[1])),
+    }
+}
+
+fn out_slowlog_entry(out: &mut Vec<u8>, entry: &slowlog::SlowLogEntry) {
+    out_arr(out, 6);
+    out_int(out, entry.id as i64);
+    out_int(out, entry.timestamp as i64);
+    out_int(out, entry.duration.as_micros() as i64);
+    out_arr(out, entry.args.len());
+    for arg in &entry.args {
+        out_str(out, arg);
+    }
+    out_str(out, entry.addr.map_or(String::new(), |a| a.to_string()).as_bytes());
+    out_str(out, entry.client_name.as_bytes());
+}
+
+/// `slowlog get [count]`, `slowlog len` and `slowlog reset`
+pub fn slowlog(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    // Nothing is logged when not running in a server
+    let mut empty = SlowLog::default();
+    let log = match ctx.slowlog.as_deref_mut() {
+        Some(log) => log,
+        None => &mut empty,
+    };
+
+    let sub = String::from_utf8_lossy(&args[1]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("get", 2 | 3) => {
+            let count = match args.get(2).map(|n| parse_arg::<i64>(n)) {
+                None => 10,
+                Some(Some(-1)) => log.len(),
+                Some(Some(n)) if n >= 0 => n as usize,
+                Some(_) => return out_err(out, ErrorCode::Err, "count should be greater than or equal to -1"),
+            };
+            let entries = log.newest(count).collect::<Vec<_>>();
+            out_arr(out, entries.len());
+            for entry in entries {
+                out_slowlog_entry(out, entry);
+            }
+        }
+        ("len", 2) => out_int(out, log.len() as i64),
+        ("reset", 2) => {
+            log.reset();
+            out_ok(out);
+        }
+        ("get" | "len" | "reset", _) => out_wrong_arity(out, &format!("slowlog|{}", sub)),
+        _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", String::from_utf8_lossy(&args[1]))),
+    }
+}
+
+/// `dbsize`, number of keys in the selected database
+pub fn dbsize(ctx: &mut Context, _args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    out_int(out, ctx.db().len() as i64);
+}
+
+/// Whether `flushdb` and `flushall` free on a background thread
+fn parse_flush_mode(args: &[Vec<u8>]) -> Option<bool> {
+    match args.get(1).map(|arg| arg.to_ascii_lowercase()).as_deref() {
+        None | Some(b"sync") => Some(false),
+        Some(b"async") => Some(true),
+        Some(_) => None,
+    }
+}
+
+/// `flushdb [async|sync]`
+pub fn flushdb(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(lazy) = parse_flush_mode(args) else {
+        return out_err(out, ErrorCode::Err, "syntax error");
+    };
+    ctx.db().clear(lazy);
+    out_ok(out);
+}
+
+/// `flushall [async|sync]`
+pub fn flushall(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(lazy) = parse_flush_mode(args) else {
+        return out_err(out, ErrorCode::Err, "syntax error");
+    };
+    for db in ctx.dbs.iter_mut() {
+        db.clear(lazy);
+    }
+    out_ok(out);
+}
+
+/// `swapdb index1 index2`, clients using one database see the other's keys
+/// from now on
+pub fn swapdb(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    let Some(a) = ctx.parse_db_index(&args[1], out) else {
+        return;
+    };
+    let Some(b) = ctx.parse_db_index(&args[2], out) else {
+        return;
+    };
+    ctx.dbs.swap(a, b);
+    if let Some(replication) = ctx.replication.as_deref_mut() {
+        replication.propagate(&["swapdb", &a.to_string(), &b.to_string()]);
+    }
+    out_ok(out);
+}
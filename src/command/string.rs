@@ -0,0 +1,27 @@
+use crate::database::notify::NOTIFY_STRING;
+use crate::debug;
+use crate::protocol::*;
+use crate::ResponseStatus;
+
+use super::{Context, OOM_MSG, WRONGTYPE_MSG};
+
+pub fn get(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    debug!("COMMAND: get {}", String::from_utf8_lossy(&args[1]));
+    let mut value = vec![];
+    match ctx.db().get(&args[1], &mut value) {
+        ResponseStatus::Ok => out_str(out, &value),
+        ResponseStatus::Err => out_err(out, ErrorCode::WrongType, WRONGTYPE_MSG),
+        _ => out_nil(out),
+    }
+}
+
+pub fn set(ctx: &mut Context, args: &mut [Vec<u8>], out: &mut Vec<u8>) {
+    debug!("COMMAND: set {}={}", String::from_utf8_lossy(&args[1]), String::from_utf8_lossy(&args[2]));
+    match ctx.db().set(args[1].clone(), std::mem::take(&mut args[2])) {
+        ResponseStatus::Err => out_err(out, ErrorCode::Oom, OOM_MSG),
+        _ => {
+            ctx.db().notify(NOTIFY_STRING, "set", &args[1]);
+            out_ok(out);
+        }
+    }
+}
@@ -1,9 +1,17 @@
-use std::{io::{Read, Write}, net::{TcpStream}};
+use std::{io::{Read, Write}, net::SocketAddr};
+use crate::command::{self, Context};
+use crate::tls::{ServerConnection, Stream};
+use crate::{debug, verbose};
 use crate::database::Database;
 
-use super::ResponseStatus;
+/// Largest request body accepted, like the largest reply a client reads
+pub const MAX_MSG: usize = 512 * 1024 * 1024;
 
-pub const MAX_MSG: usize = 4096usize;
+/// Size the read buffer starts at, it grows for larger requests
+const INITIAL_RBUF: usize = 4 + 4096;
+
+/// Stop reading from a client once this many bytes of replies are queued
+const MAX_PENDING_OUTPUT: usize = 64 * INITIAL_RBUF;
 
 #[derive(PartialEq)]
 pub enum ConnectionState {
@@ -12,30 +20,57 @@ pub enum ConnectionState {
     StateEnd
 }
 
+/// The I/O side of a client: reads and frames requests, queues and writes
+/// replies. Commands are executed elsewhere.
 pub struct Connection {
-    pub fd: TcpStream,
+    pub id: u64,
+    pub stream: Stream<ServerConnection>,
+    pub addr: Option<SocketAddr>,
     pub state: ConnectionState,
     pub rbuf_size: usize,
-    pub rbuf: [u8; 4 + MAX_MSG],
-    pub wbuf_size: usize,
+    pub rbuf: Vec<u8>,
     pub wbuf_sent: usize,
-    pub wbuf: [u8; 4 + MAX_MSG],
-    pub database: Database,
+    pub wbuf: Vec<u8>,
+    /// Hang up once the queued replies are written
+    pub closing: bool,
+    /// Bytes read from and written to the socket so far
+    pub net_input_bytes: u64,
+    pub net_output_bytes: u64,
 }
 
 impl Connection {
-    pub fn state_req(&mut self) {
-        while self.try_fill_buffer() {
-            println!("state_req looping");
+    pub fn new(id: u64, stream: Stream<ServerConnection>) -> Connection {
+        Connection {
+            id,
+            addr: stream.tcp().peer_addr().ok(),
+            stream,
+            state: ConnectionState::StateReq,
+            rbuf_size: 0,
+            rbuf: vec![0; INITIAL_RBUF],
+            wbuf_sent: 0,
+            wbuf: Vec::new(),
+            closing: false,
+            net_input_bytes: 0,
+            net_output_bytes: 0,
         }
     }
 
-    fn try_fill_buffer(&mut self) -> bool {
+    /// Read what's available and append all complete requests to `requests`
+    pub fn state_req(&mut self, requests: &mut Vec<Vec<Vec<u8>>>) {
+        while !self.closing && self.wbuf.len() < MAX_PENDING_OUTPUT && self.try_fill_buffer(requests) {}
+    }
+
+    fn try_fill_buffer(&mut self, requests: &mut Vec<Vec<Vec<u8>>>) -> bool {
+        // A full buffer holds the start of a request larger than it
+        if self.rbuf_size == self.rbuf.len() {
+            let size = (self.rbuf.len() * 2).min(4 + MAX_MSG);
+            self.rbuf.resize(size, 0);
+        }
         assert!(self.rbuf_size < self.rbuf.len());
 
-        let mut rv = 0usize;
+        let rv;
         loop {
-            match self.fd.read(&mut self.rbuf[self.rbuf_size..]) {
+            match self.stream.read(&mut self.rbuf[self.rbuf_size..]) {
                 Ok(n) => {
                     rv = n;
                     break;
@@ -44,8 +79,8 @@ impl Connection {
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     return false;
                 },
-                Err(_) => {
-                    eprintln!("read() error");
+                Err(e) => {
+                    verbose!("Reading from client {}: {}", self.id, e);
                     self.state = ConnectionState::StateEnd;
                     return false;
                 }
@@ -54,81 +89,87 @@ impl Connection {
 
         if rv == 0 {
             if self.rbuf_size > 0 {
-                eprintln!("Unexpected EOF");
+                verbose!("Client {} closed the connection mid-request", self.id);
             } else {
-                eprintln!("EOF");
+                verbose!("Client {} closed the connection", self.id);
             }
             self.state = ConnectionState::StateEnd;
             return false;
         }
 
         self.rbuf_size += rv;
+        self.net_input_bytes += rv as u64;
         assert!(self.rbuf_size <= self.rbuf.len());
 
-        println!("Received {} bytes", rv);
+        debug!("Received {} bytes from client {}", rv, self.id);
 
-        while self.try_one_request() {}
+        while self.try_one_request(requests) {}
 
-        self.state == ConnectionState::StateReq
+        self.state != ConnectionState::StateEnd
     }
 
-    fn try_one_request(&mut self) -> bool {
+    fn try_one_request(&mut self, requests: &mut Vec<Vec<Vec<u8>>>) -> bool {
         if self.rbuf_size < 4 {
             return false;
         }
 
         let len = u32::from_le_bytes(self.rbuf[0..4].try_into().expect("need 4-byte array")) as usize;
         if len > MAX_MSG {
-            eprintln!("Message too long");
+            verbose!("Client {} sent a request over {} bytes", self.id, MAX_MSG);
             self.state = ConnectionState::StateEnd;
             return false;
         }
         if 4 + len > self.rbuf_size {
-            println!("Not enough data yet, need {}, have {}", 4 + len, self.rbuf_size);
+            debug!("Not enough data yet, need {}, have {}", 4 + len, self.rbuf_size);
             return false;
         }
 
-        let msg = String::from_utf8_lossy(&self.rbuf[4..4+len]);
-        println!("Client says: {}", msg);
-
-        // self.wbuf[..4].copy_from_slice((len as u32).to_le_bytes().as_slice());
-        // self.wbuf[4..4 + len].copy_from_slice(&self.rbuf[4..4 + len]);
-        // self.wbuf_size = 4 + len;
+        debug!("Client {} says: {}", self.id, String::from_utf8_lossy(&self.rbuf[4..4 + len]));
 
-        let mut res_len = 0usize;
-        let status = Connection::do_request(&mut self.database, &self.rbuf[4..], len, &mut self.wbuf[4 + 4..], &mut res_len);
-        if status == ResponseStatus::Err {
-            eprintln!("Error processing request");
-            self.state = ConnectionState::StateEnd;
-            return false;
+        match Connection::parse_req(&self.rbuf[4..], len) {
+            Some(args) => requests.push(args),
+            None => {
+                verbose!("Client {} sent a malformed request", self.id);
+                self.state = ConnectionState::StateEnd;
+                return false;
+            }
         }
-        res_len += 4;
-        self.wbuf[0..4].copy_from_slice(&(res_len as u32).to_le_bytes());
-        self.wbuf[4..8].copy_from_slice(&(status as u32).to_le_bytes());
-        self.wbuf_size = res_len + 4;
 
         let remain = self.rbuf_size - 4 - len;
         if remain > 0 {
-            self.rbuf.copy_within(4 + len.., 0);
+            self.rbuf.copy_within(4 + len..4 + len + remain, 0);
         }
         self.rbuf_size = remain;
-        self.state = ConnectionState::StateRes;
-        self.state_res();
+        // Give back the room a large request took
+        if self.rbuf.len() > INITIAL_RBUF && remain <= INITIAL_RBUF {
+            self.rbuf.truncate(INITIAL_RBUF);
+            self.rbuf.shrink_to_fit();
+        }
 
-        self.state == ConnectionState::StateReq
+        true
     }
 
-    pub fn state_res(&mut self) {
-        while self.try_flush_buffer() {
-            println!("state_res looping");
+    /// Queue a reply frame with the serialized `body` for writing
+    pub fn push_reply(&mut self, body: &[u8]) {
+        self.wbuf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        self.wbuf.extend_from_slice(body);
+        if self.state == ConnectionState::StateReq {
+            self.state = ConnectionState::StateRes;
         }
     }
 
+    pub fn state_res(&mut self) {
+        while self.try_flush_buffer() {}
+    }
+
     fn try_flush_buffer(&mut self) -> bool {
-        let mut rv = 0usize;
+        if self.wbuf_sent == self.wbuf.len() {
+            return self.try_flush_stream();
+        }
+
+        let rv;
         loop {
-            let remain = self.wbuf_size - self.wbuf_sent;
-            match self.fd.write(&mut self.wbuf[self.wbuf_sent..self.wbuf_sent+remain]) {
+            match self.stream.write(&self.wbuf[self.wbuf_sent..]) {
                 Ok(n) => {
                     rv = n;
                     break;
@@ -137,8 +178,8 @@ impl Connection {
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     return false;
                 },
-                Err(_) => {
-                    eprintln!("write() error");
+                Err(e) => {
+                    verbose!("Writing to client {}: {}", self.id, e);
                     self.state = ConnectionState::StateEnd;
                     return false;
                 }
@@ -146,19 +187,41 @@ impl Connection {
         }
 
         self.wbuf_sent += rv;
-        assert!(self.wbuf_sent <= self.wbuf_size);
+        self.net_output_bytes += rv as u64;
+        assert!(self.wbuf_sent <= self.wbuf.len());
 
-        if self.wbuf_sent == self.wbuf_size {
-            self.state = ConnectionState::StateReq;
+        if self.wbuf_sent == self.wbuf.len() {
             self.wbuf_sent = 0;
-            self.wbuf_size = 0;
-            return false;
+            self.wbuf.clear();
+            return self.try_flush_stream();
         }
 
         true
     }
 
-    fn parse_req(data: &[u8], len: usize) -> Option<Vec<String>> {
+    /// Get out what TLS still holds on to, then go back to reading
+    fn try_flush_stream(&mut self) -> bool {
+        match self.stream.flush() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => return true,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                verbose!("Writing to client {}: {}", self.id, e);
+                self.state = ConnectionState::StateEnd;
+                return false;
+            }
+        }
+        self.state = if self.closing {
+            ConnectionState::StateEnd
+        } else {
+            ConnectionState::StateReq
+        };
+        false
+    }
+
+    /// The arguments of the `len` byte request body in `data`, `None` if
+    /// it's malformed
+    pub fn parse_req(data: &[u8], len: usize) -> Option<Vec<Vec<u8>>> {
         if len < 4 {
             return None;
         }
@@ -177,9 +240,7 @@ impl Connection {
             if len_arg + 4 + pos > len {
                 return None;
             }
-            let arg = String::from_utf8_lossy(&data[pos+4..pos+4+len_arg]);
-
-            ret.push(arg.to_string());
+            ret.push(data[pos+4..pos+4+len_arg].to_vec());
 
             num_commands -= 1;
             pos += 4 + len_arg;
@@ -192,60 +253,22 @@ impl Connection {
         Some(ret)
     }
 
-    fn do_request(database: &mut Database, data: &[u8], len: usize, res_buf: &mut [u8], res_len: &mut usize) -> ResponseStatus {
+    /// Parse and run a request, `false` if it isn't well-formed
+    pub fn do_request(database: &mut Database, data: &[u8], len: usize, out: &mut Vec<u8>) -> bool {
         let args = Connection::parse_req(data, len);
         if args.is_none() {
-            eprintln!("bad request");
-            return ResponseStatus::Err;
-        }
-        let mut args = args.unwrap();
-
-        assert!(args.len() > 1);
-
-        match args.first().unwrap().as_str() {
-            "get" => {
-                if args.len() != 2 {
-                    eprintln!("Invalid number of arguments for get command");
-                    return ResponseStatus::Err;
-                }
-                println!("COMMAND: get {}", args[1]);
-                let mut value = String::new();
-                let ret = database.get(&args[1], &mut value);
-                let bytes = value.as_bytes();
-                res_buf[..bytes.len()].copy_from_slice(bytes);
-                *res_len = bytes.len(); 
-                return ret;
-            },
-            "set" => {
-                if args.len() != 3 {
-                    eprintln!("Invalid number of arguments for set command");
-                    return ResponseStatus::Err;
-                }
-                println!("COMMAND: set {}={}", args[1], args[2]);
-                let ret = database.set(std::mem::take(&mut args[1]), std::mem::take(&mut args[2]));
-                return ret;
-            },
-            "del" => {
-                if args.len() != 2 {
-                    eprintln!("Invalid number of arguments for del command");
-                    return ResponseStatus::Err;
-                }
-                println!("COMMAND: del {}", args[1]);
-                let ret = database.del(&args[1]);
-                return ret;
-            },
-            x => {
-                eprintln!("Unknown command: {}", x);
-            }
+            verbose!("Malformed request");
+            return false;
         }
-
-        ResponseStatus::Err
+        command::execute(&mut Context::new(database), args.unwrap(), out);
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::Reply;
 
     #[test]
     fn test_parse_req() {
@@ -271,8 +294,8 @@ mod tests {
         assert!(res.is_some()); 
         let res = res.unwrap();
         assert_eq!(res.len(), 2);
-        assert_eq!(res[0], "hello");
-        assert_eq!(res[1], "worlds");
+        assert_eq!(res[0], b"hello");
+        assert_eq!(res[1], b"worlds");
     }
 
     #[test]
@@ -281,9 +304,7 @@ mod tests {
         buf.resize(50, u8::default());
 
         // For the resonse
-        let mut res_buf = Vec::<u8>::new();
-        res_buf.resize(MAX_MSG + 4, u8::default());
-        let mut res_len = 0usize;
+        let mut out = Vec::<u8>::new();
 
 
         let mut database = Database::new();
@@ -311,8 +332,8 @@ mod tests {
         buf[start3..start3+4].copy_from_slice(&len_arg3.to_le_bytes());
         buf[start3+4..start3+4+arg3.len()].copy_from_slice(arg3);
 
-        let status = Connection::do_request(&mut database, &buf, start3 + 4 + arg3.len(), &mut res_buf, &mut res_len);
-        assert_eq!(status, ResponseStatus::Ok);
+        assert!(Connection::do_request(&mut database, &buf, start3 + 4 + arg3.len(), &mut out));
+        assert_eq!(Reply::decode(&out).unwrap(), Reply::Str(b"OK".to_vec()));
 
         //////////////////////////////////////////////////////////////////////
         let num_args = 2u32.to_le_bytes();
@@ -329,10 +350,9 @@ mod tests {
         buf[start2..start2+4].copy_from_slice(&len_arg2.to_le_bytes());
         buf[start2+4..start2+4+arg2.len()].copy_from_slice(arg2);
 
-        let status = Connection::do_request(&mut database, &buf, start2 + 4 + arg2.len(), &mut res_buf, &mut res_len);
-        let res = String::from_utf8_lossy(&res_buf[..res_len]);
-        assert_eq!(res, "world");
-        assert_eq!(status, ResponseStatus::Ok);
+        out.clear();
+        assert!(Connection::do_request(&mut database, &buf, start2 + 4 + arg2.len(), &mut out));
+        assert_eq!(Reply::decode(&out).unwrap(), Reply::Str(b"world".to_vec()));
         //////////////////////////////////////////////////////////////////////
 
 
@@ -350,8 +370,9 @@ mod tests {
         buf[start2..start2+4].copy_from_slice(&len_arg2.to_le_bytes());
         buf[start2+4..start2+4+arg2.len()].copy_from_slice(arg2);
 
-        let status = Connection::do_request(&mut database, &buf, start2 + 4 + arg2.len(), &mut res_buf, &mut res_len);
-        assert_eq!(status, ResponseStatus::Ok);
+        out.clear();
+        assert!(Connection::do_request(&mut database, &buf, start2 + 4 + arg2.len(), &mut out));
+        assert_eq!(Reply::decode(&out).unwrap(), Reply::Int(1));
         //////////////////////////////////////////////////////////////////////
 
         let arg1 = "unknown".as_bytes();
@@ -359,7 +380,50 @@ mod tests {
         buf[4..8].copy_from_slice(&len_arg1.to_le_bytes());
         buf[8..8+arg1.len()].copy_from_slice(arg1);
 
-        let status = Connection::do_request(&mut database, &buf, 8 + arg1.len(), &mut res_buf, &mut res_len);
-        assert_eq!(status, ResponseStatus::Err);
+        // Two arguments announced, only one present
+        out.clear();
+        assert!(!Connection::do_request(&mut database, &buf, 8 + arg1.len(), &mut out));
+    }
+
+    fn build_req(args: &[&str]) -> Vec<u8> {
+        let mut buf = (args.len() as u32).to_le_bytes().to_vec();
+        for arg in args {
+            buf.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+            buf.extend_from_slice(arg.as_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_do_request_oom() {
+        let mut out = Vec::<u8>::new();
+
+        let mut database = Database::new();
+        database.set_maxmemory(1);
+
+        let req = build_req(&["set", "hello", "world"]);
+        assert!(Connection::do_request(&mut database, &req, req.len(), &mut out));
+        assert!(matches!(Reply::decode(&out).unwrap(), Reply::Error { code, .. } if code == "OOM"));
+        assert!(database.is_empty());
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let mut database = Database::new();
+        let mut out = Vec::<u8>::new();
+
+        for (args, message) in [
+            (vec!["bogus", "x"], "unknown command 'bogus'"),
+            (vec!["get"], "wrong number of arguments for 'get' command"),
+            (vec!["expire", "k", "soon"], "value is not an integer or out of range"),
+        ] {
+            let req = build_req(&args);
+            out.clear();
+            assert!(Connection::do_request(&mut database, &req, req.len(), &mut out));
+            assert_eq!(Reply::decode(&out).unwrap(), Reply::Error {
+                code: "ERR".to_string(),
+                message: message.to_string(),
+            });
+        }
     }
 }
@@ -0,0 +1,168 @@
+//! Serialized values, as sent by `migrate` to the node taking over a key.
+//! A payload is a type byte followed by the string, or by the number of
+//! elements and the elements, each a u32 length and the bytes. Sorted set
+//! members are followed by their score as an f64. Integers are little
+//! endian. Payloads travel as hex since request arguments are strings.
+
+use super::value::{EncodingLimits, Hash, List, Set, Value, Zset};
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub fn serialize(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    match value {
+        Value::Int(_) | Value::Str(_) => {
+            out.push(TYPE_STRING);
+            put_bytes(&mut out, &value.as_bytes().unwrap());
+        }
+        Value::List(list) => {
+            out.push(TYPE_LIST);
+            out.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            list.iter().for_each(|item| put_bytes(&mut out, &item));
+        }
+        Value::Hash(hash) => {
+            out.push(TYPE_HASH);
+            out.extend_from_slice(&(hash.len() as u32).to_le_bytes());
+            for (field, value) in hash.iter() {
+                put_bytes(&mut out, &field);
+                put_bytes(&mut out, &value);
+            }
+        }
+        Value::Set(set) => {
+            out.push(TYPE_SET);
+            out.extend_from_slice(&(set.len() as u32).to_le_bytes());
+            set.iter().for_each(|member| put_bytes(&mut out, &member));
+        }
+        Value::Zset(zset) => {
+            out.push(TYPE_ZSET);
+            out.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+            for (member, score) in zset.iter() {
+                put_bytes(&mut out, &member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn len(&mut self) -> Option<usize> {
+        self.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+}
+
+/// The value of a `serialize`d payload, encoded within `limits`. `None` if
+/// the payload is malformed.
+pub fn deserialize(payload: &[u8], limits: &EncodingLimits) -> Option<Value> {
+    let mut reader = Reader { buf: payload.get(1..)? };
+    let value = match payload[0] {
+        TYPE_STRING => Value::from_bytes(reader.bytes()?.to_vec()),
+        TYPE_LIST => {
+            let mut list = List::default();
+            for _ in 0..reader.len()? {
+                list.push(reader.bytes()?, false, limits);
+            }
+            Value::List(list)
+        }
+        TYPE_HASH => {
+            let mut hash = Hash::default();
+            for _ in 0..reader.len()? {
+                let field = reader.bytes()?;
+                hash.insert(field, reader.bytes()?, limits);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_SET => {
+            let mut set = Set::default();
+            for _ in 0..reader.len()? {
+                set.insert(reader.bytes()?, limits);
+            }
+            Value::Set(set)
+        }
+        TYPE_ZSET => {
+            let mut zset = Zset::default();
+            for _ in 0..reader.len()? {
+                let member = reader.bytes()?;
+                let score = f64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+                zset.insert(member, score, limits);
+            }
+            Value::Zset(zset)
+        }
+        _ => return None,
+    };
+    reader.buf.is_empty().then_some(value)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let limits = EncodingLimits::default();
+        let mut list = List::default();
+        let mut hash = Hash::default();
+        let mut set = Set::default();
+        let mut zset = Zset::default();
+        for i in 0..200 {
+            let item = i.to_string();
+            list.push(item.as_bytes(), false, &limits);
+            hash.insert(item.as_bytes(), b"v", &limits);
+            set.insert(item.as_bytes(), &limits);
+            zset.insert(item.as_bytes(), i as f64 / 2.0, &limits);
+        }
+        let values = [
+            Value::Int(-5),
+            Value::Str(b"hello".to_vec()),
+            Value::List(list),
+            Value::Hash(hash),
+            Value::Set(set),
+            Value::Zset(zset),
+        ];
+        for value in values {
+            let payload = from_hex(&to_hex(&serialize(&value))).unwrap();
+            assert_eq!(deserialize(&payload, &limits), Some(value));
+        }
+        assert_eq!(deserialize(&[TYPE_STRING, 1, 0, 0, 0], &limits), None);
+        assert_eq!(deserialize(&[9], &limits), None);
+        assert_eq!(from_hex("0g"), None);
+    }
+}
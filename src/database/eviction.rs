@@ -0,0 +1,157 @@
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What the database does when a write would take it past `maxmemory`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    VolatileLru,
+    AllKeysRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Only keys with an expiry are candidates for eviction
+    pub fn is_lfu(&self) -> bool {
+        *self == EvictionPolicy::AllKeysLfu
+    }
+
+    pub fn is_volatile(&self) -> bool {
+        matches!(self, EvictionPolicy::VolatileLru | EvictionPolicy::VolatileTtl)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Initial LFU counter of a new key, so fresh keys aren't evicted right away
+pub const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// Minutes of idleness per LFU counter decrement
+const LFU_DECAY_TIME: u64 = 1;
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock before UNIX epoch")
+        .as_millis() as u64
+}
+
+/// Access metadata kept per entry to approximate LRU and LFU
+#[derive(Debug, Clone, Copy)]
+pub struct AccessInfo {
+    /// Value of the database's logical clock at the last access
+    pub lru: u64,
+    /// Logarithmic access counter
+    pub lfu_counter: u8,
+    /// Minute (since epoch) of the last LFU decrement
+    pub lfu_decr_time: u64,
+    /// Unix milliseconds of the last access, for `object idletime`
+    pub last_access: u64,
+}
+
+impl AccessInfo {
+    pub fn new(clock: u64) -> AccessInfo {
+        let now = now_ms();
+        AccessInfo {
+            lru: clock,
+            lfu_counter: LFU_INIT_VAL,
+            lfu_decr_time: now / 60_000,
+            last_access: now,
+        }
+    }
+
+    pub fn touch(&mut self, clock: u64, rng: &mut Rng) {
+        self.lru = clock;
+        self.last_access = now_ms();
+        self.lfu_decay();
+        self.lfu_log_incr(rng);
+    }
+
+    /// Counter value after applying the decay for the elapsed time
+    pub fn lfu_decayed(&self) -> u8 {
+        let elapsed = (now_ms() / 60_000).saturating_sub(self.lfu_decr_time);
+        let periods = elapsed / LFU_DECAY_TIME;
+        self.lfu_counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    fn lfu_decay(&mut self) {
+        self.lfu_counter = self.lfu_decayed();
+        self.lfu_decr_time = now_ms() / 60_000;
+    }
+
+    /// Increment the counter with a probability that falls as it grows,
+    /// so 255 represents about a million accesses
+    fn lfu_log_incr(&mut self, rng: &mut Rng) {
+        if self.lfu_counter == u8::MAX {
+            return;
+        }
+        let base = self.lfu_counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if rng.next_f64() < p {
+            self.lfu_counter += 1;
+        }
+    }
+}
+
+/// Small xorshift generator used for sampling; quality is not a concern here
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Rng {
+        let seed = now_ms() ^ (std::process::id() as u64).rotate_left(32);
+        Rng { state: seed | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Random number in `0..n`, `n` must be > 0
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new()
+    }
+}
@@ -0,0 +1,22 @@
+use std::any::Any;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// Values handed to the background thread, which does nothing but drop them
+static QUEUE: OnceLock<Mutex<Sender<Box<dyn Any + Send>>>> = OnceLock::new();
+
+/// Drop `value` on a background thread so freeing something big doesn't
+/// hold up the executor
+pub fn free<T: Send + 'static>(value: T) {
+    let queue = QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Box<dyn Any + Send>>();
+        thread::Builder::new()
+            .name("lazyfree".to_string())
+            .spawn(move || rx.into_iter().for_each(drop))
+            .expect("Couldn't spawn lazyfree thread");
+        Mutex::new(tx)
+    });
+    // The thread never exits, so sending can't fail
+    let _ = queue.lock().unwrap().send(Box::new(value));
+}
@@ -0,0 +1,332 @@
+//! A list of small strings and integers packed into one byte buffer, used
+//! for collections with few elements.
+//!
+//! Each entry is an encoding byte, its payload and the length of both
+//! written backwards (the backlen), so the list can be walked from either
+//! end:
+//!
+//! - `0xxxxxxx`: integer 0..=127, no payload
+//! - `10xxxxxx`: string of up to 63 bytes
+//! - `0xf0`: string with a 4 byte length
+//! - `0xf1`, `0xf2`, `0xf3`: 16, 32 and 64 bit integer
+
+use std::borrow::Cow;
+
+const STR_6BIT: u8 = 0x80;
+const STR_32BIT: u8 = 0xf0;
+const INT_16BIT: u8 = 0xf1;
+const INT_32BIT: u8 = 0xf2;
+const INT_64BIT: u8 = 0xf3;
+
+/// An element as stored, strings that look like integers come back as
+/// integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Item<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl<'a> Item<'a> {
+    pub fn as_bytes(self) -> Cow<'a, [u8]> {
+        match self {
+            Item::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            Item::Str(s) => Cow::Borrowed(s),
+        }
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.as_bytes().into_owned()
+    }
+
+    /// Whether the element equals `value` as stored by `push_back`
+    pub fn matches(&self, value: &[u8]) -> bool {
+        match (self, parse_int(value)) {
+            (Item::Int(n), Some(m)) => *n == m,
+            (Item::Str(s), None) => *s == value,
+            _ => false,
+        }
+    }
+}
+
+/// The integer `value` spells, if it's in canonical form so that it can be
+/// given back unchanged
+fn parse_int(value: &[u8]) -> Option<i64> {
+    if value.is_empty() || value.len() > 20 {
+        return None;
+    }
+    let n = std::str::from_utf8(value).ok()?.parse::<i64>().ok()?;
+    (n.to_string().as_bytes() == value).then_some(n)
+}
+
+fn encode(value: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    match parse_int(value) {
+        Some(n @ 0..=127) => out.push(n as u8),
+        Some(n) if i16::try_from(n).is_ok() => {
+            out.push(INT_16BIT);
+            out.extend_from_slice(&(n as i16).to_le_bytes());
+        }
+        Some(n) if i32::try_from(n).is_ok() => {
+            out.push(INT_32BIT);
+            out.extend_from_slice(&(n as i32).to_le_bytes());
+        }
+        Some(n) => {
+            out.push(INT_64BIT);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        None if value.len() < 64 => {
+            out.push(STR_6BIT | value.len() as u8);
+            out.extend_from_slice(value);
+        }
+        None => {
+            out.push(STR_32BIT);
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+    }
+    let mut len = out.len() - start;
+    // 7 bits per byte, the byte read last going backwards has no high bit
+    let mut backlen = vec![];
+    loop {
+        backlen.push((len & 0x7f) as u8);
+        len >>= 7;
+        if len == 0 {
+            break;
+        }
+    }
+    for (i, b) in backlen.iter().enumerate().rev() {
+        out.push(if i == backlen.len() - 1 { *b } else { b | 0x80 });
+    }
+}
+
+fn backlen_size(len: usize) -> usize {
+    let mut size = 1;
+    while len >> (7 * size) != 0 {
+        size += 1;
+    }
+    size
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Listpack {
+        Listpack::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the buffer
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decode the entry at byte offset `off`, returns it and the offset of
+    /// the next one
+    fn decode(&self, off: usize) -> (Item<'_>, usize) {
+        let buf = &self.buf;
+        let tag = buf[off];
+        let (item, len) = match tag {
+            0..=0x7f => (Item::Int(tag as i64), 1),
+            INT_16BIT => (Item::Int(i16::from_le_bytes([buf[off + 1], buf[off + 2]]) as i64), 3),
+            INT_32BIT => {
+                let bytes = buf[off + 1..off + 5].try_into().expect("need 4-byte array");
+                (Item::Int(i32::from_le_bytes(bytes) as i64), 5)
+            }
+            INT_64BIT => {
+                let bytes = buf[off + 1..off + 9].try_into().expect("need 8-byte array");
+                (Item::Int(i64::from_le_bytes(bytes)), 9)
+            }
+            STR_32BIT => {
+                let n = u32::from_le_bytes(buf[off + 1..off + 5].try_into().expect("need 4-byte array")) as usize;
+                (Item::Str(&buf[off + 5..off + 5 + n]), 5 + n)
+            }
+            _ => {
+                let n = (tag & 0x3f) as usize;
+                (Item::Str(&buf[off + 1..off + 1 + n]), 1 + n)
+            }
+        };
+        (item, off + len + backlen_size(len))
+    }
+
+    /// Offset of the entry ending right before `end`
+    fn prev(&self, end: usize) -> usize {
+        let mut len = 0;
+        let mut shift = 0;
+        let mut pos = end;
+        loop {
+            pos -= 1;
+            let b = self.buf[pos];
+            len |= ((b & 0x7f) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        end - backlen_size(len) - len
+    }
+
+    /// Byte offset of entry `index`, `buf.len()` for `index == len`
+    fn offset(&self, index: usize) -> usize {
+        // Walk from whichever end is closer
+        if index > self.len / 2 {
+            let mut off = self.buf.len();
+            for _ in index..self.len {
+                off = self.prev(off);
+            }
+            off
+        } else {
+            let mut off = 0;
+            for _ in 0..index {
+                off = self.decode(off).1;
+            }
+            off
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { lp: self, off: 0 }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Item<'_>> {
+        (index < self.len).then(|| self.decode(self.offset(index)).0)
+    }
+
+    /// Index of the first element equal to `value` among those at
+    /// `0, step, 2 * step, ...`
+    pub fn find(&self, value: &[u8], step: usize) -> Option<usize> {
+        self.iter().step_by(step.max(1)).position(|item| item.matches(value)).map(|i| i * step.max(1))
+    }
+
+    pub fn insert(&mut self, index: usize, value: &[u8]) {
+        assert!(index <= self.len);
+        let off = self.offset(index);
+        let mut entry = vec![];
+        encode(value, &mut entry);
+        self.buf.splice(off..off, entry);
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, value: &[u8]) {
+        encode(value, &mut self.buf);
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, value: &[u8]) {
+        self.insert(0, value);
+    }
+
+    /// Remove `count` elements starting at `index`
+    pub fn remove(&mut self, index: usize, count: usize) {
+        let count = count.min(self.len.saturating_sub(index));
+        if count == 0 {
+            return;
+        }
+        let start = self.offset(index);
+        let mut end = start;
+        for _ in 0..count {
+            end = self.decode(end).1;
+        }
+        self.buf.drain(start..end);
+        self.len -= count;
+    }
+
+    pub fn replace(&mut self, index: usize, value: &[u8]) {
+        assert!(index < self.len);
+        let start = self.offset(index);
+        let end = self.decode(start).1;
+        let mut entry = vec![];
+        encode(value, &mut entry);
+        self.buf.splice(start..end, entry);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let value = self.get(0)?.to_vec();
+        self.remove(0, 1);
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        let value = self.get(self.len.checked_sub(1)?)?.to_vec();
+        self.remove(self.len - 1, 1);
+        Some(value)
+    }
+}
+
+pub struct Iter<'a> {
+    lp: &'a Listpack,
+    off: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Item<'a>;
+
+    fn next(&mut self) -> Option<Item<'a>> {
+        if self.off >= self.lp.buf.len() {
+            return None;
+        }
+        let (item, next) = self.lp.decode(self.off);
+        self.off = next;
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut lp = Listpack::new();
+        let long = "x".repeat(300);
+        let values = ["0", "127", "128", "-1", "70000", "-9223372036854775808", "007", "+1", "", "hello", &long];
+        for value in values {
+            lp.push_back(value.as_bytes());
+        }
+        assert_eq!(lp.len(), values.len());
+        for (item, value) in lp.iter().zip(values) {
+            assert_eq!(item.as_bytes(), value.as_bytes());
+            assert!(item.matches(value.as_bytes()));
+        }
+        assert_eq!(lp.get(1), Some(Item::Int(127)));
+        assert_eq!(lp.get(6), Some(Item::Str(b"007")));
+        // Walking backwards needs the multi-byte backlen of the long string
+        assert_eq!(lp.get(values.len() - 2), Some(Item::Str(b"hello")));
+        assert_eq!(lp.pop_back(), Some(long.as_bytes().to_vec()));
+        assert_eq!(lp.get(values.len() - 2), Some(Item::Str(b"hello")));
+    }
+
+    #[test]
+    fn test_edits() {
+        let mut lp = Listpack::new();
+        for value in ["b", "c", "e"] {
+            lp.push_back(value.as_bytes());
+        }
+        lp.push_front(b"a");
+        lp.insert(3, b"d");
+        let all = |lp: &Listpack| lp.iter().map(|i| String::from_utf8(i.to_vec()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(all(&lp), ["a", "b", "c", "d", "e"]);
+
+        lp.replace(1, b"12345");
+        assert_eq!(lp.find(b"12345", 1), Some(1));
+        assert_eq!(lp.find(b"c", 2), Some(2));
+        assert_eq!(lp.find(b"d", 2), None);
+        lp.remove(1, 2);
+        assert_eq!(all(&lp), ["a", "d", "e"]);
+        assert_eq!(lp.pop_front(), Some(b"a".to_vec()));
+        lp.remove(0, 10);
+        assert!(lp.is_empty());
+        assert_eq!(lp.bytes(), 0);
+        assert_eq!(lp.pop_back(), None);
+    }
+}
@@ -1,35 +1,970 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use super::ResponseStatus;
 
+pub mod dump;
+pub mod eviction;
+pub mod hll;
+pub mod lazyfree;
+pub mod listpack;
+pub mod notify;
+pub mod value;
+
+use eviction::{now_ms, AccessInfo, EvictionPolicy, Rng};
+use notify::{KeyEvent, NOTIFY_EVICTED, NOTIFY_EXPIRED, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE};
+use value::{EncodingLimits, Value};
+
+/// Number of keys looked at per eviction round
+pub const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+
+/// Keys with an expiry looked at per round of active expiry
+const ACTIVE_EXPIRE_SAMPLES: usize = 20;
+
+/// Values taking at least this many bytes are freed on a background thread
+/// by `unlink`
+pub const LAZYFREE_THRESHOLD: usize = 64 * 1024;
+
+/// Bookkeeping cost of a key on top of its key and value bytes: the entry
+/// itself plus the two `Vec` headers (map key and sampling vector)
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Entry>() + 2 * std::mem::size_of::<Vec<u8>>();
+
+/// Memory use and budget shared by the databases of a server, so that
+/// `maxmemory` holds for all of them together
+#[derive(Default)]
+pub struct Memory {
+    used: AtomicUsize,
+    /// Budget in bytes, 0 means unlimited
+    max: AtomicUsize,
+    /// Logical clock, advanced on every access, used for LRU. Shared so
+    /// that idle times compare across databases.
+    clock: AtomicU64,
+}
+
+impl Memory {
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether `incoming` more bytes fit into the budget
+    fn fits(&self, incoming: usize) -> bool {
+        let max = self.max.load(Ordering::Relaxed);
+        max == 0 || self.used.load(Ordering::Relaxed) + incoming <= max
+    }
+}
+
+struct Entry {
+    value: Value,
+    /// Absolute expiry time in unix milliseconds
+    expire_at: Option<u64>,
+    access: AccessInfo,
+    /// Index of the key in `Database::keys`
+    pos: usize,
+    /// Index of the key in `Database::volatile`, if it has an expiry
+    volatile_pos: Option<usize>,
+}
+
+/// What `object` reports about a key
+pub struct ObjectInfo {
+    pub encoding: &'static str,
+    /// Seconds since the key was last accessed
+    pub idle: u64,
+    /// Logarithmic access frequency, see `AccessInfo`
+    pub freq: u8,
+}
+
+/// What changed in a database, see `Database::take_modified`
+pub struct Modified {
+    /// Keys written or deleted, in order, repeats included
+    pub keys: Vec<Vec<u8>>,
+    pub flushed: bool,
+    /// Whether keys expired or were evicted, which no command asked for
+    pub dropped: bool,
+}
+
 pub struct Database {
-    data: HashMap<String, String>,
+    data: HashMap<Vec<u8>, Entry>,
+    /// All keys, so that eviction can draw random samples in O(1)
+    keys: Vec<Vec<u8>>,
+    /// Keys with an expiry, so that active expiry can sample them
+    volatile: Vec<Vec<u8>>,
+    /// Bytes taken by this database, part of `memory`
+    used_memory: usize,
+    memory: Arc<Memory>,
+    policy: EvictionPolicy,
+    samples: usize,
+    limits: EncodingLimits,
+    /// `notify-keyspace-events` flags
+    notify_flags: u32,
+    /// Key events not yet published
+    notifications: Vec<KeyEvent>,
+    /// Record written keys for client side caching
+    track_modified: bool,
+    modified: Vec<Vec<u8>>,
+    /// Whether `clear` was called since the last `take_modified`
+    flushed: bool,
+    /// Whether keys expired or were evicted since the last `take_modified`
+    dropped: bool,
+    rng: Rng,
+    evicted_keys: u64,
+    expired_keys: u64,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database::new()
+    }
 }
 
 impl Database {
     pub fn new() -> Database {
         Database {
             data: HashMap::new(),
+            keys: Vec::new(),
+            volatile: Vec::new(),
+            used_memory: 0,
+            memory: Arc::new(Memory::default()),
+            policy: EvictionPolicy::NoEviction,
+            samples: DEFAULT_MAXMEMORY_SAMPLES,
+            limits: EncodingLimits::default(),
+            notify_flags: 0,
+            notifications: Vec::new(),
+            track_modified: false,
+            modified: Vec::new(),
+            flushed: false,
+            dropped: false,
+            rng: Rng::new(),
+            evicted_keys: 0,
+            expired_keys: 0,
+        }
+    }
+
+    /// `n` databases sharing one memory budget, like those of a server
+    pub fn group(n: usize) -> Vec<Database> {
+        let memory = Arc::new(Memory::default());
+        (0..n).map(|_| Database { memory: memory.clone(), ..Database::new() }).collect()
+    }
+
+    /// Set the budget of all the databases in the group
+    pub fn set_maxmemory(&mut self, bytes: usize) {
+        self.memory.max.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_maxmemory_samples(&mut self, samples: usize) {
+        self.samples = samples.max(1);
+    }
+
+    pub fn set_encoding_limits(&mut self, limits: EncodingLimits) {
+        self.limits = limits;
+    }
+
+    pub fn encoding_limits(&self) -> &EncodingLimits {
+        &self.limits
+    }
+
+    pub fn set_notify_keyspace_events(&mut self, flags: u32) {
+        self.notify_flags = flags;
+    }
+
+    pub fn notify_keyspace_events(&self) -> u32 {
+        self.notify_flags
+    }
+
+    /// Record `event` on `key` if events of `class` are to be published
+    pub fn notify(&mut self, class: u32, event: &'static str, key: &[u8]) {
+        if self.notify_flags & class != 0 && self.notify_flags & (NOTIFY_KEYSPACE | NOTIFY_KEYEVENT) != 0 {
+            self.notifications.push(KeyEvent { event, key: key.to_vec() });
+        }
+    }
+
+    /// The events recorded since the last call, oldest first
+    pub fn take_notifications(&mut self) -> Vec<KeyEvent> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    pub fn set_track_modified(&mut self, on: bool) {
+        self.track_modified = on;
+    }
+
+    /// What changed since the last call, if `set_track_modified` is on
+    pub fn take_modified(&mut self) -> Modified {
+        Modified {
+            keys: std::mem::take(&mut self.modified),
+            flushed: std::mem::take(&mut self.flushed),
+            dropped: std::mem::take(&mut self.dropped),
+        }
+    }
+
+    fn modified(&mut self, key: &[u8]) {
+        if self.track_modified {
+            self.modified.push(key.to_vec());
+        }
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.memory.max.load(Ordering::Relaxed)
+    }
+
+    pub fn maxmemory_samples(&self) -> usize {
+        self.samples
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Estimated number of bytes taken by keys and values
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// `used_memory` of the whole group
+    pub fn group_used_memory(&self) -> usize {
+        self.memory.used.load(Ordering::Relaxed)
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Number of keys with an expiry set
+    pub fn volatile_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Store the string `value` under `key`, discarding any expiry.
+    /// Returns `Err` if the value doesn't fit into `maxmemory`.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> ResponseStatus {
+        self.set_value(key, Value::from_bytes(value))
+    }
+
+    fn set_value(&mut self, key: Vec<u8>, value: Value) -> ResponseStatus {
+        let new_size = Database::entry_size(&key, &value);
+        let old_size = match self.data.get(&key) {
+            Some(e) => Database::entry_size(&key, &e.value),
+            None => 0,
+        };
+        if new_size > old_size && !self.make_room(new_size - old_size, &key) {
+            return ResponseStatus::Err;
         }
+
+        let clock = self.memory.tick();
+        self.modified(&key);
+        match self.data.get_mut(&key) {
+            Some(entry) => {
+                entry.value = value;
+                entry.access.touch(clock, &mut self.rng);
+                self.shrink(old_size);
+                self.set_expire_at(&key, None);
+            }
+            None => {
+                let entry = Entry {
+                    value,
+                    expire_at: None,
+                    access: AccessInfo::new(clock),
+                    pos: self.keys.len(),
+                    volatile_pos: None,
+                };
+                self.keys.push(key.clone());
+                self.data.insert(key, entry);
+            }
+        }
+        self.grow(new_size);
+        ResponseStatus::Ok
     }
 
-    pub fn set(&mut self, key: String, value: String) -> ResponseStatus {
-        self.data.insert(key, value);
+    /// Returns `Err` if `key` doesn't hold a string
+    pub fn get(&mut self, key: &[u8], value: &mut Vec<u8>) -> ResponseStatus {
+        if self.expire_if_needed(key) {
+            return ResponseStatus::Nx;
+        }
+        let clock = self.memory.tick();
+        match self.data.get_mut(key) {
+            Some(entry) => {
+                entry.access.touch(clock, &mut self.rng);
+                match entry.value.as_bytes() {
+                    Some(s) => {
+                        value.extend_from_slice(&s);
+                        ResponseStatus::Ok
+                    }
+                    None => ResponseStatus::Err,
+                }
+            }
+            None => ResponseStatus::Nx,
+        }
+    }
+
+    /// Whether `key` exists, without counting as an access
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        !self.expire_if_needed(key) && self.data.contains_key(key)
+    }
+
+    /// Remove `key`, returning its value and expiry time in unix
+    /// milliseconds
+    pub fn take(&mut self, key: &[u8]) -> Option<(Value, Option<u64>)> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        let expire_at = self.data.get(key)?.expire_at;
+        self.remove(key).map(|value| (value, expire_at))
+    }
+
+    /// Store `value` under `key` expiring at `expire_at`, the counterpart of
+    /// `take`. Returns `Err` if the value doesn't fit into `maxmemory`.
+    pub fn put(&mut self, key: Vec<u8>, value: Value, expire_at: Option<u64>) -> ResponseStatus {
+        let Some(at) = expire_at else {
+            return self.set_value(key, value);
+        };
+        if self.set_value(key.clone(), value) == ResponseStatus::Err {
+            return ResponseStatus::Err;
+        }
+        self.set_expire_at(&key, Some(at));
         ResponseStatus::Ok
     }
 
-    pub fn get(&self, key: &str, value: &mut String) -> ResponseStatus {
-        match self.data.get(key) {
-            Some(v) => {
-                value.push_str(v);
+    /// Delete all keys, freeing them on a background thread if `lazy`
+    pub fn clear(&mut self, lazy: bool) {
+        let data = std::mem::take(&mut self.data);
+        let keys = std::mem::take(&mut self.keys);
+        let volatile = std::mem::take(&mut self.volatile);
+        self.shrink(self.used_memory);
+        self.flushed = self.track_modified;
+        if lazy {
+            lazyfree::free((data, keys, volatile));
+        }
+    }
+
+    /// Type of the value at `key`, `None` if there is none
+    pub fn value_type(&mut self, key: &[u8]) -> Option<&'static str> {
+        self.peek(key).map(|(value, _)| value.type_name())
+    }
+
+    /// Value and expiry time of `key`, without counting as an access
+    pub fn peek(&mut self, key: &[u8]) -> Option<(&Value, Option<u64>)> {
+        if self.expire_if_needed(key) {
+            return None;
+        }
+        self.data.get(key).map(|e| (&e.value, e.expire_at))
+    }
+
+    /// The bytes of the string at `key` to change in place, counting as an
+    /// access and a write. `None` if there is no such key or it doesn't
+    /// hold a string stored as bytes.
+    pub fn string_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        if self.expire_if_needed(key) || !matches!(self.data.get(key), Some(Entry { value: Value::Str(_), .. })) {
+            return None;
+        }
+        self.modified(key);
+        let clock = self.memory.tick();
+        let entry = self.data.get_mut(key)?;
+        entry.access.touch(clock, &mut self.rng);
+        match &mut entry.value {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Count an access to `key`, returns false if it doesn't exist
+    pub fn touch(&mut self, key: &[u8]) -> bool {
+        if self.expire_if_needed(key) {
+            return false;
+        }
+        let clock = self.memory.tick();
+        match self.data.get_mut(key) {
+            Some(entry) => {
+                entry.access.touch(clock, &mut self.rng);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        // Every miss removes an expired key, so this ends
+        while !self.keys.is_empty() {
+            let key = self.keys[self.rng.below(self.keys.len())].clone();
+            if !self.expire_if_needed(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    /// All keys that haven't expired, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        let now = now_ms();
+        self.keys
+            .iter()
+            .filter(move |key| self.data[key.as_slice()].expire_at.is_none_or(|at| at > now))
+            .map(|key| key.as_slice())
+    }
+
+    pub fn object(&mut self, key: &[u8]) -> Option<ObjectInfo> {
+        let encoding = self.peek(key)?.0.encoding();
+        let access = &self.data[key].access;
+        Some(ObjectInfo {
+            encoding,
+            idle: now_ms().saturating_sub(access.last_access) / 1000,
+            freq: access.lfu_decayed(),
+        })
+    }
+
+    /// Returns `Nx` if there was nothing to delete
+    pub fn del(&mut self, key: &[u8]) -> ResponseStatus {
+        if self.expire_if_needed(key) {
+            return ResponseStatus::Nx;
+        }
+        match self.remove(key) {
+            Some(_) => ResponseStatus::Ok,
+            None => ResponseStatus::Nx,
+        }
+    }
+
+    /// Like `del`, but big values are freed on a background thread
+    pub fn unlink(&mut self, key: &[u8]) -> ResponseStatus {
+        if self.expire_if_needed(key) {
+            return ResponseStatus::Nx;
+        }
+        match self.remove(key) {
+            Some(value) => {
+                if Database::entry_size(key, &value) >= LAZYFREE_THRESHOLD {
+                    lazyfree::free(value);
+                }
                 ResponseStatus::Ok
             }
             None => ResponseStatus::Nx,
         }
     }
 
-    pub fn del(&mut self, key: &str) -> ResponseStatus {
-        self.data.remove(key);
+    /// Let `key` expire in `seconds`; a non-positive value deletes it.
+    /// Returns `Err` if the expiry time doesn't fit in milliseconds.
+    pub fn expire(&mut self, key: &[u8], seconds: i64) -> ResponseStatus {
+        let Some(at) = seconds.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms() as i64)) else {
+            return ResponseStatus::Err;
+        };
+        if self.expire_if_needed(key) || !self.data.contains_key(key) {
+            return ResponseStatus::Nx;
+        }
+        if seconds <= 0 {
+            self.remove(key);
+            return ResponseStatus::Ok;
+        }
+        self.set_expire_at(key, Some(at as u64));
+        self.modified(key);
         ResponseStatus::Ok
     }
+
+    /// Remaining time to live in seconds, -1 if the key has no expiry
+    /// and -2 if it doesn't exist
+    pub fn ttl(&mut self, key: &[u8]) -> i64 {
+        if self.expire_if_needed(key) {
+            return -2;
+        }
+        match self.data.get(key) {
+            Some(Entry { expire_at: Some(at), .. }) => {
+                (at.saturating_sub(now_ms()) as i64 + 999) / 1000
+            }
+            Some(_) => -1,
+            None => -2,
+        }
+    }
+
+    fn entry_size(key: &[u8], value: &Value) -> usize {
+        2 * key.len() + value.memory() + ENTRY_OVERHEAD
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        let entry = self.data.remove(key)?;
+        self.modified(key);
+        self.keys.swap_remove(entry.pos);
+        if let Some(moved) = self.keys.get(entry.pos) {
+            self.data.get_mut(moved).unwrap().pos = entry.pos;
+        }
+        if let Some(pos) = entry.volatile_pos {
+            self.unlist_volatile(pos);
+        }
+        self.shrink(Database::entry_size(key, &entry.value));
+        Some(entry.value)
+    }
+
+    /// Set or clear the expiry of the existing `key`
+    fn set_expire_at(&mut self, key: &[u8], expire_at: Option<u64>) {
+        let entry = self.data.get_mut(key).unwrap();
+        entry.expire_at = expire_at;
+        match (expire_at, entry.volatile_pos) {
+            (Some(_), None) => {
+                entry.volatile_pos = Some(self.volatile.len());
+                self.volatile.push(key.to_vec());
+            }
+            (None, Some(pos)) => {
+                entry.volatile_pos = None;
+                self.unlist_volatile(pos);
+            }
+            _ => {}
+        }
+    }
+
+    fn unlist_volatile(&mut self, pos: usize) {
+        self.volatile.swap_remove(pos);
+        if let Some(moved) = self.volatile.get(pos) {
+            self.data.get_mut(moved).unwrap().volatile_pos = Some(pos);
+        }
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.used_memory += bytes;
+        self.memory.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.used_memory -= bytes;
+        self.memory.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Delete keys whose time to live has passed even if nothing reads
+    /// them. Random keys with an expiry are looked at in rounds, going on
+    /// while more than a quarter of a round had expired, until `deadline`.
+    /// Returns the number of keys deleted.
+    pub fn active_expire(&mut self, deadline: Instant) -> usize {
+        let mut expired = 0;
+        loop {
+            let samples = ACTIVE_EXPIRE_SAMPLES.min(self.volatile.len());
+            let mut round = 0;
+            for _ in 0..samples {
+                if self.volatile.is_empty() {
+                    break;
+                }
+                let key = self.volatile[self.rng.below(self.volatile.len())].clone();
+                if self.expire_if_needed(&key) {
+                    round += 1;
+                }
+            }
+            expired += round;
+            if round * 4 <= samples || Instant::now() >= deadline {
+                return expired;
+            }
+        }
+    }
+
+    /// Delete `key` if its time to live has passed, returns whether it did
+    fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        match self.data.get(key) {
+            Some(Entry { expire_at: Some(at), .. }) if *at <= now_ms() => {
+                self.remove(key);
+                self.dropped = self.track_modified;
+                self.expired_keys += 1;
+                self.notify(NOTIFY_EXPIRED, "expired", key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Evict keys of this database until `incoming` more bytes fit into
+    /// `maxmemory`. `protect` is the key being written, which is never
+    /// chosen. See `make_room` for evicting from the whole group.
+    fn make_room(&mut self, incoming: usize, protect: &[u8]) -> bool {
+        let max = self.maxmemory();
+        if max != 0 && incoming > max {
+            return false;
+        }
+        while !self.memory.fits(incoming) {
+            if self.policy == EvictionPolicy::NoEviction {
+                return false;
+            }
+            match self.pick_victim(protect) {
+                Some((victim, _)) => self.evict(&victim),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn evict(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.dropped = self.track_modified;
+        self.evicted_keys += 1;
+        self.notify(NOTIFY_EVICTED, "evicted", key);
+    }
+
+    /// Approximate the best key to evict by sampling a few random keys and
+    /// taking the one that scores highest under the current policy, along
+    /// with its score
+    fn pick_victim(&mut self, protect: &[u8]) -> Option<(Vec<u8>, u64)> {
+        // Volatile policies sample the keys with an expiry only
+        let pool = if self.policy.is_volatile() { &self.volatile } else { &self.keys };
+        if pool.is_empty() || (pool.len() == 1 && pool[0] == protect) {
+            return None;
+        }
+
+        let now = now_ms();
+        // Sampling with replacement can miss the best key, so pools no
+        // bigger than the sample are looked at whole
+        let exhaustive = self.samples >= pool.len();
+        // Leave room for drawing the protected key
+        let max_tries = if exhaustive { pool.len() } else { self.samples * 2 };
+        let mut sampled = 0;
+        let mut best: Option<(u64, usize)> = None;
+
+        for i in 0..max_tries {
+            if sampled == self.samples {
+                break;
+            }
+            let pos = if exhaustive { i } else { self.rng.below(pool.len()) };
+            let key = &pool[pos];
+            if key == protect {
+                continue;
+            }
+            let entry = &self.data[key];
+            sampled += 1;
+
+            // Already expired keys are the cheapest to get rid of
+            if entry.expire_at.is_some_and(|at| at <= now) {
+                return Some((key.clone(), u64::MAX));
+            }
+            let score = match self.policy {
+                EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => {
+                    self.memory.clock.load(Ordering::Relaxed) - entry.access.lru
+                }
+                EvictionPolicy::AllKeysLfu => (u8::MAX - entry.access.lfu_decayed()) as u64,
+                EvictionPolicy::VolatileTtl => u64::MAX - entry.expire_at.unwrap(),
+                EvictionPolicy::AllKeysRandom => return Some((key.clone(), self.rng.next_u64())),
+                EvictionPolicy::NoEviction => return None,
+            };
+            if best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, pos));
+            }
+        }
+
+        match best {
+            Some((score, pos)) => Some((pool[pos].clone(), score)),
+            // Sampling only drew the protected key
+            None => pool.iter().find(|k| *k != protect).map(|k| (k.clone(), 0)),
+        }
+    }
+}
+
+/// Evict keys from any of `dbs`, a group sharing their memory budget,
+/// until `incoming` more bytes fit. Writes only evict from the database
+/// they go to, so this runs first to make room for them across the group.
+/// Returns false if there's nothing left to evict.
+pub fn make_room(dbs: &mut [Database], incoming: usize) -> bool {
+    let Some(first) = dbs.first() else {
+        return true;
+    };
+    let (memory, policy) = (first.memory.clone(), first.policy);
+    while !memory.fits(incoming) {
+        if policy == EvictionPolicy::NoEviction {
+            return false;
+        }
+        // The best candidate of each database, scores compare across them
+        let victim = dbs
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, db)| db.pick_victim(b"").map(|(key, score)| (score, i, key)))
+            .max_by_key(|(score, _, _)| *score);
+        match victim {
+            Some((_, i, key)) => dbs[i].evict(&key),
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(policy: EvictionPolicy, keys: usize) -> Database {
+        let mut db = Database::new();
+        for i in 0..keys {
+            assert_eq!(db.set(format!("key{}", i).into_bytes(), b"x".repeat(100)), ResponseStatus::Ok);
+        }
+        db.set_eviction_policy(policy);
+        db.set_maxmemory(db.used_memory());
+        db
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let mut db = Database::new();
+        assert_eq!(db.used_memory(), 0);
+
+        db.set(b"hello".to_vec(), b"world".to_vec());
+        let one = db.used_memory();
+        assert!(one >= 10 + 5);
+
+        db.set(b"hello".to_vec(), b"a much longer world".to_vec());
+        assert_eq!(db.used_memory(), one + 14);
+
+        db.set(b"other".to_vec(), b"x".to_vec());
+        db.del(b"hello");
+        db.del(b"other");
+        assert_eq!(db.used_memory(), 0);
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn test_noeviction_returns_err() {
+        let mut db = filled(EvictionPolicy::NoEviction, 10);
+        assert_eq!(db.set(b"new".to_vec(), b"value".to_vec()), ResponseStatus::Err);
+        assert_eq!(db.len(), 10);
+
+        // Shrinking an existing value is always allowed
+        assert_eq!(db.set(b"key0".to_vec(), b"y".to_vec()), ResponseStatus::Ok);
+        db.del(b"key1");
+        assert_eq!(db.set(b"new".to_vec(), b"value".to_vec()), ResponseStatus::Ok);
+    }
+
+    #[test]
+    fn test_allkeys_policies_evict() {
+        for policy in [
+            EvictionPolicy::AllKeysLru,
+            EvictionPolicy::AllKeysLfu,
+            EvictionPolicy::AllKeysRandom,
+        ] {
+            let mut db = filled(policy, 50);
+            for i in 0..50 {
+                assert_eq!(db.set(format!("new{}", i).into_bytes(), b"x".repeat(100)), ResponseStatus::Ok);
+                assert!(db.used_memory() <= db.maxmemory());
+            }
+            assert!(db.evicted_keys() >= 50);
+        }
+    }
+
+    #[test]
+    fn test_lru_prefers_idle_keys() {
+        let mut db = filled(EvictionPolicy::AllKeysLru, 100);
+        db.set_maxmemory_samples(100);
+        let mut value = vec![];
+        for i in 1..100 {
+            db.get(format!("key{}", i).as_bytes(), &mut value);
+        }
+        db.set(b"new".to_vec(), b"x".repeat(100));
+        assert_eq!(db.get(b"key0", &mut value), ResponseStatus::Nx);
+    }
+
+    #[test]
+    fn test_volatile_policies() {
+        let mut db = filled(EvictionPolicy::VolatileLru, 10);
+        assert_eq!(db.set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Err);
+
+        db.expire(b"key3", 100);
+        assert_eq!(db.set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Ok);
+        assert_eq!(db.ttl(b"key3"), -2);
+
+        db.set_eviction_policy(EvictionPolicy::VolatileTtl);
+        db.set_maxmemory_samples(100);
+        db.expire(b"key4", 1000);
+        db.expire(b"key5", 10);
+        assert_eq!(db.set(b"new2".to_vec(), b"x".repeat(100)), ResponseStatus::Ok);
+        assert_eq!(db.ttl(b"key5"), -2);
+        assert!(db.ttl(b"key4") > 0);
+
+        // A few keys with an expiry among many without are all sampled
+        let mut db = filled(EvictionPolicy::VolatileTtl, 500);
+        db.set_maxmemory_samples(5);
+        for (i, ttl) in [(10, 300), (20, 100), (30, 200)] {
+            db.expire(format!("key{}", i).as_bytes(), ttl);
+        }
+        assert_eq!(db.set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Ok);
+        assert_eq!((db.ttl(b"key20"), db.volatile_len()), (-2, 2));
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        let mut dbs = Database::group(2);
+        for i in 0..10 {
+            dbs[0].set(format!("key{}", i).into_bytes(), b"x".repeat(100));
+        }
+        let budget = dbs[0].used_memory();
+        assert_eq!(dbs[1].group_used_memory(), budget);
+        dbs[1].set_maxmemory(budget);
+        assert_eq!(dbs[0].maxmemory(), budget);
+
+        // Neither database may go over the budget on its own
+        assert_eq!(dbs[0].set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Err);
+        assert_eq!(dbs[1].set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Err);
+
+        // Keys of one database make room for the other
+        for db in dbs.iter_mut() {
+            db.set_eviction_policy(EvictionPolicy::AllKeysLru);
+            db.set_maxmemory_samples(100);
+        }
+        let mut value = vec![];
+        for i in 1..10 {
+            dbs[0].get(format!("key{}", i).as_bytes(), &mut value);
+        }
+        assert!(make_room(&mut dbs, Database::entry_size(b"new", &Value::from_string("x".repeat(100)))));
+        assert_eq!(dbs[1].set(b"new".to_vec(), b"x".repeat(100)), ResponseStatus::Ok);
+        assert_eq!((dbs[0].evicted_keys(), dbs[0].len()), (1, 9));
+        assert!(!dbs[0].contains(b"key0"));
+        assert!(dbs[0].group_used_memory() <= budget);
+        assert_eq!(dbs[0].used_memory() + dbs[1].used_memory(), dbs[0].group_used_memory());
+
+        dbs[0].clear(false);
+        dbs[1].del(b"new");
+        assert_eq!(dbs[1].group_used_memory(), 0);
+        assert!(!make_room(&mut dbs, budget + 1));
+    }
+
+    #[test]
+    fn test_notifications() {
+        let mut db = filled(EvictionPolicy::AllKeysRandom, 1);
+        db.notify(notify::NOTIFY_GENERIC, "del", b"nobody listens");
+        db.set_notify_keyspace_events(notify::parse_flags("Ee").unwrap());
+        db.notify(notify::NOTIFY_GENERIC, "del", b"not this class");
+        db.set(b"new".to_vec(), b"x".repeat(100));
+        assert_eq!(db.take_notifications(), [KeyEvent { event: "evicted", key: b"key0".to_vec() }]);
+
+        db.set_notify_keyspace_events(notify::parse_flags("Kx").unwrap());
+        db.expire(b"new", 0);
+        assert!(db.take_notifications().is_empty());
+        db.set(b"k".to_vec(), b"v".to_vec());
+        db.set_expire_at(b"k", Some(0));
+        assert!(!db.contains(b"k"));
+        assert_eq!(db.take_notifications(), [KeyEvent { event: "expired", key: b"k".to_vec() }]);
+        assert!(db.take_notifications().is_empty());
+    }
+
+    #[test]
+    fn test_expire_and_ttl() {
+        let mut db = Database::new();
+        assert_eq!(db.expire(b"nope", 10), ResponseStatus::Nx);
+        assert_eq!(db.ttl(b"nope"), -2);
+
+        db.set(b"hello".to_vec(), b"world".to_vec());
+        assert_eq!(db.ttl(b"hello"), -1);
+        db.expire(b"hello", 10);
+        assert_eq!(db.ttl(b"hello"), 10);
+
+        db.set(b"hello".to_vec(), b"again".to_vec());
+        assert_eq!(db.ttl(b"hello"), -1);
+
+        assert_eq!(db.expire(b"hello", i64::MAX), ResponseStatus::Err);
+        assert_eq!(db.expire(b"nope", i64::MAX), ResponseStatus::Err);
+        assert_eq!(db.ttl(b"hello"), -1);
+
+        db.expire(b"hello", 0);
+        let mut value = vec![];
+        assert_eq!(db.get(b"hello", &mut value), ResponseStatus::Nx);
+    }
+
+    #[test]
+    fn test_active_expire() {
+        let mut db = Database::new();
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        assert_eq!(db.active_expire(deadline), 0);
+
+        db.set_notify_keyspace_events(notify::parse_flags("Ex").unwrap());
+        for i in 0..100 {
+            let key = format!("key{}", i).into_bytes();
+            db.set(key.clone(), b"v".to_vec());
+            db.set_expire_at(&key, Some(if i < 50 { 0 } else { u64::MAX }));
+        }
+        // Keys go without being read
+        let expired = (0..1000).map(|_| db.active_expire(deadline)).sum::<usize>();
+        assert_eq!((expired, db.len(), db.volatile_len()), (50, 50, 50));
+        assert_eq!(db.expired_keys(), 50);
+        let events = db.take_notifications();
+        assert!(events.len() == 50 && events.iter().all(|e| e.event == "expired"));
+        let left = db.keys().map(|key| String::from_utf8_lossy(key).into_owned()).collect::<Vec<String>>();
+        assert!(left.iter().all(|key| key["key".len()..].parse::<usize>().unwrap() >= 50));
+
+        db.set(b"key50".to_vec(), b"v".to_vec());
+        assert_eq!(db.volatile_len(), 49);
+        db.clear(false);
+        assert_eq!(db.volatile_len(), 0);
+    }
+
+    #[test]
+    fn test_del_and_unlink() {
+        let mut db = Database::new();
+        assert_eq!(db.del(b"nope"), ResponseStatus::Nx);
+        db.set(b"a".to_vec(), b"1".to_vec());
+        db.set(b"b".to_vec(), b"x".repeat(LAZYFREE_THRESHOLD));
+        assert_eq!(db.del(b"a"), ResponseStatus::Ok);
+        assert_eq!(db.unlink(b"b"), ResponseStatus::Ok);
+        assert_eq!(db.unlink(b"b"), ResponseStatus::Nx);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_object() {
+        let mut db = Database::new();
+        assert!(db.object(b"nope").is_none());
+        assert!(db.random_key().is_none());
+        for (value, encoding) in [("123", "int"), ("0123", "embstr"), ("hello", "embstr")] {
+            db.set(b"k".to_vec(), value.as_bytes().to_vec());
+            assert_eq!(db.object(b"k").unwrap().encoding, encoding);
+        }
+        db.set(b"k".to_vec(), b"x".repeat(45));
+        let info = db.object(b"k").unwrap();
+        assert_eq!((info.encoding, info.idle), ("raw", 0));
+        assert_eq!(db.random_key(), Some(b"k".to_vec()));
+    }
+
+    #[test]
+    fn test_collection_values() {
+        let mut db = Database::new();
+        let mut set = value::Set::default();
+        for member in ["a", "b", "c"] {
+            set.insert(member.as_bytes(), db.encoding_limits());
+        }
+        let size = Value::Set(set.clone()).memory();
+        assert_eq!(db.put(b"s".to_vec(), Value::Set(set), None), ResponseStatus::Ok);
+        assert_eq!(db.used_memory(), Database::entry_size(b"s", &Value::Int(0)) + size);
+        assert_eq!(db.value_type(b"s"), Some("set"));
+        assert_eq!(db.object(b"s").unwrap().encoding, "listpack");
+        let mut value = vec![];
+        assert_eq!(db.get(b"s", &mut value), ResponseStatus::Err);
+
+        // Strings are overwritten whatever the key held
+        db.set(b"s".to_vec(), b"42".to_vec());
+        assert_eq!((db.value_type(b"s"), db.object(b"s").unwrap().encoding), (Some("string"), "int"));
+        assert_eq!(db.get(b"s", &mut value), ResponseStatus::Ok);
+        assert_eq!(value, b"42");
+        db.del(b"s");
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_take_and_put() {
+        let mut db = Database::new();
+        db.set(b"hello".to_vec(), b"world".to_vec());
+        db.expire(b"hello", 10);
+        let (value, expire_at) = db.take(b"hello").unwrap();
+        assert!(db.is_empty());
+        assert_eq!(db.volatile_len(), 0);
+        assert_eq!(db.used_memory(), 0);
+
+        let mut other = Database::new();
+        assert_eq!(other.put(b"hello".to_vec(), value, expire_at), ResponseStatus::Ok);
+        assert_eq!(other.ttl(b"hello"), 10);
+        assert_eq!(other.volatile_len(), 1);
+        assert!(db.take(b"hello").is_none());
+
+        other.set(b"plain".to_vec(), b"x".to_vec());
+        other.clear(true);
+        assert!(other.is_empty());
+        assert_eq!((other.volatile_len(), other.used_memory()), (0, 0));
+    }
 }
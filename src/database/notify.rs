@@ -0,0 +1,89 @@
+//! Keyspace notifications: which key events are published, as set with the
+//! `notify-keyspace-events` flags
+
+/// Publish to `__keyspace@<db>__:<key>` with the event as message
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0;
+/// Publish to `__keyevent@<db>__:<event>` with the key as message
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1;
+/// Commands that work on any type: `del`, `expire`, `rename`, ...
+pub const NOTIFY_GENERIC: u32 = 1 << 2;
+pub const NOTIFY_STRING: u32 = 1 << 3;
+pub const NOTIFY_LIST: u32 = 1 << 4;
+pub const NOTIFY_SET: u32 = 1 << 5;
+pub const NOTIFY_HASH: u32 = 1 << 6;
+pub const NOTIFY_ZSET: u32 = 1 << 7;
+/// A key's time to live ran out
+pub const NOTIFY_EXPIRED: u32 = 1 << 8;
+/// A key was evicted to stay under `maxmemory`
+pub const NOTIFY_EVICTED: u32 = 1 << 9;
+/// Every class of event, `A` in the flags
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED;
+
+const FLAG_CHARS: [(char, u32); 10] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('K', NOTIFY_KEYSPACE),
+    ('E', NOTIFY_KEYEVENT),
+];
+
+/// Parse flags like `KEA` or `Kgx`, the empty string disables notifications
+pub fn parse_flags(s: &str) -> Option<u32> {
+    let mut flags = 0;
+    for c in s.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            _ => FLAG_CHARS.iter().find(|(f, _)| *f == c)?.1,
+        };
+    }
+    Some(flags)
+}
+
+/// The inverse of `parse_flags`, using `A` where possible
+pub fn flags_to_string(flags: u32) -> String {
+    let mut s = String::new();
+    let all = flags & NOTIFY_ALL == NOTIFY_ALL;
+    if all {
+        s.push('A');
+    }
+    for (c, flag) in FLAG_CHARS {
+        if flags & flag != 0 && !(all && NOTIFY_ALL & flag != 0) {
+            s.push(c);
+        }
+    }
+    s
+}
+
+/// Something that happened to a key, waiting to be published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub event: &'static str,
+    pub key: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("KEA"), Some(NOTIFY_ALL | NOTIFY_KEYSPACE | NOTIFY_KEYEVENT));
+        assert_eq!(parse_flags("Kq"), None);
+        assert_eq!(flags_to_string(parse_flags("EAK").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("x$gK").unwrap()), "g$xK");
+        assert_eq!(flags_to_string(0), "");
+    }
+}
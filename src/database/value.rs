@@ -0,0 +1,641 @@
+//! What keys hold. Integers are stored inline and small collections in a
+//! single listpack; collections move to full data structures once they
+//! outgrow the limits in `EncodingLimits` and never move back.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use super::listpack::Listpack;
+
+/// Longest string stored along with its header by Redis, reported as the
+/// `embstr` encoding
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Bookkeeping cost of an element of a fully encoded collection on top of
+/// its bytes
+const ELEMENT_OVERHEAD: usize = 2 * std::mem::size_of::<Vec<u8>>();
+
+/// Largest collections kept in a listpack, as number of elements and
+/// length of the longest element, and largest sparse HyperLogLog in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodingLimits {
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    pub list_max_listpack_size: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+    pub hll_sparse_max_bytes: usize,
+}
+
+impl Default for EncodingLimits {
+    fn default() -> Self {
+        EncodingLimits {
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            list_max_listpack_size: 128,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+            hll_sparse_max_bytes: 3000,
+        }
+    }
+}
+
+impl EncodingLimits {
+    /// Names of the limits as config parameters
+    pub const PARAMS: [&'static str; 8] = [
+        "hash-max-listpack-entries",
+        "hash-max-listpack-value",
+        "list-max-listpack-size",
+        "set-max-listpack-entries",
+        "set-max-listpack-value",
+        "zset-max-listpack-entries",
+        "zset-max-listpack-value",
+        "hll-sparse-max-bytes",
+    ];
+
+    fn field(&mut self, param: &str) -> Option<&mut usize> {
+        match param {
+            "hash-max-listpack-entries" => Some(&mut self.hash_max_listpack_entries),
+            "hash-max-listpack-value" => Some(&mut self.hash_max_listpack_value),
+            "list-max-listpack-size" => Some(&mut self.list_max_listpack_size),
+            "set-max-listpack-entries" => Some(&mut self.set_max_listpack_entries),
+            "set-max-listpack-value" => Some(&mut self.set_max_listpack_value),
+            "zset-max-listpack-entries" => Some(&mut self.zset_max_listpack_entries),
+            "zset-max-listpack-value" => Some(&mut self.zset_max_listpack_value),
+            "hll-sparse-max-bytes" => Some(&mut self.hll_sparse_max_bytes),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, param: &str) -> Option<usize> {
+        let mut limits = *self;
+        limits.field(param).copied()
+    }
+
+    /// Returns false if there is no such limit
+    pub fn set(&mut self, param: &str, value: usize) -> bool {
+        self.field(param).map(|f| *f = value).is_some()
+    }
+}
+
+/// Fields and values of a hash
+pub type HashIter<'a> = Box<dyn Iterator<Item = (Cow<'a, [u8]>, Cow<'a, [u8]>)> + 'a>;
+
+fn element_size(bytes: &[u8]) -> usize {
+    bytes.len() + ELEMENT_OVERHEAD
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Str(Vec<u8>),
+    List(List),
+    Hash(Hash),
+    Set(Set),
+    Zset(Zset),
+}
+
+impl Value {
+    /// A string value, stored as an integer if it reads back the same
+    pub fn from_string(s: String) -> Value {
+        Value::from_bytes(s.into_bytes())
+    }
+
+    /// A binary string value, stored as an integer if it reads back the same
+    pub fn from_bytes(bytes: Vec<u8>) -> Value {
+        let n = std::str::from_utf8(&bytes).ok().and_then(|s| s.parse::<i64>().ok());
+        match n {
+            Some(n) if bytes.len() <= 20 && n.to_string().as_bytes() == bytes => Value::Int(n),
+            _ => Value::Str(bytes),
+        }
+    }
+
+    /// The bytes of a string, `None` for collections
+    pub fn as_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Value::Int(n) => Some(Cow::Owned(n.to_string().into_bytes())),
+            Value::Str(s) => Some(Cow::Borrowed(s)),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) | Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::Zset(_) => "zset",
+        }
+    }
+
+    /// Name of the encoding as reported by `object encoding`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Str(s) if s.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            Value::Str(_) => "raw",
+            Value::List(List::Packed(_))
+            | Value::Hash(Hash::Packed(_))
+            | Value::Set(Set::Packed(_))
+            | Value::Zset(Zset::Packed(_)) => "listpack",
+            Value::List(List::Full(..)) => "quicklist",
+            Value::Hash(Hash::Full(..)) | Value::Set(Set::Full(..)) => "hashtable",
+            Value::Zset(Zset::Full(..)) => "skiplist",
+        }
+    }
+
+    /// Estimated number of bytes taken beyond the value itself
+    pub fn memory(&self) -> usize {
+        match self {
+            Value::Int(_) => 0,
+            Value::Str(s) => s.len(),
+            Value::List(List::Packed(lp))
+            | Value::Hash(Hash::Packed(lp))
+            | Value::Set(Set::Packed(lp))
+            | Value::Zset(Zset::Packed(lp)) => lp.bytes(),
+            Value::List(List::Full(_, bytes))
+            | Value::Hash(Hash::Full(_, bytes))
+            | Value::Set(Set::Full(_, bytes))
+            | Value::Zset(Zset::Full(_, bytes)) => *bytes,
+        }
+    }
+}
+
+/// The full encodings carry the size of their elements, so that memory
+/// accounting doesn't have to walk them
+#[derive(Debug, Clone, PartialEq)]
+pub enum List {
+    Packed(Listpack),
+    Full(VecDeque<Vec<u8>>, usize),
+}
+
+impl Default for List {
+    fn default() -> Self {
+        List::Packed(Listpack::new())
+    }
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        match self {
+            List::Packed(lp) => lp.len(),
+            List::Full(list, _) => list.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, value: &[u8], front: bool, limits: &EncodingLimits) {
+        if let List::Packed(lp) = self {
+            if lp.len() < limits.list_max_listpack_size {
+                return if front { lp.push_front(value) } else { lp.push_back(value) };
+            }
+            let bytes = lp.iter().map(|i| element_size(&i.as_bytes())).sum();
+            *self = List::Full(lp.iter().map(|i| i.to_vec()).collect(), bytes);
+        }
+        if let List::Full(list, bytes) = self {
+            *bytes += element_size(value);
+            if front {
+                list.push_front(value.to_vec());
+            } else {
+                list.push_back(value.to_vec());
+            }
+        }
+    }
+
+    pub fn pop(&mut self, front: bool) -> Option<Vec<u8>> {
+        match self {
+            List::Packed(lp) if front => lp.pop_front(),
+            List::Packed(lp) => lp.pop_back(),
+            List::Full(list, bytes) => {
+                let value = if front { list.pop_front() } else { list.pop_back() }?;
+                *bytes -= element_size(&value);
+                Some(value)
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<Cow<'_, [u8]>> {
+        match self {
+            List::Packed(lp) => lp.get(index).map(|i| i.as_bytes()),
+            List::Full(list, _) => list.get(index).map(|v| Cow::Borrowed(v.as_slice())),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match self {
+            List::Packed(lp) => Box::new(lp.iter().map(|i| i.as_bytes())),
+            List::Full(list, _) => Box::new(list.iter().map(|v| Cow::Borrowed(v.as_slice()))),
+        }
+    }
+}
+
+/// Packed as field, value, field, value, ...
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hash {
+    Packed(Listpack),
+    Full(HashMap<Vec<u8>, Vec<u8>>, usize),
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::Packed(Listpack::new())
+    }
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Packed(lp) => lp.len() / 2,
+            Hash::Full(map, _) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Hash::Packed(lp) => lp.find(field, 2).and_then(|i| lp.get(i + 1)).map(|i| i.as_bytes()),
+            Hash::Full(map, _) => map.get(field).map(|v| Cow::Borrowed(v.as_slice())),
+        }
+    }
+
+    /// Returns whether `field` is new
+    pub fn insert(&mut self, field: &[u8], value: &[u8], limits: &EncodingLimits) -> bool {
+        if let Hash::Packed(lp) = self {
+            match lp.find(field, 2) {
+                Some(i) if value.len() <= limits.hash_max_listpack_value => {
+                    lp.replace(i + 1, value);
+                    return false;
+                }
+                None if lp.len() / 2 < limits.hash_max_listpack_entries
+                    && field.len().max(value.len()) <= limits.hash_max_listpack_value =>
+                {
+                    lp.push_back(field);
+                    lp.push_back(value);
+                    return true;
+                }
+                _ => self.convert(),
+            }
+        }
+        let Hash::Full(map, bytes) = self else {
+            unreachable!("converted above");
+        };
+        *bytes += element_size(value);
+        match map.insert(field.to_vec(), value.to_vec()) {
+            Some(old) => {
+                *bytes -= element_size(&old);
+                false
+            }
+            None => {
+                *bytes += element_size(field);
+                true
+            }
+        }
+    }
+
+    /// Returns false if there was no such field
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        match self {
+            Hash::Packed(lp) => lp.find(field, 2).map(|i| lp.remove(i, 2)).is_some(),
+            Hash::Full(map, bytes) => match map.remove(field) {
+                Some(value) => {
+                    *bytes -= element_size(field) + element_size(&value);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    pub fn iter(&self) -> HashIter<'_> {
+        match self {
+            Hash::Packed(lp) => {
+                let mut items = lp.iter();
+                Box::new(std::iter::from_fn(move || Some((items.next()?.as_bytes(), items.next()?.as_bytes()))))
+            }
+            Hash::Full(map, _) => Box::new(map.iter().map(|(f, v)| (Cow::Borrowed(f.as_slice()), Cow::Borrowed(v.as_slice())))),
+        }
+    }
+
+    fn convert(&mut self) {
+        let map = self.iter().map(|(f, v)| (f.into_owned(), v.into_owned())).collect::<HashMap<_, _>>();
+        let bytes = map.iter().map(|(f, v)| element_size(f) + element_size(v)).sum();
+        *self = Hash::Full(map, bytes);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Set {
+    Packed(Listpack),
+    Full(HashSet<Vec<u8>>, usize),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Packed(Listpack::new())
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Packed(lp) => lp.len(),
+            Set::Full(set, _) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Packed(lp) => lp.find(member, 1).is_some(),
+            Set::Full(set, _) => set.contains(member),
+        }
+    }
+
+    /// Returns false if `member` was already there
+    pub fn insert(&mut self, member: &[u8], limits: &EncodingLimits) -> bool {
+        if self.contains(member) {
+            return false;
+        }
+        if let Set::Packed(lp) = self {
+            if lp.len() < limits.set_max_listpack_entries && member.len() <= limits.set_max_listpack_value {
+                lp.push_back(member);
+                return true;
+            }
+            let set = lp.iter().map(|i| i.to_vec()).collect::<HashSet<_>>();
+            let bytes = set.iter().map(|m| element_size(m)).sum();
+            *self = Set::Full(set, bytes);
+        }
+        if let Set::Full(set, bytes) = self {
+            *bytes += element_size(member);
+            set.insert(member.to_vec());
+        }
+        true
+    }
+
+    /// Returns false if there was no such member
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Packed(lp) => lp.find(member, 1).map(|i| lp.remove(i, 1)).is_some(),
+            Set::Full(set, bytes) => {
+                let removed = set.remove(member);
+                if removed {
+                    *bytes -= element_size(member);
+                }
+                removed
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match self {
+            Set::Packed(lp) => Box::new(lp.iter().map(|i| i.as_bytes())),
+            Set::Full(set, _) => Box::new(set.iter().map(|m| Cow::Borrowed(m.as_slice()))),
+        }
+    }
+}
+
+/// A score ordered with `f64::total_cmp`, so that it can key a `BTreeSet`
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score then by member, with a map for score lookups
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+/// Packed as member, score, member, score, ... in order
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zset {
+    Packed(Listpack),
+    Full(SortedSet, usize),
+}
+
+impl Default for Zset {
+    fn default() -> Self {
+        Zset::Packed(Listpack::new())
+    }
+}
+
+fn parse_score(bytes: &[u8]) -> f64 {
+    std::str::from_utf8(bytes).ok().and_then(|s| s.parse().ok()).expect("scores are stored as numbers")
+}
+
+impl Zset {
+    pub fn len(&self) -> usize {
+        match self {
+            Zset::Packed(lp) => lp.len() / 2,
+            Zset::Full(zset, _) => zset.scores.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Zset::Packed(lp) => lp.find(member, 2).and_then(|i| lp.get(i + 1)).map(|s| parse_score(&s.as_bytes())),
+            Zset::Full(zset, _) => zset.scores.get(member).copied(),
+        }
+    }
+
+    /// Add `member` or change its score, returns whether it is new
+    pub fn insert(&mut self, member: &[u8], score: f64, limits: &EncodingLimits) -> bool {
+        let new = !self.remove(member);
+        if let Zset::Packed(lp) = self {
+            if lp.len() / 2 < limits.zset_max_listpack_entries && member.len() <= limits.zset_max_listpack_value {
+                let key = (Score(score), member);
+                let pos = (0..lp.len() / 2)
+                    .find(|&i| {
+                        let m = lp.get(2 * i).unwrap().as_bytes();
+                        let s = parse_score(&lp.get(2 * i + 1).unwrap().as_bytes());
+                        (Score(s), &*m) > key
+                    })
+                    .unwrap_or(lp.len() / 2);
+                lp.insert(2 * pos, member);
+                lp.insert(2 * pos + 1, score.to_string().as_bytes());
+                return new;
+            }
+            let mut zset = SortedSet::default();
+            let bytes = self.iter().map(|(m, _)| element_size(&m)).sum();
+            for (m, s) in self.iter() {
+                zset.scores.insert(m.to_vec(), s);
+                zset.order.insert((Score(s), m.into_owned()));
+            }
+            *self = Zset::Full(zset, bytes);
+        }
+        if let Zset::Full(zset, bytes) = self {
+            *bytes += element_size(member);
+            zset.scores.insert(member.to_vec(), score);
+            zset.order.insert((Score(score), member.to_vec()));
+        }
+        new
+    }
+
+    /// Returns false if there was no such member
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Zset::Packed(lp) => lp.find(member, 2).map(|i| lp.remove(i, 2)).is_some(),
+            Zset::Full(zset, bytes) => match zset.scores.remove(member) {
+                Some(score) => {
+                    zset.order.remove(&(Score(score), member.to_vec()));
+                    *bytes -= element_size(member);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Members and scores in ascending order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Cow<'_, [u8]>, f64)> + '_> {
+        match self {
+            Zset::Packed(lp) => {
+                let mut items = lp.iter();
+                Box::new(std::iter::from_fn(move || {
+                    Some((items.next()?.as_bytes(), parse_score(&items.next()?.as_bytes())))
+                }))
+            }
+            Zset::Full(zset, _) => Box::new(zset.order.iter().map(|(s, m)| (Cow::Borrowed(m.as_slice()), s.0))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(entries: usize) -> EncodingLimits {
+        let mut limits = EncodingLimits::default();
+        for param in EncodingLimits::PARAMS.iter().filter(|p| !p.ends_with("-value")) {
+            assert!(limits.set(param, entries));
+        }
+        limits
+    }
+
+    #[test]
+    fn test_strings() {
+        for (s, encoding) in [("123", "int"), ("-5", "int"), ("0123", "embstr"), ("1e3", "embstr")] {
+            let value = Value::from_string(s.to_string());
+            assert_eq!((value.encoding(), value.type_name()), (encoding, "string"));
+            assert_eq!(value.as_bytes().unwrap(), s.as_bytes());
+        }
+        assert_eq!(Value::from_string("x".repeat(45)).encoding(), "raw");
+        assert_eq!(Value::from_bytes(vec![0xff, b'1']).encoding(), "embstr");
+        assert_eq!(Value::List(List::default()).as_bytes(), None);
+    }
+
+    #[test]
+    fn test_list_conversion() {
+        let limits = limits(3);
+        let mut list = List::default();
+        for v in ["b", "c", "1"] {
+            list.push(v.as_bytes(), false, &limits);
+        }
+        list.push(b"a", true, &limits);
+        let value = Value::List(list.clone());
+        assert_eq!(value.encoding(), "quicklist");
+        assert_eq!(list.iter().collect::<Vec<_>>(), [&b"a"[..], b"b", b"c", b"1"]);
+        assert_eq!(list.get(3).unwrap(), &b"1"[..]);
+        assert_eq!(list.pop(true), Some(b"a".to_vec()));
+        assert_eq!(list.pop(false), Some(b"1".to_vec()));
+        assert_eq!(Value::List(list).memory(), 2 * element_size(b"b"));
+    }
+
+    #[test]
+    fn test_hash_conversion() {
+        let limits = limits(2);
+        let mut hash = Hash::default();
+        assert!(hash.insert(b"f1", b"v1", &limits));
+        assert!(!hash.insert(b"f1", b"10", &limits));
+        hash.insert(b"f2", b"v2", &limits);
+        assert_eq!((hash.len(), Value::Hash(hash.clone()).encoding()), (2, "listpack"));
+        assert_eq!(hash.get(b"f1").unwrap(), &b"10"[..]);
+
+        // Too long a value converts as well as too many fields
+        let mut long = hash.clone();
+        long.insert(b"f1", &[b'x'; 65], &limits);
+        assert_eq!(Value::Hash(long).encoding(), "hashtable");
+
+        assert!(hash.insert(b"f3", b"v3", &limits));
+        assert!(hash.remove(b"f1"));
+        assert!(!hash.remove(b"f1"));
+        let value = Value::Hash(hash.clone());
+        assert_eq!((value.encoding(), value.memory()), ("hashtable", 4 * element_size(b"f2")));
+        let mut fields = hash.iter().map(|(f, _)| f.into_owned()).collect::<Vec<_>>();
+        fields.sort();
+        assert_eq!(fields, [b"f2", b"f3"]);
+    }
+
+    #[test]
+    fn test_set_conversion() {
+        let limits = limits(2);
+        let mut set = Set::default();
+        assert!(set.insert(b"1", &limits));
+        assert!(!set.insert(b"1", &limits));
+        assert!(set.insert(b"a", &limits));
+        assert_eq!(Value::Set(set.clone()).encoding(), "listpack");
+        assert!(set.insert(b"b", &limits));
+        assert_eq!(Value::Set(set.clone()).encoding(), "hashtable");
+        assert!(set.contains(b"1") && set.remove(b"1") && !set.contains(b"1"));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_zset_conversion() {
+        let limits = limits(3);
+        let mut zset = Zset::default();
+        assert!(zset.insert(b"b", 2.0, &limits));
+        assert!(zset.insert(b"a", 2.0, &limits));
+        assert!(zset.insert(b"c", 0.5, &limits));
+        assert!(!zset.insert(b"c", 3.0, &limits));
+        let order = |z: &Zset| z.iter().map(|(m, s)| (String::from_utf8(m.to_vec()).unwrap(), s)).collect::<Vec<_>>();
+        let expected = [("a", 2.0), ("b", 2.0), ("c", 3.0)].map(|(m, s)| (m.to_string(), s));
+        assert_eq!(order(&zset), expected);
+        assert_eq!(Value::Zset(zset.clone()).encoding(), "listpack");
+
+        assert!(zset.insert(b"d", -1.5, &limits));
+        assert_eq!(Value::Zset(zset.clone()).encoding(), "skiplist");
+        assert_eq!(order(&zset)[0], ("d".to_string(), -1.5));
+        assert_eq!(zset.score(b"c"), Some(3.0));
+        assert!(zset.remove(b"d") && !zset.remove(b"d"));
+        assert_eq!(order(&zset), expected);
+    }
+}
@@ -0,0 +1,143 @@
+//! Geohash encoding of coordinates into sorted set scores, and distances on
+//! the earth's surface, as used by the GEO commands
+
+/// Bits of precision per coordinate, 52 in total fit exactly in an `f64`
+pub const STEP: u32 = 26;
+
+pub const LON_MIN: f64 = -180.0;
+pub const LON_MAX: f64 = 180.0;
+/// The limits of the Web Mercator projection
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+
+/// Mean radius used for distances, in meters
+const EARTH_RADIUS: f64 = 6372797.560856;
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Distance units, by how many meters they are
+pub fn unit_factor(unit: &str) -> Option<f64> {
+    match unit.to_ascii_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "mi" => Some(1609.34),
+        "ft" => Some(0.3048),
+        _ => None,
+    }
+}
+
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// Latitude bits go to the even positions and longitude bits to the odd
+/// ones, so that close cells share a prefix
+fn interleave(lat: u32, lon: u32) -> u64 {
+    (0..STEP).fold(0, |bits, i| {
+        bits | (((lat >> i) & 1) as u64) << (2 * i) | (((lon >> i) & 1) as u64) << (2 * i + 1)
+    })
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    (0..STEP).fold((0, 0), |(lat, lon), i| {
+        (lat | (((bits >> (2 * i)) & 1) as u32) << i, lon | (((bits >> (2 * i + 1)) & 1) as u32) << i)
+    })
+}
+
+fn encode_in(lon: f64, lat: f64, lat_min: f64, lat_max: f64) -> u64 {
+    let cells = (1u64 << STEP) as f64;
+    let cell = |v: f64, min: f64, max: f64| (((v - min) / (max - min) * cells) as u64).min((1 << STEP) - 1) as u32;
+    interleave(cell(lat, lat_min, lat_max), cell(lon, LON_MIN, LON_MAX))
+}
+
+/// The 52-bit cell of a valid pair of coordinates
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    encode_in(lon, lat, LAT_MIN, LAT_MAX)
+}
+
+/// Longitude and latitude of the center of the cell `bits`
+pub fn decode(bits: u64) -> (f64, f64) {
+    let (lat, lon) = deinterleave(bits);
+    let cells = (1u64 << STEP) as f64;
+    let center = |i: u32, min: f64, max: f64| {
+        let size = (max - min) / cells;
+        (min + (i as f64 + 0.5) * size).clamp(min, max)
+    };
+    (center(lon, LON_MIN, LON_MAX), center(lat, LAT_MIN, LAT_MAX))
+}
+
+/// The standard 11 character geohash of the cell `bits`. The standard one
+/// spans latitudes -90 to 90, so the center gets encoded again.
+pub fn to_string(bits: u64) -> String {
+    let (lon, lat) = decode(bits);
+    let bits = encode_in(lon, lat, -90.0, 90.0);
+    (0..11)
+        .map(|i| {
+            // 55 bits are needed, the last character only gets zeros
+            let index = if i == 10 { 0 } else { (bits >> (2 * STEP - (i + 1) * 5)) & 0x1f };
+            BASE32[index as usize] as char
+        })
+        .collect()
+}
+
+/// Distance in meters along the surface between two points
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    if v == 0.0 {
+        return EARTH_RADIUS * (lat2 - lat1).abs();
+    }
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Distance from the center of a box `width` by `height` meters to a point
+/// inside it, `None` if it is outside
+pub fn distance_in_box(center: (f64, f64), width: f64, height: f64, point: (f64, f64)) -> Option<f64> {
+    let (lon, lat) = center;
+    let (x, y) = point;
+    if distance(x, lat, x, y) > height / 2.0 || distance(lon, y, x, y) > width / 2.0 {
+        return None;
+    }
+    Some(distance(lon, lat, x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn test_encode() {
+        let bits = encode(PALERMO.0, PALERMO.1);
+        assert_eq!(bits, 3479099956230698);
+        assert!(bits < 1 << 52);
+        let (lon, lat) = decode(bits);
+        assert!((lon - 13.361389338970184).abs() < 1e-12 && (lat - 38.1155563954963).abs() < 1e-12);
+        assert_eq!(to_string(bits), "sqc8b49rny0");
+        assert_eq!(to_string(encode(CATANIA.0, CATANIA.1)), "sqdtr74hyu0");
+
+        for (lon, lat) in [(LON_MIN, LAT_MIN), (LON_MAX, LAT_MAX), (0.0, 0.0)] {
+            let (x, y) = decode(encode(lon, lat));
+            assert!((x - lon).abs() < 1e-5 && (y - lat).abs() < 1e-5);
+        }
+        assert!(!is_valid(181.0, 0.0) && !is_valid(0.0, 86.0));
+    }
+
+    #[test]
+    fn test_distance() {
+        let d = distance(PALERMO.0, PALERMO.1, CATANIA.0, CATANIA.1);
+        assert!((d - 166274.15).abs() < 1.0, "{}", d);
+        assert!((distance(0.0, 0.0, 0.0, 1.0) - 111226.3).abs() < 1.0);
+
+        assert_eq!(unit_factor("KM"), Some(1000.0));
+        assert_eq!(unit_factor("yd"), None);
+
+        let center = (15.0, 37.0);
+        assert!(distance_in_box(center, 400_000.0, 400_000.0, CATANIA).is_some());
+        assert!(distance_in_box(center, 400_000.0, 100_000.0, PALERMO).is_none());
+    }
+}
@@ -0,0 +1,115 @@
+//! Glob-style pattern matching as used by KEYS, ACL key patterns and
+//! PSUBSCRIBE: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, i));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                true
+            }
+            Some(b'[') => match match_class(pattern, p + 1, s[i]) {
+                Some((true, end)) => {
+                    p = end;
+                    true
+                }
+                _ => false,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => {
+                p += 2;
+                pattern[p - 1] == s[i]
+            }
+            Some(&c) => {
+                p += 1;
+                c == s[i]
+            }
+            None => false,
+        };
+
+        if matched {
+            i += 1;
+        } else if let Some((bp, bi)) = backtrack {
+            p = bp;
+            i = bi + 1;
+            backtrack = Some((bp, bi + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the class starting after `[` at `start`. Returns
+/// whether it matched and the index after the closing `]`, `None` if the
+/// class isn't closed.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut p = start;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern.get(p)? {
+            b']' => return Some((matched != negate, p + 1)),
+            b'\\' => {
+                p += 1;
+                matched |= *pattern.get(p)? == c;
+            }
+            &lo if pattern.get(p + 1) == Some(&b'-') && pattern.get(p + 2).is_some_and(|&hi| hi != b']') => {
+                let hi = pattern[p + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= (lo..=hi).contains(&c);
+                p += 2;
+            }
+            &other => matched |= other == c,
+        }
+        p += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        glob_match(pattern.as_bytes(), s.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "users:42"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("*a*b", "xxaxxb"));
+        assert!(!matches("*a*b", "xxaxxbx"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(!matches("h[ab", "ha"));
+    }
+}
@@ -1,17 +1,27 @@
 use std::net::TcpStream;
 use std::io::{Read, Write};
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ResponseStatus {
     Ok,
     Err,
     Nx
 }
 
+pub mod acl;
+pub mod client;
+pub mod cluster;
+pub mod command;
 pub mod connection;
 pub mod database;
-
-use crate::connection::MAX_MSG;
+pub mod geo;
+pub mod glob;
+pub mod log;
+pub mod protocol;
+pub mod scripting;
+pub mod sentinel;
+pub mod server;
+pub mod tls;
 
 //use std::{io::{Read, Write}, net::{TcpStream}};
 
@@ -74,48 +84,3 @@ pub fn write_all(stream: &mut TcpStream, buf: &[u8], n: usize) -> bool {
 //     write_all(stream, &buf, 4 + reply.len())
 // }
 //
-pub fn send_req(stream: &mut TcpStream, text: &str) -> bool {
-    let mut buf: [u8; 4 + MAX_MSG] = [0; 4 + MAX_MSG];
-
-    let split = text.split_whitespace().collect::<Vec<&str>>();
-
-    let num_args = (split.len() as u32).to_le_bytes();
-    buf[4..8].copy_from_slice(&num_args);
-
-    let mut pos = 8usize;
-    for s in split {
-        let arg_bytes = s.as_bytes();
-        let len_arg = arg_bytes.len() as u32;
-        buf[pos..pos+4].copy_from_slice(&len_arg.to_le_bytes());
-        pos += 4;
-        buf[pos..pos+arg_bytes.len()].copy_from_slice(arg_bytes);
-        pos += arg_bytes.len();
-    }
-    buf[0..4].copy_from_slice(&((pos - 4) as u32).to_le_bytes());
-
-    write_all(stream, &buf, pos)
-}
-
-pub fn read_res(stream: &mut TcpStream) -> bool {
-    let mut buf: [u8; 4 + MAX_MSG] = [0; 4 + MAX_MSG];
-
-    let err = read_full(stream, &mut buf, 4);
-    if !err {
-        eprintln!("Error reading buffer length");
-        return false;
-    }
-
-    let len = u32::from_le_bytes(buf[0..4].try_into().expect("Must be a 4 byte array"));
-    let err = read_full(stream, &mut buf[4..], len as usize);
-    if !err {
-        eprintln!("Error reading message");
-        return false;
-    }
-    let status = u32::from_le_bytes(buf[4..8].try_into().expect("Must be a 4 byte array"));
-
-    let msg = String::from_utf8_lossy(&buf[8..]);
-    println!("Server says: ({}, '{}')", status, msg);
-
-    true
-}
-//
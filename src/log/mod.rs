@@ -0,0 +1,266 @@
+//! Leveled logging. Lines below the current level cost a single atomic
+//! load, the message isn't even formatted.
+//!
+//! Use the `debug!`, `verbose!`, `notice!` and `warning!` macros.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug = 0,
+    Verbose = 1,
+    Notice = 2,
+    Warning = 3,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        }
+    }
+
+    fn from_u8(n: u8) -> Level {
+        match n {
+            0 => Level::Debug,
+            1 => Level::Verbose,
+            2 => Level::Notice,
+            _ => Level::Warning,
+        }
+    }
+
+    /// Marker Redis puts in front of the message
+    fn marker(&self) -> char {
+        match self {
+            Level::Debug => '.',
+            Level::Verbose => '-',
+            Level::Notice => '*',
+            Level::Warning => '#',
+        }
+    }
+
+    /// Syslog severity
+    fn severity(&self) -> u8 {
+        match self {
+            Level::Debug => 7,
+            Level::Verbose => 6,
+            Level::Notice => 5,
+            Level::Warning => 4,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "verbose" => Ok(Level::Verbose),
+            "notice" => Ok(Level::Notice),
+            "warning" => Ok(Level::Warning),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `pid:M 19 Oct 2026 12:00:00.000 * message`
+    Redis,
+    /// `<pri>Oct 19 12:00:00 redis[pid]: message`, for syslog daemons
+    /// reading a file or pipe
+    Syslog,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "redis" => Ok(Format::Redis),
+            "syslog" => Ok(Format::Syslog),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Output {
+    format: Format,
+    writer: Box<dyn Write + Send>,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+static OUTPUT: Mutex<Option<Output>> = Mutex::new(None);
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Can be changed at any time, from any thread
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Log to `writer` instead of stdout
+pub fn set_writer(writer: Box<dyn Write + Send>, format: Format) {
+    *OUTPUT.lock().unwrap() = Some(Output { format, writer });
+}
+
+/// Log to stdout if `path` is empty, otherwise append to the file at `path`
+pub fn set_output(path: &str, format: Format) -> io::Result<()> {
+    let writer: Box<dyn Write + Send> = if path.is_empty() {
+        Box::new(io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    set_writer(writer, format);
+    Ok(())
+}
+
+/// Called by the macros once the level check passed
+pub fn log(level: Level, args: fmt::Arguments) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut output = OUTPUT.lock().unwrap();
+    let format = output.as_ref().map_or(Format::Redis, |o| o.format);
+    let line = format_line(format, level, now.as_millis() as u64, std::process::id(), args);
+    // Nowhere to report a failure to log
+    let _ = match output.as_mut() {
+        Some(output) => output.writer.write_all(line.as_bytes()).and_then(|_| output.writer.flush()),
+        None => io::stdout().write_all(line.as_bytes()),
+    };
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Year, month (1-12) and day of a count of days since the epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn format_line(format: Format, level: Level, now_ms: u64, pid: u32, args: fmt::Arguments) -> String {
+    let secs = now_ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let month = MONTHS[month as usize - 1];
+    let (h, m, s) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    match format {
+        Format::Redis => format!(
+            "{}:M {:02} {} {} {:02}:{:02}:{:02}.{:03} {} {}\n",
+            pid,
+            day,
+            month,
+            year,
+            h,
+            m,
+            s,
+            now_ms % 1000,
+            level.marker(),
+            args
+        ),
+        // Facility "user"
+        Format::Syslog => format!(
+            "<{}>{} {:>2} {:02}:{:02}:{:02} redis[{}]: {}\n",
+            8 + level.severity(),
+            month,
+            day,
+            h,
+            m,
+            s,
+            pid,
+            args
+        ),
+    }
+}
+
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::log($level, format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::Level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::Level::Verbose, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::Level::Notice, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::Level::Warning, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        // 2026-10-19 13:05:09.042 UTC
+        let now = 1792415109042;
+        assert_eq!(
+            format_line(Format::Redis, Level::Notice, now, 42, format_args!("Ready {}", 1)),
+            "42:M 19 Oct 2026 13:05:09.042 * Ready 1\n"
+        );
+        assert_eq!(
+            format_line(Format::Syslog, Level::Warning, now, 42, format_args!("Oops")),
+            "<12>Oct 19 13:05:09 redis[42]: Oops\n"
+        );
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!("VERBOSE".parse::<Level>(), Ok(Level::Verbose));
+        assert!("loud".parse::<Level>().is_err());
+        assert!(Level::Debug < Level::Warning);
+
+        set_level(Level::Warning);
+        assert_eq!(level(), Level::Warning);
+        assert!(enabled(Level::Warning));
+        assert!(!enabled(Level::Notice));
+        set_level(Level::Notice);
+    }
+}
@@ -0,0 +1,135 @@
+//! Serialization of replies. Every reply frame is the length of the body
+//! followed by a single tagged value. Push frames are sent by the server on
+//! its own rather than in reply to a request:
+//!
+//! | tag | layout                                     |
+//! |-----|--------------------------------------------|
+//! | NIL | -                                          |
+//! | ERR | code len, code, message len, message       |
+//! | STR | len, bytes                                 |
+//! | INT | i64                                        |
+//! | ARR | number of elements, elements               |
+//! | PSH | number of elements, elements               |
+//!
+//! All lengths are u32, all integers little endian.
+
+use std::fmt;
+
+pub const TAG_NIL: u8 = 0;
+pub const TAG_ERR: u8 = 1;
+pub const TAG_STR: u8 = 2;
+pub const TAG_INT: u8 = 3;
+pub const TAG_ARR: u8 = 4;
+pub const TAG_PSH: u8 = 5;
+
+/// Category of an error reply, sent as its first word like Redis does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Err,
+    WrongType,
+    NoScript,
+    Oom,
+    Busy,
+    NotBusy,
+    Unkillable,
+    NoAuth,
+    NoPerm,
+    WrongPass,
+    NoProto,
+    Moved,
+    Ask,
+    TryAgain,
+    CrossSlot,
+    ClusterDown,
+    BusyKey,
+    IoErr,
+    ReadOnly,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Err => "ERR",
+            ErrorCode::WrongType => "WRONGTYPE",
+            ErrorCode::NoScript => "NOSCRIPT",
+            ErrorCode::Oom => "OOM",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::NotBusy => "NOTBUSY",
+            ErrorCode::Unkillable => "UNKILLABLE",
+            ErrorCode::NoAuth => "NOAUTH",
+            ErrorCode::NoPerm => "NOPERM",
+            ErrorCode::WrongPass => "WRONGPASS",
+            ErrorCode::NoProto => "NOPROTO",
+            ErrorCode::Moved => "MOVED",
+            ErrorCode::Ask => "ASK",
+            ErrorCode::TryAgain => "TRYAGAIN",
+            ErrorCode::CrossSlot => "CROSSSLOT",
+            ErrorCode::ClusterDown => "CLUSTERDOWN",
+            ErrorCode::BusyKey => "BUSYKEY",
+            ErrorCode::IoErr => "IOERR",
+            ErrorCode::ReadOnly => "READONLY",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn out_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+pub fn out_nil(out: &mut Vec<u8>) {
+    out.push(TAG_NIL);
+}
+
+pub fn out_err(out: &mut Vec<u8>, code: ErrorCode, msg: &str) {
+    out_err_code(out, code.as_str(), msg);
+}
+
+/// Error reply with a code that isn't one of ours, like those raised by scripts
+pub fn out_err_code(out: &mut Vec<u8>, code: &str, msg: &str) {
+    out.push(TAG_ERR);
+    out_len(out, code.len());
+    out.extend_from_slice(code.as_bytes());
+    out_len(out, msg.len());
+    out.extend_from_slice(msg.as_bytes());
+}
+
+pub fn out_str(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(TAG_STR);
+    out_len(out, s.len());
+    out.extend_from_slice(s);
+}
+
+pub fn out_int(out: &mut Vec<u8>, n: i64) {
+    out.push(TAG_INT);
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Start an array, the caller then writes `n` values
+pub fn out_arr(out: &mut Vec<u8>, n: usize) {
+    out.push(TAG_ARR);
+    out_len(out, n);
+}
+
+/// Start a push frame, the caller then writes `n` values
+pub fn out_push(out: &mut Vec<u8>, n: usize) {
+    out.push(TAG_PSH);
+    out_len(out, n);
+}
+
+pub fn out_ok(out: &mut Vec<u8>) {
+    out_str(out, b"OK");
+}
+
+pub fn out_wrong_arity(out: &mut Vec<u8>, cmd: &str) {
+    out_err(out, ErrorCode::Err, &format!("wrong number of arguments for '{}' command", cmd));
+}
+
+pub fn out_not_int(out: &mut Vec<u8>) {
+    out_err(out, ErrorCode::Err, "value is not an integer or out of range");
+}
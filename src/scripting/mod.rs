@@ -0,0 +1,403 @@
+//! Lua scripting: the interpreter, the script cache and the `redis` library
+//! scripts use to call back into the server
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mlua::{Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Value, Variadic};
+use sha1::{Digest, Sha1};
+
+use crate::client::Reply;
+use crate::command::{self, Context, CMD_NOSCRIPT, CMD_WRITE};
+use crate::protocol::*;
+
+pub const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(5);
+
+/// How often the interpreter checks whether the time limit has passed
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+/// Deepest table nesting converted to a reply
+const MAX_DEPTH: usize = 64;
+
+const KILLED_MSG: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub struct Scripting {
+    lua: Lua,
+    /// Compiled scripts by the SHA1 of their source
+    scripts: HashMap<String, RegistryKey>,
+    /// Scripts running longer than this let other clients in for `script kill`
+    time_limit: Duration,
+}
+
+impl Scripting {
+    pub fn new(time_limit: Duration) -> Scripting {
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH;
+        let lua = Lua::new_with(libs, LuaOptions::default()).expect("Couldn't create Lua state");
+        Scripting::init_redis_lib(&lua).expect("Couldn't set up the redis Lua library");
+        Scripting {
+            lua,
+            scripts: HashMap::new(),
+            time_limit,
+        }
+    }
+
+    /// The parts of the `redis` table that don't need the database,
+    /// `call` and `pcall` are bound for each run
+    fn init_redis_lib(lua: &Lua) -> mlua::Result<()> {
+        let redis = lua.create_table()?;
+        redis.set(
+            "sha1hex",
+            lua.create_function(|_, s: mlua::String| Ok(sha1_hex(s.as_bytes())))?,
+        )?;
+        redis.set(
+            "error_reply",
+            lua.create_function(|lua, msg: String| lua.create_table_from([("err", msg)]))?,
+        )?;
+        redis.set(
+            "status_reply",
+            lua.create_function(|lua, msg: String| lua.create_table_from([("ok", msg)]))?,
+        )?;
+        lua.globals().set("redis", redis)
+    }
+
+    /// Compile `source` and cache it, returns its SHA1
+    pub fn load(&mut self, source: &[u8]) -> Result<String, String> {
+        let sha = sha1_hex(source);
+        if self.scripts.contains_key(&sha) {
+            return Ok(sha);
+        }
+        let func = self
+            .lua
+            .load(source)
+            .set_name(format!("@user_script:{}", sha))
+            .into_function()
+            .map_err(|e| format!("Error compiling script: {}", root_message(&e)))?;
+        let key = self
+            .lua
+            .create_registry_value(func)
+            .map_err(|e| format!("Error compiling script: {}", e))?;
+        self.scripts.insert(sha.clone(), key);
+        Ok(sha)
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts.contains_key(&sha.to_ascii_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    pub fn flush(&mut self) {
+        self.scripts.clear();
+        self.lua.expire_registry_values();
+    }
+
+    /// Run the cached script `sha` with `KEYS` and `ARGV` set, `redis.call`
+    /// runs commands against `ctx`. The reply goes to `out`.
+    pub fn run(&self, ctx: &mut Context, sha: &str, keys: &[Vec<u8>], argv: &[Vec<u8>], out: &mut Vec<u8>) {
+        let Some(key) = self.scripts.get(&sha.to_ascii_lowercase()) else {
+            return out_err(out, ErrorCode::NoScript, "No matching script. Please use EVAL.");
+        };
+
+        // Once a write went through the script can't be undone, so it must
+        // not be killed either
+        let killable = Rc::new(Cell::new(true));
+        if let Some(on_busy) = ctx.on_busy.clone() {
+            let killable = killable.clone();
+            let start = Instant::now();
+            let limit = self.time_limit;
+            self.lua.set_hook(
+                HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+                move |_, _| {
+                    if start.elapsed() >= limit && on_busy(killable.get()) {
+                        return Err(mlua::Error::RuntimeError(KILLED_MSG.to_string()));
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        // A `select` in the script doesn't affect the caller
+        let db_index = ctx.db_index;
+        let ctx = RefCell::new(ctx);
+        let result = self.lua.scope(|scope| {
+            let globals = self.lua.globals();
+            let strings =
+                |args: &[Vec<u8>]| args.iter().map(|a| self.lua.create_string(a)).collect::<mlua::Result<Vec<_>>>();
+            globals.set("KEYS", strings(keys)?)?;
+            globals.set("ARGV", strings(argv)?)?;
+            let redis: mlua::Table = globals.get("redis")?;
+            redis.set(
+                "call",
+                scope.create_function(|lua, args| call(lua, &ctx, &killable, args, true))?,
+            )?;
+            redis.set(
+                "pcall",
+                scope.create_function(|lua, args| call(lua, &ctx, &killable, args, false))?,
+            )?;
+
+            let func: Function = self.lua.registry_value(key)?;
+            let value: Value = func.call(())?;
+            let mut reply = vec![];
+            lua_to_reply(&value, &mut reply, 0);
+            Ok(reply)
+        });
+        self.lua.remove_hook();
+        ctx.into_inner().db_index = db_index;
+
+        match result {
+            Ok(reply) => out.extend_from_slice(&reply),
+            Err(e) => out_err_message(out, &root_message(&e), "Error running script: "),
+        }
+    }
+}
+
+/// The innermost error message, without Lua tracebacks
+fn root_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => root_message(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        e => e.to_string(),
+    }
+    .lines()
+    .next()
+    .unwrap_or_default()
+    .to_string()
+}
+
+/// Write an error reply for `msg`, using its first word as the code if it
+/// looks like one, otherwise `ERR` with `prefix` prepended to the message
+fn out_err_message(out: &mut Vec<u8>, msg: &str, prefix: &str) {
+    match msg.split_once(' ') {
+        Some((code, rest)) if code.len() > 1 && code.bytes().all(|b| b.is_ascii_uppercase()) => {
+            out_err_code(out, code, rest)
+        }
+        _ => out_err(out, ErrorCode::Err, &format!("{}{}", prefix, msg)),
+    }
+}
+
+/// `redis.call` raises errors, `redis.pcall` returns them as `{err = ...}`
+fn script_error(lua: &Lua, raise: bool, msg: String) -> mlua::Result<Value<'_>> {
+    if raise {
+        Err(mlua::Error::RuntimeError(msg))
+    } else {
+        Ok(Value::Table(lua.create_table_from([("err", msg)])?))
+    }
+}
+
+fn call<'lua>(
+    lua: &'lua Lua,
+    ctx: &RefCell<&mut Context>,
+    killable: &Cell<bool>,
+    args: Variadic<Value<'lua>>,
+    raise: bool,
+) -> mlua::Result<Value<'lua>> {
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        match arg {
+            Value::String(s) => argv.push(s.as_bytes().to_vec()),
+            Value::Integer(n) => argv.push(n.to_string().into_bytes()),
+            Value::Number(n) => argv.push(n.to_string().into_bytes()),
+            _ => {
+                let msg = "ERR Lua redis lib command arguments must be strings or integers";
+                return script_error(lua, raise, msg.to_string());
+            }
+        }
+    }
+    if argv.is_empty() {
+        let msg = "ERR Please specify at least one argument for this redis lib call";
+        return script_error(lua, raise, msg.to_string());
+    }
+
+    let Some(cmd) = std::str::from_utf8(&argv[0]).ok().and_then(command::lookup) else {
+        return script_error(lua, raise, "ERR Unknown Redis command called from script".to_string());
+    };
+    if cmd.has_flag(CMD_NOSCRIPT) {
+        return script_error(lua, raise, "ERR This Redis command is not allowed from script".to_string());
+    }
+    if cmd.has_flag(CMD_WRITE) {
+        killable.set(false);
+    }
+
+    // Only the script as a whole goes to the slow log
+    let mut ctx = ctx.borrow_mut();
+    let slowlog = ctx.slowlog.take();
+    let mut out = vec![];
+    command::execute(&mut ctx, argv, &mut out);
+    ctx.slowlog = slowlog;
+    let reply = Reply::decode(&out).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+    reply_to_lua(lua, reply, raise)
+}
+
+fn reply_to_lua(lua: &Lua, reply: Reply, raise: bool) -> mlua::Result<Value<'_>> {
+    Ok(match reply {
+        Reply::Nil => Value::Boolean(false),
+        Reply::Str(s) => Value::String(lua.create_string(&s)?),
+        Reply::Int(n) => Value::Integer(n),
+        Reply::Error { code, message } => return script_error(lua, raise, format!("{} {}", code, message)),
+        Reply::Array(items) | Reply::Push(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                // Errors nested in arrays are values, not failures
+                table.raw_set(i + 1, reply_to_lua(lua, item, false)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+/// Convert what a script returned using the Redis rules: numbers are
+/// truncated to integers, false is nil, tables are arrays up to the first
+/// nil unless they have an `err` or `ok` field
+fn lua_to_reply(value: &Value, out: &mut Vec<u8>, depth: usize) {
+    match value {
+        Value::Boolean(true) => out_int(out, 1),
+        Value::Integer(n) => out_int(out, *n),
+        Value::Number(n) => out_int(out, *n as i64),
+        Value::String(s) => out_str(out, s.as_bytes()),
+        Value::Table(_) if depth >= MAX_DEPTH => {
+            out_err(out, ErrorCode::Err, "reached lua stack limit")
+        }
+        Value::Table(t) => {
+            if let Ok(Value::String(msg)) = t.raw_get::<_, Value>("err") {
+                return out_err_message(out, &msg.to_string_lossy(), "");
+            }
+            if let Ok(Value::String(status)) = t.raw_get::<_, Value>("ok") {
+                return out_str(out, status.as_bytes());
+            }
+            let mut items = vec![];
+            while let Ok(item) = t.raw_get::<_, Value>(items.len() + 1) {
+                if item == Value::Nil {
+                    break;
+                }
+                items.push(item);
+            }
+            out_arr(out, items.len());
+            for item in &items {
+                lua_to_reply(item, out, depth + 1);
+            }
+        }
+        _ => out_nil(out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    fn eval(scripting: &mut Scripting, ctx: &mut Context, source: &str, keys: &[&str], argv: &[&str]) -> Reply {
+        let sha = scripting.load(source.as_bytes()).unwrap();
+        let keys = keys.iter().map(|s| s.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>();
+        let argv = argv.iter().map(|s| s.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>();
+        let mut out = vec![];
+        scripting.run(ctx, &sha, &keys, &argv, &mut out);
+        Reply::decode(&out).unwrap()
+    }
+
+    fn err(code: &str, message: &str) -> Reply {
+        Reply::Error { code: code.to_string(), message: message.to_string() }
+    }
+
+    #[test]
+    fn test_sha1_hex() {
+        assert_eq!(sha1_hex(b"return 1"), "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut scripting = Scripting::new(DEFAULT_TIME_LIMIT);
+        let mut db = Database::new();
+        let mut ctx = Context::new(&mut db);
+
+        assert_eq!(eval(&mut scripting, &mut ctx, "return 3.9", &[], &[]), Reply::Int(3));
+        assert_eq!(eval(&mut scripting, &mut ctx, "return true", &[], &[]), Reply::Int(1));
+        assert_eq!(eval(&mut scripting, &mut ctx, "return false", &[], &[]), Reply::Nil);
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "return {KEYS[1], ARGV[1], {1}, nil, 'lost'}", &["k"], &["a"]),
+            Reply::Array(vec![
+                Reply::Str(b"k".to_vec()),
+                Reply::Str(b"a".to_vec()),
+                Reply::Array(vec![Reply::Int(1)]),
+            ])
+        );
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "return redis.error_reply('WRONGTYPE bad')", &[], &[]),
+            err("WRONGTYPE", "bad")
+        );
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "return redis.status_reply('FINE')", &[], &[]),
+            Reply::Str(b"FINE".to_vec())
+        );
+        assert!(matches!(
+            eval(&mut scripting, &mut ctx, "return nosuch()", &[], &[]),
+            Reply::Error { code, message } if code == "ERR" && message.starts_with("Error running script")
+        ));
+        assert!(scripting.load(b"return (").is_err());
+    }
+
+    #[test]
+    fn test_redis_call() {
+        let mut scripting = Scripting::new(DEFAULT_TIME_LIMIT);
+        let mut db = Database::new();
+        let mut ctx = Context::new(&mut db);
+
+        let source = "redis.call('set', KEYS[1], ARGV[1]); return redis.call('get', KEYS[1])";
+        assert_eq!(eval(&mut scripting, &mut ctx, source, &["k"], &["v"]), Reply::Str(b"v".to_vec()));
+        assert_eq!(eval(&mut scripting, &mut ctx, "return redis.call('get', 'nope')", &[], &[]), Reply::Nil);
+
+        // call raises errors with their code, pcall hands them back
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "return redis.call('expire', 'k', 'x')", &[], &[]),
+            err("ERR", "value is not an integer or out of range")
+        );
+        let source = "local r = redis.pcall('bogus'); return r.err";
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, source, &[], &[]),
+            Reply::Str(b"ERR Unknown Redis command called from script".to_vec())
+        );
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "return redis.call('eval', 'return 1', 0)", &[], &[]),
+            err("ERR", "This Redis command is not allowed from script")
+        );
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let mut scripting = Scripting::new(Duration::ZERO);
+        let mut db = Database::new();
+
+        // Killed as soon as the busy hook says so
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut ctx = Context::new(&mut db);
+        ctx.on_busy = Some(Rc::new(move |killable| {
+            counter.set(counter.get() + 1);
+            killable
+        }));
+        assert_eq!(
+            eval(&mut scripting, &mut ctx, "while true do end", &[], &[]),
+            err("ERR", "Script killed by user with SCRIPT KILL...")
+        );
+        assert_eq!(calls.get(), 1);
+
+        // But not once it wrote something
+        let source = "redis.call('set', 'a', 'b'); for i = 1, 1000000 do end; return 1";
+        assert_eq!(eval(&mut scripting, &mut ctx, source, &[], &[]), Reply::Int(1));
+        assert!(calls.get() > 1);
+
+        scripting.flush();
+        assert!(scripting.is_empty());
+    }
+}
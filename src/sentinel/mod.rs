@@ -0,0 +1,894 @@
+//! Watching a primary and its replicas, and promoting a replica when the
+//! primary goes away.
+//!
+//! Every sentinel pings the instances, reads `info replication` from them
+//! to find the replicas, and publishes hello messages on `HELLO_CHANNEL`
+//! through each of them, which is how sentinels watching the same primary
+//! find each other. A sentinel that can't reach the primary for
+//! `down_after` considers it subjectively down and asks the others with
+//! `sentinel is-master-down-by-addr`. Once `quorum` sentinels agree, the
+//! primary is objectively down and a failover starts in a new epoch. Only
+//! the sentinel voted leader by a majority goes on: it promotes the replica
+//! furthest along the replication stream and points the other instances at
+//! it. The others learn about the new primary from its hello messages,
+//! which carry the epoch of the configuration they describe.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cluster::random_node_id;
+use crate::notice;
+use crate::protocol::*;
+
+mod net;
+
+pub const DEFAULT_PORT: u16 = 26379;
+/// Where sentinels announce themselves, on every instance they watch
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+/// How often instances are pinged and the state is looked at
+const PERIOD: Duration = Duration::from_millis(100);
+const HELLO_PERIOD: Duration = Duration::from_millis(500);
+/// How often the other sentinels are asked about a primary that looks down
+const ASK_PERIOD: Duration = Duration::from_millis(200);
+/// How long a sentinel saying the primary is down counts towards the quorum
+const DOWN_REPLY_VALIDITY: Duration = Duration::from_secs(1);
+/// Longest random delay before a failover attempt, so that sentinels
+/// seeing the primary go down together don't all ask for votes at once
+const MAX_DESYNC: Duration = Duration::from_millis(500);
+/// How long an instance may report the wrong role or primary before it's
+/// reconfigured. Hello messages about a new primary arrive well before.
+const MISCONFIG_GRACE: Duration = Duration::from_secs(2);
+const MAX_ELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Config {
+    pub bind: String,
+    /// IP given to the other sentinels, by default the one of `bind`
+    pub announce_ip: Option<String>,
+    /// What clients call the primary when asking for its address
+    pub name: String,
+    pub primary: String,
+    /// Number of sentinels that must agree the primary is down
+    pub quorum: usize,
+    /// How long the primary has to be unreachable to be considered down
+    pub down_after: Duration,
+    /// How long a failover may take before it's given up
+    pub failover_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: format!("0.0.0.0:{}", DEFAULT_PORT),
+            announce_ip: None,
+            name: "mymaster".to_string(),
+            primary: "127.0.0.1:6379".to_string(),
+            quorum: 2,
+            down_after: Duration::from_secs(30),
+            failover_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+/// What `info replication` says about an instance
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstanceInfo {
+    pub is_primary: bool,
+    /// `host:port` of the primary, for replicas
+    pub primary: Option<String>,
+    /// Whether a replica is synced with its primary
+    pub link_up: bool,
+    pub offset: u64,
+    /// The replicas of a primary
+    pub replicas: Vec<SocketAddr>,
+}
+
+impl InstanceInfo {
+    pub fn parse(info: &str) -> InstanceInfo {
+        let mut parsed = InstanceInfo::default();
+        let (mut host, mut port) = (None, None);
+        for line in info.lines() {
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            match field {
+                "role" => parsed.is_primary = value == "master",
+                "master_host" => host = Some(value),
+                "master_port" => port = Some(value),
+                "master_link_status" => parsed.link_up = value == "up",
+                "slave_repl_offset" | "master_repl_offset" if parsed.offset == 0 => {
+                    parsed.offset = value.parse().unwrap_or(0);
+                }
+                field if field.starts_with("slave") && field[5..].parse::<usize>().is_ok() => {
+                    let attr = |name: &str| value.split(',').find_map(|kv| kv.strip_prefix(name)?.strip_prefix('='));
+                    let addr = attr("ip").zip(attr("port")).and_then(|(ip, port)| format!("{}:{}", ip, port).parse().ok());
+                    parsed.replicas.extend(addr.filter(|addr: &SocketAddr| addr.port() != 0));
+                }
+                _ => {}
+            }
+        }
+        if let (Some(host), Some(port), false) = (host, port, parsed.is_primary) {
+            parsed.primary = Some(format!("{}:{}", host, port));
+        }
+        parsed
+    }
+}
+
+/// The primary or a replica
+struct Instance {
+    addr: SocketAddr,
+    /// When it last answered a ping
+    last_ok: Instant,
+    info: Option<InstanceInfo>,
+    /// Since when it reports a role or primary other than the one we expect
+    misconfigured_since: Option<Instant>,
+    /// When we last sent it `replicaof`
+    reconfigured: Option<Instant>,
+}
+
+impl Instance {
+    fn new(addr: SocketAddr, now: Instant) -> Instance {
+        Instance { addr, last_ok: now, info: None, misconfigured_since: None, reconfigured: None }
+    }
+}
+
+/// Another sentinel watching the same primary
+struct Peer {
+    addr: SocketAddr,
+    last_hello: Instant,
+    /// When it last said the primary is down
+    down: Option<Instant>,
+    /// Who it voted for as failover leader, and in which epoch
+    leader: Option<(String, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Waiting for the votes of the other sentinels
+    Election,
+    /// We are the leader, a replica has to be picked
+    Elected,
+    /// `replicaof no one` was sent to the replica
+    Promotion(SocketAddr),
+}
+
+struct Failover {
+    epoch: u64,
+    started: Instant,
+    stage: Stage,
+}
+
+/// What the sentinel has to do after looking at its state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Ask the sentinel `peer` whether `primary` is down, and for its vote
+    /// if we are a `candidate` in `epoch`
+    Ask { peer: String, addr: SocketAddr, primary: SocketAddr, epoch: u64, candidate: Option<String> },
+    /// Send `replicaof no one`
+    Promote(SocketAddr),
+    /// Make `instance` a replica of `primary`
+    Reconfigure { instance: SocketAddr, primary: SocketAddr },
+}
+
+/// What one sentinel knows, fed by the threads talking to instances and
+/// other sentinels
+pub struct State {
+    id: String,
+    name: String,
+    /// Where other sentinels reach us
+    announce: SocketAddr,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    primary: Instance,
+    replicas: BTreeMap<SocketAddr, Instance>,
+    /// Other sentinels, by id
+    peers: BTreeMap<String, Peer>,
+    current_epoch: u64,
+    /// Epoch of the failover that made the primary what it is
+    config_epoch: u64,
+    /// Who we voted for as failover leader, and in which epoch
+    vote: Option<(String, u64)>,
+    failover: Option<Failover>,
+    /// No failover attempt before this
+    next_attempt: Instant,
+    last_ask: Option<Instant>,
+    sdown: bool,
+    odown: bool,
+}
+
+/// A random delay up to `MAX_DESYNC`
+fn desync() -> Duration {
+    // Every `RandomState` is seeded differently
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % MAX_DESYNC.as_millis() as u64)
+}
+
+impl State {
+    pub fn new(config: &Config, primary: SocketAddr, announce: SocketAddr, now: Instant) -> State {
+        State {
+            id: random_node_id(),
+            name: config.name.clone(),
+            announce,
+            quorum: config.quorum.max(1),
+            down_after: config.down_after,
+            failover_timeout: config.failover_timeout,
+            primary: Instance::new(primary, now),
+            replicas: BTreeMap::new(),
+            peers: BTreeMap::new(),
+            current_epoch: 0,
+            config_epoch: 0,
+            vote: None,
+            failover: None,
+            next_attempt: now,
+            last_ask: None,
+            sdown: false,
+            odown: false,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary.addr
+    }
+
+    /// The primary and the replicas
+    pub fn instances(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.primary.addr).chain(self.replicas.keys().copied()).collect()
+    }
+
+    fn instance_mut(&mut self, addr: SocketAddr) -> Option<&mut Instance> {
+        if addr == self.primary.addr {
+            Some(&mut self.primary)
+        } else {
+            self.replicas.get_mut(&addr)
+        }
+    }
+
+    fn is_down(&self, instance: &Instance, now: Instant) -> bool {
+        now.saturating_duration_since(instance.last_ok) > self.down_after
+    }
+
+    pub fn ping_ok(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(instance) = self.instance_mut(addr) {
+            instance.last_ok = now;
+        }
+    }
+
+    pub fn info_received(&mut self, addr: SocketAddr, info: InstanceInfo, now: Instant) {
+        let primary = self.primary.addr;
+        if addr == primary && info.is_primary {
+            for replica in &info.replicas {
+                if *replica != primary && !self.replicas.contains_key(replica) {
+                    notice!("+slave {} {}", self.name, replica);
+                    self.replicas.insert(*replica, Instance::new(*replica, now));
+                }
+            }
+        }
+        let Some(instance) = self.instance_mut(addr) else {
+            return;
+        };
+        let expected = if addr == primary {
+            info.is_primary
+        } else {
+            !info.is_primary && info.primary == Some(primary.to_string())
+        };
+        if expected {
+            instance.misconfigured_since = None;
+        } else {
+            instance.misconfigured_since.get_or_insert(now);
+        }
+        instance.info = Some(info);
+    }
+
+    /// What we publish on `HELLO_CHANNEL`: our address, id and epoch, and
+    /// the primary with the epoch of its configuration
+    pub fn hello(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.announce.ip(),
+            self.announce.port(),
+            self.id,
+            self.current_epoch,
+            self.name,
+            self.primary.addr.ip(),
+            self.primary.addr.port(),
+            self.config_epoch,
+        )
+    }
+
+    pub fn hello_received(&mut self, hello: &str, now: Instant) {
+        let parts = hello.split(',').collect::<Vec<&str>>();
+        let [ip, port, id, current_epoch, name, primary_ip, primary_port, config_epoch] = parts[..] else {
+            return;
+        };
+        if id == self.id || name != self.name {
+            return;
+        }
+        let (Ok(addr), Ok(primary), Ok(current_epoch), Ok(config_epoch)) = (
+            format!("{}:{}", ip, port).parse::<SocketAddr>(),
+            format!("{}:{}", primary_ip, primary_port).parse::<SocketAddr>(),
+            current_epoch.parse::<u64>(),
+            config_epoch.parse::<u64>(),
+        ) else {
+            return;
+        };
+        match self.peers.get_mut(id) {
+            Some(peer) => {
+                peer.addr = addr;
+                peer.last_hello = now;
+            }
+            None => {
+                notice!("+sentinel {} {}", id, addr);
+                self.peers.insert(id.to_string(), Peer { addr, last_hello: now, down: None, leader: None });
+            }
+        }
+        self.current_epoch = self.current_epoch.max(current_epoch);
+        if config_epoch > self.config_epoch {
+            if primary != self.primary.addr {
+                self.switch_primary(primary, config_epoch, now);
+            }
+            self.config_epoch = config_epoch;
+        }
+    }
+
+    /// A reply to `is-master-down-by-addr` from `peer`
+    pub fn peer_replied(&mut self, peer: &str, down: bool, leader: Option<(String, u64)>, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.down = down.then_some(now);
+            if leader.is_some() {
+                peer.leader = leader;
+            }
+        }
+    }
+
+    /// Answer `is-master-down-by-addr` from another sentinel: whether we
+    /// think the primary at `addr` is down, and who we vote for as leader,
+    /// voting for `candidate` if no one got our vote in `epoch` yet
+    pub fn is_master_down(
+        &mut self,
+        addr: SocketAddr,
+        epoch: u64,
+        candidate: Option<&str>,
+        now: Instant,
+    ) -> (bool, Option<(String, u64)>) {
+        if addr != self.primary.addr {
+            return (false, None);
+        }
+        if let Some(candidate) = candidate {
+            self.current_epoch = self.current_epoch.max(epoch);
+            if self.vote.as_ref().is_none_or(|(_, voted)| *voted < epoch) {
+                notice!("+vote-for-leader {} {}", candidate, epoch);
+                self.vote = Some((candidate.to_string(), epoch));
+                // Give the candidate time to finish before trying ourselves
+                if candidate != self.id {
+                    self.next_attempt = now + 2 * self.failover_timeout + desync();
+                }
+            }
+        }
+        (self.is_down(&self.primary, now), self.vote.clone())
+    }
+
+    /// Number of sentinels, us included, that think the primary is down
+    fn agreeing(&self, now: Instant) -> usize {
+        let peers = self.peers.values().filter(|p| p.down.is_some_and(|at| now.duration_since(at) <= DOWN_REPLY_VALIDITY));
+        1 + peers.count()
+    }
+
+    fn votes(&self, epoch: u64) -> usize {
+        let mine = self.vote.as_ref().is_some_and(|(id, e)| *id == self.id && *e == epoch) as usize;
+        let leader = Some((self.id.clone(), epoch));
+        mine + self.peers.values().filter(|p| p.leader == leader).count()
+    }
+
+    /// A majority of the sentinels, and no less than the quorum
+    fn votes_needed(&self) -> usize {
+        let sentinels = self.peers.len() + 1;
+        self.quorum.max(sentinels / 2 + 1)
+    }
+
+    /// The replica to promote: reachable and furthest along the stream
+    fn pick_replica(&self, now: Instant) -> Option<SocketAddr> {
+        self.replicas
+            .values()
+            .filter(|r| !self.is_down(r, now) && r.info.as_ref().is_some_and(|info| !info.is_primary))
+            .max_by_key(|r| (r.info.as_ref().map_or(0, |info| info.offset), std::cmp::Reverse(r.addr)))
+            .map(|r| r.addr)
+    }
+
+    fn start_failover(&mut self, now: Instant, stage: Stage) {
+        self.current_epoch += 1;
+        notice!("+try-failover {} {} epoch {}", self.name, self.primary.addr, self.current_epoch);
+        self.vote = Some((self.id.clone(), self.current_epoch));
+        self.failover = Some(Failover { epoch: self.current_epoch, started: now, stage });
+        self.next_attempt = now + 2 * self.failover_timeout + desync();
+        self.last_ask = None;
+    }
+
+    fn abort_failover(&mut self, reason: &str) {
+        if self.failover.take().is_some() {
+            notice!("-failover-abort-{} {} {}", reason, self.name, self.primary.addr);
+        }
+    }
+
+    /// The replica at `addr` is the primary as of `epoch`, the old primary
+    /// becomes one of its replicas
+    fn switch_primary(&mut self, addr: SocketAddr, epoch: u64, now: Instant) {
+        notice!("+switch-master {} {} {}", self.name, self.primary.addr, addr);
+        let new = self.replicas.remove(&addr).unwrap_or_else(|| Instance::new(addr, now));
+        let old = std::mem::replace(&mut self.primary, new);
+        self.replicas.insert(old.addr, Instance { info: None, misconfigured_since: None, ..old });
+        self.primary.misconfigured_since = None;
+        for replica in self.replicas.values_mut() {
+            replica.misconfigured_since = None;
+            replica.reconfigured = None;
+        }
+        for peer in self.peers.values_mut() {
+            peer.down = None;
+        }
+        self.config_epoch = epoch;
+        self.failover = None;
+    }
+
+    /// Start `sentinel failover`: promote a replica without asking anyone
+    pub fn force_failover(&mut self, now: Instant) -> Result<(), (&'static str, &'static str)> {
+        if self.failover.is_some() {
+            return Err(("INPROG", "Failover already in progress"));
+        }
+        if self.pick_replica(now).is_none() {
+            return Err(("NOGOODSLAVE", "No suitable replica to promote"));
+        }
+        self.start_failover(now, Stage::Elected);
+        Ok(())
+    }
+
+    /// Look at the state every `PERIOD`, returning what has to be done
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = vec![];
+        let sdown = self.is_down(&self.primary, now);
+        if sdown != self.sdown {
+            notice!("{}sdown master {} {}", if sdown { "+" } else { "-" }, self.name, self.primary.addr);
+            self.sdown = sdown;
+        }
+        let odown = sdown && self.agreeing(now) >= self.quorum;
+        if odown != self.odown {
+            notice!("{}odown master {} {}", if odown { "+" } else { "-" }, self.name, self.primary.addr);
+            if odown {
+                self.next_attempt = self.next_attempt.max(now + desync());
+            }
+            self.odown = odown;
+        }
+
+        match self.failover.as_ref().map(|f| (f.epoch, f.started, f.stage)) {
+            None if odown && now >= self.next_attempt => self.start_failover(now, Stage::Election),
+            Some((epoch, started, Stage::Election)) => {
+                if self.votes(epoch) >= self.votes_needed() {
+                    notice!("+elected-leader {} {} epoch {}", self.name, self.primary.addr, epoch);
+                    self.failover.as_mut().unwrap().stage = Stage::Elected;
+                } else if !sdown {
+                    self.abort_failover("master-up");
+                } else if now.duration_since(started) > self.failover_timeout.min(MAX_ELECTION_TIMEOUT) {
+                    self.abort_failover("not-elected");
+                }
+            }
+            Some((epoch, started, Stage::Promotion(replica))) => {
+                let promoted = self.replicas.get(&replica).and_then(|r| r.info.as_ref()).is_some_and(|i| i.is_primary);
+                if promoted {
+                    notice!("+promoted-slave slave {}", replica);
+                    let others = self.instances().into_iter().filter(|addr| *addr != replica);
+                    actions.extend(others.map(|instance| Action::Reconfigure { instance, primary: replica }));
+                    self.switch_primary(replica, epoch, now);
+                    for replica in self.replicas.values_mut() {
+                        replica.reconfigured = Some(now);
+                    }
+                } else if now.duration_since(started) > self.failover_timeout {
+                    self.abort_failover("timeout");
+                }
+            }
+            _ => {}
+        }
+        if let Some(Failover { stage: Stage::Elected, .. }) = self.failover {
+            match self.pick_replica(now) {
+                Some(replica) => {
+                    notice!("+selected-slave slave {}", replica);
+                    actions.push(Action::Promote(replica));
+                    self.failover.as_mut().unwrap().stage = Stage::Promotion(replica);
+                }
+                None => self.abort_failover("no-good-slave"),
+            }
+        }
+
+        if sdown && self.last_ask.is_none_or(|at| now.duration_since(at) >= ASK_PERIOD) {
+            let (epoch, candidate) = match &self.failover {
+                Some(Failover { epoch, stage: Stage::Election, .. }) => (*epoch, Some(self.id.clone())),
+                _ => (self.current_epoch, None),
+            };
+            for (peer, p) in &self.peers {
+                let (addr, primary, candidate) = (p.addr, self.primary.addr, candidate.clone());
+                actions.push(Action::Ask { peer: peer.clone(), addr, primary, epoch, candidate });
+            }
+            self.last_ask = Some(now);
+        }
+
+        // Replicas that still follow an old primary, or came back as one
+        if self.failover.is_none() && !sdown {
+            let primary = self.primary.addr;
+            for replica in self.replicas.values_mut() {
+                let misconfigured = replica.misconfigured_since.is_some_and(|at| now.duration_since(at) >= MISCONFIG_GRACE);
+                let recently = replica.reconfigured.is_some_and(|at| now.duration_since(at) < MISCONFIG_GRACE);
+                if misconfigured && !recently {
+                    notice!("+fix-slave-config slave {} {}", replica.addr, primary);
+                    replica.reconfigured = Some(now);
+                    actions.push(Action::Reconfigure { instance: replica.addr, primary });
+                }
+            }
+        }
+        actions
+    }
+
+    /// Fields of `sentinel master` and `sentinel masters`
+    fn describe_primary(&self, now: Instant) -> Vec<(&'static str, String)> {
+        let mut flags = "master".to_string();
+        if self.is_down(&self.primary, now) {
+            flags.push_str(",s_down");
+        }
+        if self.odown {
+            flags.push_str(",o_down");
+        }
+        if self.failover.is_some() {
+            flags.push_str(",failover_in_progress");
+        }
+        vec![
+            ("name", self.name.clone()),
+            ("ip", self.primary.addr.ip().to_string()),
+            ("port", self.primary.addr.port().to_string()),
+            ("flags", flags),
+            ("num-slaves", self.replicas.len().to_string()),
+            ("num-other-sentinels", self.peers.len().to_string()),
+            ("quorum", self.quorum.to_string()),
+            ("config-epoch", self.config_epoch.to_string()),
+            ("down-after-milliseconds", self.down_after.as_millis().to_string()),
+            ("failover-timeout", self.failover_timeout.as_millis().to_string()),
+        ]
+    }
+
+    fn describe_replica(&self, replica: &Instance, now: Instant) -> Vec<(&'static str, String)> {
+        let info = replica.info.clone().unwrap_or_default();
+        let (host, port) = info.primary.as_deref().and_then(|p| p.rsplit_once(':')).unwrap_or(("?", "0"));
+        let flags = if self.is_down(replica, now) { "slave,s_down" } else { "slave" };
+        vec![
+            ("name", replica.addr.to_string()),
+            ("ip", replica.addr.ip().to_string()),
+            ("port", replica.addr.port().to_string()),
+            ("flags", flags.to_string()),
+            ("master-host", host.to_string()),
+            ("master-port", port.to_string()),
+            ("master-link-status", if info.link_up { "ok" } else { "err" }.to_string()),
+            ("slave-repl-offset", info.offset.to_string()),
+        ]
+    }
+
+    fn describe_peer(peer: (&String, &Peer), now: Instant) -> Vec<(&'static str, String)> {
+        let (id, peer) = peer;
+        vec![
+            ("name", id.clone()),
+            ("ip", peer.addr.ip().to_string()),
+            ("port", peer.addr.port().to_string()),
+            ("runid", id.clone()),
+            ("last-hello-message", now.duration_since(peer.last_hello).as_millis().to_string()),
+        ]
+    }
+
+    /// Run a command from a client or another sentinel
+    pub fn command(&mut self, args: &[String], now: Instant, out: &mut Vec<u8>) {
+        let Some(name) = args.first() else {
+            return out_err(out, ErrorCode::Err, "empty request");
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "ping" => out_str(out, b"PONG"),
+            "sentinel" if args.len() >= 2 => self.sentinel(args, now, out),
+            "sentinel" => out_wrong_arity(out, "sentinel"),
+            _ => out_err(out, ErrorCode::Err, &format!("unknown command '{}'", name)),
+        }
+    }
+
+    fn sentinel(&mut self, args: &[String], now: Instant, out: &mut Vec<u8>) {
+        let sub = args[1].to_ascii_lowercase();
+        let known = |name: &String| *name == self.name;
+        match (sub.as_str(), &args[2..]) {
+            ("myid", []) => out_str(out, self.id.as_bytes()),
+            ("get-master-addr-by-name", [name]) if known(name) => {
+                out_arr(out, 2);
+                out_str(out, self.primary.addr.ip().to_string().as_bytes());
+                out_str(out, self.primary.addr.port().to_string().as_bytes());
+            }
+            ("get-master-addr-by-name", [_]) => out_nil(out),
+            ("masters", []) => {
+                out_arr(out, 1);
+                out_fields(out, &self.describe_primary(now));
+            }
+            ("master", [name]) if known(name) => out_fields(out, &self.describe_primary(now)),
+            ("replicas" | "slaves", [name]) if known(name) => {
+                out_arr(out, self.replicas.len());
+                for replica in self.replicas.values() {
+                    out_fields(out, &self.describe_replica(replica, now));
+                }
+            }
+            ("sentinels", [name]) if known(name) => {
+                out_arr(out, self.peers.len());
+                for peer in &self.peers {
+                    out_fields(out, &State::describe_peer(peer, now));
+                }
+            }
+            ("failover", [name]) if known(name) => match self.force_failover(now) {
+                Ok(()) => out_ok(out),
+                Err((code, msg)) => out_err_code(out, code, msg),
+            },
+            ("master" | "replicas" | "slaves" | "sentinels" | "failover", [_]) => {
+                out_err(out, ErrorCode::Err, "No such master with that name")
+            }
+            ("is-master-down-by-addr", [ip, port, epoch, candidate]) => {
+                let (Ok(addr), Ok(epoch)) = (format!("{}:{}", ip, port).parse::<SocketAddr>(), epoch.parse::<u64>()) else {
+                    return out_err(out, ErrorCode::Err, "invalid address or epoch");
+                };
+                let candidate = Some(candidate.as_str()).filter(|c| *c != "*");
+                let (down, leader) = self.is_master_down(addr, epoch, candidate, now);
+                out_arr(out, 3);
+                out_int(out, down as i64);
+                let (leader, leader_epoch) = leader.unwrap_or(("*".to_string(), 0));
+                out_str(out, leader.as_bytes());
+                out_int(out, leader_epoch as i64);
+            }
+            (
+                "myid" | "get-master-addr-by-name" | "masters" | "master" | "replicas" | "slaves" | "sentinels"
+                | "failover" | "is-master-down-by-addr",
+                _,
+            ) => out_wrong_arity(out, &format!("sentinel|{}", sub)),
+            _ => out_err(out, ErrorCode::Err, &format!("unknown subcommand '{}'", args[1])),
+        }
+    }
+}
+
+/// An array alternating field names and values
+fn out_fields(out: &mut Vec<u8>, fields: &[(&str, String)]) {
+    out_arr(out, fields.len() * 2);
+    for (name, value) in fields {
+        out_str(out, name.as_bytes());
+        out_str(out, value.as_bytes());
+    }
+}
+
+/// A sentinel bound to its port, ready to run
+pub struct Sentinel {
+    listener: TcpListener,
+    state: Arc<Mutex<State>>,
+}
+
+impl Sentinel {
+    pub fn bind(config: Config) -> std::io::Result<Sentinel> {
+        let primary = config
+            .primary
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address for the primary"))?;
+        let listener = TcpListener::bind(&config.bind)?;
+        let mut announce = listener.local_addr()?;
+        match &config.announce_ip {
+            Some(ip) => {
+                announce.set_ip(ip.parse().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "the announced IP isn't valid")
+                })?);
+            }
+            // Listening everywhere, only our own host can be sure to reach us
+            None if announce.ip().is_unspecified() => announce.set_ip([127, 0, 0, 1].into()),
+            None => {}
+        }
+        let state = State::new(&config, primary, announce, Instant::now());
+        Ok(Sentinel { listener, state: Arc::new(Mutex::new(state)) })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Watch the primary forever on the calling thread
+    pub fn run(self) {
+        net::run(self.listener, self.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, Reply};
+    use crate::server::{self, Server};
+    use std::thread;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn replica_info(primary: u16, offset: u64) -> InstanceInfo {
+        let info = format!(
+            "# Replication\r\nrole:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:{}\r\n\
+             master_link_status:up\r\nslave_repl_offset:{}\r\nmaster_repl_offset:{}\r\n",
+            primary, offset, offset
+        );
+        InstanceInfo::parse(&info)
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+                    slave0:ip=127.0.0.1,port=7001,state=online\r\nslave1:ip=127.0.0.1,port=0,state=online\r\n\
+                    master_repl_offset:42\r\n";
+        let expected = InstanceInfo { is_primary: true, offset: 42, replicas: vec![addr(7001)], ..InstanceInfo::default() };
+        assert_eq!(InstanceInfo::parse(info), expected);
+        let replica = replica_info(7000, 5);
+        assert_eq!((replica.is_primary, replica.primary.as_deref(), replica.link_up, replica.offset), (false, Some("127.0.0.1:7000"), true, 5));
+    }
+
+    #[test]
+    fn test_failover() {
+        let config = Config {
+            quorum: 2,
+            down_after: Duration::from_secs(1),
+            failover_timeout: Duration::from_secs(10),
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let mut state = State::new(&config, addr(7000), addr(26379), start);
+        let mut peer = State::new(&config, addr(7000), addr(26380), start);
+        let primary_info = InstanceInfo { is_primary: true, replicas: vec![addr(7001), addr(7002)], ..InstanceInfo::default() };
+        for state in [&mut state, &mut peer] {
+            state.info_received(addr(7000), primary_info.clone(), start);
+            state.info_received(addr(7001), replica_info(7000, 10), start);
+            state.info_received(addr(7002), replica_info(7000, 20), start);
+        }
+        state.hello_received(&peer.hello(), start);
+        peer.hello_received(&state.hello(), start);
+        assert_eq!(state.instances(), [addr(7000), addr(7001), addr(7002)]);
+        assert_eq!(state.tick(start), []);
+
+        // Down for us only, then for the peer too
+        let now = start + Duration::from_millis(1500);
+        for addr in [addr(7001), addr(7002)] {
+            state.ping_ok(addr, now);
+        }
+        let ask = Action::Ask { peer: peer.id.clone(), addr: addr(26380), primary: addr(7000), epoch: 0, candidate: None };
+        assert_eq!(state.tick(now), [ask]);
+        assert!(!state.odown);
+        peer.ping_ok(addr(7000), now);
+        let (down, leader) = peer.is_master_down(addr(7000), 0, None, now);
+        state.peer_replied(&peer.id, down, leader, now);
+        assert!(!down && state.tick(now).is_empty());
+        state.peer_replied(&peer.id.clone(), true, None, now);
+
+        // Objectively down, the failover starts after a random delay
+        let now = now + ASK_PERIOD;
+        state.tick(now);
+        assert!(state.odown && state.failover.is_none());
+        let now = now + MAX_DESYNC;
+        let actions = state.tick(now);
+        let candidate = Some(state.id.clone());
+        assert_eq!(actions, [Action::Ask { peer: peer.id.clone(), addr: addr(26380), primary: addr(7000), epoch: 1, candidate }]);
+        let (_, leader) = peer.is_master_down(addr(7000), 1, Some(&state.id.clone()), now);
+        assert_eq!(leader, Some((state.id.clone(), 1)));
+        // One vote per epoch
+        assert_eq!(peer.is_master_down(addr(7000), 1, Some(&peer.id.clone()), now).1, leader);
+        state.peer_replied(&peer.id.clone(), true, leader, now);
+
+        // Elected, the replica with the highest offset gets promoted
+        let actions = state.tick(now);
+        assert_eq!(actions[0], Action::Promote(addr(7002)));
+        let mut promoted = InstanceInfo { is_primary: true, ..InstanceInfo::default() };
+        state.info_received(addr(7002), promoted.clone(), now);
+        let reconfigure = |instance: u16| Action::Reconfigure { instance: addr(instance), primary: addr(7002) };
+        assert_eq!(state.tick(now)[..2], [reconfigure(7000), reconfigure(7001)]);
+        assert_eq!((state.primary(), state.config_epoch), (addr(7002), 1));
+
+        // The peer hears about it from the hello messages
+        peer.hello_received(&state.hello(), now);
+        assert_eq!((peer.primary(), peer.config_epoch, peer.current_epoch), (addr(7002), 1, 1));
+        peer.hello_received(&state.hello(), now);
+        assert_eq!(peer.primary(), addr(7002));
+
+        // The old primary comes back as one, and is made a replica again
+        let now = now + Duration::from_millis(100);
+        promoted.replicas = vec![addr(7001)];
+        state.ping_ok(addr(7002), now);
+        state.info_received(addr(7002), promoted, now);
+        state.ping_ok(addr(7000), now);
+        state.info_received(addr(7000), primary_info, now);
+        assert_eq!(state.tick(now), []);
+        let now = now + MISCONFIG_GRACE;
+        state.ping_ok(addr(7002), now);
+        assert_eq!(state.tick(now), [reconfigure(7000)]);
+        assert_eq!(state.tick(now), []);
+
+        let mut out = vec![];
+        state.command(&["sentinel".to_string(), "get-master-addr-by-name".to_string(), "mymaster".to_string()], now, &mut out);
+        assert_eq!(Reply::decode(&out).unwrap(), Reply::Array(vec![Reply::Str(b"127.0.0.1".to_vec()), Reply::Str(b"7002".to_vec())]));
+    }
+
+    #[test]
+    fn test_sentinels() {
+        let servers = (0..3)
+            .map(|_| {
+                let config = server::Config { bind: "127.0.0.1:0".to_string(), ..server::Config::default() };
+                let server = Server::bind(config).unwrap();
+                let addr = server.local_addr().unwrap();
+                thread::spawn(move || server.run());
+                addr
+            })
+            .collect::<Vec<_>>();
+        let mut clients = servers.iter().map(|addr| Client::connect(addr).unwrap()).collect::<Vec<_>>();
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let port = |addr: &SocketAddr| addr.port().to_string();
+        for client in &mut clients[1..] {
+            assert_eq!(client.command(&["replicaof", "127.0.0.1", &port(&servers[0])]).unwrap(), bulk("OK"));
+        }
+        clients[0].command(&["set", "a", "1"]).unwrap();
+
+        let mut sentinels = (0..3)
+            .map(|_| {
+                let config = Config {
+                    bind: "127.0.0.1:0".to_string(),
+                    primary: servers[0].to_string(),
+                    quorum: 2,
+                    down_after: Duration::from_millis(300),
+                    failover_timeout: Duration::from_secs(2),
+                    ..Config::default()
+                };
+                let sentinel = Sentinel::bind(config).unwrap();
+                let addr = sentinel.local_addr().unwrap();
+                thread::spawn(move || sentinel.run());
+                Client::connect(addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let wait_for = |client: &mut Client, args: &[&str], expected: &dyn Fn(&Reply) -> bool| {
+            for _ in 0..1500 {
+                if expected(&client.command(args).unwrap()) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{:?} never got the expected reply", args);
+        };
+        let count = |n: usize| move |reply: &Reply| matches!(reply, Reply::Array(items) if items.len() == n);
+        for sentinel in &mut sentinels {
+            wait_for(sentinel, &["sentinel", "sentinels", "mymaster"], &count(2));
+            wait_for(sentinel, &["sentinel", "replicas", "mymaster"], &count(2));
+        }
+
+        // The primary stops answering, one of the replicas takes over
+        clients[0].command(&["client", "pause", "4000"]).unwrap();
+        let address = ["sentinel", "get-master-addr-by-name", "mymaster"];
+        let moved = |reply: &Reply| *reply != Reply::Array(vec![bulk("127.0.0.1"), bulk(&port(&servers[0]))]);
+        for sentinel in &mut sentinels {
+            wait_for(sentinel, &address, &moved);
+        }
+        let Reply::Array(primary) = sentinels[0].command(&address).unwrap() else {
+            panic!("expected an array");
+        };
+        let new = servers.iter().position(|addr| primary[1] == bulk(&port(addr))).unwrap();
+        assert_ne!(new, 0);
+        let role = |role: &'static str| move |reply: &Reply| matches!(reply, Reply::Array(items) if items[0] == bulk(role));
+        wait_for(&mut clients[new], &["role"], &role("master"));
+        assert_eq!(clients[new].command(&["get", "a"]).unwrap(), bulk("1"));
+        assert_eq!(clients[new].command(&["set", "b", "2"]).unwrap(), bulk("OK"));
+
+        // Everyone else follows the new primary, the old one once it's back
+        for (_, client) in clients.iter_mut().enumerate().filter(|(i, _)| *i != new) {
+            wait_for(client, &["role"], &role("slave"));
+            wait_for(client, &["get", "b"], &|reply| *reply == bulk("2"));
+            assert_eq!(client.command(&["get", "a"]).unwrap(), bulk("1"));
+        }
+    }
+}
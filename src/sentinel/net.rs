@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{self, Client, Reply};
+use crate::connection::{Connection, MAX_MSG};
+use crate::{notice, verbose, warning};
+
+use super::{Action, InstanceInfo, State, HELLO_CHANNEL, HELLO_PERIOD, PERIOD};
+
+/// How long connecting to an instance or another sentinel, and each
+/// request, may take
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+/// A hello connection this quiet is broken, ours alone come every
+/// `HELLO_PERIOD`
+const HELLO_TIMEOUT: Duration = Duration::from_millis(5 * HELLO_PERIOD.as_millis() as u64);
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) {
+    if let Err(e) = thread::Builder::new().name(name.to_string()).spawn(f) {
+        warning!("Couldn't spawn {} thread: {}", name, e);
+    }
+}
+
+/// Serve clients on `listener` and look at the state every `PERIOD`,
+/// starting threads for the instances as they are found
+pub(super) fn run(listener: TcpListener, state: Arc<Mutex<State>>) {
+    if let Ok(addr) = listener.local_addr() {
+        let state = state.lock().unwrap();
+        notice!("Sentinel {} watching {} on port {}", state.id(), state.primary(), addr.port());
+    }
+    let clients = state.clone();
+    spawn("sentinel-acceptor", move || accept(listener, clients));
+
+    let mut watched = HashSet::new();
+    loop {
+        let (actions, instances) = {
+            let mut state = state.lock().unwrap();
+            (state.tick(Instant::now()), state.instances())
+        };
+        for addr in instances {
+            if watched.insert(addr) {
+                let (pings, hellos) = (state.clone(), state.clone());
+                spawn("sentinel-monitor", move || monitor(addr, pings));
+                spawn("sentinel-hello", move || listen_hellos(addr, hellos));
+            }
+        }
+        for action in actions {
+            let state = state.clone();
+            spawn("sentinel-action", move || perform(action, state));
+        }
+        thread::sleep(PERIOD);
+    }
+}
+
+fn accept(listener: TcpListener, state: Arc<Mutex<State>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                spawn("sentinel-client", move || serve(stream, state));
+            }
+            Err(e) => warning!("Error accepting connection: {}", e),
+        }
+    }
+}
+
+/// Answer the requests of one client until it hangs up
+fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let _ = stream.set_nodelay(true);
+    loop {
+        let mut header = [0u8; 4];
+        if stream.read_exact(&mut header).is_err() {
+            return;
+        }
+        let len = u32::from_le_bytes(header) as usize;
+        if len > MAX_MSG {
+            verbose!("Client sent a request over {} bytes", MAX_MSG);
+            return;
+        }
+        let mut body = vec![0u8; len];
+        if stream.read_exact(&mut body).is_err() {
+            return;
+        }
+        let Some(args) = Connection::parse_req(&body, len) else {
+            verbose!("Client sent a malformed request");
+            return;
+        };
+        // Sentinel commands are all text
+        let args = args.iter().map(|arg| String::from_utf8_lossy(arg).into_owned()).collect::<Vec<String>>();
+        let mut reply = vec![0; 4];
+        state.lock().unwrap().command(&args, Instant::now(), &mut reply);
+        let body_len = (reply.len() - 4) as u32;
+        reply[..4].copy_from_slice(&body_len.to_le_bytes());
+        if stream.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn connect(addr: SocketAddr, timeout: Duration) -> client::Result<Client> {
+    let mut client = Client::connect_timeout(&addr, LINK_TIMEOUT)?;
+    client.set_timeout(Some(timeout))?;
+    Ok(client)
+}
+
+/// Ping `addr` and read its `info replication` every `PERIOD`, and
+/// publish our hello message through it every `HELLO_PERIOD`
+fn monitor(addr: SocketAddr, state: Arc<Mutex<State>>) {
+    let mut link = None;
+    let mut last_hello: Option<Instant> = None;
+    loop {
+        thread::sleep(PERIOD);
+        if link.is_none() {
+            link = connect(addr, LINK_TIMEOUT).ok();
+        }
+        let Some(client) = link.as_mut() else {
+            continue;
+        };
+        let info = client.ping().and_then(|_| client.command(&["info", "replication"])?.into_bytes());
+        let Ok(Some(info)) = info else {
+            link = None;
+            continue;
+        };
+        let now = Instant::now();
+        let hello = {
+            let mut state = state.lock().unwrap();
+            state.ping_ok(addr, now);
+            state.info_received(addr, InstanceInfo::parse(&String::from_utf8_lossy(&info)), now);
+            state.hello()
+        };
+        if last_hello.is_none_or(|at| now.duration_since(at) >= HELLO_PERIOD) {
+            last_hello = Some(now);
+            if client.command(&["publish", HELLO_CHANNEL, &hello]).is_err() {
+                link = None;
+            }
+        }
+    }
+}
+
+/// Hand the hello messages published through `addr` to the state
+fn listen_hellos(addr: SocketAddr, state: Arc<Mutex<State>>) {
+    loop {
+        if let Ok(mut client) = connect(addr, HELLO_TIMEOUT) {
+            if client.command(&["subscribe", HELLO_CHANNEL]).is_ok() {
+                while let Ok(message) = client.receive() {
+                    if let Reply::Array(items) = message {
+                        if let [_, _, Reply::Str(hello)] = &items[..] {
+                            state.lock().unwrap().hello_received(&String::from_utf8_lossy(hello), Instant::now());
+                        }
+                    }
+                }
+            }
+        }
+        thread::sleep(HELLO_PERIOD);
+    }
+}
+
+fn request(addr: SocketAddr, args: &[&str]) -> client::Result<Reply> {
+    connect(addr, LINK_TIMEOUT)?.command(args)?.into_result()
+}
+
+fn perform(action: Action, state: Arc<Mutex<State>>) {
+    match action {
+        Action::Ask { peer, addr, primary, epoch, candidate } => {
+            let (ip, port, epoch) = (primary.ip().to_string(), primary.port().to_string(), epoch.to_string());
+            let candidate = candidate.as_deref().unwrap_or("*");
+            let reply = request(addr, &["sentinel", "is-master-down-by-addr", &ip, &port, &epoch, candidate]);
+            if let Ok(Reply::Array(items)) = reply {
+                if let [Reply::Int(down), Reply::Str(leader), Reply::Int(leader_epoch)] = &items[..] {
+                    let leader = String::from_utf8_lossy(leader).to_string();
+                    let leader = (leader != "*").then_some((leader, *leader_epoch as u64));
+                    state.lock().unwrap().peer_replied(&peer, *down == 1, leader, Instant::now());
+                }
+            }
+        }
+        Action::Promote(addr) => {
+            if let Err(e) = request(addr, &["replicaof", "no", "one"]) {
+                warning!("Couldn't promote {}: {}", addr, e);
+            }
+        }
+        Action::Reconfigure { instance, primary } => {
+            let (ip, port) = (primary.ip().to_string(), primary.port().to_string());
+            if let Err(e) = request(instance, &["replicaof", &ip, &port]) {
+                verbose!("Couldn't point {} at {}: {}", instance, primary, e);
+            }
+        }
+    }
+}
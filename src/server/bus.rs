@@ -0,0 +1,100 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::cluster::message::Message;
+use crate::cluster::PING_INTERVAL;
+use crate::{verbose, warning};
+
+use super::Event;
+
+/// Longest message accepted on the bus
+const MAX_MESSAGE: usize = 1024 * 1024;
+/// How long writing to a node may take before giving up on it
+const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Accept connections from other nodes, reading each one on its own thread
+pub(super) fn listen(listener: TcpListener, events: Sender<Event>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warning!("Error accepting cluster bus connection: {}", e);
+                continue;
+            }
+        };
+        let events = events.clone();
+        let spawned = thread::Builder::new()
+            .name("cluster-bus".to_string())
+            .spawn(move || read_messages(stream, events));
+        if let Err(e) = spawned {
+            warning!("Couldn't spawn cluster bus thread: {}", e);
+        }
+    }
+}
+
+/// Hand the messages of one node to the executor until it hangs up
+fn read_messages(mut stream: TcpStream, events: Sender<Event>) {
+    let (Ok(peer), Ok(local)) = (stream.peer_addr(), stream.local_addr()) else {
+        return;
+    };
+    verbose!("Accepted cluster bus connection from {}", peer);
+    loop {
+        let mut header = [0u8; 4];
+        if stream.read_exact(&mut header).is_err() {
+            return;
+        }
+        let len = u32::from_le_bytes(header) as usize;
+        if len > MAX_MESSAGE {
+            warning!("Cluster bus message of {} bytes from {}, closing", len, peer);
+            return;
+        }
+        let mut body = vec![0u8; len];
+        if stream.read_exact(&mut body).is_err() {
+            return;
+        }
+        let Some(message) = Message::decode(&body) else {
+            warning!("Malformed cluster bus message from {}, closing", peer);
+            return;
+        };
+        if events.send(Event::Bus { message, from: peer.ip(), local: local.ip() }).is_err() {
+            return;
+        }
+    }
+}
+
+/// Write framed messages to other nodes, keeping a connection to each
+pub(super) fn send(rx: Receiver<(SocketAddr, Vec<u8>)>) {
+    let mut links: HashMap<SocketAddr, TcpStream> = HashMap::new();
+    for (addr, frame) in rx {
+        let stream = match links.entry(addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Unreachable nodes are retried on the next ping
+                let Ok(stream) = TcpStream::connect_timeout(&addr, SEND_TIMEOUT) else {
+                    continue;
+                };
+                let _ = stream.set_write_timeout(Some(SEND_TIMEOUT));
+                let _ = stream.set_nodelay(true);
+                entry.insert(stream)
+            }
+        };
+        if stream.write_all(&frame).is_err() {
+            links.remove(&addr);
+        }
+    }
+}
+
+/// Wake the executor every `PING_INTERVAL` to ping the other nodes
+pub(super) fn tick(events: Sender<Event>) {
+    loop {
+        thread::sleep(PING_INTERVAL);
+        if events.send(Event::ClusterTick).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// What the executor knows about a connected client
+pub struct ClientInfo {
+    pub id: u64,
+    /// I/O thread owning the connection
+    pub thread: usize,
+    pub addr: Option<SocketAddr>,
+    pub name: String,
+    pub created: Instant,
+    pub last_interaction: Instant,
+    pub db: usize,
+    /// Name of the last command run
+    pub last_cmd: &'static str,
+    /// Who the client authenticated as, `None` if it didn't
+    pub user: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(id: u64, thread: usize, addr: Option<SocketAddr>) -> ClientInfo {
+        let now = Instant::now();
+        ClientInfo {
+            id,
+            thread,
+            addr,
+            name: String::new(),
+            created: now,
+            last_interaction: now,
+            db: 0,
+            last_cmd: "NULL",
+            user: None,
+        }
+    }
+
+    /// One line of `client list`, newline included
+    pub fn describe(&self, out: &mut String) {
+        let now = Instant::now();
+        let _ = writeln!(
+            out,
+            "id={} addr={} name={} age={} idle={} db={} cmd={} user={}",
+            self.id,
+            self.addr.map_or(String::new(), |a| a.to_string()),
+            self.name,
+            now.duration_since(self.created).as_secs(),
+            now.duration_since(self.last_interaction).as_secs(),
+            self.db,
+            self.last_cmd,
+            self.user.as_deref().unwrap_or(""),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Only commands that may modify the dataset wait
+    Write,
+    All,
+}
+
+/// The connected clients, by id
+#[derive(Default)]
+pub struct Clients {
+    clients: BTreeMap<u64, ClientInfo>,
+    pause: Option<(Instant, PauseMode)>,
+    /// Clients killed by the last command, the executor hangs up on them
+    killed: Vec<u64>,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients::default()
+    }
+
+    pub fn add(&mut self, client: ClientInfo) {
+        self.clients.insert(client.id, client);
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<ClientInfo> {
+        self.clients.remove(&id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ClientInfo> {
+        self.clients.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut ClientInfo> {
+        self.clients.get_mut(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ClientInfo> {
+        self.clients.values()
+    }
+
+    /// Mark `id` to be disconnected once the current command is done
+    pub fn kill(&mut self, id: u64) {
+        self.killed.push(id);
+    }
+
+    pub fn take_killed(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.killed)
+    }
+
+    pub fn pause(&mut self, duration: Duration, mode: PauseMode) {
+        self.pause = Some((Instant::now() + duration, mode));
+    }
+
+    pub fn unpause(&mut self) {
+        self.pause = None;
+    }
+
+    /// The current pause and when it ends, if any
+    pub fn paused(&mut self) -> Option<(Instant, PauseMode)> {
+        if self.pause.is_some_and(|(until, _)| until <= Instant::now()) {
+            self.pause = None;
+        }
+        self.pause
+    }
+}
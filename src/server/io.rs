@@ -0,0 +1,87 @@
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::connection::{Connection, ConnectionState};
+use crate::verbose;
+
+use super::{Event, IoMessage, Job, Stats};
+
+/// Idle loop iterations, yielding each time, before the thread parks itself
+const IDLE_SPINS: u32 = 1000;
+/// Upper bound on how long a parked thread ignores its sockets
+const PARK_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Main loop of an I/O thread: frame requests from our connections and hand
+/// them to the executor, write back the replies it sends us
+pub(super) fn run(index: usize, rx: Receiver<IoMessage>, events: Sender<Event>, stats: &Stats) {
+    let mut connections: Vec<Connection> = vec![];
+    let mut requests = vec![];
+    let mut idle = 0u32;
+
+    loop {
+        let mut busy = false;
+
+        loop {
+            match rx.try_recv() {
+                Ok(IoMessage::Accepted(conn)) => connections.push(*conn),
+                Ok(IoMessage::Reply { conn_id, body, close }) => {
+                    // The client may have gone away in the meantime
+                    if let Some(conn) = connections.iter_mut().find(|c| c.id == conn_id) {
+                        conn.push_reply(&body);
+                        conn.closing |= close;
+                    }
+                }
+                Ok(IoMessage::Close { conn_id }) => {
+                    if let Some(conn) = connections.iter_mut().find(|c| c.id == conn_id) {
+                        conn.state = ConnectionState::StateEnd;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+            busy = true;
+        }
+
+        for conn in &mut connections {
+            if conn.state == ConnectionState::StateEnd {
+                continue;
+            }
+            let (net_in, net_out) = (conn.net_input_bytes, conn.net_output_bytes);
+            conn.state_req(&mut requests);
+            for args in requests.drain(..) {
+                busy = true;
+                let job = Job { thread: index, conn_id: conn.id, args };
+                if events.send(Event::Job(job)).is_err() {
+                    return;
+                }
+            }
+            if conn.state == ConnectionState::StateRes {
+                busy = true;
+                conn.state_res();
+            }
+            Stats::add(&stats.net_input_bytes, conn.net_input_bytes - net_in);
+            Stats::add(&stats.net_output_bytes, conn.net_output_bytes - net_out);
+        }
+
+        connections.retain(|e| {
+            if e.state == ConnectionState::StateEnd {
+                verbose!("Client {} disconnected", e.id);
+                Stats::sub(&stats.connected_clients, 1);
+                let _ = events.send(Event::Closed { conn_id: e.id });
+            }
+            e.state != ConnectionState::StateEnd
+        });
+
+        if busy {
+            idle = 0;
+        } else {
+            idle += 1;
+            if idle >= IDLE_SPINS {
+                thread::park_timeout(PARK_TIMEOUT);
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+}
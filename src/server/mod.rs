@@ -0,0 +1,1363 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use crate::acl::Acl;
+use crate::cluster::message::Message;
+use crate::cluster::{Cluster, BUS_PORT_OFFSET};
+use crate::command::{self, Command, Context, CMD_WRITE};
+use crate::connection::Connection;
+use crate::database::eviction::EvictionPolicy;
+use crate::database::value::EncodingLimits;
+use crate::database::{Database, DEFAULT_MAXMEMORY_SAMPLES};
+use crate::protocol::*;
+use crate::scripting::{self, Scripting};
+use crate::tls::{self, ServerConnection, Stream};
+use crate::{notice, verbose, warning};
+
+mod bus;
+pub mod clients;
+mod io;
+pub mod pubsub;
+pub mod replication;
+pub mod slowlog;
+pub mod stats;
+pub mod tracking;
+
+pub use clients::{ClientInfo, Clients, PauseMode};
+pub use pubsub::PubSub;
+pub use replication::Replication;
+pub use slowlog::SlowLog;
+pub use stats::Stats;
+pub use tracking::Tracking;
+
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Connection id of the commands replicated from our primary, client ids
+/// start at 1
+const PRIMARY_CONN: u64 = 0;
+
+/// How often keys whose time to live passed are looked for, and for how
+/// long at most
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+
+pub struct Config {
+    pub bind: String,
+    /// Number of threads doing socket reads, parsing and writes
+    pub io_threads: usize,
+    /// Number of logical databases, selected with `select`
+    pub databases: usize,
+    /// Memory budget of all databases together, 0 means unlimited
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub maxmemory_samples: usize,
+    /// Sizes up to which collections are kept in a listpack
+    pub encoding_limits: EncodingLimits,
+    /// Which key events are published, see `database::notify`
+    pub notify_keyspace_events: u32,
+    /// How long a script runs before other clients get BUSY replies and
+    /// may kill it
+    pub lua_time_limit: Duration,
+    /// Commands taking at least this many microseconds go to the slow log,
+    /// negative disables it
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    /// Password of the default user, clients must `auth` before anything else
+    pub requirepass: Option<String>,
+    /// Where `acl save` and `acl load` keep the users
+    pub aclfile: Option<PathBuf>,
+    /// Address to accept TLS connections on, next to the plain ones on `bind`
+    pub tls_bind: Option<String>,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// CA that client certificates are checked against
+    pub tls_ca_cert_file: Option<PathBuf>,
+    /// Refuse TLS clients without a certificate signed by the CA
+    pub tls_auth_clients: bool,
+    /// Serve a share of the hash slots and talk to other nodes on the
+    /// cluster bus
+    pub cluster_enabled: bool,
+    /// Address of the cluster bus, by default the client port plus 10000
+    pub cluster_bus_bind: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "0.0.0.0:1234".to_string(),
+            io_threads: 1,
+            databases: DEFAULT_DATABASES,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            encoding_limits: EncodingLimits::default(),
+            notify_keyspace_events: 0,
+            lua_time_limit: scripting::DEFAULT_TIME_LIMIT,
+            slowlog_log_slower_than: slowlog::DEFAULT_SLOWER_THAN,
+            slowlog_max_len: slowlog::DEFAULT_MAX_LEN,
+            requirepass: None,
+            aclfile: None,
+            tls_bind: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: false,
+            cluster_enabled: false,
+            cluster_bus_bind: None,
+        }
+    }
+}
+
+/// Parse a memory amount like `1024`, `100kb`, `64mb` or `1gb`
+pub fn parse_memory(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
+    };
+    let n = digits.parse::<usize>().ok()?;
+    let mult = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    n.checked_mul(mult)
+}
+
+/// A parsed request on its way from an I/O thread to the executor
+struct Job {
+    thread: usize,
+    conn_id: u64,
+    args: Vec<Vec<u8>>,
+}
+
+impl Job {
+    /// The command asked for, `None` if there is no such command
+    fn command(&self) -> Option<&'static Command> {
+        command::lookup(std::str::from_utf8(self.args.first()?).ok()?)
+    }
+}
+
+/// Messages handed to the executor
+enum Event {
+    Connected(ClientInfo),
+    Job(Job),
+    Closed { conn_id: u64 },
+    /// A message from another node, on a bus connection we accepted on `local`
+    Bus { message: Message, from: IpAddr, local: IpAddr },
+    /// Time to ping the other nodes
+    ClusterTick,
+    /// Time to delete expired keys that nobody reads
+    ExpireTick,
+    /// Our primary accepted `sync` on replication link `link`
+    Synced { link: u64, offset: u64 },
+    /// A command from our primary, `bytes` long in its stream
+    Replicated { link: u64, args: Vec<Vec<u8>>, bytes: usize },
+    PrimaryDown { link: u64 },
+}
+
+/// Messages handed to an I/O thread
+enum IoMessage {
+    Accepted(Box<Connection>),
+    Reply {
+        conn_id: u64,
+        body: Vec<u8>,
+        /// Hang up after sending
+        close: bool,
+    },
+    /// Hang up right away
+    Close { conn_id: u64 },
+}
+
+/// Handle to an I/O thread, used to pass it work and wake it up
+struct IoHandle {
+    tx: Sender<IoMessage>,
+    thread: Thread,
+}
+
+impl IoHandle {
+    fn send(&self, msg: IoMessage) {
+        if self.tx.send(msg).is_ok() {
+            self.thread.unpark();
+        }
+    }
+}
+
+/// Connections are spread over the I/O threads while all commands run on
+/// the single executor thread, so `Database` needs no locking
+pub struct Server {
+    config: Config,
+    listener: TcpListener,
+    tls: Option<(TcpListener, Arc<rustls::ServerConfig>)>,
+    /// The cluster bus, in cluster mode
+    bus: Option<TcpListener>,
+    acl: Acl,
+}
+
+impl Server {
+    pub fn bind(config: Config) -> std::io::Result<Server> {
+        let mut acl = Acl::new();
+        acl.set_file(config.aclfile.clone());
+        if config.aclfile.as_ref().is_some_and(|path| path.exists()) {
+            acl.load().map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg))?;
+        }
+        if let Some(password) = &config.requirepass {
+            acl.set_requirepass(password);
+        }
+
+        let tls = match &config.tls_bind {
+            Some(bind) => {
+                let (Some(cert), Some(key)) = (&config.tls_cert_file, &config.tls_key_file) else {
+                    let msg = "TLS needs both a certificate and a key file";
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+                };
+                let ca = config.tls_ca_cert_file.as_deref();
+                let tls_config = tls::server_config(cert, key, ca, config.tls_auth_clients)?;
+                Some((TcpListener::bind(bind)?, tls_config))
+            }
+            None => None,
+        };
+
+        let listener = TcpListener::bind(&config.bind)?;
+        let bus = match (&config.cluster_bus_bind, config.cluster_enabled) {
+            (_, false) => None,
+            (Some(bind), true) => Some(TcpListener::bind(bind)?),
+            (None, true) => {
+                let addr = listener.local_addr()?;
+                let Some(port) = addr.port().checked_add(BUS_PORT_OFFSET) else {
+                    let msg = "the cluster bus port would be out of range";
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+                };
+                Some(TcpListener::bind(SocketAddr::new(addr.ip(), port))?)
+            }
+        };
+        Ok(Server { config, listener, tls, bus, acl })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Where other cluster nodes connect to, in cluster mode
+    pub fn cluster_bus_addr(&self) -> Option<SocketAddr> {
+        self.bus.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Where TLS connections are accepted, if they are
+    pub fn tls_local_addr(&self) -> Option<SocketAddr> {
+        self.tls.as_ref().and_then(|(listener, _)| listener.local_addr().ok())
+    }
+
+    /// Serve clients forever on the calling thread
+    pub fn run(self) {
+        let (events_tx, events_rx) = mpsc::channel::<Event>();
+        let io_threads = self.config.io_threads.max(1);
+        let local_addr = self.listener.local_addr().ok();
+        let port = local_addr.map_or(0, |addr| addr.port());
+        let stats = Arc::new(Stats::new(port, io_threads));
+
+        let io_handles = (0..io_threads)
+            .map(|index| {
+                let (tx, rx) = mpsc::channel();
+                let events_tx = events_tx.clone();
+                let stats = stats.clone();
+                let handle = thread::Builder::new()
+                    .name(format!("io-{}", index))
+                    .spawn(move || io::run(index, rx, events_tx, &stats))
+                    .expect("Couldn't spawn I/O thread");
+                IoHandle { tx, thread: handle.thread().clone() }
+            })
+            .collect::<Vec<IoHandle>>();
+
+        // Both acceptors hand out ids from the same sequence
+        let next_id = Arc::new(AtomicU64::new(1));
+        let listeners = [Some((self.listener, None)), self.tls.map(|(listener, config)| (listener, Some(config)))];
+        for (listener, tls) in listeners.into_iter().flatten() {
+            let acceptor = Acceptor {
+                io_handles: io_handles
+                    .iter()
+                    .map(|h| IoHandle { tx: h.tx.clone(), thread: h.thread.clone() })
+                    .collect(),
+                events: events_tx.clone(),
+                stats: stats.clone(),
+                next_id: next_id.clone(),
+                tls,
+            };
+            let name = if acceptor.tls.is_some() {
+                if let Ok(addr) = listener.local_addr() {
+                    notice!("Accepting TLS connections on port {}", addr.port());
+                }
+                "tls-acceptor"
+            } else {
+                "acceptor"
+            };
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || acceptor.run(listener))
+                .expect("Couldn't spawn acceptor thread");
+        }
+
+        let mut cluster = None;
+        let mut bus = None;
+        if let (Some(listener), Some(addr)) = (self.bus, local_addr) {
+            let bus_port = listener.local_addr().map_or(0, |a| a.port());
+            notice!("Cluster bus listening on port {}", bus_port);
+            cluster = Some(Cluster::new(addr.ip(), port, bus_port));
+            let (tx, rx) = mpsc::channel();
+            bus = Some(tx);
+            let events = events_tx.clone();
+            thread::Builder::new()
+                .name("bus-acceptor".to_string())
+                .spawn(move || bus::listen(listener, events))
+                .expect("Couldn't spawn cluster bus thread");
+            thread::Builder::new()
+                .name("bus-sender".to_string())
+                .spawn(move || bus::send(rx))
+                .expect("Couldn't spawn cluster bus thread");
+            let events = events_tx.clone();
+            thread::Builder::new()
+                .name("bus-ticker".to_string())
+                .spawn(move || bus::tick(events))
+                .expect("Couldn't spawn cluster bus thread");
+        }
+        let events = events_tx.clone();
+        thread::Builder::new()
+            .name("expire-ticker".to_string())
+            .spawn(move || {
+                thread::sleep(ACTIVE_EXPIRE_INTERVAL);
+                while events.send(Event::ExpireTick).is_ok() {
+                    thread::sleep(ACTIVE_EXPIRE_INTERVAL);
+                }
+            })
+            .expect("Couldn't spawn expire thread");
+
+        let mut databases = Database::group(self.config.databases.max(1));
+        for database in databases.iter_mut() {
+            database.set_maxmemory(self.config.maxmemory);
+            database.set_eviction_policy(self.config.maxmemory_policy);
+            database.set_maxmemory_samples(self.config.maxmemory_samples);
+            database.set_encoding_limits(self.config.encoding_limits);
+            database.set_notify_keyspace_events(self.config.notify_keyspace_events);
+        }
+
+        let mut scripting = Scripting::new(self.config.lua_time_limit);
+        let mut slowlog = SlowLog::new(self.config.slowlog_log_slower_than, self.config.slowlog_max_len);
+        let mut acl = self.acl;
+
+        notice!("Ready to accept connections on port {} with {} I/O threads", port, io_threads);
+
+        let executor = Rc::new(Executor {
+            events: events_rx,
+            io_handles,
+            stats,
+            bus,
+            events_tx,
+            port,
+            link: Arc::new(AtomicU64::new(0)),
+            deferred: RefCell::new(VecDeque::new()),
+            held: RefCell::new(VecDeque::new()),
+            current: Cell::new((0, 0)),
+            killers: RefCell::new(Killers::default()),
+        });
+        executor.run(&mut databases, &mut scripting, &mut slowlog, &mut acl, cluster.as_mut());
+    }
+}
+
+/// Accepts connections on one listener and spreads them over the I/O threads
+struct Acceptor {
+    io_handles: Vec<IoHandle>,
+    events: Sender<Event>,
+    stats: Arc<Stats>,
+    next_id: Arc<AtomicU64>,
+    /// Set on the TLS port
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Acceptor {
+    fn run(&self, listener: TcpListener) {
+        for client in listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warning!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+            let addr = client.peer_addr().ok();
+            if let Some(addr) = addr {
+                verbose!("Accepted {}{}", addr, if self.tls.is_some() { " over TLS" } else { "" });
+            }
+            client
+                .set_nonblocking(true)
+                .expect("Couldn't set non-blocking mode on accepted connection");
+            let _ = client.set_nodelay(true);
+
+            let stream = match &self.tls {
+                Some(config) => match ServerConnection::new(config.clone()) {
+                    Ok(conn) => Stream::tls(conn, client),
+                    Err(e) => {
+                        warning!("Error setting up TLS: {}", e);
+                        continue;
+                    }
+                },
+                None => Stream::Plain(client),
+            };
+
+            Stats::add(&self.stats.total_connections, 1);
+            Stats::add(&self.stats.connected_clients, 1);
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let thread = id as usize % self.io_handles.len();
+            // Sent before the I/O thread can see any request from the client
+            if self.events.send(Event::Connected(ClientInfo::new(id, thread, addr))).is_err() {
+                return;
+            }
+            self.io_handles[thread].send(IoMessage::Accepted(Box::new(Connection::new(id, stream))));
+        }
+    }
+}
+
+/// Runs commands one at a time on behalf of all I/O threads
+struct Executor {
+    events: Receiver<Event>,
+    io_handles: Vec<IoHandle>,
+    stats: Arc<Stats>,
+    /// Messages for other cluster nodes, in cluster mode
+    bus: Option<Sender<(SocketAddr, Vec<u8>)>>,
+    /// Handed to the threads following a primary
+    events_tx: Sender<Event>,
+    /// Port clients connect to, announced to our primary
+    port: u16,
+    /// Generation of the wanted link to our primary, see `Replication`
+    link: Arc<AtomicU64>,
+    /// Events that came in while a script was running, handled once it's done
+    deferred: RefCell<VecDeque<Event>>,
+    /// Requests waiting for `client pause` to end
+    held: RefCell<VecDeque<Job>>,
+    /// I/O thread and connection of the job being run
+    current: Cell<(usize, u64)>,
+    /// Who may `script kill` the script being run
+    killers: RefCell<Killers>,
+}
+
+/// Whether clients may `script kill`, worked out before a script starts
+/// since the ACL and the client table are in use until it's done
+#[derive(Default)]
+struct Killers {
+    /// The error each client gets, `None` if it may
+    clients: HashMap<u64, Option<(ErrorCode, String)>>,
+    /// The same for clients that connected since
+    newcomers: Option<(ErrorCode, String)>,
+}
+
+impl Killers {
+    fn new(acl: &Acl, clients: &Clients) -> Killers {
+        let cmd = command::lookup("script").unwrap();
+        let args = [b"script".to_vec(), b"kill".to_vec()];
+        let check = |user: Option<&str>| acl.check(user, cmd, &args).err();
+        Killers {
+            clients: clients.iter().map(|c| (c.id, check(c.user.as_deref()))).collect(),
+            newcomers: check(None),
+        }
+    }
+
+    /// The error `conn_id` gets for `script kill`, `None` if it may
+    fn check(&self, conn_id: u64) -> Option<&(ErrorCode, String)> {
+        self.clients.get(&conn_id).unwrap_or(&self.newcomers).as_ref()
+    }
+}
+
+impl Executor {
+    fn run(
+        self: &Rc<Executor>,
+        databases: &mut [Database],
+        scripting: &mut Scripting,
+        slowlog: &mut SlowLog,
+        acl: &mut Acl,
+        mut cluster: Option<&mut Cluster>,
+    ) {
+        let executor = self.clone();
+        let on_busy: Rc<dyn Fn(bool) -> bool> = Rc::new(move |killable| executor.answer_while_busy(killable));
+        let mut clients = Clients::new();
+        let mut pubsub = PubSub::new();
+        let mut tracking = Tracking::new();
+        let mut replication = Replication::new();
+
+        while let Some(job) = self.next_job(
+            databases,
+            &mut clients,
+            &mut pubsub,
+            &mut tracking,
+            &mut replication,
+            cluster.as_deref_mut(),
+        ) {
+            if job.conn_id == PRIMARY_CONN {
+                let mut ctx = Context {
+                    db_index: replication.stream_db(),
+                    stats: Some(&self.stats),
+                    pubsub: Some(&mut pubsub),
+                    tracking: Some(&mut tracking),
+                    ..Context::with_databases(databases)
+                };
+                command::execute(&mut ctx, job.args, &mut vec![]);
+                let db_index = ctx.db_index;
+                replication.set_stream_db(db_index);
+                self.deliver(&clients, &mut pubsub, &mut replication);
+                continue;
+            }
+
+            self.current.set((job.thread, job.conn_id));
+            let cmd = job.command();
+            let mut db_index = 0;
+            if let Some(client) = clients.get_mut(job.conn_id) {
+                client.last_interaction = Instant::now();
+                client.last_cmd = cmd.map_or("NULL", |c| c.name);
+                db_index = client.db;
+            }
+
+            if cmd.is_some_and(|c| matches!(c.name, "eval" | "evalsha")) {
+                *self.killers.borrow_mut() = Killers::new(acl, &clients);
+            }
+
+            let mut close = cmd.is_some_and(|c| c.name == "quit");
+            let mut ctx = Context {
+                dbs: databases,
+                db_index,
+                scripting: Some(scripting),
+                on_busy: Some(on_busy.clone()),
+                stats: Some(&self.stats),
+                slowlog: Some(slowlog),
+                clients: Some(&mut clients),
+                client_id: job.conn_id,
+                acl: Some(acl),
+                pubsub: Some(&mut pubsub),
+                tracking: Some(&mut tracking),
+                cluster: cluster.as_deref_mut(),
+                replication: Some(&mut replication),
+            };
+            let mut body = vec![];
+            command::execute(&mut ctx, job.args, &mut body);
+            // `select` sticks for the following commands
+            let db_index = ctx.db_index;
+            if let Some(client) = clients.get_mut(job.conn_id) {
+                client.db = db_index;
+            }
+
+            for id in clients.take_killed() {
+                // Killing ourselves still gets the reply out
+                if id == job.conn_id {
+                    close = true;
+                } else if let Some(client) = clients.remove(id) {
+                    pubsub.remove_client(id);
+                    tracking.disable(id);
+                    replication.remove_replica(id);
+                    self.io_handles[client.thread].send(IoMessage::Close { conn_id: id });
+                }
+            }
+            self.reply(job.thread, job.conn_id, body, close);
+            self.deliver(&clients, &mut pubsub, &mut replication);
+
+            self.link.store(replication.link(), Ordering::Relaxed);
+            if let Some((link, host, port)) = replication.take_pending_link() {
+                let (current, events, listening_port) = (self.link.clone(), self.events_tx.clone(), self.port);
+                let spawned = thread::Builder::new()
+                    .name("replication".to_string())
+                    .spawn(move || replication::follow(link, host, port, listening_port, current, events));
+                if let Err(e) = spawned {
+                    warning!("Couldn't spawn replication thread: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Push the messages queued for subscribers and replicas
+    fn deliver(&self, clients: &Clients, pubsub: &mut PubSub, replication: &mut Replication) {
+        for (id, message) in pubsub.take_outbox().into_iter().chain(replication.take_outbox()) {
+            if let Some(client) = clients.get(id) {
+                self.reply(client.thread, id, message, false);
+            }
+        }
+    }
+
+    /// Whether `job` has to wait for a pause in `mode` to end
+    fn must_wait(&self, job: &Job, mode: PauseMode) -> bool {
+        // Later requests of a client can't overtake the held ones
+        if mode == PauseMode::All || self.held.borrow().iter().any(|j| j.conn_id == job.conn_id) {
+            return true;
+        }
+        // Scripts may write
+        job.command()
+            .is_some_and(|cmd| cmd.has_flag(CMD_WRITE) || matches!(cmd.name, "eval" | "evalsha"))
+    }
+
+    fn next_job(
+        &self,
+        databases: &mut [Database],
+        clients: &mut Clients,
+        pubsub: &mut PubSub,
+        tracking: &mut Tracking,
+        replication: &mut Replication,
+        mut cluster: Option<&mut Cluster>,
+    ) -> Option<Job> {
+        loop {
+            let paused = clients.paused();
+            if paused.is_none() {
+                if let Some(job) = self.held.borrow_mut().pop_front() {
+                    return Some(job);
+                }
+            }
+
+            let deferred = self.deferred.borrow_mut().pop_front();
+            let event = match (deferred, paused) {
+                (Some(event), _) => event,
+                (None, Some((until, _))) => match self.events.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                },
+                (None, None) => self.events.recv().ok()?,
+            };
+
+            match event {
+                Event::Connected(client) => clients.add(client),
+                Event::Closed { conn_id } => {
+                    clients.remove(conn_id);
+                    pubsub.remove_client(conn_id);
+                    tracking.disable(conn_id);
+                    replication.remove_replica(conn_id);
+                    if let Some(cluster) = cluster.as_deref_mut() {
+                        cluster.take_asking(conn_id);
+                    }
+                    self.held.borrow_mut().retain(|job| job.conn_id != conn_id);
+                }
+                Event::Bus { message, from, local } => {
+                    if let Some(cluster) = cluster.as_deref_mut() {
+                        cluster.receive(message, from, local, Instant::now());
+                    }
+                }
+                Event::ClusterTick => {
+                    if let (Some(cluster), Some(bus)) = (cluster.as_deref(), &self.bus) {
+                        for ping in cluster.tick() {
+                            let _ = bus.send(ping);
+                        }
+                    }
+                }
+                // Replicas wait for their primary to delete keys, and pauses
+                // hold expiry like they hold writes
+                Event::ExpireTick if !replication.is_replica() && paused.is_none() => {
+                    let mut ctx = Context {
+                        stats: Some(&self.stats),
+                        pubsub: Some(pubsub),
+                        tracking: Some(tracking),
+                        replication: Some(replication),
+                        ..Context::with_databases(databases)
+                    };
+                    command::active_expire(&mut ctx, Instant::now() + ACTIVE_EXPIRE_BUDGET);
+                    self.deliver(clients, pubsub, replication);
+                }
+                Event::ExpireTick => {}
+                Event::Synced { link, offset } => replication.synced(link, offset),
+                Event::PrimaryDown { link } => replication.link_down(link),
+                // Not held by pauses, those are for our own clients
+                Event::Replicated { link, args, bytes } => {
+                    if replication.apply(link, bytes) {
+                        return Some(Job { thread: 0, conn_id: PRIMARY_CONN, args });
+                    }
+                }
+                Event::Job(job) => match paused {
+                    Some((_, mode)) if self.must_wait(&job, mode) => self.held.borrow_mut().push_back(job),
+                    _ => return Some(job),
+                },
+            }
+        }
+    }
+
+    fn reply(&self, thread: usize, conn_id: u64, body: Vec<u8>, close: bool) {
+        self.io_handles[thread].send(IoMessage::Reply { conn_id, body, close });
+    }
+
+    /// Called while a script is over its time limit: everyone else gets a
+    /// BUSY reply, except for `script kill` from clients allowed to run it.
+    /// Returns true if the script should be killed.
+    fn answer_while_busy(&self, killable: bool) -> bool {
+        let mut kill = false;
+        while let Ok(event) = self.events.try_recv() {
+            let job = match event {
+                Event::Job(job) if (job.thread, job.conn_id) != self.current.get() => job,
+                event => {
+                    self.deferred.borrow_mut().push_back(event);
+                    continue;
+                }
+            };
+
+            let is_kill = job.args.len() == 2
+                && job.args[0].eq_ignore_ascii_case(b"script")
+                && job.args[1].eq_ignore_ascii_case(b"kill");
+            let mut body = vec![];
+            let denied = is_kill.then(|| self.killers.borrow().check(job.conn_id).cloned()).flatten();
+            if let Some((code, msg)) = denied {
+                out_err(&mut body, code, &msg);
+            } else if is_kill && killable {
+                kill = true;
+                out_ok(&mut body);
+            } else if is_kill {
+                out_err(
+                    &mut body,
+                    ErrorCode::Unkillable,
+                    "Sorry the script already executed write commands against the dataset. \
+                     You can either wait the script termination or kill the server in a hard way.",
+                );
+            } else {
+                out_err(
+                    &mut body,
+                    ErrorCode::Busy,
+                    "Redis is busy running a script. You can only call SCRIPT KILL.",
+                );
+            }
+            self.reply(job.thread, job.conn_id, body, false);
+        }
+        kill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{encode_request, Client, Reply};
+    use crate::{read_full, write_all};
+    use std::net::TcpStream;
+
+    fn request<A: AsRef<[u8]>>(stream: &mut TcpStream, args: &[A]) -> Reply {
+        let mut frame = vec![];
+        encode_request(args, &mut frame).unwrap();
+        assert!(write_all(stream, &frame, frame.len()));
+        read_reply(stream)
+    }
+
+    fn read_reply(stream: &mut TcpStream) -> Reply {
+        let mut len = [0u8; 4];
+        assert!(read_full(stream, &mut len, 4));
+        let len = u32::from_le_bytes(len) as usize;
+        let mut res = vec![0u8; len];
+        assert!(read_full(stream, &mut res, len));
+        Reply::decode(&res).unwrap()
+    }
+
+    #[test]
+    fn test_clients_share_database() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            io_threads: 3,
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut clients = (0..4)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect::<Vec<TcpStream>>();
+
+        for (i, client) in clients.iter_mut().enumerate() {
+            let value = format!("value{}", i);
+            assert_eq!(request(client, &["set", &format!("key{}", i), &value]), Reply::Str(b"OK".to_vec()));
+        }
+        for client in clients.iter_mut() {
+            for i in 0..4 {
+                let value = request(client, &["get", &format!("key{}", i)]);
+                assert_eq!(value, Reply::Str(format!("value{}", i).into_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_arguments() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Neither is UTF-8, and the keys only differ in a byte that isn't
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (key, other, value) = (&b"k\xff"[..], &b"k\xfe"[..], &b"\x80\x00v"[..]);
+        assert_eq!(request(&mut client, &[&b"set"[..], key, value]), Reply::Str(b"OK".to_vec()));
+        assert_eq!(request(&mut client, &[&b"get"[..], key]), Reply::Str(value.to_vec()));
+        assert_eq!(request(&mut client, &[&b"get"[..], other]), Reply::Nil);
+        assert_eq!(request(&mut client, &["randomkey"]), Reply::Str(key.to_vec()));
+    }
+
+    #[test]
+    fn test_script_kill() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            io_threads: 2,
+            lua_time_limit: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut other = TcpStream::connect(addr).unwrap();
+        let reply = request(&mut other, &["script", "kill"]);
+        assert!(matches!(reply, Reply::Error { code, .. } if code == "NOTBUSY"));
+
+        let looping = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            let mut frame = vec![];
+            encode_request(&["eval", "while true do end", "0"], &mut frame).unwrap();
+            encode_request(&["ping"], &mut frame).unwrap();
+            assert!(write_all(&mut client, &frame, frame.len()));
+            (read_reply(&mut client), read_reply(&mut client))
+        });
+
+        // Everyone else is told to wait until the script is killed
+        loop {
+            match request(&mut other, &["ping"]) {
+                Reply::Error { code, .. } if code == "BUSY" => break,
+                _ => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert_eq!(request(&mut other, &["script", "kill"]), Reply::Str(b"OK".to_vec()));
+
+        // The pipelined ping waited for the script instead of being refused
+        let (script, ping) = looping.join().unwrap();
+        assert!(matches!(script, Reply::Error { message, .. } if message.contains("Script killed")));
+        assert_eq!(ping, Reply::Str(b"PONG".to_vec()));
+        assert_eq!(request(&mut other, &["ping"]), Reply::Str(b"PONG".to_vec()));
+    }
+
+    #[test]
+    fn test_script_kill_needs_auth() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            lua_time_limit: Duration::from_millis(10),
+            requirepass: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut admin = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut admin, &["auth", "secret"]), Reply::Str(b"OK".to_vec()));
+        let mut stranger = TcpStream::connect(addr).unwrap();
+        let looping = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            assert_eq!(request(&mut client, &["auth", "secret"]), Reply::Str(b"OK".to_vec()));
+            request(&mut client, &["eval", "while true do end", "0"])
+        });
+        loop {
+            match request(&mut admin, &["ping"]) {
+                Reply::Error { code, .. } if code == "BUSY" => break,
+                _ => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+
+        let noauth = |reply: Reply| matches!(reply, Reply::Error { code, .. } if code == "NOAUTH");
+        assert!(noauth(request(&mut stranger, &["script", "kill"])));
+        let mut newcomer = TcpStream::connect(addr).unwrap();
+        assert!(noauth(request(&mut newcomer, &["script", "kill"])));
+        assert_eq!(request(&mut admin, &["script", "kill"]), Reply::Str(b"OK".to_vec()));
+        assert!(matches!(looping.join().unwrap(), Reply::Error { message, .. } if message.contains("Script killed")));
+    }
+
+    #[test]
+    fn test_info_stats() {
+        let config = Config { bind: "127.0.0.1:0".to_string(), ..Config::default() };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        request(&mut first, &["set", "k", "v"]);
+        request(&mut second, &["get", "k"]);
+
+        let Reply::Str(info) = request(&mut first, &["info", "everything"]) else {
+            panic!("expected a string");
+        };
+        let info = String::from_utf8(info).unwrap();
+        let field = |name: &str| {
+            let line = info.lines().find(|l| l.starts_with(&format!("{}:", name))).unwrap();
+            line.split_once(':').unwrap().1.to_string()
+        };
+        assert_eq!(field("tcp_port"), addr.port().to_string());
+        assert_eq!(field("connected_clients"), "2");
+        assert_eq!(field("total_connections_received"), "2");
+        // The info command itself isn't counted until it's done
+        assert_eq!(field("total_commands_processed"), "2");
+        assert!(field("total_net_input_bytes").parse::<u64>().unwrap() > 0);
+        assert!(field("cmdstat_set").starts_with("calls=1,usec="));
+        assert_eq!(field("db0"), "keys=1,expires=0");
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            slowlog_log_slower_than: 0,
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        request(&mut client, &["set", "k", "v"]);
+        request(&mut client, &["eval", "return redis.call('get', 'k')", "0"]);
+        // Commands run by the script aren't logged on their own
+        assert_eq!(request(&mut client, &["slowlog", "len"]), Reply::Int(2));
+
+        let Reply::Array(entries) = request(&mut client, &["slowlog", "get", "1"]) else {
+            panic!("expected an array");
+        };
+        let Reply::Array(ref entry) = entries[0] else {
+            panic!("expected an array");
+        };
+        assert_eq!(entry[0], Reply::Int(2));
+        assert_eq!(entry[3], Reply::Array(vec![Reply::Str(b"slowlog".to_vec()), Reply::Str(b"len".to_vec())]));
+        assert_eq!(entry[4], Reply::Str(client.local_addr().unwrap().to_string().into_bytes()));
+
+        request(&mut client, &["config", "set", "slowlog-log-slower-than", "-1"]);
+        assert_eq!(request(&mut client, &["slowlog", "reset"]), Reply::Str(b"OK".to_vec()));
+        request(&mut client, &["get", "k"]);
+        assert_eq!(request(&mut client, &["slowlog", "len"]), Reply::Int(0));
+    }
+
+    #[test]
+    fn test_client_commands() {
+        let config = Config { bind: "127.0.0.1:0".to_string(), io_threads: 2, ..Config::default() };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut admin = TcpStream::connect(addr).unwrap();
+        let mut other = TcpStream::connect(addr).unwrap();
+        let other_id = match request(&mut other, &["client", "id"]) {
+            Reply::Int(id) => id,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(request(&mut admin, &["client", "setname", "admin"]), Reply::Str(b"OK".to_vec()));
+        assert_eq!(request(&mut admin, &["client", "getname"]), Reply::Str(b"admin".to_vec()));
+        assert!(matches!(request(&mut admin, &["client", "setname", "a b"]), Reply::Error { .. }));
+
+        let Reply::Str(list) = request(&mut admin, &["client", "list"]) else {
+            panic!("expected a string");
+        };
+        let list = String::from_utf8(list).unwrap();
+        assert_eq!(list.lines().count(), 2);
+        let mine = format!("addr={} name=admin ", admin.local_addr().unwrap());
+        assert!(list.lines().any(|l| l.contains(&mine) && l.ends_with("db=0 cmd=client user=")));
+
+        // The selected database sticks, except when selected by a script
+        request(&mut admin, &["select", "3"]);
+        request(&mut admin, &["eval", "redis.call('select', '5')", "0"]);
+        request(&mut admin, &["set", "in3", "v"]);
+        assert_eq!(request(&mut other, &["get", "in3"]), Reply::Nil);
+        let Reply::Str(info) = request(&mut admin, &["client", "info"]) else {
+            panic!("expected a string");
+        };
+        assert!(String::from_utf8(info).unwrap().contains(" db=3 "));
+        assert!(list.contains(&format!("id={} addr={} name= ", other_id, other.local_addr().unwrap())));
+
+        // Writes wait for the pause to end, reads don't
+        request(&mut admin, &["client", "pause", "200", "write"]);
+        let start = Instant::now();
+        assert_eq!(request(&mut other, &["get", "k"]), Reply::Nil);
+        assert!(start.elapsed() < Duration::from_millis(150));
+        request(&mut other, &["set", "k", "v"]);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let id = other_id.to_string();
+        assert_eq!(request(&mut admin, &["client", "kill", "id", &id]), Reply::Int(1));
+        let mut buf = [0u8; 1];
+        assert!(!read_full(&mut other, &mut buf, 1));
+        assert!(matches!(request(&mut admin, &["client", "kill", "1.2.3.4:5"]), Reply::Error { .. }));
+    }
+
+    #[test]
+    fn test_acl() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            requirepass: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let ok = Reply::Str(b"OK".to_vec());
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert!(matches!(request(&mut client, &["get", "k"]), Reply::Error { ref code, .. } if code == "NOAUTH"));
+        assert!(matches!(request(&mut client, &["auth", "wrong"]), Reply::Error { ref code, .. } if code == "WRONGPASS"));
+        assert_eq!(request(&mut client, &["auth", "secret"]), ok);
+        assert_eq!(request(&mut client, &["acl", "whoami"]), Reply::Str(b"default".to_vec()));
+
+        let rules = ["on", ">pw", "~app:*", "+@read", "+set"];
+        let setuser = [&["acl", "setuser", "app"][..], &rules[..]].concat();
+        assert_eq!(request(&mut client, &setuser), ok);
+
+        let mut app = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut app, &["auth", "app", "pw"]), ok);
+        assert_eq!(request(&mut app, &["set", "app:1", "v"]), ok);
+        assert!(matches!(request(&mut app, &["set", "other", "v"]), Reply::Error { ref code, .. } if code == "NOPERM"));
+        assert!(matches!(request(&mut app, &["del", "app:1"]), Reply::Error { ref code, .. } if code == "NOPERM"));
+        assert!(matches!(request(&mut app, &["hello", "1"]), Reply::Array(_)));
+
+        // Deleting the user logs its clients out
+        assert_eq!(request(&mut client, &["acl", "deluser", "app"]), Reply::Int(1));
+        let mut buf = [0u8; 1];
+        assert!(!read_full(&mut app, &mut buf, 1));
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            io_threads: 2,
+            notify_keyspace_events: crate::database::notify::parse_flags("KA").unwrap(),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut subscriber = Client::connect(addr).unwrap();
+        subscriber.command(&["subscribe", "__keyspace@0__:k"]).unwrap();
+        let mut client = Client::connect(addr).unwrap();
+        client.set(b"k", b"v").unwrap();
+        client.expire(b"k", 0).unwrap();
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        for event in ["set", "del"] {
+            let message = Reply::Array(vec![bulk("message"), bulk("__keyspace@0__:k"), bulk(event)]);
+            assert_eq!(subscriber.receive().unwrap(), message);
+        }
+
+        // Subscriptions go away with the client
+        drop(subscriber);
+        let numsub = Reply::Array(vec![bulk("__keyspace@0__:k"), Reply::Int(0)]);
+        for _ in 0..100 {
+            if client.command(&["pubsub", "numsub", "__keyspace@0__:k"]).unwrap() == numsub {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("subscription outlived its client");
+    }
+
+    #[test]
+    fn test_active_expire() {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            notify_keyspace_events: crate::database::notify::parse_flags("Ex").unwrap(),
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut subscriber = Client::connect(addr).unwrap();
+        subscriber.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        subscriber.command(&["subscribe", "__keyevent@0__:expired"]).unwrap();
+        let mut client = Client::connect(addr).unwrap();
+        client.set(b"k", b"v").unwrap();
+        client.expire(b"k", 1).unwrap();
+
+        // Nobody reads the key, it goes anyway
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let message = Reply::Array(vec![bulk("message"), bulk("__keyevent@0__:expired"), bulk("k")]);
+        assert_eq!(subscriber.receive().unwrap(), message);
+        assert_eq!(client.command(&["dbsize"]).unwrap(), Reply::Int(0));
+    }
+
+    #[test]
+    fn test_client_tracking() {
+        let config = Config { bind: "127.0.0.1:0".to_string(), io_threads: 2, ..Config::default() };
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let ok = bulk("OK");
+        let keys = |keys: &[&str]| Reply::Array(keys.iter().map(|k| bulk(k)).collect());
+        let invalidate = |keys: Reply| Reply::Push(vec![bulk("invalidate"), keys]);
+        let mut tracker = Client::connect(addr).unwrap();
+        let mut writer = Client::connect(addr).unwrap();
+        assert_eq!(tracker.command(&["client", "getredir"]).unwrap(), Reply::Int(-1));
+        assert_eq!(tracker.command(&["client", "tracking", "on"]).unwrap(), ok);
+        assert_eq!(tracker.command(&["client", "getredir"]).unwrap(), Reply::Int(0));
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), Reply::Nil);
+
+        // Told once, until the key is read again
+        writer.command(&["set", "k", "1"]).unwrap();
+        assert_eq!(tracker.receive().unwrap(), invalidate(keys(&["k"])));
+        writer.command(&["set", "k", "2"]).unwrap();
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), bulk("2"));
+        writer.command(&["del", "k"]).unwrap();
+        assert_eq!(tracker.receive().unwrap(), invalidate(keys(&["k"])));
+
+        // Replies stay in step with the pushes queued ahead of them
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), Reply::Nil);
+        writer.command(&["set", "k", "v"]).unwrap();
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), bulk("v"));
+        assert_eq!(tracker.command(&["ping"]).unwrap(), bulk("PONG"));
+        assert_eq!(tracker.receive().unwrap(), invalidate(keys(&["k"])));
+
+        // Expiring without being read again
+        writer.command(&["expire", "k", "1"]).unwrap();
+        assert_eq!(tracker.receive().unwrap(), invalidate(keys(&["k"])));
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), bulk("v"));
+        tracker.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(tracker.receive().unwrap(), invalidate(keys(&["k"])));
+        tracker.set_timeout(None).unwrap();
+
+        // Own writes with noloop
+        tracker.command(&["client", "tracking", "off"]).unwrap();
+        assert_eq!(tracker.command(&["client", "tracking", "on", "noloop"]).unwrap(), ok);
+        tracker.command(&["get", "k"]).unwrap();
+        tracker.command(&["set", "k", "3"]).unwrap();
+        assert_eq!(tracker.command(&["get", "k"]).unwrap(), bulk("3"));
+
+        // Broadcasting by prefix, redirected to a subscriber
+        let mut listener = Client::connect(addr).unwrap();
+        let Reply::Int(listener_id) = listener.command(&["client", "id"]).unwrap() else {
+            panic!("expected an id");
+        };
+        listener.command(&["subscribe", "__redis__:invalidate"]).unwrap();
+        let mut broadcast = Client::connect(addr).unwrap();
+        let id = listener_id.to_string();
+        let on = ["client", "tracking", "on", "bcast", "prefix", "user:", "redirect", &id];
+        assert_eq!(broadcast.command(&on).unwrap(), ok);
+        assert_eq!(broadcast.command(&["client", "getredir"]).unwrap(), Reply::Int(listener_id));
+        writer.command(&["set", "other", "1"]).unwrap();
+        writer.command(&["set", "user:1", "1"]).unwrap();
+        let message = |keys: Reply| Reply::Array(vec![bulk("message"), bulk("__redis__:invalidate"), keys]);
+        assert_eq!(listener.receive().unwrap(), message(keys(&["user:1"])));
+
+        // A flush invalidates everything
+        writer.command(&["flushall"]).unwrap();
+        assert_eq!(listener.receive().unwrap(), message(Reply::Nil));
+        assert_eq!(tracker.receive().unwrap(), invalidate(Reply::Nil));
+
+        let error = |message: &str| Reply::Error { code: "ERR".to_string(), message: message.to_string() };
+        assert_eq!(
+            broadcast.command(&["client", "tracking", "on"]).unwrap(),
+            error("You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.")
+        );
+        assert_eq!(
+            writer.command(&["client", "tracking", "on", "prefix", "a"]).unwrap(),
+            error("PREFIX option requires BCAST mode to be enabled")
+        );
+        assert_eq!(
+            writer.command(&["client", "tracking", "on", "redirect", "12345"]).unwrap(),
+            error("The client ID you want redirect to does not exist")
+        );
+        assert_eq!(writer.command(&["client", "tracking", "maybe"]).unwrap(), error("syntax error"));
+    }
+
+    #[test]
+    fn test_cluster() {
+        let nodes = (0..3)
+            .map(|_| {
+                let config = Config {
+                    bind: "127.0.0.1:0".to_string(),
+                    cluster_enabled: true,
+                    cluster_bus_bind: Some("127.0.0.1:0".to_string()),
+                    ..Config::default()
+                };
+                let server = Server::bind(config).unwrap();
+                let addrs = (server.local_addr().unwrap(), server.cluster_bus_addr().unwrap());
+                thread::spawn(move || server.run());
+                addrs
+            })
+            .collect::<Vec<_>>();
+        let mut clients = nodes.iter().map(|(addr, _)| Client::connect(addr).unwrap()).collect::<Vec<_>>();
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let ok = bulk("OK");
+        let error = |code: &str, message: String| Reply::Error { code: code.to_string(), message };
+        let ids = clients
+            .iter_mut()
+            .map(|client| match client.command(&["cluster", "myid"]).unwrap() {
+                Reply::Str(id) => String::from_utf8(id).unwrap(),
+                reply => panic!("unexpected reply {:?}", reply),
+            })
+            .collect::<Vec<_>>();
+        let wait_for = |client: &mut Client, args: &[&str], expected: &dyn Fn(&Reply) -> bool| {
+            for _ in 0..500 {
+                if expected(&client.command(args).unwrap()) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{:?} never got the expected reply", args);
+        };
+
+        // A third of the slots each, the nodes find each other through gossip
+        for (i, client) in clients.iter_mut().enumerate() {
+            let (start, end) = ((i * 16384 / 3).to_string(), ((i + 1) * 16384 / 3 - 1).to_string());
+            assert_eq!(client.command(&["cluster", "addslotsrange", &start, &end]).unwrap(), ok);
+        }
+        for (client, (addr, bus)) in [(0, nodes[1]), (2, nodes[0])] {
+            let meet = ["cluster", "meet", "127.0.0.1", &addr.port().to_string(), &bus.port().to_string()];
+            assert_eq!(clients[client].command(&meet).unwrap(), ok);
+        }
+        for client in clients.iter_mut() {
+            let info = |reply: &Reply| {
+                matches!(reply, Reply::Str(info) if String::from_utf8_lossy(info).contains("cluster_state:ok\r\ncluster_slots_assigned:16384\r\ncluster_known_nodes:3\r\n"))
+            };
+            wait_for(client, &["cluster", "info"], &info);
+        }
+
+        // "foo" is in slot 12182, served by the third node
+        let moved = |node: usize| error("MOVED", format!("12182 {}", nodes[node].0));
+        let ask = |node: usize| error("ASK", format!("12182 {}", nodes[node].0));
+        assert_eq!(clients[0].command(&["set", "foo", "bar"]).unwrap(), moved(2));
+        assert_eq!(clients[2].command(&["set", "foo", "bar"]).unwrap(), ok);
+        let Reply::Array(slots) = clients[0].command(&["cluster", "slots"]).unwrap() else {
+            panic!("expected an array");
+        };
+        assert_eq!(slots.len(), 3);
+        assert_eq!(
+            slots[2],
+            Reply::Array(vec![
+                Reply::Int(10922),
+                Reply::Int(16383),
+                Reply::Array(vec![bulk("127.0.0.1"), Reply::Int(nodes[2].0.port() as i64), bulk(&ids[2])]),
+            ])
+        );
+
+        // Along with "foo" goes a key whose dump outgrows the initial read buffer
+        assert_eq!(clients[2].command(&["setbit", "{foo}big", "80000", "1"]).unwrap(), Reply::Int(0));
+        let Reply::Str(dump) = clients[2].command(&["dump", "{foo}big"]).unwrap() else {
+            panic!("expected a dump");
+        };
+        assert!(dump.len() > 2 * 4096);
+
+        // Moving slot 12182 to the second node
+        let importing = ["cluster", "setslot", "12182", "importing", &ids[2]];
+        assert_eq!(clients[1].command(&importing).unwrap(), ok);
+        let migrating = ["cluster", "setslot", "12182", "migrating", &ids[1]];
+        assert_eq!(clients[2].command(&migrating).unwrap(), ok);
+        assert_eq!(clients[2].command(&["get", "foo"]).unwrap(), bulk("bar"));
+        assert_eq!(clients[2].command(&["get", "{foo}new"]).unwrap(), ask(1));
+        assert_eq!(clients[1].command(&["get", "{foo}new"]).unwrap(), moved(2));
+        assert_eq!(clients[1].command(&["asking"]).unwrap(), ok);
+        assert_eq!(clients[1].command(&["set", "{foo}new", "1"]).unwrap(), ok);
+        let port = nodes[1].0.port().to_string();
+        let migrate = ["migrate", "127.0.0.1", &port, "", "0", "1000", "keys", "foo", "{foo}big"];
+        assert_eq!(clients[2].command(&migrate).unwrap(), ok);
+        assert_eq!(clients[2].command(&["get", "foo"]).unwrap(), ask(1));
+        assert_eq!(clients[1].command(&["asking"]).unwrap(), ok);
+        assert_eq!(clients[1].command(&["get", "foo"]).unwrap(), bulk("bar"));
+        assert_eq!(clients[1].command(&["asking"]).unwrap(), ok);
+        assert_eq!(clients[1].command(&["getbit", "{foo}big", "80000"]).unwrap(), Reply::Int(1));
+        assert_eq!(clients[2].command(&["cluster", "countkeysinslot", "12182"]).unwrap(), Reply::Int(0));
+
+        for client in [1, 2] {
+            let node = ["cluster", "setslot", "12182", "node", &ids[1]];
+            assert_eq!(clients[client].command(&node).unwrap(), ok);
+        }
+        assert_eq!(clients[2].command(&["get", "foo"]).unwrap(), moved(1));
+        assert_eq!(clients[1].command(&["get", "foo"]).unwrap(), bulk("bar"));
+        // The first node hears about it from the new owner
+        wait_for(&mut clients[0], &["get", "foo"], &|reply| *reply == moved(1));
+    }
+
+    #[test]
+    fn test_replication() {
+        let servers = (0..2)
+            .map(|_| {
+                let server = Server::bind(Config { bind: "127.0.0.1:0".to_string(), ..Config::default() }).unwrap();
+                let addr = server.local_addr().unwrap();
+                thread::spawn(move || server.run());
+                addr
+            })
+            .collect::<Vec<_>>();
+        let mut primary = Client::connect(servers[0]).unwrap();
+        let mut replica = Client::connect(servers[1]).unwrap();
+        let bulk = |s: &str| Reply::Str(s.as_bytes().to_vec());
+        let ok = bulk("OK");
+        let wait_for = |client: &mut Client, args: &[&str], expected: &Reply| {
+            for _ in 0..500 {
+                if client.command(args).unwrap() == *expected {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("{:?} never replied {:?}", args, expected);
+        };
+
+        // The replica drops its own data for the snapshot
+        primary.command(&["set", "a", "1"]).unwrap();
+        primary.command(&["select", "3"]).unwrap();
+        primary.command(&["set", "b", "2"]).unwrap();
+        replica.command(&["set", "mine", "1"]).unwrap();
+        let port = servers[0].port().to_string();
+        assert_eq!(replica.command(&["replicaof", "127.0.0.1", &port]).unwrap(), ok);
+        wait_for(&mut replica, &["get", "a"], &bulk("1"));
+        assert_eq!(replica.command(&["exists", "mine"]).unwrap(), Reply::Int(0));
+        let readonly = Reply::Error {
+            code: "READONLY".to_string(),
+            message: "You can't write against a read only replica.".to_string(),
+        };
+        assert_eq!(replica.command(&["set", "a", "2"]).unwrap(), readonly);
+
+        // Then follows every change, in the right database
+        primary.command(&["set", "c", "3"]).unwrap();
+        primary.command(&["expire", "c", "100"]).unwrap();
+        primary.command(&["select", "0"]).unwrap();
+        primary.command(&["del", "a"]).unwrap();
+        replica.command(&["select", "3"]).unwrap();
+        wait_for(&mut replica, &["ttl", "c"], &Reply::Int(100));
+        assert_eq!(replica.command(&["get", "b"]).unwrap(), bulk("2"));
+        replica.command(&["select", "0"]).unwrap();
+        wait_for(&mut replica, &["exists", "a"], &Reply::Int(0));
+        primary.command(&["swapdb", "0", "3"]).unwrap();
+        wait_for(&mut replica, &["get", "b"], &bulk("2"));
+
+        let Reply::Array(role) = primary.command(&["role"]).unwrap() else {
+            panic!("expected an array");
+        };
+        let Reply::Int(offset) = role[1] else {
+            panic!("expected an offset");
+        };
+        let replicas = Reply::Array(vec![Reply::Array(vec![
+            bulk("127.0.0.1"),
+            bulk(&servers[1].port().to_string()),
+            bulk(&offset.to_string()),
+        ])]);
+        assert_eq!(role, [bulk("master"), Reply::Int(offset), replicas]);
+        let role = [bulk("slave"), bulk("127.0.0.1"), Reply::Int(servers[0].port() as i64), bulk("connected"), Reply::Int(offset)];
+        wait_for(&mut replica, &["role"], &Reply::Array(role.to_vec()));
+        let Reply::Str(info) = primary.command(&["info", "replication"]).unwrap() else {
+            panic!("expected a string");
+        };
+        let line = format!("slave0:ip=127.0.0.1,port={},state=online\r\n", servers[1].port());
+        assert!(String::from_utf8(info).unwrap().contains(&line));
+
+        // A promoted replica keeps its data and takes writes
+        assert_eq!(replica.command(&["replicaof", "no", "one"]).unwrap(), ok);
+        assert_eq!(replica.command(&["set", "a", "2"]).unwrap(), ok);
+        assert_eq!(replica.command(&["get", "b"]).unwrap(), bulk("2"));
+        let Reply::Str(info) = replica.command(&["info", "replication"]).unwrap() else {
+            panic!("expected a string");
+        };
+        assert!(String::from_utf8(info).unwrap().starts_with("# Replication\r\nrole:master\r\nconnected_slaves:0\r\n"));
+    }
+}
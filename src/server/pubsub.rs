@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::database::notify::{KeyEvent, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE};
+use crate::glob::glob_match;
+use crate::protocol::*;
+
+/// What one client is subscribed to
+#[derive(Default)]
+struct Subscriptions {
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+}
+
+impl Subscriptions {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+/// Channel and pattern subscriptions, and the messages pushed to clients
+/// since the executor last sent them out
+#[derive(Default)]
+pub struct PubSub {
+    channels: BTreeMap<Vec<u8>, BTreeSet<u64>>,
+    patterns: BTreeMap<Vec<u8>, BTreeSet<u64>>,
+    clients: HashMap<u64, Subscriptions>,
+    /// Reply bodies to push to subscribers, by client id
+    outbox: Vec<(u64, Vec<u8>)>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub::default()
+    }
+
+    /// Number of channels and patterns client `id` is subscribed to
+    pub fn subscriptions(&self, id: u64) -> usize {
+        self.clients.get(&id).map_or(0, |s| s.count())
+    }
+
+    pub fn is_subscribed(&self, id: u64, channel: &[u8]) -> bool {
+        self.clients.get(&id).is_some_and(|s| s.channels.contains(channel))
+    }
+
+    pub fn channels_of(&self, id: u64) -> Vec<Vec<u8>> {
+        self.clients.get(&id).map_or(vec![], |s| s.channels.iter().cloned().collect())
+    }
+
+    pub fn patterns_of(&self, id: u64) -> Vec<Vec<u8>> {
+        self.clients.get(&id).map_or(vec![], |s| s.patterns.iter().cloned().collect())
+    }
+
+    /// Both return the number of subscriptions of `id` afterwards
+    pub fn subscribe(&mut self, id: u64, channel: &[u8]) -> usize {
+        self.channels.entry(channel.to_vec()).or_default().insert(id);
+        let subs = self.clients.entry(id).or_default();
+        subs.channels.insert(channel.to_vec());
+        subs.count()
+    }
+
+    pub fn psubscribe(&mut self, id: u64, pattern: &[u8]) -> usize {
+        self.patterns.entry(pattern.to_vec()).or_default().insert(id);
+        let subs = self.clients.entry(id).or_default();
+        subs.patterns.insert(pattern.to_vec());
+        subs.count()
+    }
+
+    pub fn unsubscribe(&mut self, id: u64, channel: &[u8]) -> usize {
+        PubSub::forget(&mut self.channels, id, channel);
+        self.update_client(id, |subs| subs.channels.remove(channel))
+    }
+
+    pub fn punsubscribe(&mut self, id: u64, pattern: &[u8]) -> usize {
+        PubSub::forget(&mut self.patterns, id, pattern);
+        self.update_client(id, |subs| subs.patterns.remove(pattern))
+    }
+
+    /// Drop all subscriptions of a client that went away
+    pub fn remove_client(&mut self, id: u64) {
+        if let Some(subs) = self.clients.remove(&id) {
+            for channel in subs.channels {
+                PubSub::forget(&mut self.channels, id, &channel);
+            }
+            for pattern in subs.patterns {
+                PubSub::forget(&mut self.patterns, id, &pattern);
+            }
+        }
+    }
+
+    fn forget(map: &mut BTreeMap<Vec<u8>, BTreeSet<u64>>, id: u64, name: &[u8]) {
+        if let Some(ids) = map.get_mut(name) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                map.remove(name);
+            }
+        }
+    }
+
+    fn update_client(&mut self, id: u64, f: impl FnOnce(&mut Subscriptions) -> bool) -> usize {
+        let Some(subs) = self.clients.get_mut(&id) else {
+            return 0;
+        };
+        f(subs);
+        let count = subs.count();
+        if count == 0 {
+            self.clients.remove(&id);
+        }
+        count
+    }
+
+    /// Queue `message` for everyone subscribed to `channel` directly or by
+    /// pattern, returns how many receive it
+    pub fn publish(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        for &id in self.channels.get(channel).into_iter().flatten() {
+            let mut body = vec![];
+            out_arr(&mut body, 3);
+            out_str(&mut body, b"message");
+            out_str(&mut body, channel);
+            out_str(&mut body, message);
+            self.outbox.push((id, body));
+            receivers += 1;
+        }
+        for (pattern, ids) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for &id in ids {
+                let mut body = vec![];
+                out_arr(&mut body, 4);
+                out_str(&mut body, b"pmessage");
+                out_str(&mut body, pattern);
+                out_str(&mut body, channel);
+                out_str(&mut body, message);
+                self.outbox.push((id, body));
+                receivers += 1;
+            }
+        }
+        receivers
+    }
+
+    /// Publish a key event of database `db` on the channels `flags` ask for.
+    /// ACL channel rules were checked when the subscribers subscribed, not
+    /// here.
+    pub fn notify(&mut self, db: usize, flags: u32, event: &KeyEvent) {
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+            channel.extend_from_slice(&event.key);
+            self.publish(&channel, event.event.as_bytes());
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.publish(format!("__keyevent@{}__:{}", db, event.event).as_bytes(), &event.key);
+        }
+    }
+
+    /// Channels with at least one subscriber, matching `pattern` if given
+    pub fn active_channels(&self, pattern: Option<&[u8]>) -> Vec<&[u8]> {
+        self.channels
+            .keys()
+            .filter(|c| pattern.is_none_or(|p| glob_match(p, c)))
+            .map(|c| c.as_slice())
+            .collect()
+    }
+
+    /// Number of clients subscribed to `channel`, patterns not counted
+    pub fn num_subscribers(&self, channel: &[u8]) -> usize {
+        self.channels.get(channel).map_or(0, |ids| ids.len())
+    }
+
+    /// Number of distinct patterns subscribed to
+    pub fn num_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Queue a reply body for client `id` that isn't a published message
+    pub fn push(&mut self, id: u64, body: Vec<u8>) {
+        self.outbox.push((id, body));
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(u64, Vec<u8>)> {
+        std::mem::take(&mut self.outbox)
+    }
+}
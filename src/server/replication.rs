@@ -0,0 +1,439 @@
+//! Copying the dataset of a primary to its replicas. A replica connects to
+//! its primary, announces the port it serves clients on with `replconf
+//! listening-port` and sends `sync`. The primary replies with its offset
+//! and the number of frames in a snapshot of every key, then pushes that
+//! snapshot followed by a stream of what each write changed. Both are made
+//! of commands the replica runs as they come. Writes that only changed the
+//! keys they name are streamed as they were run. Anything else, like
+//! expiry, eviction or `expire` going by the clock, is streamed as
+//! `restore` with the absolute expiry for keys that exist and `del` for
+//! keys that are gone, along with `flushdb`, `swapdb` and `select`.
+//! Offsets count the bytes of the stream, so replicas of the same primary
+//! can tell who is ahead.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::client::{encode_request, reply_len, Reply, HEADER_LEN};
+use crate::database::{dump, Database};
+use crate::protocol::*;
+use crate::{notice, warning};
+
+use super::Event;
+
+/// How long connecting to the primary and the handshake may take
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause between attempts to reach the primary
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// How often a link thread waiting for data checks whether it's still wanted
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The primary we replicate from
+pub struct PrimaryLink {
+    pub host: String,
+    pub port: u16,
+    /// Whether we are synced and receiving the stream
+    pub up: bool,
+}
+
+/// A replica connected to us
+pub struct Replica {
+    pub ip: Option<IpAddr>,
+    /// Where it serves its own clients
+    pub port: u16,
+}
+
+#[derive(Default)]
+pub struct Replication {
+    /// `None` on a primary
+    primary: Option<PrimaryLink>,
+    /// Replicas by client id
+    replicas: BTreeMap<u64, Replica>,
+    /// Ports announced with `replconf listening-port`, until `sync`
+    announced: HashMap<u64, u16>,
+    /// Bytes of the stream sent, on a primary, or applied, on a replica
+    offset: u64,
+    /// Database selected by the stream so far
+    stream_db: usize,
+    /// Reply bodies to push to replicas, by client id
+    outbox: Vec<(u64, Vec<u8>)>,
+    /// Bumped on every `replicaof`, so that a link thread can tell when
+    /// it's no longer wanted
+    link: u64,
+    /// Set when a link thread has to be started for `link`
+    link_pending: bool,
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication::default()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    pub fn primary(&self) -> Option<&PrimaryLink> {
+        self.primary.as_ref()
+    }
+
+    pub fn replicas(&self) -> impl Iterator<Item = &Replica> {
+        self.replicas.values()
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Start replicating from `host:port`, dropping the data we have once
+    /// synced. Returns the clients that were our replicas, which have to
+    /// be disconnected.
+    pub fn follow(&mut self, host: &str, port: u16) -> Vec<u64> {
+        self.primary = Some(PrimaryLink { host: host.to_string(), port, up: false });
+        self.link += 1;
+        self.link_pending = true;
+        self.outbox.clear();
+        std::mem::take(&mut self.replicas).into_keys().collect()
+    }
+
+    /// Stop replicating and become a primary, keeping the data and offset
+    pub fn promote(&mut self) {
+        if self.primary.take().is_some() {
+            self.link += 1;
+            self.link_pending = false;
+            self.stream_db = 0;
+        }
+    }
+
+    /// Generation of the current link to the primary
+    pub fn link(&self) -> u64 {
+        self.link
+    }
+
+    /// The link thread to start after `follow`, if any
+    pub fn take_pending_link(&mut self) -> Option<(u64, String, u16)> {
+        if !std::mem::take(&mut self.link_pending) {
+            return None;
+        }
+        self.primary.as_ref().map(|p| (self.link, p.host.clone(), p.port))
+    }
+
+    /// The primary accepted our `sync`, its stream starts at `offset`
+    pub fn synced(&mut self, link: u64, offset: u64) {
+        if let Some(primary) = self.primary.as_mut().filter(|_| link == self.link) {
+            notice!("Synchronized with primary {}:{}", primary.host, primary.port);
+            primary.up = true;
+            self.offset = offset;
+            self.stream_db = 0;
+        }
+    }
+
+    pub fn link_down(&mut self, link: u64) {
+        if let Some(primary) = self.primary.as_mut().filter(|_| link == self.link) {
+            primary.up = false;
+        }
+    }
+
+    /// Whether a command from link `link` should still be run, counting
+    /// its `bytes` towards our offset
+    pub fn apply(&mut self, link: u64, bytes: usize) -> bool {
+        if link != self.link || self.primary.is_none() {
+            return false;
+        }
+        self.offset += bytes as u64;
+        true
+    }
+
+    /// Database the next command from the primary runs against
+    pub fn stream_db(&self) -> usize {
+        self.stream_db
+    }
+
+    pub fn set_stream_db(&mut self, index: usize) {
+        self.stream_db = index;
+    }
+
+    pub fn announce(&mut self, client: u64, port: u16) {
+        self.announced.insert(client, port);
+    }
+
+    /// Make client `id` a replica, queueing a snapshot of `dbs` for it.
+    /// Returns our offset and the number of frames in the snapshot.
+    pub fn add_replica(&mut self, id: u64, ip: Option<IpAddr>, dbs: &mut [Database]) -> (u64, usize) {
+        let port = self.announced.remove(&id).unwrap_or(0);
+        self.replicas.insert(id, Replica { ip, port });
+        let mut frames = vec![encode(&["flushall"])];
+        for (index, db) in dbs.iter_mut().enumerate() {
+            let keys = db.keys().map(<[u8]>::to_vec).collect::<Vec<_>>();
+            if keys.is_empty() {
+                continue;
+            }
+            frames.push(encode(&["select", &index.to_string()]));
+            frames.extend(keys.iter().filter_map(|key| restore(db, key)));
+        }
+        frames.push(encode(&["select", &self.stream_db.to_string()]));
+        let count = frames.len();
+        self.outbox.extend(frames.into_iter().map(|frame| (id, frame)));
+        (self.offset, count)
+    }
+
+    pub fn remove_replica(&mut self, id: u64) {
+        self.announced.remove(&id);
+        self.replicas.remove(&id);
+    }
+
+    /// Stream what the last command did to database `index`: the current
+    /// value of each of `keys`, after emptying it if it was `flushed`
+    pub fn propagate_changes(&mut self, index: usize, db: &mut Database, keys: Vec<Vec<u8>>, flushed: bool) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        if flushed {
+            self.feed(index, encode(&["flushdb"]));
+        }
+        let mut seen = BTreeSet::new();
+        for key in keys {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let frame = restore(db, &key).unwrap_or_else(|| encode(&[&b"del"[..], &key]));
+            self.feed(index, frame);
+        }
+    }
+
+    /// Stream a write run on database `index`, for replicas to run it too
+    pub fn propagate_command(&mut self, index: usize, args: &[Vec<u8>]) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        self.feed(index, encode(args));
+    }
+
+    /// Stream a command as is, for changes that aren't about single keys
+    pub fn propagate<A: AsRef<[u8]>>(&mut self, args: &[A]) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let frame = encode(args);
+        self.send(frame);
+    }
+
+    fn feed(&mut self, index: usize, frame: Vec<u8>) {
+        if index != self.stream_db {
+            self.stream_db = index;
+            self.send(encode(&["select", &index.to_string()]));
+        }
+        self.send(frame);
+    }
+
+    fn send(&mut self, frame: Vec<u8>) {
+        self.offset += (HEADER_LEN + frame.len()) as u64;
+        for id in self.replicas.keys() {
+            self.outbox.push((*id, frame.clone()));
+        }
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(u64, Vec<u8>)> {
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+/// A command as the body of a reply frame
+fn encode<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut out = vec![];
+    out_arr(&mut out, args.len());
+    args.iter().for_each(|arg| out_str(&mut out, arg.as_ref()));
+    out
+}
+
+/// The command recreating `key` as it is now, `None` if it doesn't exist
+fn restore(db: &mut Database, key: &[u8]) -> Option<Vec<u8>> {
+    let (value, expire_at) = db.peek(key)?;
+    let payload = dump::to_hex(&dump::serialize(value));
+    let ttl = expire_at.unwrap_or(0).to_string();
+    Some(encode(&[&b"restore"[..], key, ttl.as_bytes(), payload.as_bytes(), b"REPLACE", b"ABSTTL"]))
+}
+
+/// Copy the stream of the primary at `host:port` into events for the
+/// executor until `current` moves past `link`, reconnecting after errors
+pub(super) fn follow(link: u64, host: String, port: u16, listening_port: u16, current: Arc<AtomicU64>, events: Sender<Event>) {
+    let wanted = || current.load(Ordering::Relaxed) == link;
+    while wanted() {
+        if let Err(e) = stream(link, &host, port, listening_port, &wanted, &events) {
+            warning!("Replication link to {}:{} failed: {}", host, port, e);
+        }
+        if events.send(Event::PrimaryDown { link }).is_err() {
+            return;
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn stream(
+    link: u64,
+    host: &str,
+    port: u16,
+    listening_port: u16,
+    wanted: &dyn Fn() -> bool,
+    events: &Sender<Event>,
+) -> std::io::Result<()> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no address for the primary"))?;
+    let mut reader = FrameReader { stream: TcpStream::connect_timeout(&addr, LINK_TIMEOUT)?, buf: vec![], pos: 0 };
+    reader.stream.set_nodelay(true)?;
+    reader.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    reader.stream.set_write_timeout(Some(LINK_TIMEOUT))?;
+
+    let mut request = vec![];
+    encode_request(&["replconf", "listening-port", &listening_port.to_string()], &mut request).map_err(invalid)?;
+    encode_request(&["sync"], &mut request).map_err(invalid)?;
+    reader.stream.write_all(&request)?;
+    let mut replies = vec![];
+    while replies.len() < 2 {
+        let Some(body) = reader.next(wanted)? else {
+            return Ok(());
+        };
+        replies.push(Reply::decode(&body).and_then(Reply::into_result).map_err(invalid)?);
+    }
+    let (offset, mut snapshot) = match replies.pop() {
+        Some(Reply::Array(items)) => match items[..] {
+            [Reply::Int(offset), Reply::Int(frames)] => (offset as u64, frames as usize),
+            _ => return Err(invalid("unexpected reply to sync")),
+        },
+        _ => return Err(invalid("unexpected reply to sync")),
+    };
+    if events.send(Event::Synced { link, offset }).is_err() {
+        return Ok(());
+    }
+
+    while let Some(body) = reader.next(wanted)? {
+        let args = match Reply::decode(&body).map_err(invalid)? {
+            Reply::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Reply::Str(s) => Ok(s),
+                    _ => Err(invalid("replicated command with a non-string argument")),
+                })
+                .collect::<std::io::Result<Vec<Vec<u8>>>>()?,
+            _ => return Err(invalid("replicated frame isn't a command")),
+        };
+        // The snapshot comes before the offset we were given
+        let bytes = if snapshot > 0 {
+            snapshot -= 1;
+            0
+        } else {
+            HEADER_LEN + body.len()
+        };
+        if events.send(Event::Replicated { link, args, bytes }).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn invalid<E: ToString>(e: E) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads reply frames, giving up between reads once the link isn't wanted
+struct FrameReader {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// Start of the unread data in `buf`
+    pos: usize,
+}
+
+impl FrameReader {
+    /// The body of the next frame, `None` once `wanted` says so
+    fn next(&mut self, wanted: &dyn Fn() -> bool) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            let unread = &self.buf[self.pos..];
+            if unread.len() >= HEADER_LEN {
+                let len = reply_len(unread[..HEADER_LEN].try_into().unwrap()).map_err(invalid)?;
+                if unread.len() >= HEADER_LEN + len {
+                    let body = unread[HEADER_LEN..HEADER_LEN + len].to_vec();
+                    self.pos += HEADER_LEN + len;
+                    return Ok(Some(body));
+                }
+            }
+            if !wanted() {
+                return Ok(None);
+            }
+            if self.pos > 0 {
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(body: &[u8]) -> Vec<String> {
+        match Reply::decode(body).unwrap() {
+            Reply::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Reply::Str(s) => String::from_utf8(s).unwrap(),
+                    item => panic!("unexpected {:?}", item),
+                })
+                .collect(),
+            reply => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut dbs = [Database::new(), Database::new()];
+        dbs[1].set(b"a".to_vec(), b"1".to_vec());
+        let mut replication = Replication::new();
+        replication.announce(7, 7001);
+        assert_eq!(replication.add_replica(7, None, &mut dbs), (0, 4));
+        assert_eq!(replication.replicas().next().unwrap().port, 7001);
+        let snapshot = replication.take_outbox().into_iter().map(|(_, body)| decode(&body)).collect::<Vec<_>>();
+        assert_eq!(snapshot[0], ["flushall"]);
+        assert_eq!(snapshot[1], ["select", "1"]);
+        assert_eq!(&snapshot[2][..3], ["restore", "a", "0"]);
+        assert_eq!(snapshot[3], ["select", "0"]);
+
+        dbs[1].del(b"a");
+        replication.propagate_changes(1, &mut dbs[1], vec![b"a".to_vec(), b"a".to_vec()], true);
+        let stream = replication.take_outbox().into_iter().map(|(_, body)| decode(&body)).collect::<Vec<_>>();
+        assert_eq!(stream, [vec!["select", "1"], vec!["flushdb"], vec!["del", "a"]]);
+        assert!(replication.offset() > 0);
+
+        // Promoted replicas keep their offset, new replicas drop theirs
+        assert_eq!(replication.follow("localhost", 7000), [7]);
+        assert!(replication.is_replica() && !replication.has_replicas());
+        let link = replication.take_pending_link().unwrap().0;
+        assert_eq!(replication.take_pending_link(), None);
+        assert!(!replication.apply(link - 1, 10));
+        replication.synced(link, 100);
+        assert!(replication.apply(link, 10));
+        assert_eq!(replication.offset(), 110);
+        replication.promote();
+        assert!(!replication.is_replica() && !replication.apply(link, 10));
+        assert_eq!(replication.offset(), 110);
+    }
+}
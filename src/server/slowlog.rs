@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Commands taking this many microseconds or more are logged, negative
+/// disables the log
+pub const DEFAULT_SLOWER_THAN: i64 = 10_000;
+pub const DEFAULT_MAX_LEN: usize = 128;
+
+/// Arguments kept per entry, the last one says how many were left out
+const MAX_ARGS: usize = 32;
+/// Bytes kept per argument
+const MAX_ARG_LEN: usize = 128;
+
+pub struct SlowLogEntry {
+    pub id: u64,
+    /// Unix time the command finished at
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub args: Vec<Vec<u8>>,
+    pub addr: Option<SocketAddr>,
+    pub client_name: String,
+}
+
+/// The most recent slow commands, newest first
+pub struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+    slower_than: i64,
+    max_len: usize,
+}
+
+impl SlowLog {
+    pub fn new(slower_than: i64, max_len: usize) -> SlowLog {
+        SlowLog {
+            entries: VecDeque::new(),
+            next_id: 0,
+            slower_than,
+            max_len,
+        }
+    }
+
+    pub fn slower_than(&self) -> i64 {
+        self.slower_than
+    }
+
+    pub fn set_slower_than(&mut self, micros: i64) {
+        self.slower_than = micros;
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+        self.entries.truncate(max_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The `n` newest entries
+    pub fn newest(&self, n: usize) -> impl Iterator<Item = &SlowLogEntry> {
+        self.entries.iter().take(n)
+    }
+
+    /// Log the command if it took long enough
+    pub fn record(&mut self, args: &[Vec<u8>], duration: Duration, addr: Option<SocketAddr>, client_name: &str) {
+        if self.slower_than < 0 || duration.as_micros() < self.slower_than as u128 {
+            return;
+        }
+
+        let kept = if args.len() > MAX_ARGS { MAX_ARGS - 1 } else { args.len() };
+        let mut logged = args[..kept].iter().map(|arg| truncate(arg)).collect::<Vec<Vec<u8>>>();
+        if kept < args.len() {
+            logged.push(format!("... ({} more arguments)", args.len() - kept).into_bytes());
+        }
+
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration,
+            args: logged,
+            addr,
+            client_name: client_name.to_string(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(self.max_len);
+    }
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(DEFAULT_SLOWER_THAN, DEFAULT_MAX_LEN)
+    }
+}
+
+fn truncate(arg: &[u8]) -> Vec<u8> {
+    if arg.len() <= MAX_ARG_LEN {
+        return arg.to_vec();
+    }
+    let mut kept = arg[..MAX_ARG_LEN].to_vec();
+    kept.extend_from_slice(format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes());
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| i.to_string().into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_threshold_and_bound() {
+        let mut log = SlowLog::new(100, 2);
+        log.record(&args(1), Duration::from_micros(99), None, "");
+        assert!(log.is_empty());
+
+        for _ in 0..3 {
+            log.record(&args(1), Duration::from_micros(100), None, "");
+        }
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.newest(10).map(|e| e.id).collect::<Vec<u64>>(), [2, 1]);
+
+        log.set_slower_than(-1);
+        log.record(&args(1), Duration::from_secs(1), None, "");
+        assert_eq!(log.len(), 2);
+        log.reset();
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn test_truncation() {
+        let mut log = SlowLog::new(0, 10);
+        let mut long = args(40);
+        long[0] = vec![b'x'; 200];
+        log.record(&long, Duration::ZERO, None, "");
+
+        let entry = log.newest(1).next().unwrap();
+        assert_eq!(entry.args.len(), MAX_ARGS);
+        assert_eq!(entry.args[0], format!("{}... (72 more bytes)", "x".repeat(128)).into_bytes());
+        assert_eq!(entry.args[MAX_ARGS - 1], b"... (9 more arguments)");
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub usec: u64,
+}
+
+/// Counters shared by the acceptor, the I/O threads and the executor
+pub struct Stats {
+    pub started: Instant,
+    pub tcp_port: u16,
+    pub io_threads: usize,
+    pub connected_clients: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub commands_processed: AtomicU64,
+    pub net_input_bytes: AtomicU64,
+    pub net_output_bytes: AtomicU64,
+    commands: Mutex<HashMap<&'static str, CommandStats>>,
+}
+
+impl Stats {
+    pub fn new(tcp_port: u16, io_threads: usize) -> Stats {
+        Stats {
+            started: Instant::now(),
+            tcp_port,
+            io_threads,
+            connected_clients: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            commands: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(counter: &AtomicU64, n: u64) {
+        counter.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn record_command(&self, name: &'static str, elapsed: Duration) {
+        Stats::add(&self.commands_processed, 1);
+        let mut commands = self.commands.lock().unwrap();
+        let entry = commands.entry(name).or_default();
+        entry.calls += 1;
+        entry.usec += elapsed.as_micros() as u64;
+    }
+
+    /// Stats of every command called at least once, sorted by name
+    pub fn command_stats(&self) -> Vec<(&'static str, CommandStats)> {
+        let mut stats = self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (*name, *stats))
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|(name, _)| *name);
+        stats
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::protocol::*;
+
+use super::pubsub::PubSub;
+
+/// Channel a redirect target subscribes to for invalidation messages
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// How a client asked to be told about changed keys, see `client tracking`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackingOptions {
+    /// Client getting the invalidation messages instead of this one
+    pub redirect: Option<u64>,
+    /// Hear about every key starting with one of `prefixes`, read or not
+    pub bcast: bool,
+    pub prefixes: Vec<Vec<u8>>,
+    /// No invalidations for the client's own writes
+    pub noloop: bool,
+}
+
+/// Which clients cache which keys. In the default mode a client hears about
+/// a key it read once, the next time it changes; it has to read it again to
+/// hear about later changes.
+#[derive(Default)]
+pub struct Tracking {
+    clients: HashMap<u64, TrackingOptions>,
+    /// Keys read by clients in the default mode, across all databases.
+    /// Clients that stopped tracking are dropped lazily.
+    keys: HashMap<Vec<u8>, BTreeSet<u64>>,
+}
+
+impl Tracking {
+    pub fn new() -> Tracking {
+        Tracking::default()
+    }
+
+    /// Whether any client is tracking
+    pub fn is_active(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    pub fn options(&self, id: u64) -> Option<&TrackingOptions> {
+        self.clients.get(&id)
+    }
+
+    pub fn enable(&mut self, id: u64, options: TrackingOptions) {
+        self.clients.insert(id, options);
+    }
+
+    pub fn disable(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    /// Remember that client `id` read `keys`
+    pub fn remember<'a>(&mut self, id: u64, keys: impl Iterator<Item = &'a [u8]>) {
+        if self.clients.get(&id).is_some_and(|o| !o.bcast) {
+            for key in keys {
+                self.keys.entry(key.to_vec()).or_default().insert(id);
+            }
+        }
+    }
+
+    /// Queue invalidation messages about `keys`, changed by client `writer`
+    pub fn invalidate(&mut self, keys: &[Vec<u8>], writer: u64, pubsub: &mut PubSub) {
+        let mut targets: BTreeMap<u64, BTreeSet<&[u8]>> = BTreeMap::new();
+        for key in keys {
+            for id in self.keys.remove(key).into_iter().flatten() {
+                targets.entry(id).or_default().insert(key);
+            }
+            for (&id, options) in &self.clients {
+                if options.bcast && (options.prefixes.is_empty() || options.prefixes.iter().any(|p| key.starts_with(p))) {
+                    targets.entry(id).or_default().insert(key);
+                }
+            }
+        }
+        for (id, keys) in targets {
+            let Some(options) = self.clients.get(&id) else {
+                continue;
+            };
+            if options.noloop && id == writer {
+                continue;
+            }
+            Tracking::send(id, options, Some(&keys.into_iter().collect::<Vec<_>>()), pubsub);
+        }
+    }
+
+    /// Tell every tracking client to drop its whole cache, after a flush
+    pub fn invalidate_all(&mut self, pubsub: &mut PubSub) {
+        self.keys.clear();
+        for (&id, options) in &self.clients {
+            Tracking::send(id, options, None, pubsub);
+        }
+    }
+
+    /// Without a redirect the message is pushed to the tracking client
+    /// itself, otherwise it goes to the redirect target if it listens on
+    /// `INVALIDATE_CHANNEL`. `None` stands for all keys.
+    fn send(id: u64, options: &TrackingOptions, keys: Option<&[&[u8]]>, pubsub: &mut PubSub) {
+        let mut body = vec![];
+        let target = match options.redirect {
+            Some(target) if pubsub.is_subscribed(target, INVALIDATE_CHANNEL.as_bytes()) => {
+                out_arr(&mut body, 3);
+                out_str(&mut body, b"message");
+                out_str(&mut body, INVALIDATE_CHANNEL.as_bytes());
+                target
+            }
+            Some(_) => return,
+            None => {
+                out_push(&mut body, 2);
+                out_str(&mut body, b"invalidate");
+                id
+            }
+        };
+        match keys {
+            Some(keys) => {
+                out_arr(&mut body, keys.len());
+                for key in keys {
+                    out_str(&mut body, key);
+                }
+            }
+            None => out_nil(&mut body),
+        }
+        pubsub.push(target, body);
+    }
+}
@@ -0,0 +1,231 @@
+//! TLS for client connections: loading certificates into rustls configs
+//! and a stream type that is either plain TCP or TLS over TCP
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ConnectionCommon, RootCertStore, ServerConfig, SideData, StreamOwned};
+
+pub use rustls::{ClientConnection, ServerConnection};
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| invalid(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+    Ok(roots)
+}
+
+/// Server side config. With a CA, clients may present a certificate signed
+/// by it, `require_client_cert` turns that into a must.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    ca: Option<&Path>,
+    require_client_cert: bool,
+) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    let builder = match ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider());
+            let verifier = if require_client_cert { verifier } else { verifier.allow_unauthenticated() };
+            builder.with_client_cert_verifier(verifier.build().map_err(invalid)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?).map_err(invalid)?;
+    Ok(Arc::new(config))
+}
+
+/// Client side config trusting the certificates in `ca`, presenting the
+/// certificate and key in `identity` if the server asks for one
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(invalid)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// A TCP connection, encrypted or not. Works on non-blocking sockets, the
+/// TLS side may then hold on to written data until a later write, read or
+/// flush gets it out.
+pub enum Stream<C> {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<C, TcpStream>>),
+}
+
+impl<C> Stream<C> {
+    pub fn tls(conn: C, sock: TcpStream) -> Stream<C> {
+        Stream::Tls(Box::new(StreamOwned { conn, sock }))
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(sock) => sock,
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl<C, S> Read for Stream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<C, S> Write for Stream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    use super::*;
+    use crate::client::{Client, Error, Pipeline, Reply};
+    use crate::server::{Config, Server};
+
+    /// Write a CA and a server and client certificate signed by it to a
+    /// fresh directory
+    fn generate_certs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+
+        for (file, names) in [("server", vec!["localhost".to_string()]), ("client", vec!["client".to_string()])] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(names).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+            fs::write(dir.join(format!("{}.crt", file)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", file)), key.serialize_pem()).unwrap();
+        }
+        dir
+    }
+
+    fn start_server(dir: &Path, auth_clients: bool) -> (String, String) {
+        let config = Config {
+            bind: "127.0.0.1:0".to_string(),
+            tls_bind: Some("127.0.0.1:0".to_string()),
+            tls_cert_file: Some(dir.join("server.crt")),
+            tls_key_file: Some(dir.join("server.key")),
+            tls_ca_cert_file: Some(dir.join("ca.crt")),
+            tls_auth_clients: auth_clients,
+            ..Config::default()
+        };
+        let server = Server::bind(config).unwrap();
+        let addrs = (server.local_addr().unwrap().to_string(), server.tls_local_addr().unwrap().to_string());
+        std::thread::spawn(move || server.run());
+        addrs
+    }
+
+    #[test]
+    fn test_tls() {
+        let dir = generate_certs("optional");
+        let (plain_addr, tls_addr) = start_server(&dir, false);
+
+        let config = client_config(&dir.join("ca.crt"), None).unwrap();
+        let mut client = Client::connect_tls(&tls_addr, "localhost", config.clone()).unwrap();
+        client.set(b"k", b"v").unwrap();
+        // Both ports serve the same data
+        let mut plain = Client::connect(&plain_addr).unwrap();
+        assert_eq!(plain.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+        // Spans many TLS records both ways
+        let big = vec![b'x'; 4000];
+        let mut pipeline = Pipeline::new();
+        for _ in 0..32 {
+            pipeline.set(b"big", &big).get(b"big");
+        }
+        let replies = client.pipeline(&pipeline).unwrap();
+        assert_eq!(replies[63], Reply::Str(big));
+
+        // The certificate isn't valid for that name
+        let mut wrong = Client::connect_tls(&tls_addr, "example.com", config).unwrap();
+        assert!(matches!(wrong.ping(), Err(Error::Io(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let dir = generate_certs("required");
+        let (_, tls_addr) = start_server(&dir, true);
+
+        let config = client_config(&dir.join("ca.crt"), None).unwrap();
+        let mut anonymous = Client::connect_tls(&tls_addr, "localhost", config).unwrap();
+        assert!(anonymous.ping().is_err());
+
+        let identity = (dir.join("client.crt"), dir.join("client.key"));
+        let config = client_config(&dir.join("ca.crt"), Some((&identity.0, &identity.1))).unwrap();
+        let mut client = Client::connect_tls(&tls_addr, "localhost", config).unwrap();
+        client.ping().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}